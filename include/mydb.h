@@ -0,0 +1,188 @@
+/*
+ * The functions for embedding the engine in C programs, which are defined in src/ffi.rs. They are
+ * modelled on SQLite's: open a connection with mydb_open, prepare a statement with mydb_prepare,
+ * step through its results with mydb_step, and read their values with the mydb_column_*
+ * functions, before freeing the statement with mydb_finalize and the connection with mydb_close.
+ */
+
+#ifndef MYDB_H
+#define MYDB_H
+
+/* Generated from src/ffi.rs by cbindgen, as cbindgen.toml says. Don't edit it by hand. */
+
+#include <stdint.h>
+
+#define MYDB_OK 0
+
+#define MYDB_ERROR 1
+
+#define MYDB_BUSY 5
+
+#define MYDB_READONLY 8
+
+#define MYDB_IOERR 10
+
+#define MYDB_CORRUPT 11
+
+#define MYDB_FULL 13
+
+#define MYDB_CONSTRAINT 19
+
+#define MYDB_MISUSE 21
+
+#define MYDB_ROW 100
+
+#define MYDB_DONE 101
+
+#define MYDB_INTEGER 1
+
+#define MYDB_FLOAT 2
+
+#define MYDB_TEXT 3
+
+#define MYDB_NULL 5
+
+/*
+ A connection, which is `mydb` in C. If it couldn't be opened, it only has the error, so that
+ the program can ask what went wrong before closing it.
+ */
+typedef struct mydb mydb;
+
+/*
+ A prepared statement, which is `mydb_stmt` in C.
+ */
+typedef struct mydb_stmt mydb_stmt;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Open the database at the given path, as `Database::open` does, and point `db` at the
+ connection. It is pointed at one even if opening fails, so that `mydb_errmsg` can say why, and
+ it must be closed either way.
+
+ # Safety
+
+ `path` must be a C string, and `db` must point to where a pointer can be written.
+ */
+int mydb_open(const char *path, mydb **db);
+
+/*
+ Close a connection, unless it has statements that haven't been finalized yet, in which case
+ `MYDB_BUSY` is returned and it stays open. Closing a null connection does nothing.
+
+ # Safety
+
+ `db` must be null or a connection from `mydb_open` that hasn't been closed.
+ */
+int mydb_close(mydb *db);
+
+/*
+ Return the message of the connection's most recent error, which is valid until the next call
+ on the connection.
+
+ # Safety
+
+ `db` must be a connection from `mydb_open` that hasn't been closed.
+ */
+const char *mydb_errmsg(mydb *db);
+
+/*
+ Parse a statement, and point `stmt` at it, ready to be stepped. If it can't be parsed, `stmt`
+ is pointed at null.
+
+ # Safety
+
+ `db` must be a connection from `mydb_open` that hasn't been closed, `sql` must be a C string,
+ and `stmt` must point to where a pointer can be written.
+ */
+int mydb_prepare(mydb *db, const char *sql, mydb_stmt **stmt);
+
+/*
+ Step to the statement's next result, and return `MYDB_ROW` if there is one, or `MYDB_DONE` if
+ there isn't. The statement is executed when it's first stepped, and its results are all read
+ then. They are the rows that the shell would print: those of a SELECT statement or a RETURNING
+ clause, the lines of an EXPLAIN statement's plan, or the rows of a PRAGMA statement.
+
+ # Safety
+
+ `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+ */
+int mydb_step(mydb_stmt *stmt);
+
+/*
+ Return the number of columns in the statement's results. That is 0 for a statement other than
+ SELECT until it is stepped, and for one that has no results.
+
+ # Safety
+
+ `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+ */
+int mydb_column_count(mydb_stmt *stmt);
+
+/*
+ Return the name of a column of the statement's results, or null if there is no such column.
+ It is valid until the statement is finalized.
+
+ # Safety
+
+ `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+ */
+const char *mydb_column_name(mydb_stmt *stmt, int column);
+
+/*
+ Return the type of the current result's value in a column, which is `MYDB_NULL` if there is
+ no such value.
+
+ # Safety
+
+ `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+ */
+int mydb_column_type(mydb_stmt *stmt, int column);
+
+/*
+ Return the current result's value in a column as an integer, which is 0 if it isn't a number.
+ A real number is truncated towards zero.
+
+ # Safety
+
+ `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+ */
+int64_t mydb_column_int64(mydb_stmt *stmt, int column);
+
+/*
+ Return the current result's value in a column as a real number, which is 0 if it isn't a
+ number.
+
+ # Safety
+
+ `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+ */
+double mydb_column_double(mydb_stmt *stmt, int column);
+
+/*
+ Return the current result's value in a column as text, or null if it is NULL. A number is
+ given in decimal. The text is valid until the statement is stepped again or finalized.
+
+ # Safety
+
+ `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+ */
+const char *mydb_column_text(mydb_stmt *stmt, int column);
+
+/*
+ Free a prepared statement. Finalizing a null statement does nothing.
+
+ # Safety
+
+ `stmt` must be null or a statement from `mydb_prepare` that hasn't been finalized, on a
+ connection that hasn't been closed.
+ */
+int mydb_finalize(mydb_stmt *stmt);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MYDB_H */
@@ -0,0 +1,102 @@
+//! A connection for async programs, which is behind the `tokio` feature. Statements are executed
+//! on tokio's pool of threads for blocking work, so that a program's own tasks keep running while
+//! the database reads and writes its file.
+
+use std::io;
+use std::panic;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::vec;
+
+use futures_core::Stream;
+use tokio::task;
+
+use crate::error::Error;
+use crate::exec::Database;
+use crate::sql::Row;
+
+
+/// A connection to a database that is used from async code. Cloning it gives another handle to
+/// the same connection, whose statements are executed one at a time, as a `Database`'s are.
+#[derive(Clone)]
+pub struct AsyncDatabase {
+    database: Arc<Database>,
+}
+
+
+/// The results of a query, as a stream.
+pub struct RowStream {
+    rows: vec::IntoIter<Result<Row, Error>>,
+}
+
+
+impl AsyncDatabase {
+    /// Open the database at the given path, as `Database::open` does.
+    pub async fn open(path: &str) -> Result<Self, Error> {
+        let path = path.to_string();
+        let database = run_blocking(move || Database::open(&path)).await?;
+        Ok(AsyncDatabase::new(database))
+    }
+
+    /// Use a connection that is already open, e.g. one opened with `Database::options`.
+    pub fn new(database: Database) -> Self {
+        AsyncDatabase { database: Arc::new(database) }
+    }
+
+    /// Parse and execute a statement, as `Database::execute` does.
+    pub async fn execute(&self, command: &str) -> Result<usize, Error> {
+        let command = command.to_string();
+        self.call(move |database| database.execute(&command)).await
+    }
+
+    /// Parse and execute a SELECT statement, as `Database::query` does, and return a stream of its
+    /// results.
+    pub async fn query(&self, command: &str) -> Result<RowStream, Error> {
+        let command = command.to_string();
+        let rows = self.call(move |database| Ok(database.query(&command)?.collect::<Vec<_>>()));
+        Ok(RowStream { rows: rows.await?.into_iter() })
+    }
+
+    /// Call the given function with the connection, e.g. to run several statements in a
+    /// transaction. As with a `Database`, the statements that the connection's other handles
+    /// execute while the transaction is open are part of it.
+    pub async fn call<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Database) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let database = Arc::clone(&self.database);
+        run_blocking(move || f(&database)).await
+    }
+}
+
+
+impl Stream for RowStream {
+    type Item = Result<Row, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // The results were all read by the query, so none of them has to be waited for.
+        Poll::Ready(self.rows.next())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rows.size_hint()
+    }
+}
+
+
+/// Run the given function on the pool of threads for blocking work, and return what it returns.
+/// If it panics, the panic is passed on to the task that is waiting for it. If the runtime shuts
+/// down before the function is run, it never is, and an interrupted I/O error is returned.
+async fn run_blocking<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(Error::Io(io::Error::new(io::ErrorKind::Interrupted, e))),
+    }
+}
@@ -0,0 +1,1712 @@
+//! The B-trees that the table and its indexes are stored in, and the cursors that walk them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::exec::{Action, CommitHook, UpdateHook};
+use crate::explain::Profile;
+use crate::parser::{prepare_index_expression, prepare_index_predicate};
+use crate::pager::{
+    HEADER_PAGE_NUM, HEADER_ROOT_PAGE_OFFSET, HEADER_SCHEMA_PAGE_OFFSET, LockLevel,
+    PAGE_CHECKSUM_OFFSET, Pager, SchemaPage, read_indexes, read_schema_page, read_u32, write_u32,
+};
+use crate::sql::{
+    COLUMNS, ColumnDef, ColumnType, Expression, Predicate, Row, UserFunction, Value,
+    value_fits_column,
+};
+
+
+// Each value in a serialized row starts with a one-byte type tag. NULL has nothing after the tag,
+// an integer is followed by its 8 bytes, and text by a 4-byte length and then its bytes.
+const VALUE_TAG_SIZE: usize = 1;
+pub(crate) const NULL_TAG: u8 = 0;
+const INTEGER_TAG: u8 = 1;
+const TEXT_TAG: u8 = 2;
+const INTEGER_SIZE: usize = 8;
+const TEXT_LENGTH_SIZE: usize = 4;
+pub(crate) const ROW_SIZE: usize = max_row_size();
+
+// Common node header layout.
+const NODE_TYPE_SIZE: usize = 1;
+pub(crate) const NODE_TYPE_OFFSET: usize = 0;
+const IS_ROOT_SIZE: usize = 1;
+const IS_ROOT_OFFSET: usize = NODE_TYPE_SIZE;
+const PARENT_POINTER_SIZE: usize = 4;
+const PARENT_POINTER_OFFSET: usize = IS_ROOT_OFFSET + IS_ROOT_SIZE;
+const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
+
+// A page that is no longer used is put on the free list. It is marked by its node type, and is
+// followed by the number of the next page in the list, or 0 if it is the last one.
+pub(crate) const FREE_PAGE_TYPE: u8 = 2;
+pub(crate) const FREE_PAGE_NEXT_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+
+// Leaf node header layout. The next leaf pointer is 0 for the rightmost leaf, since page 0 is
+// always the header and so can never be a sibling.
+const LEAF_NODE_NUM_CELLS_SIZE: usize = 4;
+const LEAF_NODE_NUM_CELLS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+const LEAF_NODE_NEXT_LEAF_SIZE: usize = 4;
+const LEAF_NODE_NEXT_LEAF_OFFSET: usize = LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE;
+const LEAF_NODE_HEADER_SIZE: usize =
+    COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_CELLS_SIZE + LEAF_NODE_NEXT_LEAF_SIZE;
+
+// The body of a leaf node is an array of cells, each of which is a key followed by a value. Their
+// sizes depend on the tree (see TreeLayout).
+
+// Internal node header layout.
+const INTERNAL_NODE_NUM_KEYS_SIZE: usize = 4;
+const INTERNAL_NODE_NUM_KEYS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+const INTERNAL_NODE_RIGHT_CHILD_SIZE: usize = 4;
+const INTERNAL_NODE_RIGHT_CHILD_OFFSET: usize =
+    INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE;
+const INTERNAL_NODE_HEADER_SIZE: usize =
+    COMMON_NODE_HEADER_SIZE + INTERNAL_NODE_NUM_KEYS_SIZE + INTERNAL_NODE_RIGHT_CHILD_SIZE;
+
+// Internal node body layout. Each cell is a child pointer followed by the maximum key in that
+// child's subtree. Keys greater than all of them are in the right child.
+const INTERNAL_NODE_CHILD_SIZE: usize = 4;
+
+// Marks the right child of an internal node that is in the middle of being split.
+const INVALID_PAGE_NUM: usize = u32::MAX as usize;
+
+// The table's B-tree is keyed by the rows' ids, as big-endian integers so that they sort the same
+// way byte by byte as they do numerically.
+pub(crate) const ID_KEY_SIZE: usize = 4;
+pub(crate) const TABLE_LAYOUT: TreeLayout =
+    TreeLayout { key_size: ID_KEY_SIZE, value_size: ROW_SIZE };
+
+
+/// The sizes of the keys and values in the cells of a B-tree. Keys are compared byte by byte, so
+/// they must be encoded such that that is the order they should be in.
+#[derive(Clone, Copy)]
+pub(crate) struct TreeLayout {
+    key_size: usize,
+    value_size: usize,
+}
+
+
+impl TreeLayout {
+    /// Return the layout of an index on the given column. Its keys are the encoded value followed
+    /// by the row's id, and it has no values.
+    fn for_index(column: usize) -> TreeLayout {
+        TreeLayout { key_size: index_value_size(&COLUMNS[column]) + ID_KEY_SIZE, value_size: 0 }
+    }
+
+    fn leaf_cell_size(&self) -> usize {
+        self.key_size + self.value_size
+    }
+
+    pub(crate) fn leaf_max_cells(&self) -> usize {
+        (PAGE_CHECKSUM_OFFSET - LEAF_NODE_HEADER_SIZE) / self.leaf_cell_size()
+    }
+
+    /// Return the number of cells that stay in the old node when a full leaf node is split.
+    fn leaf_left_split_count(&self) -> usize {
+        (self.leaf_max_cells() + 1) - self.leaf_max_cells().div_ceil(2)
+    }
+
+    /// Non-root leaves with fewer cells than this after a delete are merged with or borrow from a
+    /// sibling.
+    pub(crate) fn leaf_min_cells(&self) -> usize {
+        self.leaf_max_cells() / 2
+    }
+
+    fn internal_cell_size(&self) -> usize {
+        INTERNAL_NODE_CHILD_SIZE + self.key_size
+    }
+
+    pub(crate) fn internal_max_keys(&self) -> usize {
+        // Kept small under test so that internal node splits are exercised without needing
+        // thousands of rows.
+        if cfg!(test) {
+            3
+        } else {
+            (PAGE_CHECKSUM_OFFSET - INTERNAL_NODE_HEADER_SIZE) / self.internal_cell_size()
+        }
+    }
+
+    fn internal_min_keys(&self) -> usize {
+        self.internal_max_keys() / 2
+    }
+}
+
+
+/// A B-tree in the database file: either the table itself, or one of its indexes.
+#[derive(Clone, Copy)]
+pub(crate) struct Tree {
+    pub(crate) root_page_num: usize,
+    pub(crate) layout: TreeLayout,
+}
+
+
+/// Represents the binary format of a database table.
+pub(crate) struct Table {
+    pub(crate) root_page_num: usize,
+    pub(crate) pager: Pager,
+    // The id of the most recently inserted row in this session, or 0 if there has not been one.
+    pub(crate) last_insert_rowid: u32,
+    // The number of rows that the most recent INSERT, COPY or DELETE statement changed.
+    pub(crate) changes: usize,
+    pub(crate) indexes: Vec<Index>,
+    pub(crate) views: Vec<View>,
+    pub(crate) triggers: Vec<Trigger>,
+    // What ANALYZE last found out about the table, if it has been run.
+    pub(crate) stats: Option<Stats>,
+    // The databases attached to this one, with the names that queries refer to them by. Each has
+    // its own pager and catalog.
+    pub(crate) attached: Vec<(String, Table)>,
+    // Called after each row that a statement inserts or deletes.
+    pub(crate) update_hook: Option<UpdateHook>,
+    // Called before a transaction with changes is committed, which it rolls back instead by
+    // returning true.
+    pub(crate) commit_hook: Option<CommitHook>,
+    // The functions that the program defined, which statements parsed while the connection's
+    // state is locked can call.
+    pub(crate) functions: Vec<Arc<UserFunction>>,
+    // The rows of the common table expressions of the statement being executed, by their names
+    // in lowercase, which are computed before its SELECTs read them.
+    pub(crate) common_tables: HashMap<String, Vec<Row>>,
+    // The rows of the views that the statement being executed reads, by their names in
+    // lowercase, which are likewise computed before it reads them.
+    pub(crate) view_rows: HashMap<String, Vec<Row>>,
+    // Whether a trigger's statements are being executed, since they don't fire triggers
+    // themselves.
+    pub(crate) in_trigger: bool,
+    // The steps of the statement being executed for EXPLAIN ANALYZE, if it is.
+    pub(crate) profile: Option<Profile>,
+}
+
+
+impl Table {
+    /// Return the table's own B-tree, which holds its rows.
+    pub(crate) fn tree(&self) -> Tree {
+        Tree { root_page_num: self.root_page_num, layout: TABLE_LAYOUT }
+    }
+
+    /// Take a lock on the database of at least the given level. If another connection changed the
+    /// database since this one last had a lock on it, the root and the indexes are read again.
+    pub(crate) fn lock(&mut self, level: LockLevel) -> Result<(), Error> {
+        if self.pager.lock(level)? {
+            self.read_schema()?;
+        }
+        Ok(())
+    }
+
+    /// Read the page number of the table's root and the description of its indexes from the
+    /// header, and the views, triggers, statistics and the expressions and WHERE clauses of
+    /// indexes from the page that holds them.
+    pub(crate) fn read_schema(&mut self) -> Result<(), Error> {
+        let header = self.pager.get_page_ref(HEADER_PAGE_NUM)?;
+        self.root_page_num = read_u32(header, HEADER_ROOT_PAGE_OFFSET) as usize;
+        self.indexes = read_indexes(header);
+        let schema_page_num = read_u32(header, HEADER_SCHEMA_PAGE_OFFSET) as usize;
+        let schema = match schema_page_num {
+            0 => SchemaPage::default(),
+            page_num => read_schema_page(self.pager.get_page_ref(page_num)?),
+        };
+        self.views = schema.views;
+        self.triggers = schema.triggers;
+        self.stats = schema.stats;
+        for (name, text) in schema.expressions {
+            let index = self.indexes.iter_mut().find(|index| index.name == name);
+            if let (Some(index), Ok(expression)) = (index, prepare_index_expression(&text)) {
+                index.expression = Some((text, expression));
+            }
+        }
+        for (name, text) in schema.where_clauses {
+            // The text was parsed when the index was created, and the page it is read from has a
+            // checksum, so it can be parsed again.
+            let index = self.indexes.iter_mut().find(|index| index.name == name);
+            if let (Some(index), Ok(predicate)) = (index, prepare_index_predicate(&text)) {
+                index.where_clause = Some((text, predicate));
+            }
+        }
+        Ok(())
+    }
+
+    /// Outside of a transaction, write the changes that a statement made to the database file and
+    /// release the lock on the database. In WAL mode, this ends the connection's read, so that
+    /// the next one sees what was committed since.
+    pub(crate) fn finish_statement(&mut self) -> Result<(), Error> {
+        if self.pager.in_transaction() {
+            return Ok(());
+        }
+        if self.pager.has_changes() {
+            self.lock(LockLevel::Exclusive)?;
+            if let Err(e) = self.pager.write_changes() {
+                // The error worth reporting is the one that writing failed with.
+                let _ = self.pager.unlock(LockLevel::Unlocked);
+                return Err(Error::Io(e));
+            }
+        }
+        self.pager.unlock(LockLevel::Unlocked)?;
+        Ok(())
+    }
+}
+
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        // A transaction that was never committed is rolled back.
+        self.pager.rollback();
+        let _ = self.finish_statement();
+    }
+}
+
+
+/// A secondary index on a column of the table. The keys of its B-tree are the column's value
+/// followed by the row's id, so that the entries for rows with the same value are next to each
+/// other, in order of id.
+pub(crate) struct Index {
+    pub(crate) name: String,
+    pub(crate) column: usize,
+    pub(crate) root_page_num: usize,
+    // For an index on an expression of the column, the text of the expression and the expression
+    // parsed from it. The keys hold the expression's values instead of the column's.
+    pub(crate) expression: Option<(String, Expression)>,
+    // For a partial index, the text of its WHERE clause and the predicate parsed from it. Only the
+    // rows that match the predicate have entries in the index.
+    pub(crate) where_clause: Option<(String, Predicate)>,
+}
+
+
+impl Index {
+    pub(crate) fn tree(&self) -> Tree {
+        Tree { root_page_num: self.root_page_num, layout: TreeLayout::for_index(self.column) }
+    }
+
+    /// Return whether the given row has an entry in the index.
+    pub(crate) fn covers(&self, row: &Row) -> bool {
+        self.where_clause.as_ref().is_none_or(|(_, predicate)| predicate.matches(row))
+    }
+
+    /// Return the value in the key of the given row's entry in the index. The keys are laid out
+    /// for the column's values, so an expression's text is cut short to the column's maximum
+    /// length, and a value that isn't of the column's type is NULL.
+    pub(crate) fn value(&self, row: &Row) -> Value {
+        let column = &COLUMNS[self.column];
+        let expression = match &self.expression {
+            Some((_, expression)) => expression,
+            None => return row.values[self.column].clone(),
+        };
+        match expression.evaluate(row) {
+            Value::Text(mut s) if column.column_type == ColumnType::Text => {
+                let mut length = s.len().min(column.max_length);
+                while !s.is_char_boundary(length) {
+                    length -= 1;
+                }
+                s.truncate(length);
+                Value::Text(s)
+            },
+            value if value_fits_column(&value, column) => value,
+            _ => Value::Null,
+        }
+    }
+
+    /// Return the key of the given row's entry in the index.
+    pub(crate) fn key(&self, row: &Row) -> Vec<u8> {
+        let mut key = encode_index_value(&self.value(row), &COLUMNS[self.column]);
+        key.extend_from_slice(&row.id().to_be_bytes());
+        key
+    }
+}
+
+
+/// A view, which is a SELECT with a name that queries can read from as if it were the table. Its
+/// text is parsed again each time a query reads it.
+#[derive(Clone)]
+pub(crate) struct View {
+    pub(crate) name: String,
+    pub(crate) text: String,
+}
+
+
+/// A trigger, whose statements are executed after each row that an INSERT, COPY or DELETE
+/// statement inserts or deletes, as its action says. They are kept as text, and parsed again with
+/// the row's values in place of `new.<column>` or `old.<column>` each time the trigger fires.
+#[derive(Clone)]
+pub(crate) struct Trigger {
+    pub(crate) name: String,
+    pub(crate) action: Action,
+    pub(crate) body: String,
+}
+
+
+/// What ANALYZE found out about the table and its indexes, which the planner uses to estimate how
+/// many rows match a condition. It isn't kept up to date as rows change, but only when ANALYZE is
+/// run again.
+#[derive(Clone)]
+pub(crate) struct Stats {
+    pub(crate) num_rows: usize,
+    pub(crate) indexes: Vec<IndexStats>,
+}
+
+
+/// What ANALYZE found out about the values in an index.
+#[derive(Clone)]
+pub(crate) struct IndexStats {
+    pub(crate) name: String,
+    pub(crate) num_distinct: usize,
+    // The values of entries spread evenly through the index, in order, so that about as many
+    // entries come after each as after any other. Each is encoded as in the index's keys, but
+    // without the zero bytes at the end.
+    pub(crate) samples: Vec<Vec<u8>>,
+}
+
+
+impl IndexStats {
+    /// Estimate the fraction of the entries of the index on the given column whose keys are in
+    /// the range `[start, end)`, where an end of None means the rest of the index, from the
+    /// fraction of the samples in it.
+    pub(crate) fn estimate_fraction(
+        &self, column: &ColumnDef, start: &[u8], end: Option<&[u8]>
+    ) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let num_in_range = self.samples.iter()
+            .filter(|sample| {
+                let mut value = sample.to_vec();
+                value.resize(index_value_size(column), 0);
+                *value >= *start && end.is_none_or(|end| *value < *end)
+            })
+            .count();
+        let num_samples = self.samples.len();
+        if num_in_range > 0 {
+            return num_in_range as f64 / num_samples as f64;
+        }
+
+        // A range can hold entries without holding a sample, though fewer than lie between two
+        // samples. A value that several samples have is taken to have as many entries as they
+        // stand for, and the entries left over are shared evenly between the other values.
+        let (mut num_common_values, mut num_common_samples) = (0, 0);
+        for run in self.samples.chunk_by(|a, b| a == b).filter(|run| run.len() > 1) {
+            num_common_values += 1;
+            num_common_samples += run.len();
+        }
+        let num_other_values = self.num_distinct.saturating_sub(num_common_values).max(1);
+        let other_fraction = (num_samples - num_common_samples) as f64 / num_samples as f64;
+        (other_fraction / num_other_values as f64).min(1.0 / num_samples as f64)
+    }
+}
+
+
+/// Return the number of bytes that a value of the given column takes up in an index key.
+pub(crate) fn index_value_size(column: &ColumnDef) -> usize {
+    VALUE_TAG_SIZE + match column.column_type {
+        ColumnType::Integer | ColumnType::Real => INTEGER_SIZE,
+        ColumnType::Text => column.max_length,
+    }
+}
+
+
+/// Encode a value for an index key, such that the encodings of two values compare byte by byte
+/// the same way as the values themselves.
+pub(crate) fn encode_index_value(value: &Value, column: &ColumnDef) -> Vec<u8> {
+    let mut bytes = vec![0; index_value_size(column)];
+    match value {
+        Value::Null => bytes[0] = NULL_TAG,
+        Value::Integer(n) => {
+            bytes[0] = INTEGER_TAG;
+            // Flipping the sign bit puts the negative numbers before the positive ones.
+            let biased = (*n as u64) ^ (1 << 63);
+            let number = &mut bytes[VALUE_TAG_SIZE..VALUE_TAG_SIZE+INTEGER_SIZE];
+            number.copy_from_slice(&biased.to_be_bytes());
+        },
+        Value::Real(_) => unreachable!("no column holds real numbers"),
+        Value::Text(s) => {
+            // The zero bytes after the text sort a string before any longer string that starts
+            // with it.
+            bytes[0] = TEXT_TAG;
+            bytes[VALUE_TAG_SIZE..VALUE_TAG_SIZE+s.len()].copy_from_slice(s.as_bytes());
+        },
+    }
+    bytes
+}
+
+
+/// Decode a value from an index key, as encode_index_value encoded it. Text is followed by zero
+/// bytes up to the column's maximum length, which are taken off.
+pub(crate) fn decode_index_value(bytes: &[u8]) -> Value {
+    match bytes[0] {
+        NULL_TAG => Value::Null,
+        INTEGER_TAG => {
+            let mut number = [0; INTEGER_SIZE];
+            number.copy_from_slice(&bytes[VALUE_TAG_SIZE..VALUE_TAG_SIZE+INTEGER_SIZE]);
+            Value::Integer((u64::from_be_bytes(number) ^ (1 << 63)) as i64)
+        },
+        _ => {
+            let text = &bytes[VALUE_TAG_SIZE..];
+            let length = text.iter().rposition(|&byte| byte != 0).map_or(0, |i| i + 1);
+            Value::Text(String::from_utf8_lossy(&text[..length]).into_owned())
+        },
+    }
+}
+
+
+/// Represents a location in one of the B-trees of a table.
+pub(crate) struct Cursor<'a> {
+    pub(crate) table: &'a mut Table,
+    tree: Tree,
+    pub(crate) page_num: usize,
+    pub(crate) cell_num: usize,
+    pub(crate) end_of_table: bool,
+}
+
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn from_start(table: &mut Table) -> Result<Cursor<'_>, Error> {
+        let tree = table.tree();
+        // No key is less than the empty key.
+        Cursor::seek(table, tree, &[])
+    }
+
+    /// Return a cursor at the first row whose id is not less than the given key.
+    pub(crate) fn from_key(table: &mut Table, key: u32) -> Result<Cursor<'_>, Error> {
+        let tree = table.tree();
+        Cursor::seek(table, tree, &key.to_be_bytes())
+    }
+
+    /// Return a cursor at the first entry of the tree whose key is not less than the given key.
+    pub(crate) fn seek<'t>(
+        table: &'t mut Table, tree: Tree, key: &[u8]
+    ) -> Result<Cursor<'t>, Error> {
+        let mut cursor = Cursor::find_in(table, tree, key)?;
+        let node = cursor.table.pager.get_page_ref(cursor.page_num)?;
+        if cursor.cell_num >= leaf_node_num_cells(node) {
+            // All of the keys in this leaf are smaller, so the entry is the first one in the next
+            // leaf, if there is one.
+            match leaf_node_next_leaf(node) {
+                0 => cursor.end_of_table = true,
+                next_page_num => {
+                    cursor.page_num = next_page_num;
+                    cursor.cell_num = 0;
+                },
+            }
+        }
+        Ok(cursor)
+    }
+
+    /// Return a cursor at the position of the given key in the table, or at the position where
+    /// the key would be inserted if it is not in the table.
+    pub(crate) fn find(table: &mut Table, key: u32) -> Result<Cursor<'_>, Error> {
+        let tree = table.tree();
+        Cursor::find_in(table, tree, &key.to_be_bytes())
+    }
+
+    /// Like find, but in the given tree.
+    pub(crate) fn find_in<'t>(
+        table: &'t mut Table, tree: Tree, key: &[u8]
+    ) -> Result<Cursor<'t>, Error> {
+        let mut page_num = tree.root_page_num;
+        loop {
+            let node = table.pager.get_page_ref(page_num)?;
+            match get_node_type(node) {
+                NodeType::Leaf => {
+                    let cell_num = leaf_node_find(node, tree.layout, key);
+                    return Ok(Cursor { table, tree, page_num, cell_num, end_of_table: false });
+                },
+                NodeType::Internal => {
+                    if internal_node_num_keys(node) > tree.layout.internal_max_keys() {
+                        return Err(Error::Corrupt("malformed B-tree node"));
+                    }
+                    let child_index = internal_node_find_child(node, tree.layout, key);
+                    page_num = internal_node_child(node, tree.layout, child_index)?;
+                },
+            }
+        }
+    }
+
+    /// Return a copy of the key of the entry that the cursor points to.
+    pub(crate) fn key(&mut self) -> Result<Vec<u8>, Error> {
+        let node = self.table.pager.get_page_ref(self.page_num)?;
+        Ok(leaf_node_key(node, self.tree.layout, self.cell_num).to_vec())
+    }
+
+    /// Return true if the cursor points to the entry with the given key.
+    pub(crate) fn is_at_key(&mut self, key: &[u8]) -> Result<bool, Error> {
+        let node = self.table.pager.get_page_ref(self.page_num)?;
+        Ok(self.cell_num < leaf_node_num_cells(node)
+            && leaf_node_key(node, self.tree.layout, self.cell_num) == key)
+    }
+
+    pub(crate) fn advance(&mut self) -> Result<(), Error> {
+        let node = self.table.pager.get_page_ref(self.page_num)?;
+        self.cell_num += 1;
+        if self.cell_num >= leaf_node_num_cells(node) {
+            // Advance to the next leaf node, unless this was the rightmost leaf.
+            let next_page_num = leaf_node_next_leaf(node);
+            if next_page_num == 0 {
+                self.end_of_table = true;
+            } else {
+                self.page_num = next_page_num;
+                self.cell_num = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum NodeType {
+    Internal,
+    Leaf,
+}
+
+
+pub(crate) fn initialize_leaf_node(node: &mut [u8]) {
+    set_node_type(node, NodeType::Leaf);
+    set_node_root(node, false);
+    set_leaf_node_num_cells(node, 0);
+    set_leaf_node_next_leaf(node, 0);
+}
+
+
+fn initialize_internal_node(node: &mut [u8]) {
+    set_node_type(node, NodeType::Internal);
+    set_node_root(node, false);
+    set_internal_node_num_keys(node, 0);
+    set_internal_node_right_child(node, INVALID_PAGE_NUM);
+}
+
+
+pub(crate) fn get_node_type(node: &[u8]) -> NodeType {
+    match node[NODE_TYPE_OFFSET] {
+        0 => NodeType::Internal,
+        _ => NodeType::Leaf,
+    }
+}
+
+
+fn set_node_type(node: &mut [u8], node_type: NodeType) {
+    node[NODE_TYPE_OFFSET] = match node_type {
+        NodeType::Internal => 0,
+        NodeType::Leaf => 1,
+    };
+}
+
+
+fn is_node_root(node: &[u8]) -> bool {
+    node[IS_ROOT_OFFSET] != 0
+}
+
+
+pub(crate) fn set_node_root(node: &mut [u8], is_root: bool) {
+    node[IS_ROOT_OFFSET] = u8::from(is_root);
+}
+
+
+pub(crate) fn node_parent(node: &[u8]) -> usize {
+    read_u32(node, PARENT_POINTER_OFFSET) as usize
+}
+
+
+pub(crate) fn set_node_parent(node: &mut [u8], parent_page_num: usize) {
+    write_u32(node, PARENT_POINTER_OFFSET, parent_page_num as u32);
+}
+
+
+/// Return the largest key in the subtree rooted at the given page.
+fn get_node_max_key(
+    pager: &mut Pager, layout: TreeLayout, page_num: usize
+) -> Result<Vec<u8>, Error> {
+    let node = pager.get_page_ref(page_num)?;
+    match get_node_type(node) {
+        NodeType::Leaf => Ok(leaf_node_key(node, layout, leaf_node_num_cells(node) - 1).to_vec()),
+        NodeType::Internal => {
+            let right_child = internal_node_right_child(node);
+            get_node_max_key(pager, layout, right_child)
+        },
+    }
+}
+
+
+pub(crate) fn leaf_node_num_cells(node: &[u8]) -> usize {
+    read_u32(node, LEAF_NODE_NUM_CELLS_OFFSET) as usize
+}
+
+
+pub(crate) fn set_leaf_node_num_cells(node: &mut [u8], num_cells: usize) {
+    write_u32(node, LEAF_NODE_NUM_CELLS_OFFSET, num_cells as u32);
+}
+
+
+pub(crate) fn leaf_node_next_leaf(node: &[u8]) -> usize {
+    read_u32(node, LEAF_NODE_NEXT_LEAF_OFFSET) as usize
+}
+
+
+fn set_leaf_node_next_leaf(node: &mut [u8], next_leaf: usize) {
+    write_u32(node, LEAF_NODE_NEXT_LEAF_OFFSET, next_leaf as u32);
+}
+
+
+/// Return the byte offset of the given cell within a leaf node.
+fn leaf_node_cell_offset(layout: TreeLayout, cell_num: usize) -> usize {
+    LEAF_NODE_HEADER_SIZE + cell_num * layout.leaf_cell_size()
+}
+
+
+pub(crate) fn leaf_node_key(node: &[u8], layout: TreeLayout, cell_num: usize) -> &[u8] {
+    let offset = leaf_node_cell_offset(layout, cell_num);
+    &node[offset..offset+layout.key_size]
+}
+
+
+/// Return the byte offset of the value (for the table, the serialized row) in the given cell of a
+/// leaf node.
+pub(crate) fn leaf_node_value_offset(layout: TreeLayout, cell_num: usize) -> usize {
+    leaf_node_cell_offset(layout, cell_num) + layout.key_size
+}
+
+
+/// Return the index of the first cell in a leaf node whose key is not less than the given key.
+pub(crate) fn leaf_node_find(node: &[u8], layout: TreeLayout, key: &[u8]) -> usize {
+    let mut min_index = 0;
+    let mut one_past_max_index = leaf_node_num_cells(node);
+    while one_past_max_index != min_index {
+        let index = (min_index + one_past_max_index) / 2;
+        if key <= leaf_node_key(node, layout, index) {
+            one_past_max_index = index;
+        } else {
+            min_index = index + 1;
+        }
+    }
+    min_index
+}
+
+
+/// Insert a cell into the leaf node that the cursor points to, at the cursor's position, splitting
+/// the node if it is full.
+pub(crate) fn leaf_node_insert(
+    cursor: &mut Cursor, key: &[u8], value: &[u8]
+) -> Result<(), Error> {
+    let layout = cursor.tree.layout;
+    let node = cursor.table.pager.get_page(cursor.page_num)?;
+
+    let num_cells = leaf_node_num_cells(node);
+    if num_cells >= layout.leaf_max_cells() {
+        leaf_node_split_and_insert(cursor, key, value)?;
+        return Ok(());
+    }
+
+    if cursor.cell_num < num_cells {
+        // Make room for the new cell by shifting the later cells over by one.
+        let start = leaf_node_cell_offset(layout, cursor.cell_num);
+        let end = leaf_node_cell_offset(layout, num_cells);
+        node.copy_within(start..end, leaf_node_cell_offset(layout, cursor.cell_num + 1));
+    }
+
+    set_leaf_node_num_cells(node, num_cells + 1);
+    let offset = leaf_node_cell_offset(layout, cursor.cell_num);
+    node[offset..offset+layout.key_size].copy_from_slice(key);
+    let offset = leaf_node_value_offset(layout, cursor.cell_num);
+    node[offset..offset+layout.value_size].copy_from_slice(value);
+    Ok(())
+}
+
+
+/// Split a full leaf node in two, insert the new cell into whichever half it belongs in, and
+/// update the parent (creating a new root if the split node was the root).
+fn leaf_node_split_and_insert(
+    cursor: &mut Cursor, key: &[u8], value: &[u8]
+) -> Result<(), Error> {
+    let tree = cursor.tree;
+    let layout = tree.layout;
+    let table = &mut *cursor.table;
+    let old_page_num = cursor.page_num;
+    let old_max = get_node_max_key(&mut table.pager, layout, old_page_num)?;
+
+    // Gather the existing cells plus the new one, in order.
+    let old_node = table.pager.get_page(old_page_num)?;
+    let mut cells = leaf_node_cells(old_node, layout);
+    cells.insert(cursor.cell_num, [key, value].concat());
+    let left_split_count = layout.leaf_left_split_count();
+
+    let parent_page_num = node_parent(old_node);
+    let old_next_leaf = leaf_node_next_leaf(old_node);
+    let splitting_root = is_node_root(old_node);
+
+    let new_page_num = table.pager.get_unused_page_num()?;
+    let new_node = table.pager.get_page(new_page_num)?;
+    initialize_leaf_node(new_node);
+    set_node_parent(new_node, parent_page_num);
+    set_leaf_node_next_leaf(new_node, old_next_leaf);
+    write_leaf_node_cells(new_node, layout, &cells[left_split_count..]);
+
+    let old_node = table.pager.get_page(old_page_num)?;
+    set_leaf_node_next_leaf(old_node, new_page_num);
+    write_leaf_node_cells(old_node, layout, &cells[..left_split_count]);
+
+    if splitting_root {
+        create_new_root(table, tree, new_page_num)?;
+    } else {
+        let new_max = get_node_max_key(&mut table.pager, layout, old_page_num)?;
+        let parent = table.pager.get_page(parent_page_num)?;
+        update_internal_node_key(parent, layout, &old_max, &new_max);
+        internal_node_insert(table, tree, parent_page_num, new_page_num)?;
+    }
+    Ok(())
+}
+
+
+/// Handle splitting the root. The old root is copied to a new page and becomes the left child,
+/// the given page becomes the right child, and the root page is reinitialized as an internal node
+/// pointing to both. The root stays at the same page number so that it is always easy to find.
+fn create_new_root(
+    table: &mut Table, tree: Tree, right_child_page_num: usize
+) -> Result<(), Error> {
+    let root_page_num = tree.root_page_num;
+    let layout = tree.layout;
+    let root = table.pager.get_page(root_page_num)?.to_vec();
+    if get_node_type(&root) == NodeType::Internal {
+        initialize_internal_node(table.pager.get_page(right_child_page_num)?);
+    }
+
+    let left_child_page_num = table.pager.get_unused_page_num()?;
+    let left_child = table.pager.get_page(left_child_page_num)?;
+    left_child.copy_from_slice(&root);
+    set_node_root(left_child, false);
+
+    if get_node_type(left_child) == NodeType::Internal {
+        let num_keys = internal_node_num_keys(left_child);
+        let mut children = (0..num_keys)
+            .map(|i| internal_node_child(left_child, layout, i))
+            .collect::<Result<Vec<usize>, Error>>()?;
+        children.push(internal_node_right_child(left_child));
+        for child_page_num in children {
+            set_node_parent(table.pager.get_page(child_page_num)?, left_child_page_num);
+        }
+    }
+
+    let left_child_max_key = get_node_max_key(&mut table.pager, layout, left_child_page_num)?;
+    let root = table.pager.get_page(root_page_num)?;
+    initialize_internal_node(root);
+    set_node_root(root, true);
+    set_internal_node_num_keys(root, 1);
+    set_internal_node_child(root, layout, 0, left_child_page_num);
+    set_internal_node_key(root, layout, 0, &left_child_max_key);
+    set_internal_node_right_child(root, right_child_page_num);
+
+    set_node_parent(table.pager.get_page(left_child_page_num)?, root_page_num);
+    set_node_parent(table.pager.get_page(right_child_page_num)?, root_page_num);
+    Ok(())
+}
+
+
+pub(crate) fn internal_node_num_keys(node: &[u8]) -> usize {
+    read_u32(node, INTERNAL_NODE_NUM_KEYS_OFFSET) as usize
+}
+
+
+pub(crate) fn set_internal_node_num_keys(node: &mut [u8], num_keys: usize) {
+    write_u32(node, INTERNAL_NODE_NUM_KEYS_OFFSET, num_keys as u32);
+}
+
+
+pub(crate) fn internal_node_right_child(node: &[u8]) -> usize {
+    read_u32(node, INTERNAL_NODE_RIGHT_CHILD_OFFSET) as usize
+}
+
+
+fn set_internal_node_right_child(node: &mut [u8], page_num: usize) {
+    write_u32(node, INTERNAL_NODE_RIGHT_CHILD_OFFSET, page_num as u32);
+}
+
+
+/// Return the byte offset of the given cell within an internal node.
+fn internal_node_cell_offset(layout: TreeLayout, cell_num: usize) -> usize {
+    INTERNAL_NODE_HEADER_SIZE + cell_num * layout.internal_cell_size()
+}
+
+
+/// Return the page number of the given child of an internal node, where the child after the last
+/// key is the right child.
+pub(crate) fn internal_node_child(
+    node: &[u8], layout: TreeLayout, child_num: usize
+) -> Result<usize, Error> {
+    let num_keys = internal_node_num_keys(node);
+    let offset = internal_node_cell_offset(layout, child_num);
+    if child_num > num_keys || offset + layout.internal_cell_size() > PAGE_CHECKSUM_OFFSET {
+        Err(Error::Corrupt("malformed B-tree node"))
+    } else if child_num == num_keys {
+        Ok(internal_node_right_child(node))
+    } else {
+        Ok(read_u32(node, offset) as usize)
+    }
+}
+
+
+pub(crate) fn set_internal_node_child(
+    node: &mut [u8],
+    layout: TreeLayout,
+    child_num: usize,
+    page_num: usize,
+) {
+    write_u32(node, internal_node_cell_offset(layout, child_num), page_num as u32);
+}
+
+
+pub(crate) fn internal_node_key(node: &[u8], layout: TreeLayout, key_num: usize) -> &[u8] {
+    let offset = internal_node_cell_offset(layout, key_num) + INTERNAL_NODE_CHILD_SIZE;
+    &node[offset..offset+layout.key_size]
+}
+
+
+fn set_internal_node_key(node: &mut [u8], layout: TreeLayout, key_num: usize, key: &[u8]) {
+    let offset = internal_node_cell_offset(layout, key_num) + INTERNAL_NODE_CHILD_SIZE;
+    node[offset..offset+layout.key_size].copy_from_slice(key);
+}
+
+
+/// Return the index of the child of an internal node which should contain the given key.
+pub(crate) fn internal_node_find_child(node: &[u8], layout: TreeLayout, key: &[u8]) -> usize {
+    let mut min_index = 0;
+    let mut max_index = internal_node_num_keys(node);  // There is one more child than key.
+    while min_index != max_index {
+        let index = (min_index + max_index) / 2;
+        if internal_node_key(node, layout, index) >= key {
+            max_index = index;
+        } else {
+            min_index = index + 1;
+        }
+    }
+    min_index
+}
+
+
+fn update_internal_node_key(node: &mut [u8], layout: TreeLayout, old_key: &[u8], new_key: &[u8]) {
+    let old_child_index = internal_node_find_child(node, layout, old_key);
+    // The right child has no key of its own to update.
+    if old_child_index < internal_node_num_keys(node) {
+        set_internal_node_key(node, layout, old_child_index, new_key);
+    }
+}
+
+
+/// Add a child/key pair to an internal node, splitting the node if it is full.
+fn internal_node_insert(
+    table: &mut Table, tree: Tree, parent_page_num: usize, child_page_num: usize
+) -> Result<(), Error> {
+    let layout = tree.layout;
+    let child_max_key = get_node_max_key(&mut table.pager, layout, child_page_num)?;
+    let parent = table.pager.get_page(parent_page_num)?;
+    let index = internal_node_find_child(parent, layout, &child_max_key);
+
+    let original_num_keys = internal_node_num_keys(parent);
+    if original_num_keys >= layout.internal_max_keys() {
+        internal_node_split_and_insert(table, tree, parent_page_num, child_page_num)?;
+        return Ok(());
+    }
+
+    let right_child_page_num = internal_node_right_child(parent);
+    if right_child_page_num == INVALID_PAGE_NUM {
+        // An empty node produced by a split.
+        set_internal_node_right_child(parent, child_page_num);
+        return Ok(());
+    }
+
+    let right_max_key = get_node_max_key(&mut table.pager, layout, right_child_page_num)?;
+    let parent = table.pager.get_page(parent_page_num)?;
+    set_internal_node_num_keys(parent, original_num_keys + 1);
+
+    if child_max_key > right_max_key {
+        // Replace the right child.
+        set_internal_node_child(parent, layout, original_num_keys, right_child_page_num);
+        set_internal_node_key(parent, layout, original_num_keys, &right_max_key);
+        set_internal_node_right_child(parent, child_page_num);
+    } else {
+        // Make room for the new cell.
+        parent.copy_within(
+            internal_node_cell_offset(layout, index)
+                ..internal_node_cell_offset(layout, original_num_keys),
+            internal_node_cell_offset(layout, index + 1),
+        );
+        set_internal_node_child(parent, layout, index, child_page_num);
+        set_internal_node_key(parent, layout, index, &child_max_key);
+    }
+    Ok(())
+}
+
+
+/// Split a full internal node in two and add the child/key pair to whichever half it belongs in,
+/// then insert the new node into the parent (creating a new root if necessary).
+fn internal_node_split_and_insert(
+    table: &mut Table, tree: Tree, parent_page_num: usize, child_page_num: usize
+) -> Result<(), Error> {
+    let layout = tree.layout;
+    let mut old_page_num = parent_page_num;
+    let old_max = get_node_max_key(&mut table.pager, layout, old_page_num)?;
+    let child_max = get_node_max_key(&mut table.pager, layout, child_page_num)?;
+
+    let new_page_num = table.pager.get_unused_page_num()?;
+    let splitting_root = is_node_root(table.pager.get_page_ref(old_page_num)?);
+
+    let parent_page_num = if splitting_root {
+        create_new_root(table, tree, new_page_num)?;
+        // The old root's contents now live in the new root's left child; the new page is
+        // already the right child.
+        let root = table.pager.get_page_ref(tree.root_page_num)?;
+        old_page_num = internal_node_child(root, layout, 0)?;
+        tree.root_page_num
+    } else {
+        initialize_internal_node(table.pager.get_page(new_page_num)?);
+        node_parent(table.pager.get_page_ref(old_page_num)?)
+    };
+
+    // First move the right child into the new node.
+    let old_right_child = internal_node_right_child(table.pager.get_page_ref(old_page_num)?);
+    internal_node_insert(table, tree, new_page_num, old_right_child)?;
+    set_node_parent(table.pager.get_page(old_right_child)?, new_page_num);
+    set_internal_node_right_child(table.pager.get_page(old_page_num)?, INVALID_PAGE_NUM);
+
+    // Then move each key above the middle, along with its child, into the new node.
+    let max_keys = layout.internal_max_keys();
+    for i in (max_keys / 2 + 1..max_keys).rev() {
+        let cur_page_num = internal_node_child(table.pager.get_page_ref(old_page_num)?, layout, i)?;
+        internal_node_insert(table, tree, new_page_num, cur_page_num)?;
+        set_node_parent(table.pager.get_page(cur_page_num)?, new_page_num);
+
+        let old_node = table.pager.get_page(old_page_num)?;
+        let num_keys = internal_node_num_keys(old_node);
+        set_internal_node_num_keys(old_node, num_keys - 1);
+    }
+
+    // The child before the middle key, which is now the highest key, becomes the old node's
+    // right child.
+    let old_node = table.pager.get_page(old_page_num)?;
+    let num_keys = internal_node_num_keys(old_node);
+    let new_right_child = internal_node_child(old_node, layout, num_keys - 1)?;
+    set_internal_node_right_child(old_node, new_right_child);
+    set_internal_node_num_keys(old_node, num_keys - 1);
+
+    // Insert the child into whichever of the two nodes it belongs in.
+    let max_after_split = get_node_max_key(&mut table.pager, layout, old_page_num)?;
+    let destination_page_num =
+        if child_max < max_after_split { old_page_num } else { new_page_num };
+    internal_node_insert(table, tree, destination_page_num, child_page_num)?;
+    set_node_parent(table.pager.get_page(child_page_num)?, destination_page_num);
+
+    let new_old_max = get_node_max_key(&mut table.pager, layout, old_page_num)?;
+    let parent = table.pager.get_page(parent_page_num)?;
+    update_internal_node_key(parent, layout, &old_max, &new_old_max);
+
+    if !splitting_root {
+        // Set the parent first, since inserting may split the parent and move the new node again.
+        set_node_parent(table.pager.get_page(new_page_num)?, parent_page_num);
+        internal_node_insert(table, tree, parent_page_num, new_page_num)?;
+    }
+    Ok(())
+}
+
+
+/// Remove the cell that the cursor points to from its leaf node, rebalancing the tree if the node
+/// is left with too few cells.
+pub(crate) fn leaf_node_delete(cursor: &mut Cursor) -> Result<(), Error> {
+    let tree = cursor.tree;
+    let layout = tree.layout;
+    let table = &mut *cursor.table;
+    let page_num = cursor.page_num;
+    let node = table.pager.get_page(page_num)?;
+
+    let num_cells = leaf_node_num_cells(node);
+    let old_max = leaf_node_key(node, layout, num_cells - 1).to_vec();
+    let start = leaf_node_cell_offset(layout, cursor.cell_num + 1);
+    let end = leaf_node_cell_offset(layout, num_cells);
+    node.copy_within(start..end, leaf_node_cell_offset(layout, cursor.cell_num));
+    set_leaf_node_num_cells(node, num_cells - 1);
+
+    if is_node_root(node) {
+        return Ok(());
+    }
+
+    if cursor.cell_num == num_cells - 1 {
+        // The node's maximum key changed, so the keys in its ancestors must change too.
+        let new_max = leaf_node_key(node, layout, num_cells - 2).to_vec();
+        update_ancestor_keys(table, layout, page_num, &old_max, &new_max)?;
+    }
+
+    if num_cells - 1 < layout.leaf_min_cells() {
+        rebalance_node(table, tree, page_num)?;
+    }
+    Ok(())
+}
+
+
+/// Replace the maximum key of a node in its ancestors, after it changed from `old_max` to
+/// `new_max`.
+fn update_ancestor_keys(
+    table: &mut Table, layout: TreeLayout, page_num: usize, old_max: &[u8], new_max: &[u8]
+) -> Result<(), Error> {
+    let mut child_page_num = page_num;
+    while !is_node_root(table.pager.get_page_ref(child_page_num)?) {
+        let parent_page_num = node_parent(table.pager.get_page_ref(child_page_num)?);
+        let parent = table.pager.get_page(parent_page_num)?;
+        update_internal_node_key(parent, layout, old_max, new_max);
+
+        // Only the right child's maximum key is also its parent's maximum key.
+        if internal_node_right_child(parent) != child_page_num {
+            break;
+        }
+        child_page_num = parent_page_num;
+    }
+    Ok(())
+}
+
+
+/// Fix up a non-root node that has too few cells, by merging it with a sibling if the two fit in
+/// one node and otherwise by evening out the cells between them.
+fn rebalance_node(table: &mut Table, tree: Tree, page_num: usize) -> Result<(), Error> {
+    let layout = tree.layout;
+    let parent_page_num = node_parent(table.pager.get_page_ref(page_num)?);
+    let mut parent_entries = internal_node_entries(&mut table.pager, layout, parent_page_num)?;
+    let index = parent_entries.iter().position(|(child, _)| *child == page_num).unwrap();
+
+    // Pair the node with its left sibling, or with its right sibling if it is the first child.
+    let left_index = if index > 0 { index - 1 } else { index };
+    let left_page_num = parent_entries[left_index].0;
+    let right_page_num = parent_entries[left_index + 1].0;
+
+    let merged = if get_node_type(table.pager.get_page_ref(page_num)?) == NodeType::Leaf {
+        let left_node = table.pager.get_page(left_page_num)?;
+        let mut cells = leaf_node_cells(left_node, layout);
+        let left_next_leaf = leaf_node_next_leaf(left_node);
+        let right_node = table.pager.get_page_ref(right_page_num)?;
+        cells.extend(leaf_node_cells(right_node, layout));
+        let right_next_leaf = leaf_node_next_leaf(right_node);
+
+        if cells.len() <= layout.leaf_max_cells() {
+            let left_node = table.pager.get_page(left_page_num)?;
+            write_leaf_node_cells(left_node, layout, &cells);
+            set_leaf_node_next_leaf(left_node, right_next_leaf);
+            true
+        } else {
+            let split = cells.len().div_ceil(2);
+            let left_node = table.pager.get_page(left_page_num)?;
+            write_leaf_node_cells(left_node, layout, &cells[..split]);
+            set_leaf_node_next_leaf(left_node, left_next_leaf);
+            write_leaf_node_cells(table.pager.get_page(right_page_num)?, layout, &cells[split..]);
+            false
+        }
+    } else {
+        let mut entries = internal_node_entries(&mut table.pager, layout, left_page_num)?;
+        entries.extend(internal_node_entries(&mut table.pager, layout, right_page_num)?);
+
+        if entries.len() <= layout.internal_max_keys() + 1 {
+            write_internal_node_entries(table.pager.get_page(left_page_num)?, layout, &entries);
+            set_children_parent(&mut table.pager, &entries, left_page_num)?;
+            true
+        } else {
+            let split = entries.len().div_ceil(2);
+            let (left_entries, right_entries) = entries.split_at(split);
+            let left_node = table.pager.get_page(left_page_num)?;
+            write_internal_node_entries(left_node, layout, left_entries);
+            let right_node = table.pager.get_page(right_page_num)?;
+            write_internal_node_entries(right_node, layout, right_entries);
+            set_children_parent(&mut table.pager, left_entries, left_page_num)?;
+            set_children_parent(&mut table.pager, right_entries, right_page_num)?;
+            false
+        }
+    };
+
+    if merged {
+        // The left node takes over the right node's place, and maximum key, in the parent. The
+        // right node's page is no longer used.
+        let (_, right_max) = parent_entries.remove(left_index + 1);
+        parent_entries[left_index].1 = right_max;
+        table.pager.free_page(right_page_num)?;
+    } else {
+        parent_entries[left_index].1 = get_node_max_key(&mut table.pager, layout, left_page_num)?;
+    }
+
+    let parent = table.pager.get_page(parent_page_num)?;
+    write_internal_node_entries(parent, layout, &parent_entries);
+
+    if is_node_root(parent) {
+        if parent_entries.len() == 1 {
+            collapse_root(table, tree)?;
+        }
+    } else if parent_entries.len() - 1 < layout.internal_min_keys() {
+        rebalance_node(table, tree, parent_page_num)?;
+    }
+    Ok(())
+}
+
+
+/// Replace a root internal node that has a single child with that child, shrinking the tree by
+/// one level. The root stays on the same page.
+fn collapse_root(table: &mut Table, tree: Tree) -> Result<(), Error> {
+    let root_page_num = tree.root_page_num;
+    let child_page_num = internal_node_right_child(table.pager.get_page_ref(root_page_num)?);
+    let child = table.pager.get_page_ref(child_page_num)?.to_vec();
+
+    let root = table.pager.get_page(root_page_num)?;
+    root.copy_from_slice(&child);
+    set_node_root(root, true);
+
+    if get_node_type(root) == NodeType::Internal {
+        let entries = internal_node_entries(&mut table.pager, tree.layout, root_page_num)?;
+        set_children_parent(&mut table.pager, &entries, root_page_num)?;
+    }
+    table.pager.free_page(child_page_num)?;
+    Ok(())
+}
+
+
+/// Return copies of all of the cells in a leaf node.
+fn leaf_node_cells(node: &[u8], layout: TreeLayout) -> Vec<Vec<u8>> {
+    (0..leaf_node_num_cells(node))
+        .map(|i| {
+            node[leaf_node_cell_offset(layout, i)..leaf_node_cell_offset(layout, i + 1)].to_vec()
+        })
+        .collect()
+}
+
+
+/// Replace all of the cells in a leaf node.
+fn write_leaf_node_cells(node: &mut [u8], layout: TreeLayout, cells: &[Vec<u8>]) {
+    for (i, cell) in cells.iter().enumerate() {
+        node[leaf_node_cell_offset(layout, i)..leaf_node_cell_offset(layout, i + 1)]
+            .copy_from_slice(cell);
+    }
+    set_leaf_node_num_cells(node, cells.len());
+}
+
+
+/// Return (child page number, maximum key) for every child of an internal node, including the
+/// right child.
+fn internal_node_entries(
+    pager: &mut Pager, layout: TreeLayout, page_num: usize
+) -> Result<Vec<(usize, Vec<u8>)>, Error> {
+    let node = pager.get_page_ref(page_num)?;
+    let num_keys = internal_node_num_keys(node);
+    let mut entries = (0..num_keys)
+        .map(|i| {
+            let child = internal_node_child(node, layout, i)?;
+            Ok((child, internal_node_key(node, layout, i).to_vec()))
+        })
+        .collect::<Result<Vec<(usize, Vec<u8>)>, Error>>()?;
+
+    let right_child = internal_node_right_child(node);
+    entries.push((right_child, get_node_max_key(pager, layout, right_child)?));
+    Ok(entries)
+}
+
+
+/// Replace all of the children of an internal node. The last entry becomes the right child.
+fn write_internal_node_entries(node: &mut [u8], layout: TreeLayout, entries: &[(usize, Vec<u8>)]) {
+    let num_keys = entries.len() - 1;
+    for (i, (child, key)) in entries[..num_keys].iter().enumerate() {
+        set_internal_node_child(node, layout, i, *child);
+        set_internal_node_key(node, layout, i, key);
+    }
+    set_internal_node_num_keys(node, num_keys);
+    set_internal_node_right_child(node, entries[num_keys].0);
+}
+
+
+fn set_children_parent(
+    pager: &mut Pager, entries: &[(usize, Vec<u8>)], parent_page_num: usize
+) -> Result<(), Error> {
+    for (child, _) in entries {
+        set_node_parent(pager.get_page(*child)?, parent_page_num);
+    }
+    Ok(())
+}
+
+
+/// Insert entries, which are (key, value) pairs in order of key that aren't already in the tree,
+/// into the given B-tree. If the tree is empty, it is built from the leaves up instead of one
+/// entry at a time, which writes each page once and never splits a node.
+pub(crate) fn insert_sorted(
+    table: &mut Table, tree: Tree, entries: &[(Vec<u8>, Vec<u8>)]
+) -> Result<(), Error> {
+    let root = table.pager.get_page_ref(tree.root_page_num)?;
+    if get_node_type(root) == NodeType::Leaf && leaf_node_num_cells(root) == 0 {
+        build_tree(table, tree, entries)?;
+        return Ok(());
+    }
+
+    for (key, value) in entries {
+        let mut cursor = Cursor::find_in(table, tree, key)?;
+        leaf_node_insert(&mut cursor, key, value)?;
+    }
+    Ok(())
+}
+
+
+/// Fill an empty B-tree with entries in order of key: first the leaves, and then each level of
+/// internal nodes above them until one node, the root, holds the rest. Each level's cells are
+/// spread evenly over as few nodes as they fit in, so that none of them has too few.
+fn build_tree(
+    table: &mut Table, tree: Tree, entries: &[(Vec<u8>, Vec<u8>)]
+) -> Result<(), Error> {
+    let layout = tree.layout;
+    let cells: Vec<Vec<u8>> =
+        entries.iter().map(|(key, value)| [&key[..], value].concat()).collect();
+    if cells.len() <= layout.leaf_max_cells() {
+        write_leaf_node_cells(table.pager.get_page(tree.root_page_num)?, layout, &cells);
+        return Ok(());
+    }
+
+    // Each level is the (page number, maximum key) of each of its nodes, in order.
+    let mut level = Vec::new();
+    for chunk in even_chunks(&cells, layout.leaf_max_cells()) {
+        let page_num = table.pager.get_unused_page_num()?;
+        let node = table.pager.get_page(page_num)?;
+        initialize_leaf_node(node);
+        write_leaf_node_cells(node, layout, chunk);
+        if let Some(&(previous_page_num, _)) = level.last() {
+            set_leaf_node_next_leaf(table.pager.get_page(previous_page_num)?, page_num);
+        }
+        level.push((page_num, chunk[chunk.len() - 1][..layout.key_size].to_vec()));
+    }
+
+    while level.len() > layout.internal_max_keys() + 1 {
+        let mut parents = Vec::new();
+        for chunk in even_chunks(&level, layout.internal_max_keys() + 1) {
+            let page_num = table.pager.get_unused_page_num()?;
+            let node = table.pager.get_page(page_num)?;
+            initialize_internal_node(node);
+            write_internal_node_entries(node, layout, chunk);
+            set_children_parent(&mut table.pager, chunk, page_num)?;
+            parents.push((page_num, chunk[chunk.len() - 1].1.clone()));
+        }
+        level = parents;
+    }
+
+    let root = table.pager.get_page(tree.root_page_num)?;
+    initialize_internal_node(root);
+    set_node_root(root, true);
+    write_internal_node_entries(root, layout, &level);
+    set_children_parent(&mut table.pager, &level, tree.root_page_num)?;
+    Ok(())
+}
+
+
+/// Split the items into as few chunks of at most `max_len` items as they fit in, with the items
+/// spread evenly across them.
+fn even_chunks<T>(items: &[T], max_len: usize) -> Vec<&[T]> {
+    let num_chunks = items.len().div_ceil(max_len);
+    let mut start = 0;
+    (1..=num_chunks)
+        .map(|i| {
+            let end = items.len() * i / num_chunks;
+            let chunk = &items[start..end];
+            start = end;
+            chunk
+        })
+        .collect()
+}
+
+
+/// Return the number of levels in the given B-tree, counting the leaves.
+pub(crate) fn tree_height(table: &mut Table, tree: Tree) -> Result<usize, Error> {
+    let mut height = 1;
+    let mut page_num = tree.root_page_num;
+    loop {
+        let node = table.pager.get_page_ref(page_num)?;
+        if get_node_type(node) == NodeType::Leaf {
+            return Ok(height);
+        }
+        page_num = internal_node_child(node, tree.layout, 0)?;
+        height += 1;
+    }
+}
+
+
+/// Write a row to the destination buffer.
+pub(crate) fn serialize_row(row: &Row, destination: &mut [u8], offset: usize) {
+    let mut position = offset;
+    for value in &row.values {
+        match value {
+            Value::Null => {
+                destination[position] = NULL_TAG;
+                position += VALUE_TAG_SIZE;
+            },
+            Value::Integer(n) => {
+                destination[position] = INTEGER_TAG;
+                position += VALUE_TAG_SIZE;
+                destination[position..position+INTEGER_SIZE].copy_from_slice(&n.to_be_bytes());
+                position += INTEGER_SIZE;
+            },
+            Value::Real(_) => unreachable!("no column holds real numbers"),
+            Value::Text(s) => {
+                destination[position] = TEXT_TAG;
+                position += VALUE_TAG_SIZE;
+                write_u32(destination, position, s.len() as u32);
+                position += TEXT_LENGTH_SIZE;
+                destination[position..position+s.len()].copy_from_slice(s.as_bytes());
+                position += s.len();
+            },
+        }
+    }
+}
+
+
+/// Read a row from the source buffer, or return an error if it doesn't hold one.
+pub(crate) fn deserialize_row(source: &[u8], offset: usize) -> Result<Row, Error> {
+    let mut position = offset;
+    let mut values = Vec::with_capacity(COLUMNS.len());
+    for column in &COLUMNS {
+        let tag = source[position];
+        position += VALUE_TAG_SIZE;
+        match tag {
+            NULL_TAG => values.push(Value::Null),
+            INTEGER_TAG => {
+                let mut bytes = [0; INTEGER_SIZE];
+                bytes.copy_from_slice(&source[position..position+INTEGER_SIZE]);
+                values.push(Value::Integer(i64::from_be_bytes(bytes)));
+                position += INTEGER_SIZE;
+            },
+            TEXT_TAG => {
+                let length = read_u32(source, position) as usize;
+                if length > column.max_length {
+                    return Err(Error::Corrupt("malformed row"));
+                }
+                position += TEXT_LENGTH_SIZE;
+                let bytes = &source[position..position+length];
+                values.push(Value::Text(String::from_utf8_lossy(bytes).into_owned()));
+                position += length;
+            },
+            _ => return Err(Error::Corrupt("malformed row")),
+        }
+    }
+    Ok(Row { values, columns: None })
+}
+
+
+/// Return the largest number of bytes that a serialized row can take up.
+const fn max_row_size() -> usize {
+    let mut size = 0;
+    let mut i = 0;
+    while i < COLUMNS.len() {
+        size += VALUE_TAG_SIZE + match COLUMNS[i].column_type {
+            ColumnType::Integer | ColumnType::Real => INTEGER_SIZE,
+            ColumnType::Text => TEXT_LENGTH_SIZE + COLUMNS[i].max_length,
+        };
+        i += 1;
+    }
+    size
+}
+
+
+/// Return (page number, byte offset) for position indicated by the given cursor. Also reads the
+/// page from disk if it is not already in memory (which is why Cursor is mutable).
+pub(crate) fn cursor_value(cursor: &mut Cursor) -> Result<(usize, usize), Error> {
+    cursor.table.pager.get_page_ref(cursor.page_num)?;
+    Ok((cursor.page_num, leaf_node_value_offset(cursor.tree.layout, cursor.cell_num)))
+}
+
+
+/// Return the number of rows in the table.
+pub(crate) fn table_num_rows(table: &mut Table) -> Result<usize, Error> {
+    let mut cursor = Cursor::from_start(table)?;
+    let mut nrows = 0;
+    loop {
+        let node = cursor.table.pager.get_page_ref(cursor.page_num)?;
+        nrows += leaf_node_num_cells(node);
+        match leaf_node_next_leaf(node) {
+            0 => return Ok(nrows),
+            next_page_num => cursor.page_num = next_page_num,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+
+    use crate::btree::*;
+    use crate::error::Error;
+    use crate::exec::*;
+    use crate::pager::*;
+    use crate::parser::*;
+    use crate::sql::*;
+    use crate::testing::*;
+
+    #[test]
+    fn insert_and_retrieve() {
+        let mut table = db_open_new(&temp_path("testdb.mysql"));
+
+        let insert = Statement {
+            rows_to_insert: Some(vec![Row {
+                values: vec![
+                    Value::Integer(1),
+                    Value::Text(String::from("jdoe")),
+                    Value::Text(String::from("jdoe@example.com")),
+                ],
+                columns: None,
+            }]),
+            ..Statement::new(StatementKind::Insert)
+        };
+
+        let mut result = execute_statement(&insert, &mut table);
+        assert!(result.is_ok());
+
+        let select = Statement::new(StatementKind::Select);
+
+        result = execute_statement(&select, &mut table);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn bulk_load_builds_bottom_up() {
+        let mut table = db_open_new(&temp_path("testdb-bulk.mysql"));
+        run(&mut table, "create index u on users(username)").unwrap();
+        let mut rows: Vec<Row> = (0..500)
+            .map(|id| prepare_row(&[&id.to_string(), &format!("user{}", id % 40), "e"]).unwrap())
+            .rev()
+            .collect();
+        assert!(bulk_load(&mut rows, &mut table).is_ok());
+
+        // The tree has more than one level of internal nodes, and is the same as inserting the
+        // rows one at a time would have made it, except for how full its nodes are.
+        let tree = table.tree();
+        assert!(tree_height(&mut table, tree).unwrap() > 2);
+        assert_eq!(check_table(&mut table), (0..500).collect::<Vec<u32>>());
+        check_index(&mut table, 0);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        // Once the tree isn't empty, more rows are inserted into it in place.
+        let mut rows = vec![prepare_row(&["1000", "alice", "a@x"]).unwrap()];
+        assert!(bulk_load(&mut rows, &mut table).is_ok());
+        assert_eq!(check_table(&mut table).len(), 501);
+        check_index(&mut table, 0);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        assert_eq!(run(&mut table, "insert into users (username) values ('bob')"), Ok(1));
+        assert_eq!(table.last_insert_rowid, 1001);
+    }
+
+    #[test]
+    fn rows_persist_after_reopen() {
+        let mut table = db_open_new(&temp_path("testdb-reopen.mysql"));
+        for id in 1..4 {
+            let command = format!("insert {} user{} user{}@example.com", id, id, id);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+        drop(table);
+
+        let mut table = db_open(&temp_path("testdb-reopen.mysql")).unwrap();
+        assert_eq!(table_num_rows(&mut table).unwrap(), 3);
+
+        let node = table.pager.get_page_ref(table.root_page_num).unwrap();
+        assert_eq!(leaf_node_key(node, TABLE_LAYOUT, 2), 3u32.to_be_bytes());
+        let row = deserialize_row(node, leaf_node_value_offset(TABLE_LAYOUT, 2)).unwrap();
+        assert_eq!(row.values[1], Value::Text(String::from("user3")));
+        assert_eq!(row.values[2], Value::Text(String::from("user3@example.com")));
+    }
+
+    #[test]
+    fn rows_stay_sorted_across_splits() {
+        let mut table = db_open_new(&temp_path("testdb-splits.mysql"));
+
+        // Insert the ids 0..200 in a scrambled order; 7 is coprime with 200.
+        for i in 0..200 {
+            let id = (i * 7) % 200;
+            let command = format!("insert {} user{} user{}@example.com", id, id, id);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        // With only a few keys per internal node under test, the tree is several levels deep.
+        let tree = table.tree();
+        assert!(tree_height(&mut table, tree).unwrap() > 2);
+        let keys = check_table(&mut table);
+        assert_eq!(keys, (0..200).collect::<Vec<u32>>());
+
+        let mut cursor = Cursor::from_start(&mut table).unwrap();
+        let mut ids = Vec::new();
+        while !cursor.end_of_table {
+            let (page_num, offset) = cursor_value(&mut cursor).unwrap();
+            let page = cursor.table.pager.get_page_ref(page_num).unwrap();
+            ids.push(deserialize_row(page, offset).unwrap().id());
+            cursor.advance().unwrap();
+        }
+        assert_eq!(ids, (0..200).collect::<Vec<u32>>());
+
+        let cursor = Cursor::find(&mut table, 123).unwrap();
+        let node = cursor.table.pager.get_page_ref(cursor.page_num).unwrap();
+        assert_eq!(leaf_node_key(node, TABLE_LAYOUT, cursor.cell_num), 123u32.to_be_bytes());
+    }
+
+    #[test]
+    fn delete_rows() {
+        let mut table = db_open_new(&temp_path("testdb-delete.mysql"));
+        for id in 0..200 {
+            let command = format!("insert {} user{} user{}@example.com", id, id, id);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        // Deleting an id that isn't there is not an error.
+        let delete = prepare_statement("delete 1000").unwrap();
+        assert!(execute_statement(&delete, &mut table).is_ok());
+
+        for id in (0..200).filter(|id| id % 3 != 0) {
+            let delete = prepare_statement(&format!("delete {}", id)).unwrap();
+            assert!(execute_statement(&delete, &mut table).is_ok());
+        }
+
+        let keys = check_table(&mut table);
+        assert_eq!(keys, (0..200).filter(|id| id % 3 == 0).collect::<Vec<u32>>());
+        assert_eq!(table_num_rows(&mut table).unwrap(), keys.len());
+
+        // Emptying the table shrinks the tree back down to a single leaf.
+        for id in keys {
+            let delete = prepare_statement(&format!("delete {}", id)).unwrap();
+            assert!(execute_statement(&delete, &mut table).is_ok());
+        }
+        let tree = table.tree();
+        assert_eq!(tree_height(&mut table, tree).unwrap(), 1);
+        assert_eq!(table_num_rows(&mut table).unwrap(), 0);
+
+        assert!(prepare_statement("delete abc").is_err());
+    }
+
+    #[test]
+    fn typed_values() {
+        let row = prepare_row(&["7", "123", "'jdoe@example.com'"]).unwrap();
+        assert_eq!(row.values[0], Value::Integer(7));
+        assert_eq!(row.values[1], Value::Text(String::from("123")));
+        assert_eq!(row.values[2], Value::Text(String::from("jdoe@example.com")));
+
+        let mut buffer = vec![0; ROW_SIZE];
+        serialize_row(&row, &mut buffer, 0);
+        assert_eq!(deserialize_row(&buffer, 0).unwrap().values, row.values);
+
+        // The id column is an INTEGER, and also has to fit in a B-tree key.
+        assert!(prepare_row(&["abc", "jdoe", "jdoe@example.com"]).is_none());
+        assert!(prepare_row(&["-1", "jdoe", "jdoe@example.com"]).is_none());
+        assert!(prepare_row(&["4294967296", "jdoe", "jdoe@example.com"]).is_none());
+        assert!(prepare_row(&["1", "jdoe"]).is_none());
+    }
+
+    #[test]
+    fn corrupt_pages() {
+        let path = &temp_path("testdb-corrupt.mysql");
+        let mut table = db_open_new(path);
+        for id in 1..=100 {
+            let insert = format!("insert into users values ({}, 'user{}', 'email')", id, id);
+            assert!(execute_statement(&prepare_statement(&insert).unwrap(), &mut table).is_ok());
+        }
+        drop(table);
+        let select = prepare_statement("select").unwrap();
+        let original = fs::read(path).unwrap();
+        let page = |page_num: usize| page_num * PAGE_SIZE..(page_num + 1) * PAGE_SIZE;
+        assert_eq!(get_node_type(&original[page(TABLE_ROOT_PAGE_NUM)]), NodeType::Internal);
+
+        // The root says that it has more keys than a node can hold.
+        let mut contents = original.clone();
+        set_internal_node_num_keys(&mut contents[page(TABLE_ROOT_PAGE_NUM)], 1 << 16);
+        fs::write(path, &contents).unwrap();
+        let result = execute_statement(&select, &mut db_open(path).unwrap());
+        assert_eq!(result.err(), Some(Error::Corrupt("malformed B-tree node")));
+
+        // The first value of a row has a type that doesn't exist.
+        let mut contents = original;
+        let mut page_num = TABLE_ROOT_PAGE_NUM;
+        while get_node_type(&contents[page(page_num)]) == NodeType::Internal {
+            page_num = internal_node_child(&contents[page(page_num)], TABLE_LAYOUT, 0).unwrap();
+        }
+        contents[page(page_num)][leaf_node_value_offset(TABLE_LAYOUT, 0)] = 0xff;
+        fs::write(path, &contents).unwrap();
+        let result = execute_statement(&select, &mut db_open(path).unwrap());
+        assert_eq!(result.err(), Some(Error::Corrupt("malformed row")));
+    }
+
+    #[test]
+    fn integrity_checks() {
+        let path = &temp_path("testdb-integrity.mysql");
+        let mut table = db_open_new(path);
+        run(&mut table, "create index u on users(username)").unwrap();
+        for i in 1..60 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            run(&mut table, &command).unwrap();
+        }
+        run(&mut table, "delete 30").unwrap();
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        drop(table);
+
+        // Every page that was written has a valid checksum.
+        let mut table = db_open(path).unwrap();
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        let num_pages = table.pager.num_pages;
+        drop(table);
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start((2 * PAGE_SIZE + 100) as u64)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        let mut table = db_open(path).unwrap();
+        let problems = integrity_check(&mut table).unwrap();
+        assert_eq!(problems[0], "page 2: checksum does not match");
+
+        // Problems in the trees are found whether or not they have been written yet.
+        let header = table.pager.get_page(HEADER_PAGE_NUM).unwrap();
+        set_header_row_count(header, 1);
+        let index_root = table.indexes[0].root_page_num;
+        assert_eq!(get_node_type(table.pager.get_page_ref(index_root).unwrap()), NodeType::Leaf);
+        let index_node = table.pager.get_page(index_root).unwrap();
+        set_leaf_node_num_cells(index_node, leaf_node_num_cells(index_node) - 1);
+        let problems = integrity_check(&mut table).unwrap();
+        assert_eq!(
+            &problems[1..],
+            [
+                "header: row count is 1 but the table has 58",
+                "index u: entries do not match the table's rows",
+            ]
+        );
+
+        // The rest of the checks rely on the table's tree being well-formed.
+        let root = table.root_page_num;
+        let root_node = table.pager.get_page_ref(root).unwrap();
+        let child = internal_node_child(root_node, TABLE_LAYOUT, 0).unwrap();
+        set_node_parent(table.pager.get_page(child).unwrap(), num_pages - 1);
+        let problems = integrity_check(&mut table).unwrap();
+        assert_eq!(&problems[1..], [format!("table: page {} has the wrong parent", child)]);
+
+        set_node_parent(table.pager.get_page(child).unwrap(), root);
+        let leaf = Cursor::from_start(&mut table).unwrap().page_num;
+        let parent = node_parent(table.pager.get_page_ref(leaf).unwrap());
+        let parent_node = table.pager.get_page_ref(parent).unwrap();
+        let sibling = internal_node_child(parent_node, TABLE_LAYOUT, 1).unwrap();
+        set_internal_node_child(table.pager.get_page(parent).unwrap(), TABLE_LAYOUT, 0, sibling);
+        let problems = integrity_check(&mut table).unwrap();
+        assert!(problems.contains(&format!("table: page {} is used more than once", sibling)));
+        let problem = "is not the largest key of its child";
+        assert!(problems.contains(&format!("table: key 0 of page {} {}", parent, problem)));
+    }
+
+    #[test]
+    fn index_keys_sort_like_values() {
+        let column = ColumnDef { name: "n", column_type: ColumnType::Integer, max_length: 0 };
+        let values = [
+            Value::Null,
+            Value::Integer(i64::MIN),
+            Value::Integer(-1),
+            Value::Integer(0),
+            Value::Integer(7),
+            Value::Integer(i64::MAX),
+        ];
+        for pair in values.windows(2) {
+            assert!(encode_index_value(&pair[0], &column) < encode_index_value(&pair[1], &column));
+        }
+
+        let values = [
+            Value::Null,
+            Value::Text(String::from("")),
+            Value::Text(String::from("a")),
+            Value::Text(String::from("ab")),
+            Value::Text(String::from("b")),
+        ];
+        for pair in values.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            assert!(encode_index_value(a, &COLUMNS[1]) < encode_index_value(b, &COLUMNS[1]));
+        }
+    }
+}
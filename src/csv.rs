@@ -0,0 +1,294 @@
+//! Reading the rows of a CSV file, for COPY.
+//!
+//! The format is the one that RFC 4180 describes: records are separated by line breaks, either
+//! CRLF or LF, and their fields by commas. A field in double quotes can have commas and line
+//! breaks in it, and a pair of double quotes in it stands for one.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::error::Error;
+use crate::sql::{
+    COLUMNS, ColumnDef, ColumnType, ID_COLUMN, Row, Value, column_index, is_valid_id,
+    parse_number, values_fit_columns,
+};
+
+
+/// A field of a record, and whether it was in quotes.
+struct Field {
+    text: String,
+    quoted: bool,
+}
+
+
+/// Parse the contents of a CSV file into rows, skipping blank lines.
+///
+/// If the first record names each of the table's columns once, in any order, it is a header, and
+/// the fields of the other records are for the columns in its order. Otherwise, they are for the
+/// columns in the order of the table. An unquoted field that is empty or `null` is NULL, and
+/// spaces around unquoted fields are ignored, but a quoted field is always taken as it is.
+pub(crate) fn parse_csv_rows(contents: &str) -> Result<Vec<Row>, Error> {
+    let mut records = read_records(contents)?;
+    let order = match records.first().and_then(|(_, fields)| header_order(fields)) {
+        Some(order) => {
+            records.remove(0);
+            order
+        },
+        None => (0..COLUMNS.len()).collect(),
+    };
+
+    records
+        .iter()
+        .map(|(line, fields)| {
+            record_row(fields, &order).map_err(|message| csv_error(&message, *line))
+        })
+        .collect()
+}
+
+
+/// Return the columns that a header names, in its order, if the record is one.
+fn header_order(fields: &[Field]) -> Option<Vec<usize>> {
+    let order = fields
+        .iter()
+        .map(|field| column_index(&field.text))
+        .collect::<Option<Vec<usize>>>()?;
+    let names_each_column = (0..COLUMNS.len()).all(|column| order.contains(&column));
+    if order.len() == COLUMNS.len() && names_each_column {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+
+/// Build a row from a record whose fields are for the columns in the given order, or return why
+/// the record is invalid.
+fn record_row(fields: &[Field], order: &[usize]) -> Result<Row, String> {
+    if fields.len() != COLUMNS.len() {
+        return Err(format!("expected {} fields, found {}", COLUMNS.len(), fields.len()));
+    }
+
+    let mut values = vec![Value::Null; COLUMNS.len()];
+    for (field, &column) in fields.iter().zip(order) {
+        values[column] = field_value(field, &COLUMNS[column])
+            .ok_or_else(|| format!("invalid value for {}", COLUMNS[column].name))?;
+    }
+    if !values_fit_columns(&values) {
+        return Err(String::from("text longer than its column allows"));
+    }
+    if !is_valid_id(&values[ID_COLUMN]) {
+        return Err(format!("id must be an integer from 0 to {}", u32::MAX));
+    }
+    Ok(Row { values, columns: None })
+}
+
+
+/// Return the value of a field for the given column, or None if it isn't one of its type.
+fn field_value(field: &Field, column: &ColumnDef) -> Option<Value> {
+    if !field.quoted && (field.text.is_empty() || field.text.eq_ignore_ascii_case("null")) {
+        return Some(Value::Null);
+    }
+
+    match column.column_type {
+        ColumnType::Text => Some(Value::Text(field.text.clone())),
+        ColumnType::Integer | ColumnType::Real => {
+            parse_number(field.text.trim()).ok()?.coerce(column.column_type)
+        },
+    }
+}
+
+
+/// Split the contents of a CSV file into records, each along with the number of the line that it
+/// starts on. A blank line is not a record.
+fn read_records(contents: &str) -> Result<Vec<(usize, Vec<Field>)>, Error> {
+    let mut chars = contents.chars().peekable();
+    let mut line = 1;
+    let mut records = Vec::new();
+    while chars.peek().is_some() {
+        let first_line = line;
+        let mut fields = Vec::new();
+        loop {
+            fields.push(read_field(&mut chars, &mut line)?);
+            match chars.next() {
+                Some(',') => {},
+                Some('\n') => {
+                    line += 1;
+                    break;
+                },
+                _ => break,
+            }
+        }
+
+        let is_blank = match fields.as_slice() {
+            [field] => !field.quoted && field.text.is_empty(),
+            _ => false,
+        };
+        if !is_blank {
+            records.push((first_line, fields));
+        }
+    }
+    Ok(records)
+}
+
+
+/// Read the field that starts at the next character, and leave the comma or line break after it,
+/// if there is one, to be read next.
+fn read_field(chars: &mut Peekable<Chars<'_>>, line: &mut usize) -> Result<Field, Error> {
+    skip_spaces(chars);
+    if chars.peek() != Some(&'"') {
+        let mut text = String::new();
+        while let Some(c) = chars.next_if(|&c| c != ',' && c != '\n') {
+            text.push(c);
+        }
+        // The trim also removes the carriage return of a CRLF line break.
+        return Ok(Field { text: String::from(text.trim()), quoted: false });
+    }
+
+    let first_line = *line;
+    chars.next();
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('"') if chars.next_if_eq(&'"').is_some() => text.push('"'),
+            Some('"') => break,
+            Some(c) => {
+                if c == '\n' {
+                    *line += 1;
+                }
+                text.push(c);
+            },
+            None => return Err(csv_error("unterminated quoted field", first_line)),
+        }
+    }
+
+    skip_spaces(chars);
+    chars.next_if_eq(&'\r');
+    match chars.peek() {
+        None | Some(',') | Some('\n') => Ok(Field { text, quoted: true }),
+        Some(_) => Err(csv_error("unexpected text after quoted field", *line)),
+    }
+}
+
+
+/// Return an error for a problem with the record or field on the given line.
+fn csv_error(message: &str, line: usize) -> Error {
+    Error::Parse(format!("{} on line {} of CSV file", message, line))
+}
+
+
+fn skip_spaces(chars: &mut Peekable<Chars<'_>>) {
+    while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::btree::*;
+    use crate::csv::*;
+    use crate::error::Error;
+    use crate::exec::*;
+    use crate::parser::*;
+    use crate::sql::*;
+    use crate::testing::*;
+
+    #[test]
+    fn copy_from_csv() {
+        let csv_path = temp_path("testdb-copy.csv");
+        let contents = "1,jdoe,jdoe@example.com\n\n2, asmith , asmith@example.com\n";
+        fs::write(&csv_path, contents).unwrap();
+        let path = &temp_path("testdb-copy.mysql");
+        let mut table = db_open_new(path);
+
+        let copy = prepare_statement(&format!("copy users from '{}'", csv_path)).unwrap();
+        let result = execute_statement(&copy, &mut table);
+        assert!(result.is_ok());
+        assert_eq!(table_num_rows(&mut table).unwrap(), 2);
+
+        let mut cursor = Cursor::from_start(&mut table).unwrap();
+        cursor.advance().unwrap();
+        let (page_num, offset) = cursor_value(&mut cursor).unwrap();
+        let page = cursor.table.pager.get_page_ref(page_num).unwrap();
+        let row = deserialize_row(page, offset).unwrap();
+        assert_eq!(row.id(), 2);
+        assert_eq!(row.values[1], Value::Text(String::from("asmith")));
+        drop(table);
+
+        // Programs can load a file without writing out the statement, whatever its path, and
+        // either all of its rows are loaded or none are.
+        let csv_path = temp_path("testdb-copy's.csv");
+        fs::write(&csv_path, "id,username,email\n3,\"c, d\",c@x\n").unwrap();
+        let database = Database::open(path).unwrap();
+        assert_eq!(database.copy_from(&csv_path), Ok(1));
+        let duplicate = database.copy_from(&csv_path);
+        assert_eq!(duplicate, Err(Error::Constraint("duplicate key")));
+        assert!(database.copy_from(&temp_path("testdb-missing.csv")).is_err());
+        let row = database.query("select where id = 3").unwrap().next().unwrap().unwrap();
+        assert_eq!(row.values[1], Value::Text(String::from("c, d")));
+        assert_eq!(database.query("select").unwrap().count(), 3);
+    }
+
+    #[test]
+    fn copy_malformed_csv() {
+        let error = |contents: &str| match parse_csv_rows(contents) {
+            Err(Error::Parse(message)) => message,
+            result => panic!("expected a parse error, got {:?}", result.map(|rows| rows.len())),
+        };
+        assert_eq!(
+            error("1,jdoe,jdoe@example.com\n2,asmith\n"),
+            "expected 3 fields, found 2 on line 2 of CSV file"
+        );
+        assert_eq!(
+            error("abc,jdoe,jdoe@example.com\n"),
+            "invalid value for id on line 1 of CSV file"
+        );
+        assert_eq!(
+            error("1,jdoe,jdoe@example.com\n2,\"asmith,asmith@example.com\n"),
+            "unterminated quoted field on line 2 of CSV file"
+        );
+        assert_eq!(
+            error("1,\"jdoe\"x,jdoe@example.com\n"),
+            "unexpected text after quoted field on line 1 of CSV file"
+        );
+        assert_eq!(
+            error(",jdoe,jdoe@example.com\n"),
+            "id must be an integer from 0 to 4294967295 on line 1 of CSV file"
+        );
+        assert!(prepare_statement("copy users from testdb.csv").is_err());
+    }
+
+    #[test]
+    fn csv_quoting() {
+        // Quoted fields can have commas, line breaks and quotes in them, and are taken as they
+        // are, so that an empty one is empty text rather than NULL.
+        let contents = "1,\"doe, jane\",\"\"\"jd\"\"@example.com\"\r\n\
+                        2 , \"line\nbreak\" ,\r\n\
+                        3,\"null\",\"\"\r\n";
+        let text = |text: &str| Value::Text(String::from(text));
+        let rows = parse_csv_rows(contents).unwrap();
+        let values: Vec<Vec<Value>> = rows.into_iter().map(|row| row.values).collect();
+        assert_eq!(
+            values,
+            [
+                [Value::Integer(1), text("doe, jane"), text("\"jd\"@example.com")],
+                [Value::Integer(2), text("line\nbreak"), Value::Null],
+                [Value::Integer(3), text("null"), text("")],
+            ]
+        );
+        // The records of a quoted line break are counted by the line they start on.
+        assert_eq!(
+            parse_csv_rows("1,\"a\nb\",c\n2,d\n").unwrap_err().to_string(),
+            "expected 3 fields, found 2 on line 3 of CSV file"
+        );
+
+        // A header gives the order of the columns, in any case, and is otherwise skipped.
+        let rows = parse_csv_rows("Email,id,username\nb@x,2,bob\na@x,1,alice\n").unwrap();
+        assert_eq!(rows[0].values, [Value::Integer(2), text("bob"), text("b@x")]);
+        assert_eq!(rows[1].id(), 1);
+        assert!(parse_csv_rows("id,username,email\n").unwrap().is_empty());
+        // A first record that doesn't name each column once is an ordinary one.
+        assert!(parse_csv_rows("id,id,email\n").is_err());
+        assert!(parse_csv_rows("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+//! The errors that opening a database and executing statements on it can fail with.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+
+/// An error from the engine. Each kind of error is its own variant, so that a program can tell a
+/// locked database, which is worth trying again, from a statement that will never work.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed, e.g. because of its permissions or a full disk.
+    Io(io::Error),
+    /// A statement, or a file of rows being loaded, could not be parsed.
+    Parse(String),
+    /// A change would break a constraint on the table, e.g. two rows with the same id.
+    Constraint(&'static str),
+    /// The file is not a database, or is one in a format that isn't supported.
+    Corrupt(&'static str),
+    /// A limit of the database format was reached.
+    Full(&'static str),
+    /// Another connection's lock was in the way for longer than the busy timeout.
+    Locked,
+    /// The database was opened read-only, and the statement would write to it.
+    ReadOnly,
+    /// A statement that can't be executed as it is, e.g. COMMIT without a transaction.
+    Invalid(&'static str),
+    /// A row couldn't be converted to or from a Rust type.
+    Conversion(String),
+}
+
+
+/// Why the text of a statement couldn't be parsed, and where in the text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    message: String,
+    span: Range<usize>,
+    // Counted in characters from 1, and only known once the error has been located in the text.
+    column: usize,
+}
+
+
+/// The result of anything in the engine that can fail.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "disk I/O error: {}", e),
+            Error::Parse(message) | Error::Conversion(message) => write!(f, "{}", message),
+            Error::Constraint(message)
+            | Error::Corrupt(message)
+            | Error::Full(message)
+            | Error::Invalid(message) => write!(f, "{}", message),
+            Error::Locked => write!(f, "database is locked"),
+            Error::ReadOnly => write!(f, "attempt to write a read-only database"),
+        }
+    }
+}
+
+
+impl ParseError {
+    pub(crate) fn new(message: String, span: Range<usize>) -> Self {
+        ParseError { message, span, column: 0 }
+    }
+
+    /// Work out which column of the text the error is at.
+    pub(crate) fn locate(mut self, text: &str) -> Self {
+        self.column = text[..self.span.start].chars().count() + 1;
+        self
+    }
+
+    /// What was wrong, e.g. `expected integer id, found 'abc'`.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The byte offsets in the text of the part that couldn't be parsed. The span is empty if the
+    /// text ended too soon.
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    /// The column of the text, counting characters from 1, at which the error is.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+
+impl error::Error for ParseError {}
+
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+// I/O errors can't be compared, so two are taken to be the same if they are of the same kind.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind(),
+            (Error::Parse(a), Error::Parse(b)) | (Error::Conversion(a), Error::Conversion(b)) => {
+                a == b
+            },
+            (Error::Constraint(a), Error::Constraint(b))
+            | (Error::Corrupt(a), Error::Corrupt(b))
+            | (Error::Full(a), Error::Full(b))
+            | (Error::Invalid(a), Error::Invalid(b)) => a == b,
+            (Error::Locked, Error::Locked) | (Error::ReadOnly, Error::ReadOnly) => true,
+            _ => false,
+        }
+    }
+}
+
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
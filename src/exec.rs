@@ -0,0 +1,4145 @@
+//! Opening databases and executing statements on them.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::str;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::btree::{
+    Cursor, FREE_PAGE_NEXT_OFFSET, FREE_PAGE_TYPE, ID_KEY_SIZE, Index, IndexStats, NODE_TYPE_OFFSET,
+    NodeType, ROW_SIZE, Stats, TABLE_LAYOUT, Table, Tree, TreeLayout, Trigger, View, cursor_value,
+    decode_index_value, deserialize_row, get_node_type, initialize_leaf_node, insert_sorted,
+    internal_node_child, internal_node_find_child, internal_node_key, internal_node_num_keys,
+    internal_node_right_child, leaf_node_delete, leaf_node_find, leaf_node_insert, leaf_node_key,
+    leaf_node_next_leaf, leaf_node_num_cells, node_parent, serialize_row, set_node_root,
+    table_num_rows, tree_height,
+};
+use crate::csv::parse_csv_rows;
+use crate::error::Error;
+use crate::explain::{
+    describe_grouping, describe_search, describe_sort, describe_triggers, describe_write, explain,
+    num_results, profile_step,
+};
+use crate::log::debug;
+use crate::os::is_same_file;
+use crate::parser::{
+    prepare_index_expression, prepare_index_predicate, prepare_statement, prepare_trigger,
+};
+use crate::pager::{
+    DEFAULT_CACHE_SIZE, FORMAT_VERSION, FileVfs, HEADER_CHANGE_COUNTER_OFFSET,
+    HEADER_FORMAT_VERSION_OFFSET, HEADER_MAGIC, HEADER_MAGIC_OFFSET, HEADER_MAGIC_SIZE,
+    HEADER_PAGE_NUM, HEADER_PAGE_SIZE_OFFSET, HEADER_SCHEMA_PAGE_OFFSET,
+    LockLevel, MAX_INDEXES, MemoryVfs, PAGE_CHECKSUM_OFFSET, PAGE_SIZE, Pager, Synchronous,
+    TABLE_ROOT_PAGE_NUM, Vfs, append_index, encode_schema_page, find_wal, header_free_list,
+    header_max_id, header_row_count, header_user_version, initialize_header,
+    is_page_checksum_valid, read_u32, set_header_max_id,
+    set_header_row_count, set_header_user_version, write_u32,
+};
+use crate::sql::{
+    COLUMNS, Condition, CreateIndex, ID_COLUMN, OrderBy, Predicate, Row, SelectItem,
+    SetOperator, Statement, StatementKind, TABLE_NAME, UserFunction, Value, function_index,
+    replace_user_functions, type_name,
+};
+
+
+/// Open the database at the given path, creating it if the file doesn't exist or is empty. It is
+/// an error if the file is not a database or its format is not supported. The path `:memory:`
+/// opens a new database that is only kept in memory.
+pub(crate) fn db_open(path: &str) -> Result<Table, Error> {
+    db_open_with(path, &OpenOptions::new())
+}
+
+
+/// Open the database at the given path as `db_open` does, but with the given options.
+pub(crate) fn db_open_with(path: &str, options: &OpenOptions) -> Result<Table, Error> {
+    if options.page_size != PAGE_SIZE {
+        return Err(Error::Invalid("unsupported page size"));
+    } else if options.cache_pages == 0 {
+        return Err(Error::Invalid("invalid number of pages"));
+    }
+    if path == MEMORY_DATABASE_PATH {
+        let mut table = db_open_vfs(path, Box::new(MemoryVfs::default()))?;
+        table.pager.set_cache_size(options.cache_pages);
+        return Ok(table);
+    }
+
+    // Check that the file is a database before anything is written to it, which recovering from an
+    // interrupted session can do. Without a journal, only an empty file can lack the magic bytes.
+    if fs::metadata(format!("{}-journal", path)).is_err() {
+        if let Ok(file) = File::open(path) {
+            let mut magic = Vec::new();
+            file.take(HEADER_MAGIC_SIZE as u64)
+                .read_to_end(&mut magic)?;
+            if !magic.is_empty() && magic != HEADER_MAGIC {
+                return Err(Error::Corrupt("file is not a database"));
+            }
+        }
+    }
+
+    let vfs = FileVfs::open(path, options.read_only, options.create)?;
+    let mut table = db_open_vfs(path, Box::new(vfs))?;
+    table.pager.set_cache_size(options.cache_pages);
+    Ok(table)
+}
+
+
+/// Open the database at the given path, which is kept in the given storage. The path still names
+/// the journal and the write-ahead log, which are always files.
+pub(crate) fn db_open_vfs(path: &str, mut vfs: Box<dyn Vfs>) -> Result<Table, Error> {
+    // Opening the database only reads it, so other connections can be reading it meanwhile.
+    // Recovering from an interrupted session and creating a new database both write to the file,
+    // though, so only then is an exclusive lock taken. None of that is needed if another connection
+    // in this process has the database open in WAL mode, and so has already done it. A read-only
+    // connection can do neither.
+    let is_joining = vfs.is_persistent() && find_wal(path).is_some();
+    let is_read_only = vfs.is_read_only();
+    let mut level = LockLevel::Shared;
+    if !is_joining {
+        if !vfs.lock(LockLevel::Shared)? {
+            return Err(Error::Locked);
+        }
+        // A connection that is using a journal or a log has an exclusive lock, so with a shared
+        // lock, one that is there was left behind.
+        let is_left_behind = |suffix: &str| {
+            vfs.is_persistent() && fs::metadata(format!("{}-{}", path, suffix)).is_ok()
+        };
+        let is_journal_hot = is_left_behind("journal");
+        let is_wal_hot = is_left_behind("wal");
+        if is_read_only && is_journal_hot {
+            return Err(Error::ReadOnly);
+        }
+        if !is_read_only && (is_journal_hot || is_wal_hot || vfs.is_empty()?) {
+            level = LockLevel::Exclusive;
+            for next in [LockLevel::Reserved, LockLevel::Exclusive] {
+                if !vfs.lock(next)? {
+                    vfs.unlock(LockLevel::Unlocked)?;
+                    return Err(Error::Locked);
+                }
+            }
+        }
+    }
+
+    let mut pager = Pager::new(path, vfs)?;
+    if is_joining {
+        pager.lock(LockLevel::Shared)?;
+    } else {
+        pager.lock = level;
+    }
+    if pager.num_pages == 0 {
+        if is_read_only {
+            return Err(Error::ReadOnly);
+        }
+        // New database file, so initialize the header followed by the table's root as an empty
+        // leaf node.
+        initialize_header(pager.get_page(HEADER_PAGE_NUM)?);
+        let root = pager.get_page(TABLE_ROOT_PAGE_NUM)?;
+        initialize_leaf_node(root);
+        set_node_root(root, true);
+        pager.write_changes()?;
+    }
+
+    let header = pager.get_page_ref(HEADER_PAGE_NUM)?;
+    if header[HEADER_MAGIC_OFFSET..HEADER_MAGIC_OFFSET+HEADER_MAGIC_SIZE] != HEADER_MAGIC[..] {
+        return Err(Error::Corrupt("file is not a database"));
+    } else if read_u32(header, HEADER_FORMAT_VERSION_OFFSET) != FORMAT_VERSION {
+        return Err(Error::Corrupt("unsupported database format version"));
+    } else if read_u32(header, HEADER_PAGE_SIZE_OFFSET) as usize != PAGE_SIZE {
+        return Err(Error::Corrupt("unsupported database page size"));
+    }
+
+    pager.change_counter = read_u32(header, HEADER_CHANGE_COUNTER_OFFSET);
+    let mut table = Table {
+        root_page_num: TABLE_ROOT_PAGE_NUM,
+        pager,
+        last_insert_rowid: 0,
+        changes: 0,
+        indexes: Vec::new(),
+        views: Vec::new(),
+        triggers: Vec::new(),
+        stats: None,
+        attached: Vec::new(),
+        update_hook: None,
+        commit_hook: None,
+        functions: Vec::new(),
+        common_tables: HashMap::new(),
+        view_rows: HashMap::new(),
+        in_trigger: false,
+        profile: None,
+    };
+    table.read_schema()?;
+    table.pager.unlock(LockLevel::Unlocked)?;
+    Ok(table)
+}
+
+
+/// A connection to a database, which is what programs that embed the engine use it through.
+///
+/// A connection is Send and Sync, so threads can share one, e.g. in an `Arc`. Its state is behind
+/// a mutex, and each call holds it for as long as it runs, so the statements of different threads
+/// are executed one at a time. A transaction belongs to the connection rather than to a thread,
+/// so statements that other threads execute while one is open are part of it.
+///
+/// Connections only ever read what other connections have committed, and each statement, or each
+/// transaction begun with BEGIN, reads the database as of one commit. With a rollback journal,
+/// that is because a reader holds a shared lock until it is done, which keeps writers from
+/// committing in the meantime. In WAL mode, readers read the log as of when they began instead,
+/// so they don't hold writers off.
+pub struct Database {
+    table: Mutex<Table>,
+}
+
+
+/// A kind of change to a row, as passed to the update hook, and that a trigger fires after.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Insert,
+    Delete,
+}
+
+
+pub(crate) type UpdateHook = Box<dyn FnMut(Action, &str, i64) + Send>;
+pub(crate) type CommitHook = Box<dyn FnMut() -> bool + Send>;
+
+
+/// How to open a database, e.g. `Database::options().read_only(true).open(path)`. Each option
+/// starts out as what `Database::open` does.
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    read_only: bool,
+    create: bool,
+    cache_pages: usize,
+    page_size: usize,
+}
+
+
+impl Database {
+    /// Open the database at the given path, as `db_open` does.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Ok(Database::new(db_open(path)?))
+    }
+
+    /// Open a database that is kept in memory, which starts out as the given contents of a database
+    /// file, or as a new database if they are empty. Each time a change is written, the whole
+    /// database is passed to `persist`, which can save it elsewhere, e.g. in a browser's IndexedDB
+    /// where there are no files. That happens as often as a file would be synced, so not at all
+    /// with `pragma synchronous = off`.
+    pub fn open_in_memory<F>(contents: Vec<u8>, persist: F) -> Result<Self, Error>
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let vfs = MemoryVfs::with_persist(contents, Box::new(persist));
+        Ok(Database::new(db_open_vfs(MEMORY_DATABASE_PATH, Box::new(vfs))?))
+    }
+
+    /// Open the database at the given path, as `open` does, but keep it in the given storage
+    /// instead of the file at the path. The path still names the database, and its journal, if it
+    /// has one, is next to where its file would be.
+    pub fn open_with_vfs(path: &str, vfs: Box<dyn Vfs>) -> Result<Self, Error> {
+        Ok(Database::new(db_open_vfs(path, vfs)?))
+    }
+
+    /// Return the default options for opening a database, to be changed before opening one.
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    fn new(table: Table) -> Self {
+        Database { table: Mutex::new(table) }
+    }
+
+    /// Lock the connection's state for a call. If a call panicked while holding it, the state is
+    /// still used rather than every later call panicking too, so that a transaction dropped while
+    /// unwinding can roll back what the statement left behind.
+    pub(crate) fn table(&self) -> TableGuard<'_> {
+        let table = self.table.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous_functions = replace_user_functions(table.functions.clone());
+        TableGuard { table, previous_functions }
+    }
+
+    /// Return what was done when the database was opened to recover from the last session being
+    /// interrupted, if anything.
+    pub fn recovered(&self) -> Vec<String> {
+        self.table().pager.recovered.clone()
+    }
+
+    /// Parse a statement, to be executed with `execute_statement`. Unlike `Statement::parse`, it
+    /// can call the functions defined on the connection.
+    pub fn prepare(&self, command: &str) -> Result<Statement, Error> {
+        let _table = self.table();
+        parse_command(command)
+    }
+
+    /// Parse and execute a statement, and return the number of rows that it inserted or deleted,
+    /// which is 0 for any other kind of statement. The rows that it outputs, if any, are dropped;
+    /// `query` returns a SELECT statement's results instead.
+    pub fn execute(&self, command: &str) -> Result<usize, Error> {
+        self.execute_statement(&self.prepare(command)?)
+    }
+
+    /// Parse and execute a SELECT statement, and return its results. They are all read before this
+    /// returns, so that they can be kept while other statements are executed, which also means
+    /// that none of them is an error.
+    pub fn query(
+        &self,
+        command: &str,
+    ) -> Result<impl Iterator<Item = Result<Row, Error>>, Error> {
+        let rows = self.query_statement(&self.prepare(command)?)?;
+        Ok(rows.into_iter().map(Ok))
+    }
+
+    /// Execute a SELECT statement that has already been parsed, and return its results. The
+    /// results of an EXPLAIN statement are the lines of its plan, in a column named `plan`, and
+    /// those of a PRAGMA statement are its setting's value or the rows that it lists.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub(crate) fn query_statement(&self, statement: &Statement) -> Result<Vec<Row>, Error> {
+        // EXPLAIN ANALYZE executes the statement, which only a SELECT can be for a query, and a
+        // query can't set the user version, since it doesn't change the database.
+        let can_query = match statement.kind {
+            StatementKind::Select => true,
+            StatementKind::Pragma => !is_write_statement(statement),
+            _ => statement.explain && !statement.analyze,
+        };
+        if !can_query {
+            return Err(Error::Invalid("only a SELECT or PRAGMA statement can be queried"));
+        }
+        let table = &mut *self.table();
+        let rows = table.lock(LockLevel::Shared).and_then(|()| {
+            if statement.explain {
+                explain_rows(statement, table)
+            } else if let StatementKind::Pragma = statement.kind {
+                pragma_rows(statement, table)
+            } else {
+                query_rows(statement, table)
+            }
+        });
+        let finished = table.finish_statement();
+        rows.and_then(|rows| finished.map(|()| rows))
+    }
+
+    /// Execute a statement that has already been parsed, as `execute` does.
+    pub fn execute_statement(&self, statement: &Statement) -> Result<usize, Error> {
+        execute_statement(statement, &mut self.table())
+    }
+
+    /// Execute a statement that has already been parsed, and return the number of rows that it
+    /// inserted or deleted, as `execute_statement` does, along with the rows that it outputs,
+    /// which are what a shell prints. These are the results of a SELECT statement or of a
+    /// RETURNING clause, the lines of an EXPLAIN statement's plan in a column named `plan`, the
+    /// rows of a PRAGMA statement, or the number that `select last_insert_rowid()` or
+    /// `select changes()` asks for.
+    pub fn execute_statement_with_rows(
+        &self,
+        statement: &Statement,
+    ) -> Result<(usize, Vec<Row>), Error> {
+        execute_statement_with_rows(statement, &mut self.table())
+    }
+
+    /// Load the rows of the CSV file at the given path into the table, as a
+    /// `copy users from '<path>'` statement does, and return the number of rows loaded. Either
+    /// all of them are loaded or, if any of them is invalid, none are.
+    pub fn copy_from(&self, path: &str) -> Result<usize, Error> {
+        self.execute(&format!("copy users from '{}'", path.replace('\'', "''")))
+    }
+
+    /// Begin a transaction, which is rolled back when the returned guard is dropped unless it is
+    /// committed first, so that returning early or panicking can't leave it open. Statements are
+    /// executed in the transaction through the guard.
+    pub fn transaction(&self) -> Result<Transaction<'_>, Error> {
+        self.execute("begin")?;
+        Ok(Transaction { database: self, is_committed: false })
+    }
+
+    /// Bring the database up to date by executing, in order, the migrations that haven't been
+    /// executed on it yet, all in one transaction, so that if any of them fails, none are kept.
+    /// Each migration is a string of statements separated by semicolons. The user version is the
+    /// number of migrations that have been executed, so new ones must only be added to the end of
+    /// the list. Return the number of migrations that were executed.
+    pub fn migrate(&self, migrations: &[&str]) -> Result<usize, Error> {
+        let transaction = self.transaction()?;
+        // The connection is held for the whole migration, so that no other thread's statements
+        // end up in its transaction.
+        let num_executed = {
+            let table = &mut *self.table();
+            // The database is locked for writing before its version is read, so that another
+            // connection can't execute the same migrations at the same time.
+            table.lock(LockLevel::Reserved)?;
+            let user_version = header_user_version(table.pager.get_page_ref(HEADER_PAGE_NUM)?);
+            let num_executed = usize::try_from(user_version)
+                .ok()
+                .filter(|&num_executed| num_executed <= migrations.len())
+                .ok_or(Error::Invalid("the user version is not a number of the migrations"))?;
+
+            for migration in &migrations[num_executed..] {
+                for statement in Statement::parse_all(migration) {
+                    execute_statement(&statement?, table)?;
+                }
+            }
+            let user_version = i32::try_from(migrations.len())
+                .map_err(|_| Error::Invalid("too many migrations"))?;
+            set_header_user_version(table.pager.get_page(HEADER_PAGE_NUM)?, user_version);
+            num_executed
+        };
+        transaction.commit()?;
+        Ok(migrations.len() - num_executed)
+    }
+
+    /// Call the given function after each row that a statement inserts or deletes, with the kind
+    /// of change, the name of the table and the row's id. A row replaced by INSERT OR REPLACE is
+    /// inserted. The function is called even if the change is later rolled back.
+    pub fn update_hook<F>(&self, hook: F)
+    where
+        F: FnMut(Action, &str, i64) + Send + 'static,
+    {
+        self.table().update_hook = Some(Box::new(hook));
+    }
+
+    /// Call the given function before each transaction that changed the database is committed. If
+    /// it returns true, the transaction is rolled back instead, and the commit fails.
+    pub fn commit_hook<F>(&self, hook: F)
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        self.table().commit_hook = Some(Box::new(hook));
+    }
+
+    /// Define a function that statements on the connection can call, which takes the given number
+    /// of arguments and is called with their values, any of which may be NULL. Defining a function
+    /// with the same name again replaces it in the statements parsed after that, but the built-in
+    /// functions can't be replaced. Since it is only defined on this connection, an index can't
+    /// be on an expression that calls it.
+    pub fn create_function<F>(&self, name: &str, num_args: usize, function: F) -> Result<(), Error>
+    where
+        F: Fn(&[Value]) -> Value + Send + Sync + 'static,
+    {
+        if function_index(name).is_some() {
+            return Err(Error::Invalid("cannot replace a built-in function"));
+        }
+        let functions = &mut self.table().functions;
+        functions.retain(|function| !function.name.eq_ignore_ascii_case(name));
+        let call = Box::new(function);
+        functions.push(Arc::new(UserFunction { name: String::from(name), num_args, call }));
+        Ok(())
+    }
+
+    /// Run one of the shell's meta-commands, such as `.btree`, and return its output.
+    pub fn meta_command(&self, command: &str) -> MetaCommandResult {
+        do_meta_command(command, &mut self.table())
+    }
+
+    /// Roll back the transaction that the connection left open, if any, and release its lock on
+    /// the database.
+    fn end_transaction(&self) {
+        let table = &mut *self.table();
+        if table.pager.in_transaction() {
+            table.pager.rollback();
+            let _ = table.read_schema();
+        }
+        let _ = table.finish_statement();
+    }
+}
+
+
+/// The state of a connection, locked for a call. While it is, the statements that are parsed on
+/// the thread can call the functions defined on the connection.
+pub(crate) struct TableGuard<'a> {
+    table: MutexGuard<'a, Table>,
+    // The functions that statements could call before, e.g. those of another connection, when a
+    // function defined on that one uses this one.
+    previous_functions: Vec<Arc<UserFunction>>,
+}
+
+
+impl Deref for TableGuard<'_> {
+    type Target = Table;
+
+    fn deref(&self) -> &Table {
+        &self.table
+    }
+}
+
+
+impl DerefMut for TableGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Table {
+        &mut self.table
+    }
+}
+
+
+impl Drop for TableGuard<'_> {
+    fn drop(&mut self) {
+        replace_user_functions(mem::take(&mut self.previous_functions));
+    }
+}
+
+
+/// A transaction on a connection, begun by `Database::transaction`.
+pub struct Transaction<'a> {
+    database: &'a Database,
+    is_committed: bool,
+}
+
+
+impl Transaction<'_> {
+    /// Commit the transaction. If that fails, e.g. because another connection is reading the
+    /// database, it is rolled back instead.
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.database.execute("commit")?;
+        self.is_committed = true;
+        Ok(())
+    }
+
+    /// Roll back the transaction, which is the same as dropping it.
+    pub fn rollback(self) {}
+}
+
+
+impl Deref for Transaction<'_> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.database
+    }
+}
+
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.is_committed {
+            self.database.end_transaction();
+        }
+    }
+}
+
+
+impl OpenOptions {
+    fn new() -> Self {
+        OpenOptions {
+            read_only: false,
+            create: true,
+            cache_pages: DEFAULT_CACHE_SIZE,
+            page_size: PAGE_SIZE,
+        }
+    }
+
+    /// Whether to only read the database, which is off by default. A read-only database must
+    /// already exist, and every statement that would write to it is an error.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether to create the database file if it doesn't exist, which is on by default.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// The number of pages that the cache can hold, as the `cache_size` pragma sets.
+    pub fn cache_pages(&mut self, cache_pages: usize) -> &mut Self {
+        self.cache_pages = cache_pages;
+        self
+    }
+
+    /// The size of the database's pages in bytes. Only 4096, which is the default, is supported,
+    /// so any other size is an error when the database is opened.
+    pub fn page_size(&mut self, page_size: usize) -> &mut Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Open the database at the given path with these options.
+    pub fn open(&self, path: &str) -> Result<Database, Error> {
+        Ok(Database::new(db_open_with(path, self)?))
+    }
+}
+
+
+/// Parse a statement that a program passed in as a string.
+pub(crate) fn parse_command(command: &str) -> Result<Statement, Error> {
+    Ok(Statement::parse(command)?)
+}
+
+
+/// A set of connections to one database that are handed out and then returned, so that a program
+/// that runs many short requests doesn't open the database for each one. At most `max_conns`
+/// connections are open at once, and asking for another one waits until one is returned.
+pub struct Pool {
+    path: String,
+    max_conns: usize,
+    pub(crate) state: Mutex<PoolState>,
+    // Notified whenever a connection is returned, or a slot for one is freed.
+    returned: Condvar,
+}
+
+
+pub(crate) struct PoolState {
+    // The connections that aren't handed out, the most recently returned one last.
+    idle: Vec<Database>,
+    // The number of connections that are open, whether or not they are handed out.
+    pub(crate) num_open: usize,
+}
+
+
+/// A connection handed out by a pool, which is returned to it when dropped.
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    // Only None while it is being returned.
+    database: Option<Database>,
+}
+
+
+impl Pool {
+    /// Open a pool of connections to the database at the given path. One connection is opened
+    /// right away, so that a database that can't be opened is an error here rather than later.
+    pub fn new(path: &str, max_conns: usize) -> Result<Self, Error> {
+        if max_conns == 0 {
+            return Err(Error::Invalid("a pool must allow at least one connection"));
+        }
+        let database = Database::open(path)?;
+        Ok(Pool {
+            path: path.to_string(),
+            max_conns,
+            state: Mutex::new(PoolState { idle: vec![database], num_open: 1 }),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// Hand out a connection, which is an idle one if there is one that is still healthy, and
+    /// otherwise a new one, waiting until a connection is returned if there are already
+    /// `max_conns` of them.
+    pub fn get(&self) -> Result<PooledConnection<'_>, Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(database) = state.idle.pop() {
+                if self.is_healthy(&database) {
+                    return Ok(PooledConnection { pool: self, database: Some(database) });
+                }
+                state.num_open -= 1;
+            } else if state.num_open < self.max_conns {
+                // The slot is taken before opening, which is done without holding the lock.
+                state.num_open += 1;
+                drop(state);
+                return match Database::open(&self.path) {
+                    Ok(database) => Ok(PooledConnection { pool: self, database: Some(database) }),
+                    Err(e) => {
+                        self.state.lock().unwrap().num_open -= 1;
+                        self.returned.notify_one();
+                        Err(e)
+                    },
+                };
+            } else {
+                state = self.returned.wait(state).unwrap();
+            }
+        }
+    }
+
+    /// Return a connection to the pool, first rolling back any transaction that it left open, so
+    /// that the next one to use it starts afresh.
+    fn put(&self, database: Database) {
+        database.end_transaction();
+        self.state.lock().unwrap().idle.push(database);
+        self.returned.notify_one();
+    }
+
+    /// Return whether an idle connection can still be used, which it can't if the database file
+    /// that it has open was removed or replaced since.
+    fn is_healthy(&self, database: &Database) -> bool {
+        match database.table().pager.vfs.file() {
+            Some(file) => is_same_file(file, &self.path),
+            None => true,
+        }
+    }
+}
+
+
+impl Deref for PooledConnection<'_> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.database.as_ref().unwrap()
+    }
+}
+
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Database {
+        self.database.as_mut().unwrap()
+    }
+}
+
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(database) = self.database.take() {
+            self.pool.put(database);
+        }
+    }
+}
+
+
+// Opening this path creates a database that is only kept in memory, and is gone once it's closed.
+pub(crate) const MEMORY_DATABASE_PATH: &str = ":memory:";
+
+// The name that queries can use for the database that was opened first, as opposed to the ones
+// attached to it.
+const MAIN_DATABASE_NAME: &str = "main";
+
+// How many samples of each index's values ANALYZE takes, which is how finely the planner can tell
+// what fraction of the rows a range of values holds.
+const NUM_STATS_SAMPLES: usize = 16;
+
+/// Execute a prepared statement on the database, and return the number of rows that it changed if
+/// it is an INSERT, COPY or DELETE statement, or 0 otherwise.
+pub(crate) fn execute_statement(
+    statement: &Statement,
+    table: &mut Table,
+) -> Result<usize, Error> {
+    execute_statement_with_rows(statement, table).map(|(changes, _)| changes)
+}
+
+
+/// Execute a prepared statement on the database, as `execute_statement` does, and also return the
+/// rows that it outputs: the results of a SELECT statement or a RETURNING clause, the lines of an
+/// EXPLAIN statement's plan, the rows of a PRAGMA statement, or the number that
+/// `select last_insert_rowid()` or `select changes()` asks for.
+///
+/// With the `tracing` feature, it is executed in a span, which a subscriber can time.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(kind = ?statement.kind))
+)]
+pub(crate) fn execute_statement_with_rows(
+    statement: &Statement,
+    table: &mut Table,
+) -> Result<(usize, Vec<Row>), Error> {
+    let is_write = is_write_statement(statement) && (!statement.explain || statement.analyze);
+    let level = if is_write { LockLevel::Reserved } else { LockLevel::Shared };
+    if let Err(e) = table.lock(level) {
+        let _ = table.finish_statement();
+        return Err(e);
+    }
+    let changes = table.changes;
+
+    // A statement that changes the table outside of a transaction is a transaction of its own, so
+    // that its changes can be undone if they can't be written.
+    let is_autocommit = is_write && !table.pager.in_transaction();
+    if is_autocommit {
+        table.pager.begin(None);
+    }
+
+    let mut rows = Vec::new();
+    let mut result = match statement.kind {
+        _ if statement.explain => explain_rows(statement, table).map(|plan| rows = plan),
+        StatementKind::Insert | StatementKind::InsertOrReplace => {
+            execute_insert(statement, table).map(|returned| rows = returned)
+        },
+        StatementKind::Select => query_rows(statement, table).map(|results| rows = results),
+        StatementKind::Copy => execute_copy(statement, table),
+        StatementKind::Delete => execute_delete(statement, table).map(|returned| rows = returned),
+        StatementKind::LastInsertRowid => {
+            rows = number_rows("last_insert_rowid()", i64::from(table.last_insert_rowid));
+            Ok(())
+        },
+        StatementKind::Changes => {
+            rows = number_rows("changes()", table.changes as i64);
+            Ok(())
+        },
+        StatementKind::CreateIndex => execute_create_index(statement, table),
+        StatementKind::CreateView => execute_create_view(statement, table),
+        StatementKind::DropView => execute_drop_view(statement, table),
+        StatementKind::CreateTrigger => execute_create_trigger(statement, table),
+        StatementKind::DropTrigger => execute_drop_trigger(statement, table),
+        StatementKind::Analyze => execute_analyze(table),
+        StatementKind::Begin => execute_begin(table),
+        StatementKind::Commit => execute_commit(table),
+        StatementKind::Rollback => execute_rollback(table),
+        StatementKind::Savepoint => execute_savepoint(statement, table),
+        StatementKind::RollbackTo => execute_rollback_to(statement, table),
+        StatementKind::Release => execute_release(statement, table),
+        StatementKind::Pragma => pragma_rows(statement, table).map(|values| rows = values),
+        StatementKind::Vacuum => execute_vacuum(table),
+        StatementKind::Attach => execute_attach(statement, table),
+        StatementKind::Detach => execute_detach(statement, table),
+    };
+
+    if is_autocommit {
+        if result.is_ok() && table.pager.has_changes() {
+            result = table.lock(LockLevel::Exclusive).and_then(|()| check_commit_hook(table));
+        }
+        if result.is_ok() {
+            table.pager.commit();
+        } else {
+            table.pager.rollback();
+            table.read_schema()?;
+        }
+    }
+
+    // The count is only of changes that were kept.
+    let result = result.and(table.finish_statement());
+    if result.is_err() {
+        table.changes = changes;
+    }
+    let result = result.map(|()| if statement.changes_rows() { table.changes } else { 0 });
+    debug!(?result, "executed statement");
+    result.map(|changes| (changes, rows))
+}
+
+
+/// Return whether a statement changes the table, and so needs a reserved lock.
+fn is_write_statement(statement: &Statement) -> bool {
+    match statement.kind {
+        StatementKind::Insert
+        | StatementKind::InsertOrReplace
+        | StatementKind::Copy
+        | StatementKind::Delete
+        | StatementKind::CreateIndex
+        | StatementKind::CreateView
+        | StatementKind::DropView
+        | StatementKind::CreateTrigger
+        | StatementKind::DropTrigger
+        | StatementKind::Analyze => true,
+        // The other settings belong to the connection rather than the database.
+        StatementKind::Pragma => statement.pragma.as_ref().is_some_and(|pragma| {
+            pragma.value.is_some() && pragma.name.eq_ignore_ascii_case("user_version")
+        }),
+        _ => false,
+    }
+}
+
+
+/// Execute an INSERT statement. Its rows are loaded as one batch, so if any of them has a
+/// duplicate id, none of them are inserted. With OR REPLACE, a row instead replaces any other with
+/// the same id, whether it's in the table or earlier in the statement. Return the results of its
+/// RETURNING clause, if it has one.
+fn execute_insert(statement: &Statement, table: &mut Table) -> Result<Vec<Row>, Error> {
+    let mut rows = statement.rows_to_insert.as_ref().unwrap().clone();
+    assign_ids(&mut rows, table)?;
+    let last_id = rows.last().unwrap().id();
+    let inserted = rows.clone();
+
+    if let StatementKind::InsertOrReplace = statement.kind {
+        let mut seen = HashSet::new();
+        rows.reverse();
+        rows.retain(|row| seen.insert(row.id()));
+
+        let mut new_rows = Vec::new();
+        for row in rows {
+            if Cursor::find(table, row.id())?.is_at_key(&row.id().to_be_bytes())? {
+                replace_row(table, &row)?;
+            } else {
+                new_rows.push(row);
+            }
+        }
+        rows = new_rows;
+    }
+
+    bulk_load(&mut rows, table)?;
+    table.last_insert_rowid = last_id;
+    table.changes = inserted.len();
+    for row in &inserted {
+        call_update_hook(table, Action::Insert, row.id());
+    }
+    fire_triggers(table, Action::Insert, &inserted)?;
+    Ok(returning_rows(statement, &inserted))
+}
+
+
+/// Overwrite the row with the same id as the given one, which must be in the table, and update its
+/// index entries. The row is overwritten in place, so it is never missing from the table.
+fn replace_row(table: &mut Table, row: &Row) -> Result<(), Error> {
+    let mut cursor = Cursor::find(table, row.id())?;
+    let (page_num, offset) = cursor_value(&mut cursor)?;
+    let old_row = deserialize_row(cursor.table.pager.get_page_ref(page_num)?, offset)?;
+    serialize_row(row, cursor.table.pager.get_page(page_num)?, offset);
+
+    for (tree, key) in index_entries(table, &old_row) {
+        let mut cursor = Cursor::find_in(table, tree, &key)?;
+        leaf_node_delete(&mut cursor)?;
+    }
+    for (tree, key) in index_entries(table, row) {
+        let mut cursor = Cursor::find_in(table, tree, &key)?;
+        leaf_node_insert(&mut cursor, &key, &[])?;
+    }
+    Ok(())
+}
+
+
+/// Return the tree and key of the given row's entry in each of the table's indexes.
+fn index_entries(table: &Table, row: &Row) -> Vec<(Tree, Vec<u8>)> {
+    table.indexes.iter()
+        .filter(|index| index.covers(row))
+        .map(|index| (index.tree(), index.key(row)))
+        .collect()
+}
+
+
+/// Assign an id to each row to be inserted without one, which is one more than the largest id
+/// ever assigned, counting the rows before it.
+fn assign_ids(rows: &mut [Row], table: &mut Table) -> Result<(), Error> {
+    let mut max_id = header_max_id(table.pager.get_page_ref(HEADER_PAGE_NUM)?);
+    for row in rows {
+        if row.values[ID_COLUMN] == Value::Null {
+            max_id = max_id.checked_add(1).ok_or(Error::Full("no more ids available"))?;
+            row.values[ID_COLUMN] = Value::Integer(i64::from(max_id));
+        } else {
+            max_id = max_id.max(row.id());
+        }
+    }
+    Ok(())
+}
+
+
+/// Update the largest id ever assigned, after a row with the given id has been inserted.
+fn record_inserted_id(table: &mut Table, id: u32) -> Result<(), Error> {
+    let header = table.pager.get_page(HEADER_PAGE_NUM)?;
+    if id > header_max_id(header) {
+        set_header_max_id(header, id);
+    }
+    Ok(())
+}
+
+
+/// Return the one row of a `select last_insert_rowid()` or `select changes()` statement, whose
+/// value is the number, in a column named after the function.
+fn number_rows(name: &str, number: i64) -> Vec<Row> {
+    vec![Row { values: vec![Value::Integer(number)], columns: Some([String::from(name)].into()) }]
+}
+
+
+/// Execute a BEGIN statement. Transactions cannot be nested.
+fn execute_begin(table: &mut Table) -> Result<(), Error> {
+    if table.pager.in_transaction() {
+        return Err(Error::Invalid("a transaction is already active"));
+    }
+    table.pager.begin(None);
+    Ok(())
+}
+
+
+/// Execute a COMMIT statement.
+fn execute_commit(table: &mut Table) -> Result<(), Error> {
+    if !table.pager.in_transaction() {
+        return Err(Error::Invalid("no transaction is active"));
+    }
+
+    // If another connection is reading, the transaction stays active so that it can be committed
+    // once it's done.
+    if table.pager.has_changes() {
+        table.lock(LockLevel::Exclusive)?;
+        check_commit_hook(table)?;
+    }
+    table.pager.commit();
+    Ok(())
+}
+
+
+/// Call the commit hook, if there is one, before the transaction's changes are committed. If it
+/// returns true, the transaction is rolled back instead.
+fn check_commit_hook(table: &mut Table) -> Result<(), Error> {
+    if let Some(hook) = table.commit_hook.as_mut() {
+        if hook() {
+            table.pager.rollback();
+            table.read_schema()?;
+            return Err(Error::Constraint("the commit hook rolled back the transaction"));
+        }
+    }
+    Ok(())
+}
+
+
+/// Call the update hook, if there is one, after a row of the table was inserted or deleted.
+fn call_update_hook(table: &mut Table, action: Action, id: u32) {
+    if let Some(hook) = table.update_hook.as_mut() {
+        hook(action, TABLE_NAME, i64::from(id));
+    }
+}
+
+
+/// Execute a ROLLBACK statement, which restores the table and its indexes to how they were when
+/// the transaction began.
+fn execute_rollback(table: &mut Table) -> Result<(), Error> {
+    if !table.pager.in_transaction() {
+        return Err(Error::Invalid("no transaction is active"));
+    }
+    table.pager.rollback();
+    table.read_schema()?;
+    Ok(())
+}
+
+
+/// Execute a SAVEPOINT statement, which starts a transaction if one is not already active.
+fn execute_savepoint(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    table.pager.begin(statement.savepoint.as_deref());
+    Ok(())
+}
+
+
+/// Execute a ROLLBACK TO statement, which undoes the changes made since the savepoint but keeps
+/// both the savepoint and the transaction.
+fn execute_rollback_to(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    let name = statement.savepoint.as_ref().unwrap();
+    let position = table.pager.find_savepoint(name).ok_or(Error::Invalid("no such savepoint"))?;
+    table.pager.rollback_to(position);
+    table.read_schema()?;
+    Ok(())
+}
+
+
+/// Execute a RELEASE statement, which forgets the savepoint and every one after it, keeping their
+/// changes. Releasing the savepoint that started the transaction commits it.
+fn execute_release(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    let name = statement.savepoint.as_ref().unwrap();
+    let position = table.pager.find_savepoint(name).ok_or(Error::Invalid("no such savepoint"))?;
+    if position == 0 && table.pager.has_changes() {
+        check_commit_hook(table)?;
+    }
+    table.pager.release(position);
+    Ok(())
+}
+
+
+/// Return the rows of a PRAGMA statement. For a setting, this is one row with its value, after
+/// changing it if there is a value, in a column named after it.
+fn pragma_rows(statement: &Statement, table: &mut Table) -> Result<Vec<Row>, Error> {
+    let pragma = statement.pragma.as_ref().unwrap();
+    let name = pragma.name.to_ascii_lowercase();
+    let value = pragma.value.as_deref();
+    let setting = match name.as_str() {
+        "journal_mode" => pragma_journal_mode(value, table)?,
+        "wal_autocheckpoint" => pragma_wal_autocheckpoint(value, table)?,
+        "synchronous" => pragma_synchronous(value, table)?,
+        "cache_size" => pragma_cache_size(value, table)?,
+        "mmap_size" => pragma_mmap_size(value, table)?,
+        "busy_timeout" => pragma_busy_timeout(value, table)?,
+        "page_size" => pragma_page_size(value)?,
+        "user_version" => pragma_user_version(value, table)?,
+        "wal_checkpoint" if value.is_none() => Value::Integer(table.pager.checkpoint()? as i64),
+        "wal_checkpoint" => return Err(Error::Invalid("wal_checkpoint does not take a value")),
+        "integrity_check" if value.is_none() => {
+            let mut problems = integrity_check(table)?;
+            if problems.is_empty() {
+                problems.push(String::from("ok"));
+            }
+            let columns: Arc<[String]> = [name].into();
+            let rows = problems.into_iter().map(|problem| Row {
+                values: vec![Value::Text(problem)],
+                columns: Some(columns.clone()),
+            });
+            return Ok(rows.collect());
+        },
+        "integrity_check" => {
+            return Err(Error::Invalid("integrity_check does not take a value"));
+        },
+        "table_info" => return pragma_table_info(value),
+        _ => return Err(Error::Invalid("unknown pragma")),
+    };
+    Ok(vec![Row { values: vec![setting], columns: Some([name].into()) }])
+}
+
+
+/// The `journal_mode` setting is either `delete`, to write changes to the database file through a
+/// rollback journal, or `wal`, to append them to a write-ahead log.
+fn pragma_journal_mode(value: Option<&str>, table: &mut Table) -> Result<Value, Error> {
+    let is_memory = !table.pager.vfs.is_persistent();
+    if let Some(mode) = value {
+        if table.pager.in_transaction() {
+            return Err(Error::Invalid("cannot change the journal mode within a transaction"));
+        } else if is_memory {
+            return Err(Error::Invalid("cannot change the journal mode of an in-memory database"));
+        } else if table.pager.vfs.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        if mode.eq_ignore_ascii_case("wal") {
+            table.lock(LockLevel::Exclusive)?;
+            table.pager.enable_wal()?;
+        } else if mode.eq_ignore_ascii_case("delete") {
+            table.pager.disable_wal()?;
+        } else {
+            return Err(Error::Invalid("unknown journal mode"));
+        }
+    }
+    let mode = match table.pager.wal {
+        _ if is_memory => "memory",
+        Some(_) => "wal",
+        None => "delete",
+    };
+    Ok(Value::Text(String::from(mode)))
+}
+
+
+/// The `synchronous` setting controls when writes are made durable. See `Synchronous`.
+fn pragma_synchronous(value: Option<&str>, table: &mut Table) -> Result<Value, Error> {
+    if let Some(value) = value {
+        table.pager.synchronous = Synchronous::parse(value)
+            .ok_or(Error::Invalid("unknown synchronous setting"))?;
+    }
+    Ok(Value::Text(String::from(table.pager.synchronous.name())))
+}
+
+
+/// The `cache_size` setting is the number of pages that the pager keeps in memory.
+fn pragma_cache_size(value: Option<&str>, table: &mut Table) -> Result<Value, Error> {
+    if let Some(value) = value {
+        match value.parse() {
+            Ok(cache_size) if cache_size > 0 => table.pager.set_cache_size(cache_size),
+            _ => return Err(Error::Invalid("invalid number of pages")),
+        }
+    }
+    Ok(Value::Integer(table.pager.cache_size as i64))
+}
+
+
+/// The `busy_timeout` setting is the number of milliseconds to keep trying to take a lock on the
+/// database when another connection's lock is in the way, waiting longer between each try, before
+/// failing with "database is locked". With 0, which is the default, it fails right away.
+fn pragma_busy_timeout(value: Option<&str>, table: &mut Table) -> Result<Value, Error> {
+    if let Some(value) = value {
+        table.pager.busy_timeout =
+            value.parse().map_err(|_| Error::Invalid("invalid number of milliseconds"))?;
+    }
+    Ok(Value::Integer(table.pager.busy_timeout as i64))
+}
+
+
+/// The `mmap_size` setting is the most bytes of the database file that are mapped into memory, so
+/// that pages can be read from it without read(), or 0 to not map the file. It falls back to 0 if
+/// the file can't be mapped.
+fn pragma_mmap_size(value: Option<&str>, table: &mut Table) -> Result<Value, Error> {
+    if let Some(value) = value {
+        let mmap_size = value.parse().map_err(|_| Error::Invalid("invalid number of bytes"))?;
+        table.pager.set_mmap_size(mmap_size);
+    }
+    Ok(Value::Integer(table.pager.mmap_size as i64))
+}
+
+
+/// The `wal_autocheckpoint` setting is the number of pages that the write-ahead log can hold before
+/// it is automatically checkpointed, or 0 to only checkpoint when asked.
+fn pragma_wal_autocheckpoint(value: Option<&str>, table: &mut Table) -> Result<Value, Error> {
+    if let Some(value) = value {
+        table.pager.wal_autocheckpoint =
+            value.parse().map_err(|_| Error::Invalid("invalid number of pages"))?;
+    }
+    Ok(Value::Integer(table.pager.wal_autocheckpoint as i64))
+}
+
+
+/// The `page_size` setting is the number of bytes in each page of the database file. It can't be
+/// changed, but setting it to the size it already is does nothing, as with `Options::page_size`.
+fn pragma_page_size(value: Option<&str>) -> Result<Value, Error> {
+    if let Some(value) = value {
+        let page_size: usize = value.parse().map_err(|_| Error::Invalid("invalid page size"))?;
+        if page_size != PAGE_SIZE {
+            return Err(Error::Invalid("the page size cannot be changed"));
+        }
+    }
+    Ok(Value::Integer(PAGE_SIZE as i64))
+}
+
+
+/// The `user_version` setting is a number kept in the header for the database's user, which is 0
+/// until they set it. Setting it changes the database, so it is part of the transaction.
+fn pragma_user_version(value: Option<&str>, table: &mut Table) -> Result<Value, Error> {
+    if let Some(value) = value {
+        let user_version = value.parse().map_err(|_| Error::Invalid("invalid user version"))?;
+        set_header_user_version(table.pager.get_page(HEADER_PAGE_NUM)?, user_version);
+    }
+    let user_version = header_user_version(table.pager.get_page_ref(HEADER_PAGE_NUM)?);
+    Ok(Value::Integer(i64::from(user_version)))
+}
+
+
+/// `table_info(<table>)` has a row for each column of the table, with its position, name and
+/// type, and whether it is the primary key.
+fn pragma_table_info(value: Option<&str>) -> Result<Vec<Row>, Error> {
+    match value {
+        Some(name) if name.eq_ignore_ascii_case(TABLE_NAME) => {},
+        Some(_) => return Err(Error::Invalid("no such table")),
+        None => return Err(Error::Invalid("table_info needs a table")),
+    }
+    let columns: Arc<[String]> = ["cid", "name", "type", "pk"].map(String::from).into();
+    let rows = COLUMNS.iter().enumerate().map(|(i, column)| Row {
+        values: vec![
+            Value::Integer(i as i64),
+            Value::Text(String::from(column.name)),
+            Value::Text(String::from(type_name(column.column_type))),
+            Value::Integer(i64::from(i == ID_COLUMN)),
+        ],
+        columns: Some(columns.clone()),
+    });
+    Ok(rows.collect())
+}
+
+
+/// Execute a DELETE statement. Deleting an id that is not in the table does nothing. Return the
+/// results of its RETURNING clause, if it has one.
+fn execute_delete(statement: &Statement, table: &mut Table) -> Result<Vec<Row>, Error> {
+    let id = statement.id_to_delete.unwrap();
+    let mut cursor = Cursor::find(table, id)?;
+    if !cursor.is_at_key(&id.to_be_bytes())? {
+        table.changes = 0;
+        return Ok(Vec::new());
+    }
+
+    let (page_num, offset) = cursor_value(&mut cursor)?;
+    let row = deserialize_row(cursor.table.pager.get_page_ref(page_num)?, offset)?;
+    leaf_node_delete(&mut cursor)?;
+
+    for (tree, key) in index_entries(table, &row) {
+        let mut cursor = Cursor::find_in(table, tree, &key)?;
+        leaf_node_delete(&mut cursor)?;
+    }
+    let header = table.pager.get_page(HEADER_PAGE_NUM)?;
+    set_header_row_count(header, header_row_count(header) - 1);
+    table.changes = 1;
+    call_update_hook(table, Action::Delete, id);
+    fire_triggers(table, Action::Delete, slice::from_ref(&row))?;
+    Ok(returning_rows(statement, slice::from_ref(&row)))
+}
+
+
+/// Execute a CREATE INDEX statement.
+fn execute_create_index(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    create_index(table, statement.index_to_create.as_ref().unwrap())
+}
+
+
+/// Create an index, building it from the rows already in the table.
+fn create_index(table: &mut Table, create_index: &CreateIndex) -> Result<(), Error> {
+    if table.indexes.iter().any(|index| index.name == create_index.name) {
+        return Err(Error::Invalid("index already exists"));
+    } else if table.indexes.len() >= MAX_INDEXES {
+        return Err(Error::Full("too many indexes"));
+    }
+
+    let expression = match &create_index.expression {
+        Some(text) => Some((text.clone(), prepare_index_expression(text)?)),
+        None => None,
+    };
+    let where_clause = match &create_index.where_clause {
+        Some(text) => Some((text.clone(), prepare_index_predicate(text)?)),
+        None => None,
+    };
+    let index = Index {
+        name: create_index.name.clone(),
+        column: create_index.column,
+        root_page_num: table.pager.get_unused_page_num()?,
+        expression,
+        where_clause,
+    };
+    let root = table.pager.get_page(index.root_page_num)?;
+    initialize_leaf_node(root);
+    set_node_root(root, true);
+
+    let mut keys = Vec::new();
+    let mut cursor = Cursor::from_start(table)?;
+    while !cursor.end_of_table {
+        let (page_num, offset) = cursor_value(&mut cursor)?;
+        let row = deserialize_row(cursor.table.pager.get_page_ref(page_num)?, offset)?;
+        if index.covers(&row) {
+            keys.push(index.key(&row));
+        }
+        cursor.advance()?;
+    }
+
+    // The index is empty, so as in bulk_load, its B-tree is built from the leaves up.
+    let mut entries: Vec<(Vec<u8>, Vec<u8>)> =
+        keys.into_iter().map(|key| (key, Vec::new())).collect();
+    entries.sort();
+    insert_sorted(table, index.tree(), &entries)?;
+
+    append_index(table.pager.get_page(HEADER_PAGE_NUM)?, &index);
+    let is_on_schema_page = index.expression.is_some() || index.where_clause.is_some();
+    table.indexes.push(index);
+    if is_on_schema_page {
+        set_schema(table, table.views.clone(), table.triggers.clone(), table.stats.clone())?;
+    }
+    Ok(())
+}
+
+
+/// Execute a CREATE VIEW statement. The views that the view reads must already exist.
+fn execute_create_view(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    let create_view = statement.view_to_create.as_ref().unwrap();
+    if find_view(table, &create_view.name).is_some() {
+        return Err(Error::Invalid("view already exists"));
+    }
+    let select = prepare_statement(&create_view.text)?;
+    for name in select.selects().iter().filter_map(|select| select.view.as_deref()) {
+        find_view(table, name).ok_or(Error::Invalid("no such table"))?;
+    }
+
+    let mut views = table.views.clone();
+    views.push(View { name: create_view.name.clone(), text: create_view.text.clone() });
+    set_schema(table, views, table.triggers.clone(), table.stats.clone())
+}
+
+
+/// Execute a DROP VIEW statement.
+fn execute_drop_view(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    let name = statement.view.as_ref().unwrap();
+    let mut views = table.views.clone();
+    views.retain(|view| !view.name.eq_ignore_ascii_case(name));
+    if views.len() == table.views.len() {
+        return Err(Error::Invalid("no such view"));
+    }
+    set_schema(table, views, table.triggers.clone(), table.stats.clone())
+}
+
+
+/// Execute a CREATE TRIGGER statement.
+fn execute_create_trigger(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    let create_trigger = statement.trigger_to_create.as_ref().unwrap();
+    let name = &create_trigger.name;
+    if table.triggers.iter().any(|trigger| trigger.name.eq_ignore_ascii_case(name)) {
+        return Err(Error::Invalid("trigger already exists"));
+    }
+
+    let mut triggers = table.triggers.clone();
+    triggers.push(Trigger {
+        name: name.clone(),
+        action: create_trigger.action,
+        body: create_trigger.body.clone(),
+    });
+    set_schema(table, table.views.clone(), triggers, table.stats.clone())
+}
+
+
+/// Execute a DROP TRIGGER statement.
+fn execute_drop_trigger(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    let name = statement.trigger.as_ref().unwrap();
+    let mut triggers = table.triggers.clone();
+    triggers.retain(|trigger| !trigger.name.eq_ignore_ascii_case(name));
+    if triggers.len() == table.triggers.len() {
+        return Err(Error::Invalid("no such trigger"));
+    }
+    set_schema(table, table.views.clone(), triggers, table.stats.clone())
+}
+
+
+/// Execute the statements of each trigger on the action for each of the rows, in the order the
+/// triggers were created. Triggers don't fire for the changes that their statements make, which
+/// aren't counted as changes of the statement that fired them either.
+fn fire_triggers(table: &mut Table, action: Action, rows: &[Row]) -> Result<(), Error> {
+    let triggers: Vec<Trigger> =
+        table.triggers.iter().filter(|trigger| trigger.action == action).cloned().collect();
+    if table.in_trigger || triggers.is_empty() {
+        return Ok(());
+    }
+
+    let (changes, last_insert_rowid) = (table.changes, table.last_insert_rowid);
+    table.in_trigger = true;
+    let result = profile_step(
+        table,
+        |_| describe_triggers(&triggers),
+        |table| execute_triggers(table, &triggers, rows),
+        |_| rows.len(),
+    );
+    table.in_trigger = false;
+    table.changes = changes;
+    table.last_insert_rowid = last_insert_rowid;
+    result
+}
+
+
+/// Execute the statements of each of the triggers for each of the rows.
+fn execute_triggers(table: &mut Table, triggers: &[Trigger], rows: &[Row]) -> Result<(), Error> {
+    for row in rows {
+        for trigger in triggers {
+            for statement in prepare_trigger(trigger, row)? {
+                execute_statement(&statement, table)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// Return the view with the given name, if there is one.
+pub(crate) fn find_view<'a>(table: &'a Table, name: &str) -> Option<&'a View> {
+    table.views.iter().find(|view| view.name.eq_ignore_ascii_case(name))
+}
+
+
+/// Replace the views, triggers and statistics with the given ones, and rewrite the expressions and
+/// WHERE clauses of the indexes. The page that holds them is allocated when there are first any,
+/// and freed when there are none left.
+fn set_schema(
+    table: &mut Table,
+    views: Vec<View>,
+    triggers: Vec<Trigger>,
+    stats: Option<Stats>,
+) -> Result<(), Error> {
+    let contents = encode_schema_page(&views, &triggers, stats.as_ref(), &table.indexes)
+        .ok_or(Error::Full("too many views, triggers, statistics and index definitions"))?;
+
+    let header = table.pager.get_page_ref(HEADER_PAGE_NUM)?;
+    let mut page_num = read_u32(header, HEADER_SCHEMA_PAGE_OFFSET) as usize;
+    let has_index_definitions = table.indexes.iter()
+        .any(|index| index.expression.is_some() || index.where_clause.is_some());
+    if views.is_empty() && triggers.is_empty() && stats.is_none() && !has_index_definitions {
+        if page_num != 0 {
+            table.pager.free_page(page_num)?;
+            page_num = 0;
+        }
+    } else {
+        if page_num == 0 {
+            page_num = table.pager.get_unused_page_num()?;
+        }
+        let page = table.pager.get_page(page_num)?;
+        page[..PAGE_CHECKSUM_OFFSET].fill(0);
+        page[..contents.len()].copy_from_slice(&contents);
+    }
+    write_u32(table.pager.get_page(HEADER_PAGE_NUM)?, HEADER_SCHEMA_PAGE_OFFSET, page_num as u32);
+    table.views = views;
+    table.triggers = triggers;
+    table.stats = stats;
+    Ok(())
+}
+
+
+/// Execute an ANALYZE statement, which counts the table's rows, and reads each of its indexes to
+/// count their distinct values and take samples of them, for the planner.
+fn execute_analyze(table: &mut Table) -> Result<(), Error> {
+    let num_rows = header_row_count(table.pager.get_page_ref(HEADER_PAGE_NUM)?) as usize;
+    let trees: Vec<_> = table.indexes.iter()
+        .map(|index| (index.name.clone(), index.tree(), index.where_clause.is_some()))
+        .collect();
+    let mut indexes = Vec::new();
+    for (name, tree, is_partial) in trees {
+        // A partial index only has entries for some of the rows, so they have to be counted for
+        // the samples to be spread through all of it.
+        let num_entries = if is_partial { count_entries(table, tree)? } else { num_rows };
+        indexes.push(analyze_index(table, name, tree, num_entries)?);
+    }
+    let stats = Stats { num_rows, indexes };
+    set_schema(table, table.views.clone(), table.triggers.clone(), Some(stats))
+}
+
+
+/// Count the entries in a B-tree by reading all of them.
+fn count_entries(table: &mut Table, tree: Tree) -> Result<usize, Error> {
+    let mut cursor = Cursor::seek(table, tree, &[])?;
+    let mut num_entries = 0;
+    while !cursor.end_of_table {
+        num_entries += 1;
+        cursor.advance()?;
+    }
+    Ok(num_entries)
+}
+
+
+/// Read an index, which has the given number of entries, and return the number of distinct
+/// values in it and samples of them spread evenly through it.
+fn analyze_index(
+    table: &mut Table, name: String, tree: Tree, num_entries: usize
+) -> Result<IndexStats, Error> {
+    let mut num_distinct = 0;
+    let mut samples: Vec<Vec<u8>> = Vec::new();
+    let mut previous: Option<Vec<u8>> = None;
+    let mut cursor = Cursor::seek(table, tree, &[])?;
+    let mut position = 0;
+    while !cursor.end_of_table {
+        let key = cursor.key()?;
+        let value = &key[..key.len() - ID_KEY_SIZE];
+        if previous.as_deref() != Some(value) {
+            num_distinct += 1;
+            previous = Some(value.to_vec());
+        }
+        if samples.len() < NUM_STATS_SAMPLES
+            && position * NUM_STATS_SAMPLES >= samples.len() * num_entries
+        {
+            let length = value.iter().rposition(|&byte| byte != 0).map_or(0, |i| i + 1);
+            samples.push(value[..length].to_vec());
+        }
+        position += 1;
+        cursor.advance()?;
+    }
+    Ok(IndexStats { name, num_distinct, samples })
+}
+
+
+/// Execute a VACUUM statement, which rebuilds the database in a new file, with each tree's pages
+/// as full as possible and no free pages, and then copies it over the database. It is copied
+/// through the pager like any other change, so if the process dies partway through, the database
+/// is either entirely the old one or entirely the new one, and other connections see the new one.
+fn execute_vacuum(table: &mut Table) -> Result<(), Error> {
+    if table.pager.in_transaction() {
+        return Err(Error::Invalid("cannot vacuum within a transaction"));
+    }
+    table.lock(LockLevel::Exclusive)?;
+
+    // A database in memory is copied into another one in memory, whatever its path.
+    let is_memory = !table.pager.vfs.is_persistent();
+    let vacuum_path = if is_memory {
+        String::from(MEMORY_DATABASE_PATH)
+    } else {
+        format!("{}-vacuum", table.pager.path)
+    };
+    if !is_memory {
+        let _ = fs::remove_file(&vacuum_path);
+    }
+    let mut new_table = db_open(&vacuum_path)?;
+    new_table.pager.synchronous = Synchronous::Off;
+    new_table.pager.set_cache_size(table.pager.cache_size);
+    let mut rows = scan_rows(table, None)?;
+    bulk_load(&mut rows, &mut new_table)?;
+    for index in &table.indexes {
+        let index = CreateIndex {
+            name: index.name.clone(),
+            column: index.column,
+            expression: index.expression.as_ref().map(|(text, _)| text.clone()),
+            where_clause: index.where_clause.as_ref().map(|(text, _)| text.clone()),
+        };
+        create_index(&mut new_table, &index)?;
+    }
+    let (views, triggers) = (table.views.clone(), table.triggers.clone());
+    set_schema(&mut new_table, views, triggers, table.stats.clone())?;
+
+    // The change counter carries on from the old database's, since other connections compare it
+    // with the one they last saw.
+    let header = table.pager.get_page_ref(HEADER_PAGE_NUM)?;
+    let max_id = header_max_id(header);
+    let user_version = header_user_version(header);
+    let change_counter = read_u32(header, HEADER_CHANGE_COUNTER_OFFSET);
+    let num_pages = new_table.pager.num_pages;
+    for page_num in 0..num_pages {
+        let page = new_table.pager.get_page_ref(page_num)?.to_vec();
+        table.pager.get_page(page_num)?.copy_from_slice(&page);
+    }
+    table.pager.truncate(num_pages);
+    let header = table.pager.get_page(HEADER_PAGE_NUM)?;
+    set_header_max_id(header, max_id);
+    set_header_user_version(header, user_version);
+    write_u32(header, HEADER_CHANGE_COUNTER_OFFSET, change_counter);
+    table.read_schema()?;
+
+    drop(new_table);
+    if !is_memory {
+        // A copy that is left behind is removed before the next vacuum anyway.
+        let _ = fs::remove_file(&vacuum_path);
+    }
+    Ok(())
+}
+
+
+/// Execute an ATTACH statement, which opens another database file under the given name, so that
+/// queries can refer to its table as `<name>.users`.
+fn execute_attach(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    if table.pager.in_transaction() {
+        return Err(Error::Invalid("cannot attach a database within a transaction"));
+    }
+
+    let name = statement.database.as_ref().unwrap();
+    let is_in_use = name.eq_ignore_ascii_case(MAIN_DATABASE_NAME)
+        || table.attached.iter().any(|(other, _)| other.eq_ignore_ascii_case(name));
+    if is_in_use {
+        return Err(Error::Invalid("database name is already in use"));
+    }
+    let mut attached = db_open(statement.attach_path.as_ref().unwrap())?;
+    attached.pager.busy_timeout = table.pager.busy_timeout;
+    table.attached.push((name.clone(), attached));
+    Ok(())
+}
+
+
+/// Execute a DETACH statement, which closes an attached database.
+fn execute_detach(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    if table.pager.in_transaction() {
+        return Err(Error::Invalid("cannot detach a database within a transaction"));
+    }
+
+    let name = statement.database.as_ref().unwrap();
+    let position = table.attached.iter().position(|(other, _)| other.eq_ignore_ascii_case(name));
+    table.attached.remove(position.ok_or(Error::Invalid("no such database"))?);
+    Ok(())
+}
+
+
+/// Return the table in the database with the given name: the main database for None or `main`,
+/// and otherwise one that is attached to it.
+pub(crate) fn database_table<'a>(
+    table: &'a mut Table,
+    name: Option<&str>,
+) -> Result<&'a mut Table, Error> {
+    match name {
+        Some(name) if !name.eq_ignore_ascii_case(MAIN_DATABASE_NAME) => table
+            .attached
+            .iter_mut()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, attached)| attached)
+            .ok_or(Error::Invalid("no such database")),
+        _ => Ok(table),
+    }
+}
+
+
+/// Execute a COPY statement, which bulk-loads the rows of a CSV file, as parse_csv_rows reads
+/// them.
+fn execute_copy(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    let path = statement.copy_from.as_ref().unwrap();
+    let contents = fs::read_to_string(path)?;
+    let mut rows = parse_csv_rows(&contents)?;
+    bulk_load(&mut rows, table)?;
+    table.changes = rows.len();
+    for row in &rows {
+        call_update_hook(table, Action::Insert, row.id());
+    }
+    fire_triggers(table, Action::Insert, &rows)
+}
+
+
+/// Insert a batch of rows into the table. The rows are added to the table's B-tree in order of id,
+/// and only then to each index's B-tree, in order of its keys, so that each entry lands next to
+/// the one before it, which keeps the pages being written to in memory. A B-tree that is empty,
+/// as the table's is when it is first loaded, is built from the leaves up instead. If any of the
+/// ids is a duplicate, none of the rows are inserted.
+pub(crate) fn bulk_load(rows: &mut [Row], table: &mut Table) -> Result<(), Error> {
+    rows.sort_by_key(|row| row.id());
+    if rows.windows(2).any(|pair| pair[0].id() == pair[1].id()) {
+        return Err(Error::Constraint("duplicate key"));
+    }
+    for row in rows.iter() {
+        if Cursor::find(table, row.id())?.is_at_key(&row.id().to_be_bytes())? {
+            return Err(Error::Constraint("duplicate key"));
+        }
+    }
+
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = rows
+        .iter()
+        .map(|row| {
+            let mut value = vec![0; ROW_SIZE];
+            serialize_row(row, &mut value, 0);
+            (row.id().to_be_bytes().to_vec(), value)
+        })
+        .collect();
+    let tree = table.tree();
+    insert_sorted(table, tree, &entries)?;
+
+    for i in 0..table.indexes.len() {
+        let index = &table.indexes[i];
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = rows
+            .iter()
+            .filter(|row| index.covers(row))
+            .map(|row| (index.key(row), Vec::new()))
+            .collect();
+        entries.sort();
+        let tree = index.tree();
+        insert_sorted(table, tree, &entries)?;
+    }
+
+    if let Some(row) = rows.last() {
+        record_inserted_id(table, row.id())?;
+    }
+    let header = table.pager.get_page(HEADER_PAGE_NUM)?;
+    set_header_row_count(header, header_row_count(header) + rows.len() as u32);
+    Ok(())
+}
+
+
+/// Execute a statement for EXPLAIN ANALYZE, recording each of its steps in the table's profile.
+/// A SELECT statement's results are dropped.
+pub(crate) fn execute_profiled(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    if let StatementKind::Select = statement.kind {
+        return query_rows(statement, table).map(drop);
+    }
+    let execute = |table: &mut Table| {
+        let result = match statement.kind {
+            StatementKind::Insert | StatementKind::InsertOrReplace => {
+                execute_insert(statement, table)
+            },
+            StatementKind::Copy => execute_copy(statement, table).map(|()| Vec::new()),
+            StatementKind::Delete => execute_delete(statement, table),
+            _ => unreachable!(),
+        };
+        result.map(|_| table.changes)
+    };
+    let changes = |result: &Result<usize, Error>| *result.as_ref().unwrap_or(&0);
+    profile_step(table, |_| describe_write(statement), execute, changes).map(drop)
+}
+
+
+/// Return the lines of an EXPLAIN statement's plan as rows, for a program to read.
+fn explain_rows(statement: &Statement, table: &mut Table) -> Result<Vec<Row>, Error> {
+    let columns: Arc<[String]> = [String::from("plan")].into();
+    Ok(explain(statement, table)?
+        .into_iter()
+        .map(|line| Row { values: vec![Value::Text(line)], columns: Some(columns.clone()) })
+        .collect())
+}
+
+
+/// Return the results of a SELECT statement, which are whole rows for a select list of `*`, and
+/// otherwise have the values of the select list, named by its items. The results of a compound
+/// SELECT are named by the first SELECT's items.
+pub(crate) fn query_rows(statement: &Statement, table: &mut Table) -> Result<Vec<Row>, Error> {
+    // The database that each SELECT reads, including those in subqueries, stays locked until all
+    // of them have been read, so that they see the same version of it.
+    let selects = statement.selects();
+    let mut rows = selects.iter()
+        .try_for_each(|select| {
+            database_table(table, select.database.as_deref())?.lock(LockLevel::Shared)
+        })
+        .and_then(|()| materialize_views(&selects, table, &mut Vec::new()))
+        .and_then(|()| materialize_common_tables(statement, table))
+        .and_then(|()| compound_rows(statement, table));
+    table.common_tables.clear();
+    table.view_rows.clear();
+    for select in &selects {
+        let finished = database_table(table, select.database.as_deref())
+            .and_then(|table| table.finish_statement());
+        rows = rows.and_then(|rows| finished.map(|()| rows));
+    }
+    rows
+}
+
+
+/// Compute the rows of each view that the SELECTs read, and of the views that those read in turn,
+/// so that the SELECTs can read them. `expanding` is the views whose rows are being computed,
+/// which must not read themselves.
+fn materialize_views(
+    selects: &[&Statement],
+    table: &mut Table,
+    expanding: &mut Vec<String>,
+) -> Result<(), Error> {
+    for name in selects.iter().filter_map(|select| select.view.as_deref()) {
+        let key = name.to_ascii_lowercase();
+        if table.view_rows.contains_key(&key) {
+            continue;
+        } else if expanding.contains(&key) {
+            return Err(Error::Invalid("view reads itself"));
+        }
+        let view = find_view(table, name).ok_or(Error::Invalid("no such table"))?;
+        let select = prepare_statement(&view.text)?;
+
+        let materialize = |table: &mut Table| {
+            expanding.push(key.clone());
+            materialize_views(&select.selects(), table, expanding)?;
+            expanding.pop();
+            compound_rows(&select, table)
+        };
+        let describe = |_: &Table| format!("MATERIALIZE {}", name);
+        let rows = profile_step(table, describe, materialize, num_results)?
+            .into_iter()
+            .map(|row| Row { values: row.values, columns: None })
+            .collect();
+        table.view_rows.insert(key, rows);
+    }
+    Ok(())
+}
+
+
+/// Compute the rows of each of a SELECT statement's common table expressions in turn, so that the
+/// SELECTs after it can read them.
+fn materialize_common_tables(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    for (name, select) in &statement.common_tables {
+        let describe = |_: &Table| format!("MATERIALIZE {}", name);
+        let materialize = |table: &mut Table| compound_rows(select, table);
+        let rows = profile_step(table, describe, materialize, num_results)?
+            .into_iter()
+            .map(|row| Row { values: row.values, columns: None })
+            .collect();
+        table.common_tables.insert(name.to_ascii_lowercase(), rows);
+    }
+    Ok(())
+}
+
+
+/// Return the results of a SELECT statement, combining those of each SELECT in it, on databases
+/// that are already locked.
+fn compound_rows(statement: &Statement, table: &mut Table) -> Result<Vec<Row>, Error> {
+    if statement.compound.is_empty() {
+        return simple_rows(statement, table);
+    }
+    let combine = |table: &mut Table| {
+        let describe = |_: &Table| String::from("LEFT-MOST SUBQUERY");
+        let left = |table: &mut Table| simple_rows(statement, table);
+        let rows = profile_step(table, describe, left, num_results)?;
+
+        let columns: Arc<[String]> = statement.result_columns().into();
+        let mut results: Vec<Vec<Value>> = rows.into_iter().map(|row| row.values).collect();
+        for (operator, select) in &statement.compound {
+            let describe = |_: &Table| String::from(operator.name());
+            let right = |table: &mut Table| simple_rows(select, table);
+            let right = profile_step(table, describe, right, num_results)?;
+            let right = right.into_iter().map(|row| row.values).collect();
+            results = combine_results(*operator, results, right);
+        }
+        Ok(results
+            .into_iter()
+            .map(|values| Row { values, columns: Some(columns.clone()) })
+            .collect())
+    };
+    profile_step(table, |_| String::from("COMPOUND QUERY"), combine, num_results)
+}
+
+
+/// Execute the subqueries in a SELECT, so that their results are there when its expressions are
+/// evaluated. Each result is the value of the subquery's only column.
+fn execute_subqueries(statement: &Statement, table: &mut Table) -> Result<(), Error> {
+    for subquery in statement.subqueries() {
+        let execute = |table: &mut Table| compound_rows(&subquery.select, table);
+        let values = profile_step(table, |_| String::from("SUBQUERY"), execute, num_results)?
+            .into_iter()
+            .map(|row| row.values.into_iter().next().unwrap_or(Value::Null))
+            .collect();
+        *subquery.values.lock().unwrap() = values;
+    }
+    Ok(())
+}
+
+
+/// Combine the results of two SELECTs with a set operator. UNION ALL just appends the right
+/// results to the left ones. The other operators sort both sides, so that they can be merged in
+/// one pass that leaves out duplicates, and so their results are in order.
+fn combine_results(
+    operator: SetOperator, mut left: Vec<Vec<Value>>, mut right: Vec<Vec<Value>>
+) -> Vec<Vec<Value>> {
+    if operator == SetOperator::UnionAll {
+        left.append(&mut right);
+        return left;
+    }
+
+    left.sort();
+    left.dedup();
+    right.sort();
+    right.dedup();
+    let mut results = Vec::new();
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    loop {
+        let ordering = match (left.peek(), right.peek()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => break,
+        };
+        // Each result is taken from whichever side it's on, or from both if it's on both, and
+        // kept if the operator keeps results that are on just those sides.
+        let (in_left, in_right) = match ordering {
+            Ordering::Less => (left.next(), None),
+            Ordering::Greater => (None, right.next()),
+            Ordering::Equal => (left.next(), right.next()),
+        };
+        let keep = match operator {
+            SetOperator::Union => true,
+            SetOperator::Intersect => in_left.is_some() && in_right.is_some(),
+            SetOperator::Except => in_right.is_none(),
+            SetOperator::UnionAll => unreachable!(),
+        };
+        if keep {
+            results.extend(in_left.or(in_right));
+        }
+    }
+    results
+}
+
+
+/// Return the results of a SELECT without those of any SELECTs that it is combined with.
+fn simple_rows(statement: &Statement, table: &mut Table) -> Result<Vec<Row>, Error> {
+    execute_subqueries(statement, table)?;
+    // The steps of reading an attached database are profiled along with the rest.
+    let mut profile = table.profile.take();
+    let rows = database_table(table, statement.database.as_deref()).and_then(|table| {
+        table.profile = profile.take();
+        let rows = match &statement.select_list {
+            Some(items) => {
+                let columns: Arc<[String]> = items.iter().map(SelectItem::name).collect();
+                select_results(statement, table).map(|results| {
+                    results
+                        .into_iter()
+                        .map(|values| Row { values, columns: Some(columns.clone()) })
+                        .collect()
+                })
+            },
+            None => select_rows(statement, table),
+        };
+        profile = table.profile.take();
+        rows
+    });
+    table.profile = profile;
+    rows
+}
+
+
+/// Return the results of a statement's RETURNING clause for the rows that it inserted or
+/// deleted, named by its items, or no results if it doesn't have one.
+fn returning_rows(statement: &Statement, rows: &[Row]) -> Vec<Row> {
+    let items = match &statement.returning {
+        Some(items) => items,
+        None => return Vec::new(),
+    };
+    let columns: Arc<[String]> = items.iter().map(SelectItem::name).collect();
+    rows.iter()
+        .map(|row| Row { values: items_values(items, row), columns: Some(columns.clone()) })
+        .collect()
+}
+
+
+/// Return the values of a list of items that don't include an aggregate for a row.
+pub(crate) fn items_values(items: &[SelectItem], row: &Row) -> Vec<Value> {
+    items.iter().map(|item| item.value(row, 1)).collect()
+}
+
+
+/// Return the rows that match a SELECT statement's WHERE clause, in the order given by its ORDER
+/// BY clause or else in order of id.
+pub(crate) fn select_rows(
+    statement: &Statement, table: &mut Table
+) -> Result<Vec<Row>, Error> {
+    let predicate = statement.where_clause.as_ref();
+    let mut rows = if let Some(name) = statement.common_table.as_ref().or(statement.view.as_ref()) {
+        let scan = |table: &mut Table| {
+            let key = name.to_ascii_lowercase();
+            let materialized = match statement.common_table {
+                Some(_) => &table.common_tables[&key],
+                None => &table.view_rows[&key],
+            };
+            materialized
+                .iter()
+                .filter(|row| predicate.is_none_or(|predicate| predicate.matches(row)))
+                .cloned()
+                .collect()
+        };
+        profile_step(table, |_| format!("SCAN {}", name), scan, Vec::len)
+    } else {
+        let index = match predicate {
+            Some(predicate) => choose_index(table, predicate)?,
+            None => None,
+        };
+        let search = |table: &mut Table| match index {
+            Some((index_num, condition)) => {
+                let index_only = is_index_only(statement, table, index_num);
+                index_seek_rows(table, index_num, condition, predicate.unwrap(), index_only)
+            },
+            None => scan_rows(table, predicate),
+        };
+        let describe = |table: &Table| describe_search(statement, table, index);
+        let count = |rows: &Result<Vec<Row>, Error>| rows.as_ref().map_or(0, Vec::len);
+        profile_step(table, describe, search, count)?
+    };
+
+    if let Some(order_by) = statement.order_by.as_ref().filter(|_| needs_sort(statement)) {
+        let sort = |_: &mut Table| {
+            sort_rows(&mut rows, order_by);
+            rows.len()
+        };
+        profile_step(table, |_| describe_sort(order_by), sort, |num_rows| *num_rows);
+    }
+    Ok(rows)
+}
+
+
+/// Return whether a SELECT's rows have to be sorted for its ORDER BY clause, which they don't if
+/// they are read from the table in order of id and that is the order asked for.
+pub(crate) fn needs_sort(statement: &Statement) -> bool {
+    statement.order_by.as_ref().is_some_and(|order_by| {
+        let is_materialized = statement.common_table.is_some() || statement.view.is_some();
+        order_by.column != ID_COLUMN || order_by.descending || is_materialized
+    })
+}
+
+
+/// Return the values of a SELECT statement's select list for each row that matches, or for each
+/// group of rows if the statement groups them or computes an aggregate. With DISTINCT, only the
+/// first of any identical results is kept.
+pub(crate) fn select_results(
+    statement: &Statement, table: &mut Table
+) -> Result<Vec<Vec<Value>>, Error> {
+    let items = statement.select_list.as_ref().unwrap();
+    let is_aggregate = items.iter().any(SelectItem::is_aggregate);
+    let rows = select_rows(statement, table)?;
+    let mut results = if statement.group_by.is_some() || is_aggregate {
+        let describe = |_: &Table| describe_grouping(statement).unwrap();
+        profile_step(table, describe, |_| group_results(statement, rows), Vec::len)
+    } else {
+        rows.iter().map(|row| items_values(items, row)).collect()
+    };
+
+    if statement.distinct {
+        let deduplicate = |_: &mut Table| {
+            let mut seen = HashSet::new();
+            results.retain(|values| seen.insert(values.clone()));
+            results.len()
+        };
+        profile_step(table, |_| String::from("DISTINCT"), deduplicate, |num_results| *num_results);
+    }
+    Ok(results)
+}
+
+
+/// Return the values of a SELECT statement's select list for each group of its matching rows.
+fn group_results(statement: &Statement, rows: Vec<Row>) -> Vec<Vec<Value>> {
+    let items = statement.select_list.as_ref().unwrap();
+
+    // Rows with the same value of the GROUP BY column, or else all of the rows, form a group,
+    // which is represented by its first row and the number of rows in it.
+    let group_column = statement.group_by.as_ref().map(|group_by| group_by.column);
+    let mut groups: HashMap<Value, (Row, usize)> = HashMap::new();
+    for row in rows {
+        let key = group_column.map_or(Value::Null, |column| row.values[column].clone());
+        groups.entry(key).or_insert((row, 0)).1 += 1;
+    }
+
+    let mut groups: Vec<(Row, usize)> = groups.into_values().collect();
+    if group_column.is_none() && groups.is_empty() {
+        // An aggregate over no rows is still one result, e.g. a count of 0.
+        let values = vec![Value::Null; COLUMNS.len()];
+        groups.push((Row { values, columns: None }, 0));
+    }
+
+    let having = statement.group_by.as_ref().and_then(|group_by| group_by.having.as_ref());
+    if let Some(having) = having {
+        groups.retain(|(row, count)| {
+            let value = having.item.value(row, *count);
+            having.comparison.evaluate(&value, &having.value) == Some(true)
+        });
+    }
+
+    // Groups come out in order of the GROUP BY column, which is also the only column that they
+    // can be ordered by.
+    if let Some(column) = group_column {
+        groups.sort_by(|(a, _), (b, _)| a.values[column].cmp(&b.values[column]));
+        if statement.order_by.as_ref().is_some_and(|order_by| order_by.descending) {
+            groups.reverse();
+        }
+    }
+
+    groups
+        .iter()
+        .map(|(row, count)| items.iter().map(|item| item.value(row, *count)).collect())
+        .collect()
+}
+
+
+/// Sort rows by the ORDER BY column. The sort is stable, so rows that compare equal stay in the
+/// order they were in.
+fn sort_rows(rows: &mut [Row], order_by: &OrderBy) {
+    rows.sort_by(|a, b| {
+        let ordering = a.values[order_by.column].cmp(&b.values[order_by.column]);
+        if order_by.descending { ordering.reverse() } else { ordering }
+    });
+}
+
+
+/// Return the rows that match the predicate, if any, by going through the table in order of id.
+/// Only the rows that can match the conditions on the id that the predicate requires are read.
+pub(crate) fn scan_rows(
+    table: &mut Table, predicate: Option<&Predicate>
+) -> Result<Vec<Row>, Error> {
+    let conjuncts = predicate.map_or(Vec::new(), Predicate::conjuncts);
+    let first_possible_id = conjuncts.iter().map(|condition| condition.first_possible_id()).max();
+    let mut cursor = Cursor::from_key(table, first_possible_id.unwrap_or(0))?;
+
+    let mut rows = Vec::new();
+    while !cursor.end_of_table {
+        let (page_num, offset) = cursor_value(&mut cursor)?;
+        let row = deserialize_row(cursor.table.pager.get_page_ref(page_num)?, offset)?;
+        if conjuncts.iter().any(|condition| condition.is_past_last_match(&row)) {
+            break;
+        } else if predicate.is_none_or(|predicate| predicate.matches(&row)) {
+            rows.push(row);
+        }
+        cursor.advance()?;
+    }
+    Ok(rows)
+}
+
+
+/// Return the rows that match the predicate, in order of id, by looking up the ids of the rows in
+/// the condition's range of the given index. The condition must be one of the predicate's
+/// conjuncts. If the index is all that is read, as is_index_only says, the rows are made from its
+/// keys without reading the table, and only have the values of the id and the index's column.
+fn index_seek_rows(
+    table: &mut Table,
+    index_num: usize,
+    condition: &Condition,
+    predicate: &Predicate,
+    index_only: bool,
+) -> Result<Vec<Row>, Error> {
+    let (start, end) = condition.index_range(&table.indexes[index_num]).unwrap();
+    let column = table.indexes[index_num].column;
+    let tree = table.indexes[index_num].tree();
+
+    let mut ids = Vec::new();
+    let mut index_rows = Vec::new();
+    let mut cursor = Cursor::seek(table, tree, &start)?;
+    while !cursor.end_of_table {
+        let key = cursor.key()?;
+        if end.as_ref().is_some_and(|end| key >= *end) {
+            break;
+        }
+        let id = read_u32(&key, key.len() - ID_KEY_SIZE);
+        if index_only {
+            let mut values = vec![Value::Null; COLUMNS.len()];
+            values[ID_COLUMN] = Value::Integer(i64::from(id));
+            values[column] = decode_index_value(&key[..key.len() - ID_KEY_SIZE]);
+            index_rows.push(Row { values, columns: None });
+        } else {
+            ids.push(id);
+        }
+        cursor.advance()?;
+    }
+    if index_only {
+        index_rows.retain(|row| predicate.matches(row));
+        index_rows.sort_by_key(Row::id);
+        return Ok(index_rows);
+    }
+
+    ids.sort();
+    let mut rows = Vec::new();
+    for id in ids {
+        let mut cursor = Cursor::find(table, id)?;
+        let (page_num, offset) = cursor_value(&mut cursor)?;
+        let row = deserialize_row(cursor.table.pager.get_page_ref(page_num)?, offset)?;
+        // The range only narrows down the rows by one of the conditions, and can include rows
+        // that don't match it, e.g. if it compares with NULL.
+        if predicate.matches(&row) {
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+
+/// Return whether a SELECT that reads the given index can be answered from the index alone,
+/// because every column that it reads is in the index's keys, which hold the value of the index's
+/// column and the id. An index on an expression only holds the expression's values.
+pub(crate) fn is_index_only(statement: &Statement, table: &Table, index_num: usize) -> bool {
+    let index = &table.indexes[index_num];
+    index.expression.is_none()
+        && statement.columns().iter().all(|&column| column == ID_COLUMN || column == index.column)
+}
+
+
+/// Return the number of the index to use to find the rows that match the predicate, along with
+/// the condition whose range of the index to read, or None if there is no suitable index or
+/// scanning the table is likely to be cheaper.
+///
+/// Scanning reads each of the table's leaves once. Using an index reads the index's pages on the
+/// way to the start of the range, and then looks up each matching row in the table, which reads
+/// a page at each level of the table's B-tree. The number of matching rows is estimated from the
+/// statistics that ANALYZE collected if it has been run, and otherwise from where the ends of the
+/// range fall in the index.
+pub(crate) fn choose_index<'p>(
+    table: &mut Table, predicate: &'p Predicate
+) -> Result<Option<(usize, &'p Condition)>, Error> {
+    let conjuncts = predicate.conjuncts();
+    if conjuncts.iter().any(|condition| condition.id_value().is_some()) {
+        // The table itself is sorted by id, so scan_rows already only reads the rows in range.
+        return Ok(None);
+    }
+
+    let table_tree = table.tree();
+    let num_leaves = count_leaves(table, table_tree)?;
+    // Leaves other than the root are between half full and full.
+    let layout = table_tree.layout;
+    let num_rows = match &table.stats {
+        Some(stats) => stats.num_rows,
+        None => num_leaves * (layout.leaf_min_cells() + layout.leaf_max_cells()) / 2,
+    };
+    let table_height = tree_height(table, table_tree)?;
+
+    let mut best = None;
+    let mut best_cost = num_leaves as f64;
+    for condition in conjuncts {
+        // A partial index can only be used when the query only wants rows that it has entries for.
+        let ranges: Vec<_> = table.indexes.iter().enumerate()
+            .filter(|(_, index)| match &index.where_clause {
+                Some((_, index_predicate)) => predicate.implies(index_predicate),
+                None => true,
+            })
+            .filter_map(|(index_num, index)| Some((index_num, condition.index_range(index)?)))
+            .collect();
+        for (index_num, (start, end)) in ranges {
+            let index = &table.indexes[index_num];
+            let column = index.column;
+            let index_tree = index.tree();
+            let is_partial = index.where_clause.is_some();
+            let index_stats = table.stats.as_ref().and_then(|stats| {
+                stats.indexes.iter().find(|index_stats| index_stats.name == index.name)
+            });
+            let fraction = match index_stats {
+                Some(index_stats) => {
+                    index_stats.estimate_fraction(&COLUMNS[column], &start, end.as_deref())
+                },
+                None => {
+                    let end_rank = match &end {
+                        Some(end) => estimate_rank(table, index_tree, end)?,
+                        None => 1.0,
+                    };
+                    end_rank - estimate_rank(table, index_tree, &start)?
+                },
+            };
+            // The fraction is of the index's entries, and a partial index has fewer than the table.
+            let num_entries = if is_partial {
+                estimate_entries(table, index_tree)?.min(num_rows)
+            } else {
+                num_rows
+            };
+            let cost = tree_height(table, index_tree)? as f64
+                + fraction * num_entries as f64 * table_height as f64;
+            if cost < best_cost {
+                best = Some((index_num, condition));
+                best_cost = cost;
+            }
+        }
+    }
+    Ok(best)
+}
+
+
+/// Estimate the fraction of the entries in a B-tree whose keys are less than the given key, from
+/// which child the key falls in at each level on the way down to its leaf. This assumes that the
+/// children of a node have roughly the same number of entries.
+fn estimate_rank(table: &mut Table, tree: Tree, key: &[u8]) -> Result<f64, Error> {
+    let mut rank = 0.0;
+    let mut width = 1.0;
+    let mut page_num = tree.root_page_num;
+    loop {
+        let node = table.pager.get_page_ref(page_num)?;
+        match get_node_type(node) {
+            NodeType::Leaf => {
+                let num_cells = leaf_node_num_cells(node);
+                if num_cells > 0 {
+                    let cell_num = leaf_node_find(node, tree.layout, key);
+                    rank += width * cell_num as f64 / num_cells as f64;
+                }
+                return Ok(rank);
+            },
+            NodeType::Internal => {
+                let num_children = internal_node_num_keys(node) + 1;
+                let child_index = internal_node_find_child(node, tree.layout, key);
+                rank += width * child_index as f64 / num_children as f64;
+                width /= num_children as f64;
+                page_num = internal_node_child(node, tree.layout, child_index)?;
+            },
+        }
+    }
+}
+
+
+/// Estimate the number of entries in the given B-tree from its number of leaves, which are
+/// between half full and full, unless the root is a leaf and so the number is known.
+fn estimate_entries(table: &mut Table, tree: Tree) -> Result<usize, Error> {
+    let root = table.pager.get_page_ref(tree.root_page_num)?;
+    if let NodeType::Leaf = get_node_type(root) {
+        return Ok(leaf_node_num_cells(root));
+    }
+    let layout = tree.layout;
+    Ok(count_leaves(table, tree)? * (layout.leaf_min_cells() + layout.leaf_max_cells()) / 2)
+}
+
+
+/// Return the number of leaves in the given B-tree, without reading them.
+fn count_leaves(table: &mut Table, tree: Tree) -> Result<usize, Error> {
+    let height = tree_height(table, tree)?;
+    count_subtree_leaves(table, tree.layout, tree.root_page_num, height)
+}
+
+
+fn count_subtree_leaves(
+    table: &mut Table, layout: TreeLayout, page_num: usize, height: usize
+) -> Result<usize, Error> {
+    if height == 1 {
+        return Ok(1);
+    }
+
+    let node = table.pager.get_page_ref(page_num)?;
+    let children = (0..=internal_node_num_keys(node))
+        .map(|i| internal_node_child(node, layout, i))
+        .collect::<Result<Vec<usize>, Error>>()?;
+    if height == 2 {
+        Ok(children.len())
+    } else {
+        children
+            .into_iter()
+            .map(|child| count_subtree_leaves(table, layout, child, height - 1))
+            .sum()
+    }
+}
+
+
+/// Check the whole database for corruption, and return a description of each problem found. Each
+/// page on disk must match its checksum, and each B-tree must be well-formed: its pages are in the
+/// file and in no other tree, its keys are in order, and its parent and sibling pointers are
+/// right. The header's row count must match the table, and each index must have exactly one entry
+/// for each row. Every page must be in exactly one tree or on the free list.
+pub(crate) fn integrity_check(table: &mut Table) -> Result<Vec<String>, Error> {
+    let mut problems = Vec::new();
+    for page_num in 0..table.pager.file_length / PAGE_SIZE {
+        if !is_page_checksum_valid(&table.pager.read_page(page_num)?) {
+            problems.push(format!("page {}: checksum does not match", page_num));
+        }
+    }
+
+    let mut used_pages = HashSet::new();
+    used_pages.insert(HEADER_PAGE_NUM);
+    let table_tree = table.tree();
+    let num_problems = problems.len();
+    let keys = check_tree(table, table_tree, "table", &mut used_pages, &mut problems)?;
+    if problems.len() > num_problems {
+        // The rows can't be read to check the header and indexes against.
+        return Ok(problems);
+    }
+
+    let header = table.pager.get_page_ref(HEADER_PAGE_NUM)?;
+    let row_count = header_row_count(header) as usize;
+    if row_count != keys.len() {
+        let num_rows = keys.len();
+        problems.push(format!("header: row count is {} but the table has {}", row_count, num_rows));
+    }
+    let max_id = header_max_id(header);
+    let schema_page_num = read_u32(header, HEADER_SCHEMA_PAGE_OFFSET) as usize;
+    if keys.last().is_some_and(|key| read_u32(key, 0) > max_id) {
+        problems.push(format!("header: max id {} is less than an id in the table", max_id));
+    }
+
+    let mut page_num = header_free_list(header);
+    while page_num != 0 {
+        if page_num >= table.pager.num_pages || !used_pages.insert(page_num) {
+            problems.push(format!("free list: page {} is not a free page", page_num));
+            break;
+        }
+        let page = table.pager.get_page_ref(page_num)?;
+        if page[NODE_TYPE_OFFSET] != FREE_PAGE_TYPE {
+            problems.push(format!("free list: page {} is not a free page", page_num));
+            break;
+        }
+        page_num = read_u32(page, FREE_PAGE_NEXT_OFFSET) as usize;
+    }
+
+    if schema_page_num != 0
+        && (schema_page_num >= table.pager.num_pages || !used_pages.insert(schema_page_num))
+    {
+        problems.push(format!("schema: page {} is not a schema page", schema_page_num));
+    }
+
+    let rows = scan_rows(table, None)?;
+    for index_num in 0..table.indexes.len() {
+        let index = &table.indexes[index_num];
+        let name = format!("index {}", index.name);
+        let tree = index.tree();
+        let mut expected: Vec<Vec<u8>> = rows.iter()
+            .filter(|row| index.covers(row))
+            .map(|row| index.key(row))
+            .collect();
+        expected.sort();
+        let keys = check_tree(table, tree, &name, &mut used_pages, &mut problems)?;
+        if keys != expected {
+            problems.push(format!("{}: entries do not match the table's rows", name));
+        }
+    }
+
+    for page_num in 0..table.pager.num_pages {
+        if !used_pages.contains(&page_num) {
+            problems.push(format!("page {} is neither in use nor free", page_num));
+        }
+    }
+    Ok(problems)
+}
+
+
+/// Check a B-tree, adding a description of each problem found to `problems`, and return its keys
+/// in order. `used_pages` is the pages in the trees checked so far.
+fn check_tree(
+    table: &mut Table,
+    tree: Tree,
+    name: &str,
+    used_pages: &mut HashSet<usize>,
+    problems: &mut Vec<String>,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let mut check = TreeCheck { tree, name, keys: Vec::new(), leaves: Vec::new() };
+    check_node(table, &mut check, tree.root_page_num, used_pages, problems)?;
+
+    if check.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+        problems.push(format!("{}: keys are out of order", name));
+    }
+    for (i, &page_num) in check.leaves.iter().enumerate() {
+        let next_leaf = leaf_node_next_leaf(table.pager.get_page_ref(page_num)?);
+        if next_leaf != check.leaves.get(i + 1).copied().unwrap_or(0) {
+            problems.push(format!("{}: page {} has the wrong next leaf", name, page_num));
+        }
+    }
+    Ok(check.keys)
+}
+
+
+/// What has been found so far while checking a B-tree: its keys and the page numbers of its
+/// leaves, in order.
+struct TreeCheck<'a> {
+    tree: Tree,
+    name: &'a str,
+    keys: Vec<Vec<u8>>,
+    leaves: Vec<usize>,
+}
+
+
+/// Check the subtree rooted at the given page, adding to what has been found in its tree.
+fn check_node(
+    table: &mut Table,
+    check: &mut TreeCheck,
+    page_num: usize,
+    used_pages: &mut HashSet<usize>,
+    problems: &mut Vec<String>,
+) -> Result<(), Error> {
+    let name = check.name;
+    if page_num == HEADER_PAGE_NUM || page_num >= table.pager.num_pages {
+        problems.push(format!("{}: page {} is not a node", name, page_num));
+        return Ok(());
+    } else if !used_pages.insert(page_num) {
+        problems.push(format!("{}: page {} is used more than once", name, page_num));
+        return Ok(());
+    }
+
+    let layout = check.tree.layout;
+    let node = table.pager.get_page_ref(page_num)?.to_vec();
+    if node[NODE_TYPE_OFFSET] > 1 {
+        problems.push(format!("{}: page {} has an unknown node type", name, page_num));
+        return Ok(());
+    }
+    match get_node_type(&node) {
+        NodeType::Leaf => {
+            let num_cells = leaf_node_num_cells(&node);
+            if num_cells > layout.leaf_max_cells() {
+                problems.push(format!("{}: page {} has too many cells", name, page_num));
+                return Ok(());
+            }
+            check.keys.extend((0..num_cells).map(|i| leaf_node_key(&node, layout, i).to_vec()));
+            check.leaves.push(page_num);
+        },
+        NodeType::Internal => {
+            let num_keys = internal_node_num_keys(&node);
+            if num_keys > layout.internal_max_keys() {
+                problems.push(format!("{}: page {} has too many keys", name, page_num));
+                return Ok(());
+            }
+            for i in 0..=num_keys {
+                let child = internal_node_child(&node, layout, i)?;
+                let num_child_keys = check.keys.len();
+                check_node(table, check, child, used_pages, problems)?;
+                if child >= table.pager.num_pages || child == HEADER_PAGE_NUM {
+                    continue;
+                }
+
+                if node_parent(table.pager.get_page_ref(child)?) != page_num {
+                    problems.push(format!("{}: page {} has the wrong parent", name, child));
+                }
+                let is_max_key = check.keys.len() > num_child_keys
+                    && check.keys.last().unwrap() == internal_node_key(&node, layout, i);
+                if i < num_keys && !is_max_key {
+                    problems.push(format!(
+                        "{}: key {} of page {} is not the largest key of its child",
+                        name, i, page_num,
+                    ));
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+
+/// Return the result of an integrity check: either each problem, or `ok` if there are none.
+fn integrity_check_lines(table: &mut Table) -> Result<Vec<String>, Error> {
+    let problems = integrity_check(table)?;
+    if problems.is_empty() {
+        Ok(vec![String::from("ok")])
+    } else {
+        Ok(problems)
+    }
+}
+
+
+/// Return what ANALYZE last found out about the table and its indexes, if it has been run.
+fn stats_lines(table: &Table) -> Vec<String> {
+    let stats = match &table.stats {
+        Some(stats) => stats,
+        None => return vec![String::from("no statistics; run analyze first")],
+    };
+    let mut lines = vec![format!("{}: {} row(s)", TABLE_NAME, stats.num_rows)];
+    for index in &stats.indexes {
+        lines.push(format!(
+            "{}: {} distinct value(s), {} sample(s)",
+            index.name,
+            index.num_distinct,
+            index.samples.len()
+        ));
+    }
+    lines
+}
+
+
+/// Add the lines of a representation of the table's B-tree, for debugging.
+fn tree_lines(
+    pager: &mut Pager,
+    page_num: usize,
+    indentation_level: usize,
+    lines: &mut Vec<String>,
+) -> Result<(), Error> {
+    let indent = "  ".repeat(indentation_level);
+    let node = pager.get_page_ref(page_num)?;
+    match get_node_type(node) {
+        NodeType::Leaf => {
+            let num_cells = leaf_node_num_cells(node);
+            lines.push(format!("{}- leaf (size {})", indent, num_cells));
+            for i in 0..num_cells {
+                let key = read_u32(leaf_node_key(node, TABLE_LAYOUT, i), 0);
+                lines.push(format!("{}  - {}", indent, key));
+            }
+        },
+        NodeType::Internal => {
+            let num_keys = internal_node_num_keys(node);
+            lines.push(format!("{}- internal (size {})", indent, num_keys));
+            for i in 0..num_keys {
+                let node = pager.get_page_ref(page_num)?;
+                let child = internal_node_child(node, TABLE_LAYOUT, i)?;
+                let key = read_u32(internal_node_key(node, TABLE_LAYOUT, i), 0);
+                tree_lines(pager, child, indentation_level + 1, lines)?;
+                lines.push(format!("{}  - key {}", indent, key));
+            }
+            let right_child = internal_node_right_child(pager.get_page_ref(page_num)?);
+            tree_lines(pager, right_child, indentation_level + 1, lines)?;
+        },
+    }
+    Ok(())
+}
+
+
+/// What a meta-command did. A meta-command that was run has lines of output for the shell to
+/// print, which include any error, e.g. `Error: database is locked`.
+pub enum MetaCommandResult {
+    Success(Vec<String>),
+    Exit,
+    Unrecognized,
+}
+
+
+/// Return the statements that a `.check` meta-command is to check, if it is one.
+fn check_argument(command: &str) -> Option<&str> {
+    if command == ".check" {
+        Some("")
+    } else {
+        command.strip_prefix(".check ").map(str::trim)
+    }
+}
+
+
+/// Return whether each of the statements separated by semicolons parses, and if not, where and
+/// why.
+fn check_statements(sql: &str) -> Vec<String> {
+    let mut results = Statement::parse_all(sql);
+    if results.is_empty() {
+        results.push(Statement::parse(sql));
+    }
+    let lines = results.into_iter().map(|result| match result {
+        Ok(_) => String::from("OK"),
+        Err(e) => format!("Error: {}", e),
+    });
+    lines.collect()
+}
+
+
+/// Execute a meta-command (i.e., a non-SQL statement in the shell).
+fn do_meta_command(command: &str, table: &mut Table) -> MetaCommandResult {
+    if command == ".exit" {
+        return MetaCommandResult::Exit;
+    }
+    if let Some(sql) = check_argument(command) {
+        // Checking statements only parses them, so it doesn't need the database.
+        return MetaCommandResult::Success(check_statements(sql));
+    }
+
+    // Like statements, meta-commands have a lock on the database while they use it.
+    if let Err(e) = table.lock(LockLevel::Shared) {
+        return MetaCommandResult::Success(vec![format!("Error: {}", e)]);
+    }
+    let result = if command == ".size" {
+        table_num_rows(table).map(|num_rows| vec![format!("{} row(s)", num_rows)])
+    } else if command == ".checkpoint" {
+        table.pager.checkpoint()
+            .map(|num_pages| vec![format!("{} page(s) checkpointed", num_pages)])
+            .map_err(Error::Io)
+    } else if command == ".vacuum" {
+        execute_vacuum(table).map(|()| Vec::new())
+    } else if command == ".integrity_check" {
+        integrity_check_lines(table)
+    } else if command == ".databases" {
+        let mut lines = vec![format!("{}: {}", MAIN_DATABASE_NAME, table.pager.path)];
+        for (name, attached) in &table.attached {
+            lines.push(format!("{}: {}", name, attached.pager.path));
+        }
+        Ok(lines)
+    } else if command == ".stats" {
+        Ok(stats_lines(table))
+    } else if command == ".btree" {
+        let mut lines = Vec::new();
+        tree_lines(&mut table.pager, table.root_page_num, 0, &mut lines).map(|()| lines)
+    } else {
+        let _ = table.finish_statement();
+        return MetaCommandResult::Unrecognized;
+    };
+    let mut lines = result.unwrap_or_else(|e| vec![format!("Error: {}", e)]);
+    if let Err(e) = table.finish_statement() {
+        lines.push(format!("Error: {}", e));
+    }
+    MetaCommandResult::Success(lines)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::btree::*;
+    use crate::csv::*;
+    use crate::error::{Error, Result};
+    use crate::exec::*;
+    use crate::parser::*;
+    use crate::sql::*;
+    use crate::testing::*;
+
+    #[test]
+    fn connection_pool() {
+        let path = &temp_path("testdb-pool.mysql");
+        drop(db_open_new(path));
+        let pool = Pool::new(path, 2).unwrap();
+
+        // A transaction that is left open is rolled back when the connection is returned.
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        assert!(first.execute("begin").is_ok());
+        assert!(first.execute("insert into users values (1, 'a', 'a@x')").is_ok());
+        assert!(second.execute("insert into users values (2, 'b', 'b@x')").is_err());
+        drop(first);
+        assert!(second.execute("insert into users values (2, 'b', 'b@x')").is_ok());
+        let database = pool.get().unwrap();
+        assert!(database.execute("select").is_ok());
+        assert_eq!(check_table(&mut database.table()), vec![2]);
+        drop(database);
+
+        // Once every connection is handed out, the next request waits for one to be returned.
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::scope(|scope| {
+            let first = pool.get().unwrap();
+            scope.spawn(|| {
+                let third = pool.get().unwrap();
+                sender.send(()).unwrap();
+                assert!(third.execute("insert into users values (3, 'c', 'c@x')").is_ok());
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(receiver.try_recv().is_err());
+            drop(second);
+            receiver.recv().unwrap();
+            drop(first);
+        });
+        assert_eq!(pool.state.lock().unwrap().num_open, 2);
+
+        // Connections to a database file that was replaced are closed instead of handed out.
+        fs::remove_file(path).unwrap();
+        drop(db_open_new(path));
+        let database = pool.get().unwrap();
+        assert!(check_table(&mut database.table()).is_empty());
+        assert_eq!(pool.state.lock().unwrap().num_open, 1);
+        drop(database);
+
+        assert!(Pool::new(path, 0).is_err());
+    }
+
+    #[test]
+    fn attach_database() {
+        let other_path = temp_path("testdb-attach-other.mysql");
+        let mut other = db_open_new(&other_path);
+        let statement = prepare_statement("insert into users values (7, 'a', 'b')").unwrap();
+        assert!(execute_statement(&statement, &mut other).is_ok());
+        drop(other);
+
+        let mut table = db_open_new(&temp_path("testdb-attach.mysql"));
+        let attach = format!("attach database '{}' as other", other_path);
+        assert!(run(&mut table, &attach).is_ok());
+        assert!(run(&mut table, "attach ':memory:' as scratch").is_ok());
+        for command in ["attach ':memory:' as OTHER", "attach ':memory:' as main"] {
+            assert_eq!(
+                run(&mut table, command),
+                Err(Error::Invalid("database name is already in use")),
+            );
+        }
+        assert!(prepare_statement("attach 'x.mysql' other").is_err());
+        assert!(prepare_statement("select * from other.users.x").is_err());
+
+        let statement = prepare_statement("select * from other.users where id = 7").unwrap();
+        assert_eq!(statement.database.as_deref(), Some("other"));
+        assert!(run(&mut table, "select * from other.users").is_ok());
+        assert!(run(&mut table, "select * from main.users").is_ok());
+        let other = database_table(&mut table, Some("other")).unwrap();
+        assert_eq!(check_table(other), vec![7]);
+        assert!(check_table(database_table(&mut table, Some("main")).unwrap()).is_empty());
+
+        assert!(run(&mut table, "vacuum").is_ok());
+        assert!(run(&mut table, "detach other").is_ok());
+        assert_eq!(
+            run(&mut table, "select * from other.users"),
+            Err(Error::Invalid("no such database")),
+        );
+        assert_eq!(
+            run(&mut table, "detach database other"),
+            Err(Error::Invalid("no such database")),
+        );
+        assert!(run(&mut table, "begin").is_ok());
+        assert_eq!(
+            run(&mut table, "detach scratch"),
+            Err(Error::Invalid("cannot detach a database within a transaction")),
+        );
+        assert!(run(&mut table, "commit").is_ok());
+        assert!(run(&mut table, "detach scratch").is_ok());
+        assert!(table.attached.is_empty());
+    }
+
+    #[test]
+    fn select_where() {
+        let mut table = db_open_new(&temp_path("testdb-where.mysql"));
+        for id in 0..50 {
+            let command = format!("insert {} user{} user{}@example.com", id, id % 5, id);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let select_ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+
+        assert_eq!(select_ids(&mut table, "select where id = 42"), vec![42]);
+        assert_eq!(select_ids(&mut table, "select where id=420"), Vec::<u32>::new());
+        assert_eq!(select_ids(&mut table, "select where id < 3"), vec![0, 1, 2]);
+        assert_eq!(select_ids(&mut table, "select where id > 46"), vec![47, 48, 49]);
+        assert_eq!(select_ids(&mut table, "select where id != 0").len(), 49);
+        assert_eq!(
+            select_ids(&mut table, "SELECT * FROM users WHERE username = 'user3'"),
+            vec![3, 8, 13, 18, 23, 28, 33, 38, 43, 48]
+        );
+        assert_eq!(select_ids(&mut table, "select where email > user8@example.com"), vec![9]);
+        assert_eq!(select_ids(&mut table, "select").len(), 50);
+
+        assert!(prepare_statement("select where id = abc").is_err());
+        assert!(prepare_statement("select where name = jdoe").is_err());
+        assert!(prepare_statement("select where id ~ 1").is_err());
+        assert!(prepare_statement("select everything").is_err());
+    }
+
+    #[test]
+    fn select_order_by() {
+        let mut table = db_open_new(&temp_path("testdb-order-by.mysql"));
+        for (id, username) in [(1, "carol"), (2, "alice"), (3, "bob"), (4, "alice")] {
+            let command = format!("insert {} {} {}@example.com", id, username, username);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let select_ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+
+        assert_eq!(select_ids(&mut table, "select order by username"), vec![2, 4, 3, 1]);
+        assert_eq!(select_ids(&mut table, "select order by username desc"), vec![1, 3, 2, 4]);
+        assert_eq!(select_ids(&mut table, "select order by id DESC"), vec![4, 3, 2, 1]);
+        assert_eq!(
+            select_ids(&mut table, "SELECT * FROM users WHERE id > 1 ORDER BY email ASC"),
+            vec![2, 4, 3]
+        );
+
+        assert!(prepare_statement("select order by").is_err());
+        assert!(prepare_statement("select order by name").is_err());
+        assert!(prepare_statement("select order by id sideways").is_err());
+    }
+
+    #[test]
+    fn null_values() {
+        let mut table = db_open_new(&temp_path("testdb-null.mysql"));
+        for command in ["insert 1 jdoe null", "insert 2 NULL asmith@example.com", "insert 3 x x"] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let select_ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+
+        assert_eq!(select_ids(&mut table, "select where email is null"), vec![1]);
+        assert_eq!(select_ids(&mut table, "select where email IS NOT NULL"), vec![2, 3]);
+        assert_eq!(select_ids(&mut table, "select where username is null"), vec![2]);
+        // Comparisons with NULL are never true.
+        assert_eq!(select_ids(&mut table, "select where email != x"), vec![2]);
+        assert_eq!(select_ids(&mut table, "select where email = null"), Vec::<u32>::new());
+        // NULL sorts first.
+        assert_eq!(select_ids(&mut table, "select order by username"), vec![2, 1, 3]);
+
+        // The id can't be NULL, but the string 'null' is fine.
+        assert!(prepare_statement("insert null jdoe jdoe@example.com").is_err());
+        let row = prepare_row(&["4", "'null'", "null"]).unwrap();
+        assert_eq!(row.values[1], Value::Text(String::from("null")));
+        assert_eq!(row.values[2], Value::Null);
+
+        assert!(prepare_statement("select where email is").is_err());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let mut table = db_open_new(&temp_path("testdb-duplicate.mysql"));
+        let insert = prepare_statement("insert 1 jdoe jdoe@example.com").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        assert_eq!(execute_statement(&insert, &mut table), Err(Error::Constraint("duplicate key")));
+        assert_eq!(table_num_rows(&mut table).unwrap(), 1);
+
+        // A batch is rejected as a whole if it has an id that is already in the table, or the
+        // same id twice.
+        let mut rows = parse_csv_rows("2,a,a@example.com\n1,b,b@example.com\n").unwrap();
+        assert_eq!(bulk_load(&mut rows, &mut table), Err(Error::Constraint("duplicate key")));
+        let mut rows = parse_csv_rows("2,a,a@example.com\n2,b,b@example.com\n").unwrap();
+        assert_eq!(bulk_load(&mut rows, &mut table), Err(Error::Constraint("duplicate key")));
+        assert_eq!(table_num_rows(&mut table).unwrap(), 1);
+    }
+
+    #[test]
+    fn automatic_ids() {
+        let mut table = db_open_new(&temp_path("testdb-autoincrement.mysql"));
+        assert_eq!(table.last_insert_rowid, 0);
+
+        let insert = prepare_statement("insert jdoe jdoe@example.com").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        assert_eq!(table.last_insert_rowid, 1);
+
+        let insert = prepare_statement("insert 10 asmith asmith@example.com").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        let insert = prepare_statement("insert bob bob@example.com").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        assert_eq!(table.last_insert_rowid, 11);
+
+        // Ids of deleted rows are not reused, even after reopening the database.
+        let delete = prepare_statement("delete 11").unwrap();
+        assert!(execute_statement(&delete, &mut table).is_ok());
+        drop(table);
+
+        let mut table = db_open(&temp_path("testdb-autoincrement.mysql")).unwrap();
+        let insert = prepare_statement("insert carol carol@example.com").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        assert_eq!(table.last_insert_rowid, 12);
+
+        let select = prepare_statement("select where username = carol").unwrap();
+        assert_eq!(select_rows(&select, &mut table).unwrap()[0].id(), 12);
+        assert!(prepare_statement("select last_insert_rowid()").is_ok());
+    }
+
+    #[test]
+    fn rows_affected() {
+        let mut table = db_open_new(&temp_path("testdb-changes.mysql"));
+        let insert = "insert into users values (1, 'a', 'a@x'), (2, 'b', 'b@x'), (3, 'c', 'c@x')";
+        assert_eq!(run(&mut table, insert), Ok(3));
+        assert_eq!(run(&mut table, "insert or replace into users values (1, 'd', 'd@x')"), Ok(1));
+        assert_eq!(run(&mut table, "delete 2"), Ok(1));
+        assert_eq!(run(&mut table, "delete 2"), Ok(0));
+        assert_eq!(run(&mut table, "insert 4 e e@x"), Ok(1));
+        assert_eq!(table.changes, 1);
+
+        // Other statements neither return a count nor change the one that `changes()` prints.
+        assert_eq!(run(&mut table, "select"), Ok(0));
+        assert_eq!(run(&mut table, "create index username on users (username)"), Ok(0));
+        assert_eq!(table.changes, 1);
+        assert!(prepare_statement("select changes()").is_ok());
+
+        // A statement that fails doesn't either.
+        assert!(run(&mut table, "insert 1 g g@x").is_err());
+        assert_eq!(table.changes, 1);
+    }
+
+    #[test]
+    fn insert_multiple_rows() {
+        let mut table = db_open_new(&temp_path("testdb-insert-values.mysql"));
+        let insert = prepare_statement(
+            "INSERT INTO users VALUES (5, 'jdoe', 'j doe@x'), (null, 'bob', null), (2, 'a', 'a@x')"
+        ).unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        assert_eq!(table.last_insert_rowid, 2);
+        assert_eq!(check_table(&mut table), vec![2, 5, 6]);
+
+        let select = prepare_statement("select where id = 5").unwrap();
+        let row = &select_rows(&select, &mut table).unwrap()[0];
+        assert_eq!(row.values[2], Value::Text(String::from("j doe@x")));
+
+        // A duplicate id, whether in the table or the statement, means no rows are inserted.
+        for command in [
+            "insert into users values (7, 'a', 'a'), (5, 'b', 'b')",
+            "insert into users values (7, 'a', 'a'), (7, 'b', 'b')",
+        ] {
+            let insert = prepare_statement(command).unwrap();
+            assert_eq!(
+                execute_statement(&insert, &mut table),
+                Err(Error::Constraint("duplicate key")),
+            );
+            assert_eq!(check_table(&mut table), vec![2, 5, 6]);
+        }
+
+        let insert = prepare_statement("insert into users values (1 + 2, 'x' || 'y', null)");
+        assert!(execute_statement(&insert.unwrap(), &mut table).is_ok());
+        assert_eq!(check_table(&mut table), vec![2, 3, 5, 6]);
+
+        // Columns can be given in any order, and the ones that are left out are NULL, except for
+        // the id, which is assigned.
+        let insert = prepare_statement(
+            "insert into users (email, USERNAME) values ('c@x', 'carol'), ('d@x', 'dave')"
+        ).unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        let insert = prepare_statement("insert into users ( id ) values (20)").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+
+        let select = prepare_statement("select where id > 6").unwrap();
+        let rows: Vec<Vec<Value>> =
+            select_rows(&select, &mut table).unwrap().into_iter().map(|row| row.values).collect();
+        let text = |s: &str| Value::Text(String::from(s));
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(7), text("carol"), text("c@x")],
+                vec![Value::Integer(8), text("dave"), text("d@x")],
+                vec![Value::Integer(20), Value::Null, Value::Null],
+            ]
+        );
+
+        for command in [
+            "insert into users values",
+            "insert into users values (1, 'a')",
+            "insert into users values (1, 'a', 'b', 'c')",
+            "insert into users values ('a', 'b', 'c')",
+            "insert into users values (-1, 'a', 'b')",
+            "insert into users values ('a', 'b', 'c')",
+            "insert into users values (1, username, 'b')",
+            "insert into users values (1, 'a', 'b'),",
+            "insert into users values (1, 'a', 'b') (2, 'a', 'b')",
+            "insert into accounts values (1, 'a', 'b')",
+            "insert into users (1, 'a', 'b')",
+            "insert into users (id, id) values (1, 2)",
+            "insert into users (id, name) values (1, 'a')",
+            "insert into users (id, username) values (1, 'a', 'b')",
+            "insert into users (id, username) values (1)",
+            "insert into users () values ()",
+            "insert into users (id username) values (1, 'a')",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+        let long_name = format!("insert into users values (1, '{}', 'b')", "x".repeat(33));
+        assert!(prepare_statement(&long_name).is_err());
+    }
+
+    #[test]
+    fn insert_or_replace() {
+        let mut table = db_open_new(&temp_path("testdb-replace.mysql"));
+        for command in [
+            "create index u on users(username)",
+            "insert into users values (1, 'alice', 'a@x'), (2, 'bob', 'b@x')",
+            "INSERT OR REPLACE INTO users VALUES (2, 'robert', 'r@x'), (3, 'carol', 'c@x')",
+            "insert or replace into users (id, username) values (1, 'al'), (1, 'alicia')",
+        ] {
+            let statement = prepare_statement(command).unwrap();
+            assert!(execute_statement(&statement, &mut table).is_ok(), "{}", command);
+        }
+        assert_eq!(table.last_insert_rowid, 1);
+
+        let select = prepare_statement("select").unwrap();
+        let rows: Vec<Vec<Value>> =
+            select_rows(&select, &mut table).unwrap().into_iter().map(|row| row.values).collect();
+        let text = |s: &str| Value::Text(String::from(s));
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1), text("alicia"), Value::Null],
+                vec![Value::Integer(2), text("robert"), text("r@x")],
+                vec![Value::Integer(3), text("carol"), text("c@x")],
+            ]
+        );
+        check_index(&mut table, 0);
+        let select = prepare_statement("select where username = bob").unwrap();
+        assert!(select_rows(&select, &mut table).unwrap().is_empty());
+
+        // Without OR REPLACE, an existing id is still an error.
+        let insert = prepare_statement("insert into users values (3, 'x', 'y')").unwrap();
+        assert_eq!(execute_statement(&insert, &mut table), Err(Error::Constraint("duplicate key")));
+        assert!(prepare_statement("insert or ignore into users values (3, 'x', 'y')").is_err());
+    }
+
+    #[test]
+    fn returning_clause() {
+        let mut table = db_open_new(&temp_path("testdb-returning.mysql"));
+        let insert = prepare_statement(
+            "insert into users (username) values ('alice'), ('bob') returning id, upper(username)"
+        ).unwrap();
+        let items = insert.returning.as_ref().unwrap();
+        let names: Vec<String> = items.iter().map(SelectItem::name).collect();
+        assert_eq!(names, ["id", "upper(username)"]);
+        assert!(execute_statement(&insert, &mut table).is_ok());
+
+        let row = prepare_row(&["2", "bob", "null"]).unwrap();
+        assert_eq!(
+            items_values(items, &row),
+            [Value::Integer(2), Value::Text(String::from("BOB"))]
+        );
+
+        let delete = prepare_statement("delete 2 RETURNING *").unwrap();
+        let items = delete.returning.as_ref().unwrap();
+        let names: Vec<String> = items.iter().map(SelectItem::name).collect();
+        assert_eq!(names, ["id", "username", "email"]);
+        assert!(execute_statement(&delete, &mut table).is_ok());
+        assert_eq!(check_table(&mut table), vec![1]);
+        assert!(prepare_statement("delete 1").unwrap().returning.is_none());
+
+        for command in [
+            "insert into users values (1, 'a', 'b') returning",
+            "insert into users values (1, 'a', 'b') returning count(*)",
+            "insert into users values (1, 'a', 'b') returning id,",
+            "insert into users values (1, 'a', 'b') returning nosuch",
+            "delete 1 returning",
+            "delete 1 2",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn transactions() {
+        let path = &temp_path("testdb-transactions.mysql");
+        let mut table = db_open_new(path);
+        assert!(run(&mut table, "insert into users values (1, 'alice', 'a@x')").is_ok());
+        assert_eq!(run(&mut table, "commit"), Err(Error::Invalid("no transaction is active")));
+        assert_eq!(run(&mut table, "rollback"), Err(Error::Invalid("no transaction is active")));
+
+        // Rolling back undoes new rows, new pages and new indexes.
+        assert!(run(&mut table, "BEGIN TRANSACTION").is_ok());
+        assert_eq!(
+            run(&mut table, "begin"),
+            Err(Error::Invalid("a transaction is already active")),
+        );
+        assert!(run(&mut table, "create index u on users(username)").is_ok());
+        for i in 2..40 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        assert!(run(&mut table, "delete 1").is_ok());
+        assert!(run(&mut table, "rollback").is_ok());
+        assert_eq!(check_table(&mut table), vec![1]);
+        assert!(table.indexes.is_empty());
+        assert_eq!(table.pager.num_pages, 2);
+
+        // Committed changes are kept, and a transaction left open at exit is rolled back.
+        assert!(run(&mut table, "begin").is_ok());
+        assert!(run(&mut table, "insert into users values (2, 'bob', 'b@x')").is_ok());
+        assert!(run(&mut table, "end").is_ok());
+        assert!(run(&mut table, "begin").is_ok());
+        assert!(run(&mut table, "insert into users values (3, 'carol', 'c@x')").is_ok());
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), vec![1, 2]);
+
+        assert!(prepare_statement("begin work").is_err());
+        assert!(prepare_statement("commit transaction now").is_err());
+    }
+
+    #[test]
+    fn savepoints() {
+        let path = &temp_path("testdb-savepoints.mysql");
+        let mut table = db_open_new(path);
+        assert_eq!(run(&mut table, "rollback to a"), Err(Error::Invalid("no such savepoint")));
+        assert_eq!(run(&mut table, "release a"), Err(Error::Invalid("no such savepoint")));
+
+        for command in [
+            "begin",
+            "insert into users values (1, 'alice', 'a@x')",
+            "savepoint a",
+            "insert into users values (2, 'bob', 'b@x')",
+            "SAVEPOINT b",
+            "create index e on users(email)",
+            "insert into users values (3, 'carol', 'c@x')",
+            "rollback to b",
+        ] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        assert_eq!(check_table(&mut table), vec![1, 2]);
+        assert!(table.indexes.is_empty());
+
+        // The savepoint that was rolled back to is kept, but later ones are forgotten.
+        for command in [
+            "insert into users values (4, 'dave', 'd@x')",
+            "savepoint c",
+            "rollback transaction to savepoint b",
+            "insert into users values (5, 'eve', 'e@x')",
+            "release savepoint a",
+        ] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        assert_eq!(run(&mut table, "rollback to c"), Err(Error::Invalid("no such savepoint")));
+        assert_eq!(run(&mut table, "rollback to b"), Err(Error::Invalid("no such savepoint")));
+        assert_eq!(check_table(&mut table), vec![1, 2, 5]);
+        assert!(run(&mut table, "rollback").is_ok());
+        assert!(check_table(&mut table).is_empty());
+
+        // A savepoint outside of a transaction starts one, and releasing it commits.
+        for command in ["savepoint outer", "insert into users values (6, 'f', 'f@x')"] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        assert_eq!(
+            run(&mut table, "begin"),
+            Err(Error::Invalid("a transaction is already active")),
+        );
+        assert!(run(&mut table, "release outer").is_ok());
+        assert_eq!(run(&mut table, "commit"), Err(Error::Invalid("no transaction is active")));
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), vec![6]);
+
+        for command in ["savepoint", "savepoint a b", "release", "rollback to", "savepoint 'a'"] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn vacuum() {
+        let path = &temp_path("testdb-vacuum.mysql");
+        let mut table = db_open_new(path);
+        for command in ["create index e on users(email)", "pragma journal_mode = wal"] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        for i in 1..100 {
+            let command = format!("insert into users values ({}, 'user{}', 'e{}')", i, i, i % 7);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        for i in (2..100).filter(|i| i % 3 != 0) {
+            assert!(run(&mut table, &format!("delete {}", i)).is_ok());
+        }
+        let num_pages = table.pager.num_pages;
+
+        assert!(run(&mut table, "begin").is_ok());
+        assert_eq!(
+            run(&mut table, "vacuum"),
+            Err(Error::Invalid("cannot vacuum within a transaction")),
+        );
+        assert!(run(&mut table, "rollback").is_ok());
+        assert!(run(&mut table, "VACUUM").is_ok());
+        assert!(table.pager.num_pages < num_pages);
+        assert_eq!(header_free_list(table.pager.get_page_ref(HEADER_PAGE_NUM).unwrap()), 0);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        assert!(table.pager.wal.is_some());
+        assert!(fs::metadata(format!("{}-vacuum", path)).is_err());
+
+        // Ids are still never reused.
+        assert!(run(&mut table, "insert into users (username) values ('new')").is_ok());
+        assert_eq!(table.last_insert_rowid, 100);
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        let mut ids: Vec<u32> = (1..100).filter(|i| i == &1 || i % 3 == 0).collect();
+        ids.push(100);
+        assert_eq!(check_table(&mut table), ids);
+        check_index(&mut table, 0);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+    }
+
+    #[test]
+    fn create_index() {
+        let mut table = db_open_new(&temp_path("testdb-index.mysql"));
+        for id in 0..100 {
+            let command = format!("insert {} user{} user{}@example.com", id, id % 7, id);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let create = prepare_statement("create index idx_username on users(username)").unwrap();
+        assert!(execute_statement(&create, &mut table).is_ok());
+        assert_eq!(
+            execute_statement(&create, &mut table),
+            Err(Error::Invalid("index already exists")),
+        );
+        check_index(&mut table, 0);
+
+        // The index is kept up to date as rows are inserted and deleted.
+        for id in 100..150 {
+            let command = format!("insert {} user{} null", id, id % 3);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+        for id in (0..150).filter(|id| id % 4 != 0) {
+            let delete = prepare_statement(&format!("delete {}", id)).unwrap();
+            assert!(execute_statement(&delete, &mut table).is_ok());
+        }
+        check_index(&mut table, 0);
+        drop(table);
+
+        let mut table = db_open(&temp_path("testdb-index.mysql")).unwrap();
+        assert_eq!(table.indexes.len(), 1);
+        assert_eq!(table.indexes[0].name, "idx_username");
+        assert_eq!(table.indexes[0].column, 1);
+        let create = prepare_statement("CREATE INDEX idx_email ON users (email)").unwrap();
+        assert!(execute_statement(&create, &mut table).is_ok());
+        let mut rows = parse_csv_rows("1000,jdoe,jdoe@example.com\n1001,asmith,null\n").unwrap();
+        assert!(bulk_load(&mut rows, &mut table).is_ok());
+        check_index(&mut table, 0);
+        check_index(&mut table, 1);
+
+        assert!(prepare_statement("create index idx on users(name)").is_err());
+        assert!(prepare_statement("create index idx on accounts(email)").is_err());
+        assert!(prepare_statement("create index idx-email on users(email)").is_err());
+    }
+
+    #[test]
+    fn select_with_index() {
+        let mut table = db_open_new(&temp_path("testdb-index-select.mysql"));
+        for id in 0..120 {
+            let email = if id % 10 == 0 { String::from("null") } else { format!("e{}", id) };
+            let command = format!("insert {} user{} {}", id, id % 60, email);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+        for command in ["create index u on users(username)", "create index e on users(email)"] {
+            let create = prepare_statement(command).unwrap();
+            assert!(execute_statement(&create, &mut table).is_ok());
+        }
+
+        // Each query gives the same rows with the index as with a scan, and the index is only used
+        // when few enough rows match.
+        for (command, uses_index) in [
+            ("select where username = user7", true),
+            ("select where username = nobody", true),
+            ("select where username < user0", true),
+            ("select where username > user1", false),
+            ("select where username != user7", false),
+            ("select where email is null", false),
+            ("select where email = e123", true),
+            ("select where email = null", false),
+            ("select where id = 7", false),
+            ("select where username > user1 and email = e123", true),
+            ("select where username = user7 and not email = e7", true),
+            ("select where username = user7 or email = e3", false),
+            ("select where username = user7 and id > 10", false),
+        ] {
+            let select = prepare_statement(command).unwrap();
+            let predicate = select.where_clause.as_ref().unwrap();
+            let index = choose_index(&mut table, predicate).unwrap();
+            assert_eq!(index.is_some(), uses_index, "{}", command);
+
+            let ids = |rows: Vec<Row>| rows.iter().map(|row| row.id()).collect::<Vec<u32>>();
+            let scanned = ids(scan_rows(&mut table, Some(predicate)).unwrap());
+            assert_eq!(ids(select_rows(&select, &mut table).unwrap()), scanned, "{}", command);
+        }
+
+        let select = prepare_statement("select where username = user7 order by id desc").unwrap();
+        let rows = select_rows(&select, &mut table).unwrap();
+        let ids: Vec<u32> = rows.iter().map(|row| row.id()).collect();
+        assert_eq!(ids, vec![67, 7]);
+    }
+
+    #[test]
+    fn where_boolean_expressions() {
+        let mut table = db_open_new(&temp_path("testdb-boolean.mysql"));
+        for (id, username, email) in [(1, "alice", "null"), (2, "bob", "b@x"), (3, "carol", "c@x")]
+        {
+            let command = format!("insert {} {} {}", id, username, email);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+
+        // AND binds more tightly than OR, and NOT more tightly than either.
+        assert_eq!(ids(&mut table, "select where id = 1 or id = 2 and username = carol"), [1]);
+        assert_eq!(ids(&mut table, "select where (id = 1 or id = 2) and username = bob"), [2]);
+        assert_eq!(ids(&mut table, "select where not id = 1 and not id = 3"), [2]);
+        assert_eq!(ids(&mut table, "select where NOT (id = 1 OR id = 3)"), [2]);
+        assert_eq!(ids(&mut table, "select where ((id>1))and(username='carol')"), [3]);
+        assert_eq!(ids(&mut table, "select where username = 'a(b' or id = 2"), [2]);
+        assert_eq!(ids(&mut table, "select where email is not null and id < 3"), [2]);
+
+        // Comparing with NULL is unknown, so neither the comparison nor its negation matches, but
+        // an unknown result OR true does.
+        assert_eq!(ids(&mut table, "select where email = 'b@x'"), [2]);
+        assert_eq!(ids(&mut table, "select where not email = 'b@x'"), [3]);
+        assert_eq!(ids(&mut table, "select where not email = 'b@x' or id = 1"), [1, 3]);
+        assert_eq!(ids(&mut table, "select where email = 'b@x' and id = 1"), Vec::<u32>::new());
+
+        for command in [
+            "select where (id = 1",
+            "select where id = 1)",
+            "select where id = 1 and",
+            "select where or id = 1",
+            "select where not",
+            "select where ()",
+            "select where id = 1 id = 2",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn group_by() {
+        let mut table = db_open_new(&temp_path("testdb-group-by.mysql"));
+        for (id, username) in [(1, "carol"), (2, "alice"), (3, "null"), (4, "alice"), (5, "bob")] {
+            let command = format!("insert {} {} {}@example.com", id, username, id);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+
+        assert_eq!(
+            results(&mut table, "select username, count(*) from users group by username"),
+            vec![
+                vec![Value::Null, Value::Integer(1)],
+                vec![text("alice"), Value::Integer(2)],
+                vec![text("bob"), Value::Integer(1)],
+                vec![text("carol"), Value::Integer(1)],
+            ]
+        );
+        assert_eq!(
+            results(
+                &mut table,
+                "SELECT username, COUNT(*) FROM users GROUP BY username HAVING COUNT(*) > 1"
+            ),
+            vec![vec![text("alice"), Value::Integer(2)]]
+        );
+        assert_eq!(
+            results(
+                &mut table,
+                "select username from users group by username having username > b \
+                 order by username desc"
+            ),
+            vec![vec![text("carol")], vec![text("bob")]]
+        );
+        assert_eq!(
+            results(&mut table, "select count(*) from users where id > 2"),
+            vec![vec![Value::Integer(3)]]
+        );
+        assert_eq!(
+            results(&mut table, "select count(*) where id > 100"),
+            vec![vec![Value::Integer(0)]]
+        );
+        assert_eq!(
+            results(&mut table, "select email, id from users where id < 3 order by username"),
+            vec![
+                vec![text("2@example.com"), Value::Integer(2)],
+                vec![text("1@example.com"), Value::Integer(1)],
+            ]
+        );
+
+        // Every column in the select list, HAVING and ORDER BY must be the one grouped by.
+        for command in [
+            "select email, count(*) from users group by username",
+            "select * from users group by username",
+            "select count(*) from users order by id",
+            "select username from users group by username having id > 1",
+            "select count(*) from users group by name",
+            "select count(*) from other.accounts",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn select_distinct() {
+        let mut table = db_open_new(&temp_path("testdb-distinct.mysql"));
+        for (id, username, email) in [(1, "jdoe", "a@x"), (2, "bob", "b@x"), (3, "jdoe", "a@x")] {
+            let command = format!("insert {} {} {}", id, username, email);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+
+        assert_eq!(
+            results(&mut table, "SELECT DISTINCT email FROM users"),
+            vec![vec![text("a@x")], vec![text("b@x")]]
+        );
+        assert_eq!(
+            results(&mut table, "select distinct username, email order by username"),
+            vec![
+                vec![text("bob"), text("b@x")],
+                vec![text("jdoe"), text("a@x")],
+            ]
+        );
+        assert_eq!(results(&mut table, "select email from users").len(), 3);
+        assert_eq!(results(&mut table, "select distinct id, email from users").len(), 3);
+        assert_eq!(
+            results(&mut table, "select distinct count(*) from users group by username"),
+            vec![vec![Value::Integer(1)], vec![Value::Integer(2)]]
+        );
+    }
+
+    #[test]
+    fn set_operations() {
+        let mut table = db_open_new(&temp_path("testdb-set-operations.mysql"));
+        for command in ["insert 1 a a@x", "insert 2 b b@x", "insert 3 b c@x", "insert 4 c c@x"] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            query_rows(&select, table).unwrap().into_iter().map(|row| row.values).collect()
+        };
+        let text = |s: &str| vec![Value::Text(String::from(s))];
+        // Results are in order, without duplicates, except with UNION ALL.
+        assert_eq!(
+            results(&mut table, "select username where id >= 3 union select username from users"),
+            vec![text("a"), text("b"), text("c")]
+        );
+        assert_eq!(
+            results(&mut table, "select username where id >= 3 union all select 'a'"),
+            vec![text("b"), text("c"), text("a"), text("a"), text("a"), text("a")]
+        );
+        assert_eq!(
+            results(
+                &mut table,
+                "select username where id < 3 intersect select username where id > 2",
+            ),
+            vec![text("b")]
+        );
+        assert_eq!(
+            results(&mut table, "select username except select username where id = 3"),
+            vec![text("a"), text("c")]
+        );
+        // The operators combine results from left to right.
+        assert_eq!(
+            results(
+                &mut table,
+                "select id where id = 1 union select id where id = 2 except select 1 \
+                 union all select 7 where id = 1",
+            ),
+            vec![vec![Value::Integer(2)], vec![Value::Integer(7)]]
+        );
+
+        let select = prepare_statement("select * from users u where u.id = 1 union select * \
+            from users v where v.id = 4").unwrap();
+        assert_eq!(select.result_columns(), ["id", "username", "email"]);
+        assert_eq!(query_rows(&select, &mut table).unwrap().len(), 2);
+        let select = prepare_statement("select id as n union select count(*)").unwrap();
+        let rows = query_rows(&select, &mut table).unwrap();
+        assert_eq!(rows.last().unwrap().get::<i64>("n").unwrap(), 4);
+
+        for (command, message) in [
+            (
+                "select id, username union select id",
+                "compound SELECTs must have the same number of columns at column 27",
+            ),
+            (
+                "select id order by id union select id",
+                "ORDER BY can't be used in a compound SELECT at column 1",
+            ),
+            ("select id union delete 1", "expected 'select', found 'delete' at column 17"),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn subqueries() {
+        let mut table = db_open_new(&temp_path("testdb-subqueries.mysql"));
+        for command in ["insert 1 a a@x", "insert 2 b b@x", "insert 3 b c@x", "insert 4 c NULL"] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            query_rows(&select, table).unwrap().into_iter().map(|row| row.values).collect()
+        };
+        let ids = |ids: &[i64]| -> Vec<Vec<Value>> {
+            ids.iter().map(|id| vec![Value::Integer(*id)]).collect()
+        };
+        assert_eq!(
+            results(&mut table, "select id where id = (select id from users order by id desc)"),
+            ids(&[4])
+        );
+        assert_eq!(
+            results(&mut table, "select id where id in (select id + 1 where username = 'b')"),
+            ids(&[3, 4])
+        );
+        assert_eq!(
+            results(&mut table, "select id where username not in (select username where id < 3)"),
+            ids(&[4])
+        );
+        // As with a list, NOT IN is unknown for a value that isn't a result if one is NULL.
+        assert_eq!(results(&mut table, "select id where 'x' not in (select email)"), ids(&[]));
+        assert_eq!(results(&mut table, "select id where id in (select id where id > 9)"), ids(&[]));
+        // Subqueries can be nested and qualified by their own aliases, and a scalar subquery with
+        // no results is NULL.
+        assert_eq!(
+            results(
+                &mut table,
+                "select u.id from users u where u.id > (select count(*) from users v \
+                 where v.id in (select id where email like 'b%')) + 2",
+            ),
+            ids(&[4])
+        );
+        let select = prepare_statement(
+            "select (select username where id = 9), (select count(*)) * 10 where id = 1",
+        ).unwrap();
+        assert_eq!(select.result_columns()[1], "(select count(*)) * 10");
+        assert_eq!(
+            query_rows(&select, &mut table).unwrap()[0].values,
+            vec![Value::Null, Value::Integer(40)]
+        );
+
+        // The subquery is executed again each time the statement is, and sees new rows.
+        let select = prepare_statement("select count(*) where id in (select id)").unwrap();
+        assert_eq!(query_rows(&select, &mut table).unwrap()[0].values, [Value::Integer(4)]);
+        let insert = prepare_statement("insert 5 d d@x").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+        assert_eq!(query_rows(&select, &mut table).unwrap()[0].values, [Value::Integer(5)]);
+
+        for (command, message) in [
+            (
+                "select where id in (select id, username)",
+                "a subquery must have one column at column 21",
+            ),
+            ("select where id = (select username)", "can't compare integer to text at column 14"),
+            ("select where id = (select id", "expected ')', found end of statement at column 29"),
+            (
+                "insert into users values (6, 'e', 'e@x') returning (select 1)",
+                "RETURNING can't contain a subquery at column 52",
+            ),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn common_table_expressions() {
+        let mut table = db_open_new(&temp_path("testdb-common-table-expressions.mysql"));
+        for command in ["insert 1 a a@x", "insert 2 b b@x", "insert 3 b c@x", "insert 4 c NULL"] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            query_rows(&select, table).unwrap().into_iter().map(|row| row.values).collect()
+        };
+        let ids = |ids: &[i64]| -> Vec<Vec<Value>> {
+            ids.iter().map(|id| vec![Value::Integer(*id)]).collect()
+        };
+        assert_eq!(
+            results(&mut table, "with b as (select where username = 'b') select id from b"),
+            ids(&[2, 3])
+        );
+        // Each can read the ones before it, and columns can be qualified by its name.
+        assert_eq!(
+            results(
+                &mut table,
+                "WITH b AS (select * where username = 'b'), \
+                 c AS (select id + 10, username, email from b) \
+                 select c.id, username from c where id > 12 order by id desc",
+            ),
+            vec![vec![Value::Integer(13), Value::Text(String::from("b"))]]
+        );
+        assert_eq!(
+            results(
+                &mut table,
+                "with n as (select id, NULL, email where id < 3) \
+                 select count(*) from n where username is null",
+            ),
+            ids(&[2])
+        );
+        // Subqueries and the SELECTs that the statement is combined with can read them too.
+        assert_eq!(
+            results(
+                &mut table,
+                "with b as (select where username = 'b') \
+                 select id where id in (select id + 1 from b) union select id from b",
+            ),
+            ids(&[2, 3, 4])
+        );
+
+        for (command, message) in [
+            (
+                "with b as (select id) select from b",
+                "a common table expression must select every column of the table at column 12",
+            ),
+            ("with users as (select) select", "table users already exists at column 6"),
+            ("with a as (select) delete 1", "expected 'select', found 'delete' at column 20"),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn views() {
+        let path = &temp_path("testdb-views.mysql");
+        let mut table = db_open_new(path);
+        for command in [
+            "insert 1 a a@x",
+            "insert 2 b b@x",
+            "insert 3 b c@x",
+            "create view b as select * where username = 'b'",
+            "create view c as select id + 10, username, email from b where id > 2",
+        ] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+
+        let results = |table: &mut Table, command: &str| -> Result<Vec<Vec<Value>>> {
+            let select = prepare_statement(command).unwrap();
+            Ok(query_rows(&select, table)?.into_iter().map(|row| row.values).collect())
+        };
+        let ids = |ids: &[i64]| -> Vec<Vec<Value>> {
+            ids.iter().map(|id| vec![Value::Integer(*id)]).collect()
+        };
+        assert_eq!(results(&mut table, "select id from b").unwrap(), ids(&[2, 3]));
+        assert_eq!(results(&mut table, "select c.id from c").unwrap(), ids(&[13]));
+        // A view is computed again each time it's read, and is kept in the database.
+        assert!(run(&mut table, "insert 4 b d@x").is_ok());
+        assert!(run(&mut table, "vacuum").is_ok());
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        assert_eq!(results(&mut table, "select id from c").unwrap(), ids(&[13, 14]));
+        assert_eq!(
+            results(&mut table, "select id where id not in (select id from b)").unwrap(),
+            ids(&[1])
+        );
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        assert_eq!(
+            run(&mut table, "create view b as select"),
+            Err(Error::Invalid("view already exists"))
+        );
+        assert_eq!(
+            run(&mut table, "create view d as select from e"),
+            Err(Error::Invalid("no such table"))
+        );
+        assert_eq!(run(&mut table, "drop view e"), Err(Error::Invalid("no such view")));
+        assert!(run(&mut table, "drop view b").is_ok());
+        assert_eq!(results(&mut table, "select from c"), Err(Error::Invalid("no such table")));
+        assert!(run(&mut table, "create view b as select from c").is_ok());
+        assert_eq!(results(&mut table, "select from b"), Err(Error::Invalid("view reads itself")));
+        assert!(run(&mut table, "drop view b").is_ok());
+        assert!(run(&mut table, "drop view c").is_ok());
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        for (command, message) in [
+            (
+                "create view v as select id",
+                "a view must select every column of the table at column 18",
+            ),
+            ("create view users as select", "table users already exists at column 13"),
+            ("create view v select", "expected 'as', found 'select' at column 15"),
+            ("drop v", "expected 'view' or 'trigger', found 'v' at column 6"),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn triggers() {
+        let path = &temp_path("testdb-triggers.mysql");
+        let mut table = db_open_new(path);
+        let statements = prepare_statements(
+            "create trigger audit after insert on users begin \
+                insert into users values (new.id + 100, new.username, 'audit'); \
+             end; \
+             insert 1 a a@x",
+        );
+        let changes: Vec<usize> = statements.into_iter()
+            .map(|statement| execute_statement(&statement.unwrap(), &mut table).unwrap())
+            .collect();
+        // The trigger's changes aren't counted, and don't fire it again.
+        assert_eq!(changes, [0, 1]);
+        assert_eq!(table.last_insert_rowid, 1);
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            query_rows(&select, table).unwrap().into_iter().map(|row| row.values).collect()
+        };
+        let text = |text: &str| Value::Text(String::from(text));
+        assert_eq!(
+            results(&mut table, "select id, email"),
+            vec![
+                vec![Value::Integer(1), text("a@x")],
+                vec![Value::Integer(101), text("audit")],
+            ]
+        );
+
+        assert!(run(
+            &mut table,
+            "create trigger archive after delete on users for each row begin \
+                insert into users values (old.id + 200, old.username, old.email); \
+                insert or replace into users values (1000, 'deleted', old.username); \
+             end",
+        ).is_ok());
+        assert_eq!(run(&mut table, "delete 1"), Ok(1));
+        // Triggers are kept in the database.
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        assert!(run(&mut table, "insert 2 b b@x").is_ok());
+        assert_eq!(
+            results(&mut table, "select id, username, email where id > 100"),
+            vec![
+                vec![Value::Integer(101), text("a"), text("audit")],
+                vec![Value::Integer(102), text("b"), text("audit")],
+                vec![Value::Integer(201), text("a"), text("a@x")],
+                vec![Value::Integer(1000), text("deleted"), text("a")],
+            ]
+        );
+
+        assert_eq!(
+            run(&mut table, "create trigger archive after insert on users begin delete 1; end"),
+            Err(Error::Invalid("trigger already exists"))
+        );
+        assert_eq!(run(&mut table, "drop trigger t"), Err(Error::Invalid("no such trigger")));
+        assert!(run(&mut table, "drop trigger audit").is_ok());
+        assert!(run(&mut table, "insert 3 c c@x").is_ok());
+        assert_eq!(results(&mut table, "select count(*)"), vec![vec![Value::Integer(6)]]);
+        assert!(run(&mut table, "drop trigger archive").is_ok());
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        for (command, message) in [
+            (
+                "create trigger t after update on users begin delete 1; end",
+                "expected 'insert' or 'delete', found 'update' at column 24",
+            ),
+            (
+                "create trigger t after delete on users begin delete new.id; end",
+                "no such column: new.id at column 53",
+            ),
+            (
+                "create trigger t after insert on users begin select; end",
+                "expected 'insert into' or 'delete', found 'select' at column 46",
+            ),
+            (
+                "create trigger t after insert on users begin delete 1 end",
+                "expected ';', found end of statement at column 58",
+            ),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn analyze() {
+        let path = &temp_path("testdb-analyze.mysql");
+        let mut table = db_open_new(path);
+        // Most rows have the same username, so how many rows a username matches depends on which
+        // one it is.
+        for id in 0..200 {
+            let username =
+                if id % 20 == 0 { format!("rare{}", id) } else { String::from("common") };
+            let command = format!("insert {} {} e{}", id, username, id);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        for command in ["create index u on users(username)", "create index e on users(email)"] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        assert!(table.stats.is_none());
+        assert_eq!(run(&mut table, "analyze"), Ok(0));
+        drop(table);
+
+        // The statistics are kept in the database.
+        let mut table = db_open(path).unwrap();
+        let summary = |table: &Table| -> (usize, Vec<(String, usize, usize)>) {
+            let stats = table.stats.as_ref().unwrap();
+            let indexes = stats.indexes.iter().map(|index| {
+                (index.name.clone(), index.num_distinct, index.samples.len())
+            });
+            (stats.num_rows, indexes.collect())
+        };
+        let expected = (200, vec![(String::from("u"), 11, 16), (String::from("e"), 200, 16)]);
+        assert_eq!(summary(&table), expected);
+
+        for (command, uses_index) in [
+            ("select where username = common", false),
+            ("select where username = rare40", true),
+            ("select where username = nobody", true),
+            ("select where username > rare0", true),
+            ("select where email = e123", true),
+            ("select where email > e5", false),
+        ] {
+            let select = prepare_statement(command).unwrap();
+            let predicate = select.where_clause.as_ref().unwrap();
+            let index = choose_index(&mut table, predicate).unwrap();
+            assert_eq!(index.is_some(), uses_index, "{}", command);
+        }
+
+        // The statistics are not kept up to date as rows change, but they survive a vacuum and
+        // are replaced by running ANALYZE again.
+        assert!(run(&mut table, "delete 0").is_ok());
+        assert_eq!(summary(&table).0, 200);
+        assert!(run(&mut table, "vacuum").is_ok());
+        assert_eq!(summary(&table), expected);
+        assert_eq!(run(&mut table, "analyze users"), Ok(0));
+        assert_eq!(summary(&table).0, 199);
+        assert_eq!(summary(&table).1[0].1, 10);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        assert_eq!(
+            prepare_statement("analyze emails").unwrap_err().to_string(),
+            "expected end of statement, found 'emails' at column 9"
+        );
+    }
+
+    #[test]
+    fn pragmas() {
+        let path = &temp_path("testdb-pragmas.mysql");
+        drop(db_open_new(path));
+        let mut database = Database::open(path).unwrap();
+        let query = |database: &mut Database, command: &str| -> Result<Vec<Vec<Value>>> {
+            let rows = database.query(command)?.collect::<Result<Vec<Row>>>()?;
+            Ok(rows.into_iter().map(|row| row.values().to_vec()).collect())
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+
+        // Each setting is one row, in a column named after it.
+        let mut rows = database.query("pragma cache_size = 8").unwrap();
+        assert_eq!(rows.next().unwrap().unwrap().get::<i64>("cache_size"), Ok(8));
+        assert!(rows.next().is_none());
+        assert_eq!(query(&mut database, "pragma synchronous"), Ok(vec![vec![text("full")]]));
+        let page_size = Value::Integer(PAGE_SIZE as i64);
+        assert_eq!(query(&mut database, "pragma page_size"), Ok(vec![vec![page_size]]));
+        assert!(database.execute(&format!("pragma page_size = {}", PAGE_SIZE)).is_ok());
+        assert_eq!(
+            database.execute("pragma page_size = 1024"),
+            Err(Error::Invalid("the page size cannot be changed")),
+        );
+        assert_eq!(query(&mut database, "pragma integrity_check"), Ok(vec![vec![text("ok")]]));
+        assert_eq!(
+            query(&mut database, "pragma table_info(users)"),
+            Ok(vec![
+                vec![Value::Integer(0), text("id"), text("integer"), Value::Integer(1)],
+                vec![Value::Integer(1), text("username"), text("text"), Value::Integer(0)],
+                vec![Value::Integer(2), text("email"), text("text"), Value::Integer(0)],
+            ])
+        );
+        assert_eq!(
+            query(&mut database, "pragma table_info(emails)"),
+            Err(Error::Invalid("no such table"))
+        );
+
+        // The user version is kept in the database, and setting it is part of the transaction.
+        assert_eq!(query(&mut database, "pragma user_version"), Ok(vec![vec![Value::Integer(0)]]));
+        assert!(database.execute("pragma user_version = 3").is_ok());
+        assert!(database.execute("begin").is_ok());
+        assert!(database.execute("pragma user_version = -7").is_ok());
+        assert!(database.execute("rollback").is_ok());
+        assert!(database.execute("vacuum").is_ok());
+        drop(database);
+        let mut database = Database::open(path).unwrap();
+        assert_eq!(query(&mut database, "pragma user_version"), Ok(vec![vec![Value::Integer(3)]]));
+        assert_eq!(
+            database.execute("pragma user_version = x"),
+            Err(Error::Invalid("invalid user version"))
+        );
+        assert_eq!(
+            query(&mut database, "pragma user_version = 4"),
+            Err(Error::Invalid("only a SELECT or PRAGMA statement can be queried"))
+        );
+    }
+
+    #[test]
+    fn migrations() {
+        let path = &temp_path("testdb-migrations.mysql");
+        drop(db_open_new(path));
+        let mut database = Database::open(path).unwrap();
+        let user_version = |database: &mut Database| -> i64 {
+            let mut rows = database.query("pragma user_version").unwrap();
+            rows.next().unwrap().unwrap().get("user_version").unwrap()
+        };
+        let mut migrations = vec![
+            "create index u on users(username)",
+            "insert 1 a a@x; insert 2 b b@x",
+        ];
+        assert_eq!(database.migrate(&migrations), Ok(2));
+        assert_eq!(user_version(&mut database), 2);
+        assert_eq!(database.migrate(&migrations), Ok(0));
+
+        // Only the migrations that are new are executed, and if one fails, none of them are kept.
+        migrations.push("create view v as select * from users where id > 1");
+        migrations.push("insert 3 c c@x; insert 1 d d@x");
+        assert!(matches!(database.migrate(&migrations), Err(Error::Constraint(_))));
+        assert_eq!(user_version(&mut database), 2);
+        assert!(database.query("select * from v").is_err());
+        migrations[3] = "insert 3 c c@x";
+        assert_eq!(database.migrate(&migrations), Ok(2));
+        drop(database);
+
+        let mut database = Database::open(path).unwrap();
+        assert_eq!(user_version(&mut database), 4);
+        assert_eq!(database.query("select * from v").unwrap().count(), 2);
+        assert_eq!(
+            database.migrate(&migrations[..3]),
+            Err(Error::Invalid("the user version is not a number of the migrations"))
+        );
+        assert_eq!(database.migrate(&[""; 5]), Ok(1));
+        assert_eq!(database.migrate(&["select from"; 5]), Ok(0));
+    }
+
+    #[test]
+    fn partial_indexes() {
+        let path = &temp_path("testdb-partial-indexes.mysql");
+        let mut table = db_open_new(path);
+        let num_entries = |table: &mut Table| -> usize {
+            let tree = table.indexes[0].tree();
+            let mut cursor = Cursor::seek(table, tree, &[]).unwrap();
+            let mut num_entries = 0;
+            while !cursor.end_of_table {
+                num_entries += 1;
+                cursor.advance().unwrap();
+            }
+            num_entries
+        };
+        for id in 0..1000 {
+            let username = if id % 100 == 0 { "admin" } else { "user" };
+            let command = format!("insert {} {} e{}", id, username, id);
+            assert!(run(&mut table, &command).is_ok());
+        }
+
+        // Only the rows that match the WHERE clause have entries, as rows change too.
+        let command = "create index admin_emails on users(email) where username = admin";
+        assert!(run(&mut table, command).is_ok());
+        assert_eq!(num_entries(&mut table), 10);
+        assert!(run(&mut table, "insert 1000 admin e1000").is_ok());
+        assert!(run(&mut table, "insert 1001 user e1001").is_ok());
+        let command = "insert or replace into users values (1, 'admin', 'e1')";
+        assert!(run(&mut table, command).is_ok());
+        assert!(run(&mut table, "delete 0").is_ok());
+        assert_eq!(num_entries(&mut table), 11);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        // The index is only used for a query that only wants rows it has entries for.
+        for (command, uses_index) in [
+            ("select where email = e100 and username = admin", true),
+            ("select where username = admin and email < e2", true),
+            ("select where email = e100", false),
+            ("select where email = e100 and username = user", false),
+            ("select where email = e100 and (username = admin or username = user)", false),
+        ] {
+            let select = prepare_statement(command).unwrap();
+            let predicate = select.where_clause.as_ref().unwrap();
+            let index = choose_index(&mut table, predicate).unwrap();
+            assert_eq!(index.is_some(), uses_index, "{}", command);
+        }
+        let select = prepare_statement("select where email < e2 and username = admin").unwrap();
+        assert_eq!(query_rows(&select, &mut table).unwrap().len(), 3);
+        drop(table);
+
+        // The WHERE clause is kept in the database, and survives a vacuum.
+        let mut table = db_open(path).unwrap();
+        let expected = Some(String::from("username = admin"));
+        let where_clause =
+            |table: &Table| table.indexes[0].where_clause.as_ref().map(|(text, _)| text.clone());
+        assert_eq!(where_clause(&table), expected);
+        assert!(run(&mut table, "vacuum").is_ok());
+        assert_eq!(where_clause(&table), expected);
+        assert_eq!(num_entries(&mut table), 11);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        for command in [
+            "create index i on users(email) where id > now()",
+            "create index i on users(email) where id in (select id from users)",
+        ] {
+            assert_eq!(
+                prepare_statement(command).unwrap_err().to_string().split(" at ").next(),
+                Some("an index's WHERE clause can't have a subquery or call now()"),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn expression_indexes() {
+        let path = &temp_path("testdb-expression-indexes.mysql");
+        let mut table = db_open_new(path);
+        for id in 0..1000 {
+            let command = format!("insert {} a{} User{}@Example.com", id, id, id);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        for command in [
+            "create index lower_email on users(lower(email))",
+            "create index lower_username on users(lower(username))",
+        ] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+
+        // A condition on the expression is matched against the index, but one on the column isn't.
+        let command = "select where lower(email) = 'user500@example.com'";
+        let select = prepare_statement(command).unwrap();
+        let predicate = select.where_clause.as_ref().unwrap();
+        let index = choose_index(&mut table, predicate).unwrap();
+        assert_eq!(index.map(|(index_num, _)| index_num), Some(0));
+        assert_eq!(query_rows(&select, &mut table).unwrap().len(), 1);
+        let select = prepare_statement("select where email = 'user500@example.com'").unwrap();
+        assert!(choose_index(&mut table, select.where_clause.as_ref().unwrap()).unwrap().is_none());
+        assert!(query_rows(&select, &mut table).unwrap().is_empty());
+        let statement = prepare_statement(&format!("explain {}", command)).unwrap();
+        assert_eq!(
+            explain(&statement, &mut table).unwrap(),
+            vec!["SEARCH users USING INDEX lower_email (lower(email)=?)"]
+        );
+
+        // A value that is longer than the column once lowercased is cut short in the index, and
+        // is still found by a range that starts at what it is cut to.
+        let long = "\u{130}".repeat(16);
+        let command = format!("insert into users values (1000, '{}', 'x')", long);
+        assert!(run(&mut table, &command).is_ok());
+        let cut = format!("{}i", "i\u{307}".repeat(10));
+        for (comparison, num_rows) in [(">", 1), (">=", 1), ("=", 0), ("<", 1000)] {
+            let command = format!("select where lower(username) {} '{}'", comparison, cut);
+            let select = prepare_statement(&command).unwrap();
+            let predicate = select.where_clause.as_ref().unwrap();
+            let uses_index = choose_index(&mut table, predicate).unwrap().is_some();
+            assert!(uses_index || comparison == "<", "{}", command);
+            assert_eq!(query_rows(&select, &mut table).unwrap().len(), num_rows, "{}", command);
+        }
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        drop(table);
+
+        // The expression is kept in the database, and survives a vacuum.
+        let mut table = db_open(path).unwrap();
+        let expression =
+            |table: &Table| table.indexes[0].expression.as_ref().map(|(text, _)| text.clone());
+        assert_eq!(expression(&table), Some(String::from("lower(email)")));
+        assert!(run(&mut table, "vacuum").is_ok());
+        assert_eq!(expression(&table), Some(String::from("lower(email)")));
+        assert_eq!(query_rows(&select, &mut table).unwrap().len(), 0);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        for (command, message) in [
+            (
+                "create index i on users(lower(email) || username)",
+                "an index's expression must use exactly one column",
+            ),
+            (
+                "create index i on users(length(email))",
+                "an index's expression must have the type of its column",
+            ),
+            (
+                "create index i on users(id + (select id from users))",
+                "an index's expression can't have a subquery or call now()",
+            ),
+        ] {
+            assert_eq!(
+                prepare_statement(command).unwrap_err().to_string().split(" at ").next(),
+                Some(message),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn index_only_scans() {
+        let path = &temp_path("testdb-index-only-scans.mysql");
+        let mut table = db_open_new(path);
+        for id in 0..1000 {
+            let command = format!("insert {} u{} e{}", id, id % 100, id);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        let command = "insert into users (id, email) values (1000, 'e1000')";
+        assert!(run(&mut table, command).is_ok());
+        for command in [
+            "create index u on users(username)",
+            "create index lower_email on users(lower(email))",
+        ] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+
+        let plan = |table: &mut Table, command: &str| -> String {
+            let statement = prepare_statement(&format!("explain {}", command)).unwrap();
+            explain(&statement, table).unwrap().remove(0)
+        };
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            select_results(&prepare_statement(command).unwrap(), table).unwrap()
+        };
+        let page_accesses = |table: &Table| table.pager.pages_read + table.pager.cache_hits;
+
+        // A query that only reads the id and the index's column is answered from the index, with
+        // the same results as reading the table.
+        for (command, scan) in [
+            (
+                "select id, username where username = u7 order by id",
+                "select id, username where username = u7 or id < 0 order by id",
+            ),
+            (
+                "select username, count(*) where username >= u99 group by username",
+                "select username, count(*) where username >= u99 or id < 0 group by username",
+            ),
+            ("select id, username where username is null", "select id, username where id = 1000"),
+        ] {
+            assert!(plan(&mut table, command).contains("USING INDEX ONLY u"), "{}", command);
+            assert!(!plan(&mut table, scan).contains("INDEX"), "{}", scan);
+            assert_eq!(results(&mut table, command), results(&mut table, scan), "{}", command);
+        }
+        assert_eq!(results(&mut table, "select id, username where username is null").len(), 1);
+
+        // Reading another column, or the column through an index on an expression, means reading
+        // the table too.
+        for (command, expected) in [
+            ("select email where username = u7", "SEARCH users USING INDEX u (username=?)"),
+            (
+                "select id where lower(email) = 'e7'",
+                "SEARCH users USING INDEX lower_email (lower(email)=?)",
+            ),
+        ] {
+            assert_eq!(plan(&mut table, command), expected);
+        }
+        let before = page_accesses(&table);
+        results(&mut table, "select id where username = u7");
+        let index_only = page_accesses(&table) - before;
+        let before = page_accesses(&table);
+        results(&mut table, "select email where username = u7");
+        assert!(index_only < page_accesses(&table) - before);
+    }
+
+    #[test]
+    fn statement_output() {
+        let path = &temp_path("testdb-output.mysql");
+        drop(db_open_new(path));
+        let database = Database::open(path).unwrap();
+        let output = |command: &str| -> (usize, Vec<Vec<Value>>) {
+            let statement = prepare_statement(command).unwrap();
+            let (changes, rows) = database.execute_statement_with_rows(&statement).unwrap();
+            (changes, rows.into_iter().map(|row| row.values).collect())
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+
+        // Statements return the rows that they output, rather than printing them.
+        assert_eq!(output("pragma cache_size = 4"), (0, vec![vec![Value::Integer(4)]]));
+        assert_eq!(
+            output("insert into users values (7, 'a', 'a@x') returning id, username"),
+            (1, vec![vec![Value::Integer(7), text("a")]])
+        );
+        assert_eq!(output("select last_insert_rowid()"), (0, vec![vec![Value::Integer(7)]]));
+        assert_eq!(output("select changes()"), (0, vec![vec![Value::Integer(1)]]));
+        assert_eq!(output("select username"), (0, vec![vec![text("a")]]));
+        assert_eq!(output("explain select"), (0, vec![vec![text("SCAN users")]]));
+        assert_eq!(output("delete 7"), (1, Vec::new()));
+
+        let statement = prepare_statement("explain select").unwrap();
+        let (_, rows) = database.execute_statement_with_rows(&statement).unwrap();
+        assert_eq!(rows[0].get::<String>("plan"), Ok(String::from("SCAN users")));
+        assert!(!statement.has_results());
+        assert!(prepare_statement("delete 1 returning id").unwrap().has_results());
+        assert_eq!(database.execute("select"), Ok(0));
+    }
+}
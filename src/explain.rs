@@ -0,0 +1,605 @@
+//! Describing how a statement is executed, for EXPLAIN, without executing it, and recording what
+//! each step of executing it did, for EXPLAIN ANALYZE.
+
+use std::iter;
+use std::time::{Duration, Instant};
+
+use crate::btree::{Table, Trigger};
+use crate::error::Error;
+use crate::exec::{
+    Action, choose_index, database_table, execute_profiled, find_view, is_index_only, needs_sort,
+};
+use crate::pager::LockLevel;
+use crate::parser::prepare_statement;
+use crate::sql::{
+    COLUMNS, Comparison, Condition, OrderBy, SelectItem, Statement, StatementKind, TABLE_NAME,
+};
+
+
+/// The steps of a statement that is executed for EXPLAIN ANALYZE, in the order that they began.
+#[derive(Default)]
+pub(crate) struct Profile {
+    steps: Vec<Step>,
+    // The number of steps that are being executed, which the next step is part of.
+    depth: usize,
+}
+
+
+/// A step of executing a statement, and what it did, including in the steps that are part of it.
+struct Step {
+    depth: usize,
+    description: String,
+    // The number of rows that the step produced, or for a step that changes the table, changed.
+    rows: usize,
+    pages_read: u64,
+    cache_hits: u64,
+    // None if there is no clock to time it with.
+    elapsed: Option<Duration>,
+}
+
+
+/// Return the lines of the plan for executing a statement, with each step indented under the one
+/// that it is part of. With ANALYZE, the statement is executed, and each step says how many rows
+/// it produced, how many pages it read from disk and found in the cache, and how long it took.
+///
+/// A SELECT reads one table, view or common table expression, so there is no join order to
+/// choose. Its plan says which it reads, and whether it scans all of the rows or searches for the
+/// matching ones by their ids or with an index, followed by what is done with the rows, e.g.
+/// whether they have to be sorted. Views and common table expressions are computed first, and so
+/// are subqueries, before the SELECTs that they are in.
+pub(crate) fn explain(statement: &Statement, table: &mut Table) -> Result<Vec<String>, Error> {
+    if statement.analyze {
+        return explain_analyze(statement, table);
+    }
+
+    let mut plan = Vec::new();
+    match statement.kind {
+        StatementKind::Select => {
+            // Choosing an index reads the database, which stays locked until the plan is made, as
+            // it would while executing the statement.
+            let selects = statement.selects();
+            let mut result = selects
+                .iter()
+                .try_for_each(|select| {
+                    database_table(table, select.database.as_deref())?.lock(LockLevel::Shared)
+                })
+                .and_then(|()| explain_query(statement, table, &mut plan));
+            for select in &selects {
+                let finished = database_table(table, select.database.as_deref())
+                    .and_then(|table| table.finish_statement());
+                result = result.and(finished);
+            }
+            result?;
+        },
+        StatementKind::Insert | StatementKind::InsertOrReplace | StatementKind::Copy => {
+            plan.push(describe_write(statement));
+            explain_triggers(table, Action::Insert, &mut plan);
+        },
+        StatementKind::Delete => {
+            plan.push(describe_write(statement));
+            explain_triggers(table, Action::Delete, &mut plan);
+        },
+        // The parser only lets the statements above be explained.
+        _ => unreachable!(),
+    }
+    Ok(plan)
+}
+
+
+/// Execute a statement, and return the lines that say what each of its steps did.
+fn explain_analyze(statement: &Statement, table: &mut Table) -> Result<Vec<String>, Error> {
+    table.profile = Some(Profile::default());
+    let result = execute_profiled(statement, table);
+    let profile = table.profile.take().unwrap_or_default();
+    result?;
+
+    let lines = profile.steps.iter().map(|step| {
+        let elapsed = step.elapsed.map_or(String::new(), |elapsed| {
+            format!(", time={:.3} ms", elapsed.as_secs_f64() * 1000.0)
+        });
+        format!(
+            "{}{} (rows={}, pages read={}, cache hits={}{})",
+            "  ".repeat(step.depth),
+            step.description,
+            step.rows,
+            step.pages_read,
+            step.cache_hits,
+            elapsed,
+        )
+    });
+    Ok(lines.collect())
+}
+
+
+/// Execute a step of a statement, which `execute` does, and if the statement is being profiled,
+/// record the step, which `describe` describes, along with how many rows it produced, which
+/// `count` tells from its result, how many pages it read, and how long it took. The steps that
+/// `execute` executes in turn are recorded as part of it.
+pub(crate) fn profile_step<T>(
+    table: &mut Table,
+    describe: impl FnOnce(&Table) -> String,
+    execute: impl FnOnce(&mut Table) -> T,
+    count: impl FnOnce(&T) -> usize,
+) -> T {
+    if table.profile.is_none() {
+        return execute(table);
+    }
+    let description = describe(table);
+    let profile = table.profile.as_mut().unwrap();
+    let index = profile.steps.len();
+    profile.steps.push(Step {
+        depth: profile.depth,
+        description,
+        rows: 0,
+        pages_read: 0,
+        cache_hits: 0,
+        elapsed: None,
+    });
+    profile.depth += 1;
+
+    let (pages_read, cache_hits) = page_counts(table);
+    let start = now();
+    let result = execute(table);
+    let elapsed = start.map(|start| start.elapsed());
+    let (pages_read_after, cache_hits_after) = page_counts(table);
+
+    // Steps that read an attached database move the profile to it, but always move it back.
+    let profile = table.profile.as_mut().unwrap();
+    profile.depth -= 1;
+    let step = &mut profile.steps[index];
+    step.rows = count(&result);
+    step.pages_read = pages_read_after - pages_read;
+    step.cache_hits = cache_hits_after - cache_hits;
+    step.elapsed = elapsed;
+    result
+}
+
+
+/// Return the number of results that a step produced, which is none if it failed.
+pub(crate) fn num_results<T>(results: &Result<Vec<T>, Error>) -> usize {
+    results.as_ref().map_or(0, Vec::len)
+}
+
+
+/// Return how many pages have been read from disk, and how many were found in the cache, in the
+/// database and the ones attached to it.
+fn page_counts(table: &Table) -> (u64, u64) {
+    let attached = table.attached.iter().map(|(_, attached)| &attached.pager);
+    iter::once(&table.pager).chain(attached).fold((0, 0), |(pages_read, cache_hits), pager| {
+        (pages_read + pager.pages_read, cache_hits + pager.cache_hits)
+    })
+}
+
+
+/// Return the time now, to measure how long a step takes. Without an operating system, e.g. in a
+/// browser, there is no clock to read it from, so steps aren't timed.
+#[cfg(not(all(target_family = "wasm", target_os = "unknown")))]
+fn now() -> Option<Instant> {
+    Some(Instant::now())
+}
+
+
+#[cfg(all(target_family = "wasm", target_os = "unknown"))]
+fn now() -> Option<Instant> {
+    None
+}
+
+
+/// Add the plan for a SELECT statement, in the order that it is executed: first the views that
+/// it reads, then its common table expressions, and then its SELECTs.
+fn explain_query(
+    statement: &Statement,
+    table: &mut Table,
+    plan: &mut Vec<String>,
+) -> Result<(), Error> {
+    explain_views(&statement.selects(), table, 0, plan, &mut Vec::new(), &mut Vec::new())?;
+    for (name, select) in &statement.common_tables {
+        push(plan, 0, format!("MATERIALIZE {}", name));
+        explain_select(select, table, 1, plan)?;
+    }
+    explain_select(statement, table, 0, plan)
+}
+
+
+/// Add the plan for computing each view that the SELECTs read, and the views that those read in
+/// turn, to the plan at the given depth. `expanding` is the views whose plans are being added,
+/// which must not read themselves, and `expanded` is those whose plans have been added, which
+/// are only computed once.
+fn explain_views(
+    selects: &[&Statement],
+    table: &mut Table,
+    depth: usize,
+    plan: &mut Vec<String>,
+    expanding: &mut Vec<String>,
+    expanded: &mut Vec<String>,
+) -> Result<(), Error> {
+    for name in selects.iter().filter_map(|select| select.view.as_deref()) {
+        let key = name.to_ascii_lowercase();
+        if expanded.contains(&key) {
+            continue;
+        } else if expanding.contains(&key) {
+            return Err(Error::Invalid("view reads itself"));
+        }
+        let view = find_view(table, name).ok_or(Error::Invalid("no such table"))?;
+        let select = prepare_statement(&view.text)?;
+
+        push(plan, depth, format!("MATERIALIZE {}", name));
+        expanding.push(key.clone());
+        explain_views(&select.selects(), table, depth + 1, plan, expanding, expanded)?;
+        expanding.pop();
+        explain_select(&select, table, depth + 1, plan)?;
+        expanded.push(key);
+    }
+    Ok(())
+}
+
+
+/// Add the plan for a SELECT, including the SELECTs that it is combined with, to the plan at the
+/// given depth.
+fn explain_select(
+    statement: &Statement,
+    table: &mut Table,
+    depth: usize,
+    plan: &mut Vec<String>,
+) -> Result<(), Error> {
+    if statement.compound.is_empty() {
+        return explain_simple(statement, table, depth, plan);
+    }
+    push(plan, depth, String::from("COMPOUND QUERY"));
+    push(plan, depth + 1, String::from("LEFT-MOST SUBQUERY"));
+    explain_simple(statement, table, depth + 2, plan)?;
+    for (operator, select) in &statement.compound {
+        push(plan, depth + 1, String::from(operator.name()));
+        explain_simple(select, table, depth + 2, plan)?;
+    }
+    Ok(())
+}
+
+
+/// Add the plan for a SELECT without those of any SELECTs that it is combined with, in the order
+/// that its steps are executed.
+fn explain_simple(
+    statement: &Statement,
+    table: &mut Table,
+    depth: usize,
+    plan: &mut Vec<String>,
+) -> Result<(), Error> {
+    for subquery in statement.subqueries() {
+        push(plan, depth, String::from("SUBQUERY"));
+        explain_select(&subquery.select, table, depth + 1, plan)?;
+    }
+
+    match statement.common_table.as_ref().or(statement.view.as_ref()) {
+        Some(name) => push(plan, depth, format!("SCAN {}", name)),
+        None => {
+            let table = database_table(table, statement.database.as_deref())?;
+            let predicate = statement.where_clause.as_ref();
+            let index = match predicate {
+                Some(predicate) => choose_index(table, predicate)?,
+                None => None,
+            };
+            push(plan, depth, describe_search(statement, table, index));
+        },
+    }
+
+    if let Some(order_by) = statement.order_by.as_ref().filter(|_| needs_sort(statement)) {
+        push(plan, depth, describe_sort(order_by));
+    }
+    if let Some(grouping) = describe_grouping(statement) {
+        push(plan, depth, grouping);
+    }
+    if statement.distinct {
+        push(plan, depth, String::from("DISTINCT"));
+    }
+    Ok(())
+}
+
+
+/// Add a line to the plan for the triggers that fire after the given kind of change, if any, as
+/// part of the statement that makes it.
+fn explain_triggers(table: &Table, action: Action, plan: &mut Vec<String>) {
+    let triggers: Vec<Trigger> =
+        table.triggers.iter().filter(|trigger| trigger.action == action).cloned().collect();
+    if !triggers.is_empty() {
+        push(plan, 1, describe_triggers(&triggers));
+    }
+}
+
+
+/// Return how an INSERT, COPY or DELETE statement is described in a plan.
+pub(crate) fn describe_write(statement: &Statement) -> String {
+    match statement.kind {
+        StatementKind::Insert => format!("INSERT INTO {}", TABLE_NAME),
+        StatementKind::InsertOrReplace => format!("INSERT OR REPLACE INTO {}", TABLE_NAME),
+        StatementKind::Copy => {
+            let path = statement.copy_from.as_ref().unwrap();
+            format!("COPY {} FROM '{}'", TABLE_NAME, path.replace('\'', "''"))
+        },
+        StatementKind::Delete => {
+            format!("DELETE FROM {} USING INTEGER PRIMARY KEY (id=?)", TABLE_NAME)
+        },
+        _ => unreachable!(),
+    }
+}
+
+
+/// Return how firing the given triggers is described in a plan.
+pub(crate) fn describe_triggers(triggers: &[Trigger]) -> String {
+    let names: Vec<&str> = triggers.iter().map(|trigger| trigger.name.as_str()).collect();
+    format!("FIRE TRIGGER {}", names.join(", "))
+}
+
+
+/// Return how a SELECT's step of reading the table is described in a plan, given the index that
+/// is used to find the matching rows, if any, along with the condition whose range of it is read.
+/// Without an index, only the rows whose ids can match are read.
+pub(crate) fn describe_search(
+    statement: &Statement,
+    table: &Table,
+    index: Option<(usize, &Condition)>,
+) -> String {
+    let name = match &statement.database {
+        Some(database) => format!("{}.{}", database, TABLE_NAME),
+        None => String::from(TABLE_NAME),
+    };
+    if let Some((index_num, condition)) = index {
+        let using = if is_index_only(statement, table, index_num) { "INDEX ONLY" } else { "INDEX" };
+        let index = &table.indexes[index_num].name;
+        let condition = describe_condition(condition);
+        return format!("SEARCH {} USING {} {} ({})", name, using, index, condition);
+    }
+
+    let conjuncts = statement.where_clause.as_ref().map_or(Vec::new(), |where_clause| {
+        where_clause.conjuncts()
+    });
+    let id_conditions: Vec<String> = conjuncts
+        .into_iter()
+        .filter(|condition| condition.first_possible_id() > 0 || limits_last_id(condition))
+        .map(describe_condition)
+        .collect();
+    if id_conditions.is_empty() {
+        format!("SCAN {}", name)
+    } else {
+        format!("SEARCH {} USING INTEGER PRIMARY KEY ({})", name, id_conditions.join(" AND "))
+    }
+}
+
+
+/// Return how sorting rows for an ORDER BY clause is described in a plan.
+pub(crate) fn describe_sort(order_by: &OrderBy) -> String {
+    let descending = if order_by.descending { " DESC" } else { "" };
+    format!("SORT BY {}{}", COLUMNS[order_by.column].name, descending)
+}
+
+
+/// Return how grouping a SELECT's rows is described in a plan, or None if they aren't grouped
+/// because it has no GROUP BY clause or aggregate.
+pub(crate) fn describe_grouping(statement: &Statement) -> Option<String> {
+    let items = statement.select_list.as_deref().unwrap_or(&[]);
+    match &statement.group_by {
+        Some(group_by) => Some(format!("GROUP BY {}", COLUMNS[group_by.column].name)),
+        None if items.iter().any(SelectItem::is_aggregate) => Some(String::from("AGGREGATE")),
+        None => None,
+    }
+}
+
+
+/// Return whether the condition means that the rows after some id can't match, so that a scan
+/// stops there.
+fn limits_last_id(condition: &Condition) -> bool {
+    condition.id_value().is_some()
+        && matches!(
+            condition.comparison,
+            Comparison::Equal | Comparison::LessThan | Comparison::LessThanOrEqual
+        )
+}
+
+
+/// Return how a condition that narrows down the rows to read is shown in a plan, e.g. `id>?` or
+/// `lower(email)=?`.
+fn describe_condition(condition: &Condition) -> String {
+    let column = condition.left.to_string();
+    let comparison = match condition.comparison {
+        Comparison::Equal => "=?",
+        Comparison::NotEqual => "!=?",
+        Comparison::LessThan => "<?",
+        Comparison::GreaterThan => ">?",
+        Comparison::LessThanOrEqual => "<=?",
+        Comparison::GreaterThanOrEqual => ">=?",
+        Comparison::IsNull => " IS NULL",
+        Comparison::IsNotNull => " IS NOT NULL",
+        Comparison::Like => " LIKE ?",
+        Comparison::In => " IN (...)",
+    };
+    format!("{}{}", column, comparison)
+}
+
+
+/// Add a line to the plan, indented by two spaces for each level of depth.
+fn push(plan: &mut Vec<String>, depth: usize, line: String) {
+    plan.push(format!("{}{}", "  ".repeat(depth), line));
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::btree::*;
+    use crate::exec::*;
+    use crate::explain::*;
+    use crate::testing::*;
+
+    #[test]
+    fn explain_plans() {
+        let mut table = db_open_new(&temp_path("testdb-explain.mysql"));
+        for id in 0..120 {
+            let command = format!("insert {} user{} e{}", id, id % 60, id);
+            assert!(execute_statement(&prepare_statement(&command).unwrap(), &mut table).is_ok());
+        }
+        for command in [
+            "create index u on users(username)",
+            "create view v as select * where id < 10",
+            "create trigger t after delete on users begin \
+             insert into users values (300, 'x', 'y'); end",
+        ] {
+            assert!(execute_statement(&prepare_statement(command).unwrap(), &mut table).is_ok());
+        }
+
+        let plan = |table: &mut Table, command: &str| -> Vec<String> {
+            let statement = prepare_statement(command).unwrap();
+            assert!(statement.explain);
+            explain(&statement, table).unwrap()
+        };
+        for (command, expected) in [
+            ("explain select", vec!["SCAN users"]),
+            (
+                "explain select where username = user7",
+                vec!["SEARCH users USING INDEX u (username=?)"],
+            ),
+            (
+                "explain select where id > 3 and id <= 7 order by id",
+                vec!["SEARCH users USING INTEGER PRIMARY KEY (id>? AND id<=?)"],
+            ),
+            (
+                "explain select username, count(*) where email != e1 group by username",
+                vec!["SCAN users", "GROUP BY username"],
+            ),
+            (
+                "explain select distinct username from v order by username desc",
+                vec![
+                    "MATERIALIZE v",
+                    "  SEARCH users USING INTEGER PRIMARY KEY (id<?)",
+                    "SCAN v",
+                    "SORT BY username DESC",
+                    "DISTINCT",
+                ],
+            ),
+            (
+                "explain with w as (select where id = 1) select from w union select where id in \
+                 (select id from v)",
+                vec![
+                    "MATERIALIZE v",
+                    "  SEARCH users USING INTEGER PRIMARY KEY (id<?)",
+                    "MATERIALIZE w",
+                    "  SEARCH users USING INTEGER PRIMARY KEY (id=?)",
+                    "COMPOUND QUERY",
+                    "  LEFT-MOST SUBQUERY",
+                    "    SCAN w",
+                    "  UNION",
+                    "    SUBQUERY",
+                    "      SCAN v",
+                    "    SCAN users",
+                ],
+            ),
+            ("explain insert 200 a b", vec!["INSERT INTO users"]),
+            (
+                "explain delete 5",
+                vec!["DELETE FROM users USING INTEGER PRIMARY KEY (id=?)", "  FIRE TRIGGER t"],
+            ),
+        ] {
+            assert_eq!(plan(&mut table, command), expected, "{}", command);
+        }
+
+        // Explaining a statement doesn't execute it.
+        let delete = prepare_statement("explain delete 5").unwrap();
+        assert_eq!(execute_statement(&delete, &mut table), Ok(0));
+        let select = prepare_statement("select where id = 5").unwrap();
+        assert_eq!(query_rows(&select, &mut table).unwrap().len(), 1);
+
+        for (command, message) in [
+            ("explain", "expected statement, found end of statement at column 8"),
+            (
+                "explain begin",
+                "expected SELECT, INSERT, COPY or DELETE statement, found 'begin' at column 9",
+            ),
+            (
+                "explain explain select",
+                "expected SELECT, INSERT, COPY or DELETE statement, found 'explain' at column 9",
+            ),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn explain_analyze() {
+        let path = &temp_path("testdb-explain-analyze.mysql");
+        let mut table = db_open_new(path);
+        for id in 0..120 {
+            assert!(run(&mut table, &format!("insert {} user{} e{}", id, id % 60, id)).is_ok());
+        }
+        for command in [
+            "create index u on users(username)",
+            "create trigger t after insert on users begin delete 0; end",
+        ] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        drop(table);
+
+        // Each step says how many rows it produced, and the counts of pages of the steps that are
+        // part of it are included in its own.
+        let mut table = db_open(path).unwrap();
+        let steps = |table: &mut Table, command: &str| -> Vec<(String, usize, u64, u64)> {
+            let statement = prepare_statement(command).unwrap();
+            explain(&statement, table).unwrap().iter().map(|line| {
+                let (step, counts) = line.split_once(" (rows=").unwrap();
+                let counts: Vec<&str> = counts.split(", ").collect();
+                assert!(counts[3].starts_with("time="), "{}", line);
+                let count = |i: usize| counts[i].rsplit('=').next().unwrap().to_string();
+                let (rows, pages_read, cache_hits) = (count(0), count(1), count(2));
+                (
+                    String::from(step),
+                    rows.parse().unwrap(),
+                    pages_read.parse().unwrap(),
+                    cache_hits.parse().unwrap(),
+                )
+            }).collect()
+        };
+        let names_and_rows = |steps: &[(String, usize, u64, u64)]| -> Vec<(String, usize)> {
+            steps.iter().map(|(step, rows, _, _)| (step.clone(), *rows)).collect()
+        };
+        let plan = steps(
+            &mut table,
+            "explain analyze select username, count(*) where username = user7 and email != x \
+             group by username",
+        );
+        assert_eq!(
+            names_and_rows(&plan),
+            vec![
+                (String::from("SEARCH users USING INDEX u (username=?)"), 2),
+                (String::from("GROUP BY username"), 1),
+            ]
+        );
+        // Nothing was cached when the database was opened.
+        assert!(plan[0].2 > 0);
+
+        let plan = steps(
+            &mut table,
+            "explain analyze select where id > 100 union all select where email = e5",
+        );
+        assert_eq!(
+            names_and_rows(&plan),
+            vec![
+                (String::from("COMPOUND QUERY"), 20),
+                (String::from("  LEFT-MOST SUBQUERY"), 19),
+                (String::from("    SEARCH users USING INTEGER PRIMARY KEY (id>?)"), 19),
+                (String::from("  UNION ALL"), 1),
+                (String::from("    SCAN users"), 1),
+            ]
+        );
+        assert!(plan[0].2 >= plan[1].2 + plan[3].2 && plan[0].3 >= plan[1].3 + plan[3].3);
+
+        // The statement is executed, so an INSERT inserts its rows and fires triggers.
+        assert_eq!(
+            names_and_rows(&steps(&mut table, "explain analyze insert 200 a b")),
+            vec![(String::from("INSERT INTO users"), 1), (String::from("  FIRE TRIGGER t"), 1)]
+        );
+        assert_eq!(run(&mut table, "explain analyze insert 201 a b"), Ok(1));
+        let select = prepare_statement("select where id < 10 or id > 100").unwrap();
+        assert_eq!(query_rows(&select, &mut table).unwrap().len(), 9 + 19 + 2);
+
+        assert_eq!(
+            prepare_statement("explain analyze").unwrap_err().to_string(),
+            "expected statement, found end of statement at column 16"
+        );
+    }
+}
@@ -0,0 +1,543 @@
+//! Functions for C programs, and others that call C functions, such as Python through ctypes, to
+//! use the engine through, modelled on SQLite's. They are declared in include/mydb.h, which
+//! cbindgen generates from this file with the settings in cbindgen.toml.
+//!
+//! A program opens a connection with `mydb_open`, prepares a statement on it with `mydb_prepare`,
+//! and steps through its results with `mydb_step`, reading each one's values with the
+//! `mydb_column_*` functions, before freeing it with `mydb_finalize` and closing the connection
+//! with `mydb_close`. Every pointer that is passed in must be valid, and strings must end with a
+//! NUL byte; a handle that is null is an error, but one that was already freed can't be told from
+//! a valid one.
+//!
+//! A panic in the engine must not unwind into the C program, so each function catches it. One
+//! that returns a result code returns `MYDB_ERROR` instead, with the panic's message as the
+//! connection's error, and the others return what they would for a missing value.
+
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::vec;
+
+use crate::error::Error;
+use crate::exec::{Database, parse_command};
+use crate::sql::{FromValue, Row, Statement, Value};
+
+
+// The result codes, which are the same as SQLite's.
+pub const MYDB_OK: c_int = 0;
+pub const MYDB_ERROR: c_int = 1;
+pub const MYDB_BUSY: c_int = 5;
+pub const MYDB_READONLY: c_int = 8;
+pub const MYDB_IOERR: c_int = 10;
+pub const MYDB_CORRUPT: c_int = 11;
+pub const MYDB_FULL: c_int = 13;
+pub const MYDB_CONSTRAINT: c_int = 19;
+pub const MYDB_MISUSE: c_int = 21;
+pub const MYDB_ROW: c_int = 100;
+pub const MYDB_DONE: c_int = 101;
+
+// The types of values, also as in SQLite.
+pub const MYDB_INTEGER: c_int = 1;
+pub const MYDB_FLOAT: c_int = 2;
+pub const MYDB_TEXT: c_int = 3;
+pub const MYDB_NULL: c_int = 5;
+
+
+/// A connection, which is `mydb` in C. If it couldn't be opened, it only has the error, so that
+/// the program can ask what went wrong before closing it.
+pub struct Connection {
+    pub(crate) database: Option<Database>,
+    // The message of the most recent error, if the most recent call failed.
+    error: Option<CString>,
+    // The number of statements prepared on the connection that haven't been finalized, which
+    // keep it from being closed.
+    num_statements: usize,
+}
+
+
+/// A prepared statement, which is `mydb_stmt` in C.
+pub struct PreparedStatement {
+    connection: *mut Connection,
+    statement: Statement,
+    columns: Vec<CString>,
+    // The results that haven't been stepped to yet. None until the statement is first stepped.
+    rows: Option<vec::IntoIter<Row>>,
+    // The result that was stepped to most recently, and the text of each of its values that has
+    // been asked for, which is kept until the next step.
+    row: Option<Row>,
+    texts: Vec<Option<CString>>,
+}
+
+
+impl Connection {
+    /// Record the error as the most recent one, and return its result code.
+    fn fail(&mut self, error: Error) -> c_int {
+        let code = error_code(&error);
+        // A message can't have a NUL byte in it, so one that somehow does is cut short there.
+        let message = error.to_string();
+        let message = message.split('\0').next().unwrap_or_default();
+        self.error = Some(CString::new(message).unwrap());
+        code
+    }
+
+    /// Record a panic's message as the most recent error, and return `MYDB_ERROR`.
+    fn fail_with_panic(&mut self, message: &str) -> c_int {
+        self.error = Some(CString::new(message.replace('\0', "")).unwrap());
+        MYDB_ERROR
+    }
+}
+
+
+impl PreparedStatement {
+    /// Return the value of the current result in the given column, if there is one.
+    fn value(&self, column: c_int) -> Option<&Value> {
+        let row = self.row.as_ref()?;
+        usize::try_from(column).ok().and_then(|column| row.values.get(column))
+    }
+}
+
+
+/// Return the result code for an error.
+fn error_code(error: &Error) -> c_int {
+    match error {
+        Error::Io(_) => MYDB_IOERR,
+        Error::Parse(_) | Error::Invalid(_) | Error::Conversion(_) => MYDB_ERROR,
+        Error::Constraint(_) => MYDB_CONSTRAINT,
+        Error::Corrupt(_) => MYDB_CORRUPT,
+        Error::Full(_) => MYDB_FULL,
+        Error::Locked => MYDB_BUSY,
+        Error::ReadOnly => MYDB_READONLY,
+    }
+}
+
+
+/// Call the function, catching a panic so that it doesn't unwind into the C program. Return the
+/// panic's message if it panicked.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => payload.downcast_ref::<String>().cloned().unwrap_or_default(),
+        };
+        format!("internal error: {}", message)
+    })
+}
+
+
+/// Call a function that returns a result code, and if it panics, record that as the connection's
+/// error, if there is a connection, and return `MYDB_ERROR`.
+unsafe fn catch_panic_code(db: *mut Connection, f: impl FnOnce() -> c_int) -> c_int {
+    catch_panic(f).unwrap_or_else(|message| match db.as_mut() {
+        Some(connection) => connection.fail_with_panic(&message),
+        None => MYDB_ERROR,
+    })
+}
+
+
+/// Convert the names of the columns of a statement's results to C strings, leaving out any NUL
+/// bytes in them.
+fn column_names(names: &[String]) -> Vec<CString> {
+    names.iter().map(|name| CString::new(name.replace('\0', "")).unwrap()).collect()
+}
+
+
+/// Return the string that a C string points to, or None if it's null or isn't UTF-8.
+unsafe fn c_str<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        None
+    } else {
+        CStr::from_ptr(string).to_str().ok()
+    }
+}
+
+
+/// Open the database at the given path, as `Database::open` does, and point `db` at the
+/// connection. It is pointed at one even if opening fails, so that `mydb_errmsg` can say why, and
+/// it must be closed either way.
+///
+/// # Safety
+///
+/// `path` must be a C string, and `db` must point to where a pointer can be written.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_open(path: *const c_char, db: *mut *mut Connection) -> c_int {
+    if db.is_null() {
+        return MYDB_MISUSE;
+    }
+    let mut connection = Connection { database: None, error: None, num_statements: 0 };
+    let result = catch_panic(|| match c_str(path) {
+        Some(path) => Database::open(path),
+        None => Err(Error::Invalid("the path must be a UTF-8 string")),
+    });
+    let code = match result {
+        Ok(Ok(database)) => {
+            connection.database = Some(database);
+            MYDB_OK
+        },
+        Ok(Err(e)) => connection.fail(e),
+        Err(message) => connection.fail_with_panic(&message),
+    };
+    *db = Box::into_raw(Box::new(connection));
+    code
+}
+
+
+/// Close a connection, unless it has statements that haven't been finalized yet, in which case
+/// `MYDB_BUSY` is returned and it stays open. Closing a null connection does nothing.
+///
+/// # Safety
+///
+/// `db` must be null or a connection from `mydb_open` that hasn't been closed.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_close(db: *mut Connection) -> c_int {
+    if db.is_null() {
+        return MYDB_OK;
+    }
+    if (*db).num_statements > 0 {
+        (*db).fail(Error::Invalid("the connection has statements that aren't finalized"));
+        return MYDB_BUSY;
+    }
+    // The connection is freed even if closing its database panics, since it can't be used again.
+    let connection = Box::from_raw(db);
+    catch_panic(|| drop(connection)).map_or(MYDB_ERROR, |()| MYDB_OK)
+}
+
+
+/// Return the message of the connection's most recent error, which is valid until the next call
+/// on the connection.
+///
+/// # Safety
+///
+/// `db` must be a connection from `mydb_open` that hasn't been closed.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_errmsg(db: *mut Connection) -> *const c_char {
+    const NOT_AN_ERROR: &[u8] = b"not an error\0";
+    let message = catch_panic(|| {
+        match db.as_ref().and_then(|connection| connection.error.as_ref()) {
+            Some(message) => message.as_ptr(),
+            None => NOT_AN_ERROR.as_ptr() as *const c_char,
+        }
+    });
+    message.unwrap_or(ptr::null())
+}
+
+
+/// Parse a statement, and point `stmt` at it, ready to be stepped. If it can't be parsed, `stmt`
+/// is pointed at null.
+///
+/// # Safety
+///
+/// `db` must be a connection from `mydb_open` that hasn't been closed, `sql` must be a C string,
+/// and `stmt` must point to where a pointer can be written.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_prepare(
+    db: *mut Connection,
+    sql: *const c_char,
+    stmt: *mut *mut PreparedStatement,
+) -> c_int {
+    let connection = match db.as_mut() {
+        Some(connection) if !stmt.is_null() => connection,
+        _ => return MYDB_MISUSE,
+    };
+    *stmt = ptr::null_mut();
+    connection.error = None;
+    if connection.database.is_none() {
+        return connection.fail(Error::Invalid("the database could not be opened"));
+    }
+    catch_panic_code(db, || {
+        let statement = match c_str(sql) {
+            Some(sql) => parse_command(sql.trim()),
+            None => Err(Error::Invalid("the statement must be a UTF-8 string")),
+        };
+        let statement = match statement {
+            Ok(statement) => statement,
+            Err(e) => return connection.fail(e),
+        };
+
+        let columns = column_names(&statement.result_columns());
+        connection.num_statements += 1;
+        *stmt = Box::into_raw(Box::new(PreparedStatement {
+            connection: db,
+            statement,
+            columns,
+            rows: None,
+            row: None,
+            texts: Vec::new(),
+        }));
+        MYDB_OK
+    })
+}
+
+
+/// Step to the statement's next result, and return `MYDB_ROW` if there is one, or `MYDB_DONE` if
+/// there isn't. The statement is executed when it's first stepped, and its results are all read
+/// then. They are the rows that the shell would print: those of a SELECT statement or a RETURNING
+/// clause, the lines of an EXPLAIN statement's plan, or the rows of a PRAGMA statement.
+///
+/// # Safety
+///
+/// `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_step(stmt: *mut PreparedStatement) -> c_int {
+    let prepared = match stmt.as_mut() {
+        Some(prepared) => prepared,
+        None => return MYDB_MISUSE,
+    };
+    let db = prepared.connection;
+    let connection = &mut *db;
+    connection.error = None;
+    catch_panic_code(db, || {
+        if prepared.rows.is_none() {
+            let database = connection.database.as_ref().unwrap();
+            let rows = match database.execute_statement_with_rows(&prepared.statement) {
+                Ok((_, rows)) => rows,
+                Err(e) => return connection.fail(e),
+            };
+            // Only a SELECT statement's columns are known before it is executed, so those of
+            // other statements' results are taken from them.
+            if let Some(columns) = rows.first().and_then(|row| row.columns.as_ref()) {
+                prepared.columns = column_names(columns);
+            }
+            prepared.rows = Some(rows.into_iter());
+        }
+
+        prepared.row = prepared.rows.as_mut().unwrap().next();
+        prepared.texts.clear();
+        match &prepared.row {
+            Some(row) => {
+                prepared.texts.resize(row.values.len(), None);
+                MYDB_ROW
+            },
+            None => MYDB_DONE,
+        }
+    })
+}
+
+
+/// Return the number of columns in the statement's results. That is 0 for a statement other than
+/// SELECT until it is stepped, and for one that has no results.
+///
+/// # Safety
+///
+/// `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_column_count(stmt: *mut PreparedStatement) -> c_int {
+    catch_panic(|| stmt.as_ref().map_or(0, |prepared| prepared.columns.len() as c_int))
+        .unwrap_or(0)
+}
+
+
+/// Return the name of a column of the statement's results, or null if there is no such column.
+/// It is valid until the statement is finalized.
+///
+/// # Safety
+///
+/// `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_column_name(
+    stmt: *mut PreparedStatement,
+    column: c_int,
+) -> *const c_char {
+    let name = catch_panic(|| {
+        let name = stmt.as_ref().and_then(|prepared| {
+            usize::try_from(column).ok().and_then(|column| prepared.columns.get(column))
+        });
+        name.map_or(ptr::null(), |name| name.as_ptr())
+    });
+    name.unwrap_or(ptr::null())
+}
+
+
+/// Return the type of the current result's value in a column, which is `MYDB_NULL` if there is
+/// no such value.
+///
+/// # Safety
+///
+/// `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_column_type(stmt: *mut PreparedStatement, column: c_int) -> c_int {
+    let column_type = catch_panic(|| {
+        match stmt.as_ref().and_then(|prepared| prepared.value(column)) {
+            Some(Value::Integer(_)) => MYDB_INTEGER,
+            Some(Value::Real(_)) => MYDB_FLOAT,
+            Some(Value::Text(_)) => MYDB_TEXT,
+            Some(Value::Null) | None => MYDB_NULL,
+        }
+    });
+    column_type.unwrap_or(MYDB_NULL)
+}
+
+
+/// Return the current result's value in a column as an integer, which is 0 if it isn't a number.
+/// A real number is truncated towards zero.
+///
+/// # Safety
+///
+/// `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_column_int64(stmt: *mut PreparedStatement, column: c_int) -> i64 {
+    let n = catch_panic(|| {
+        match stmt.as_ref().and_then(|prepared| prepared.value(column)) {
+            Some(Value::Integer(n)) => *n,
+            Some(Value::Real(x)) => *x as i64,
+            _ => 0,
+        }
+    });
+    n.unwrap_or(0)
+}
+
+
+/// Return the current result's value in a column as a real number, which is 0 if it isn't a
+/// number.
+///
+/// # Safety
+///
+/// `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_column_double(stmt: *mut PreparedStatement, column: c_int) -> f64 {
+    let x = catch_panic(|| {
+        stmt.as_ref()
+            .and_then(|prepared| prepared.value(column))
+            .and_then(f64::from_value)
+            .unwrap_or(0.0)
+    });
+    x.unwrap_or(0.0)
+}
+
+
+/// Return the current result's value in a column as text, or null if it is NULL. A number is
+/// given in decimal. The text is valid until the statement is stepped again or finalized.
+///
+/// # Safety
+///
+/// `stmt` must be a statement from `mydb_prepare` that hasn't been finalized.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_column_text(
+    stmt: *mut PreparedStatement,
+    column: c_int,
+) -> *const c_char {
+    let prepared = match stmt.as_mut() {
+        Some(prepared) => prepared,
+        None => return ptr::null(),
+    };
+    let text = catch_panic(|| {
+        let text = match prepared.value(column) {
+            Some(Value::Integer(n)) => n.to_string(),
+            Some(value @ Value::Real(_)) => format!("{:?}", value),
+            Some(Value::Text(s)) => s.split('\0').next().unwrap_or_default().to_string(),
+            Some(Value::Null) | None => return ptr::null(),
+        };
+        let cached = &mut prepared.texts[column as usize];
+        cached.get_or_insert_with(|| CString::new(text).unwrap()).as_ptr()
+    });
+    text.unwrap_or(ptr::null())
+}
+
+
+/// Free a prepared statement. Finalizing a null statement does nothing.
+///
+/// # Safety
+///
+/// `stmt` must be null or a statement from `mydb_prepare` that hasn't been finalized, on a
+/// connection that hasn't been closed.
+#[no_mangle]
+pub unsafe extern "C" fn mydb_finalize(stmt: *mut PreparedStatement) -> c_int {
+    if stmt.is_null() {
+        return MYDB_OK;
+    }
+    let prepared = Box::from_raw(stmt);
+    (*prepared.connection).num_statements -= 1;
+    catch_panic(|| drop(prepared)).map_or(MYDB_ERROR, |()| MYDB_OK)
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::testing::*;
+
+    #[test]
+    fn ffi() {
+        use crate::ffi::*;
+        use std::ffi::{CStr, CString};
+        use std::ptr;
+
+        let path = &temp_path("testdb-ffi.mysql");
+        drop(db_open_new(path));
+        let path = CString::new(path.as_str()).unwrap();
+        let text = |s: *const std::os::raw::c_char| unsafe { CStr::from_ptr(s) }.to_str().unwrap();
+        unsafe {
+            let mut db = ptr::null_mut();
+            assert_eq!(mydb_open(path.as_ptr(), &mut db), MYDB_OK);
+            assert_eq!(text(mydb_errmsg(db)), "not an error");
+
+            let prepare = |command: &str, stmt: &mut *mut PreparedStatement| {
+                let command = CString::new(command).unwrap();
+                mydb_prepare(db, command.as_ptr(), stmt)
+            };
+            let mut stmt = ptr::null_mut();
+            assert_eq!(prepare("insert into users values (1, 'a', NULL)", &mut stmt), MYDB_OK);
+            assert_eq!(mydb_step(stmt), MYDB_DONE);
+            assert_eq!(mydb_step(stmt), MYDB_DONE);
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+            assert_eq!(prepare("insert into users values (1, 'b', NULL)", &mut stmt), MYDB_OK);
+            assert_eq!(mydb_step(stmt), MYDB_CONSTRAINT);
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+
+            assert_eq!(prepare("select id, username, email from users", &mut stmt), MYDB_OK);
+            assert_eq!(mydb_column_count(stmt), 3);
+            assert_eq!(text(mydb_column_name(stmt, 1)), "username");
+            assert!(mydb_column_name(stmt, 3).is_null());
+            assert_eq!(mydb_step(stmt), MYDB_ROW);
+            assert_eq!(mydb_column_type(stmt, 0), MYDB_INTEGER);
+            assert_eq!(mydb_column_int64(stmt, 0), 1);
+            assert_eq!(text(mydb_column_text(stmt, 0)), "1");
+            assert_eq!(mydb_column_type(stmt, 1), MYDB_TEXT);
+            assert_eq!(text(mydb_column_text(stmt, 1)), "a");
+            assert_eq!(mydb_column_type(stmt, 2), MYDB_NULL);
+            assert!(mydb_column_text(stmt, 2).is_null());
+            // A connection can't be closed while it has a statement.
+            assert_eq!(mydb_close(db), MYDB_BUSY);
+            assert_eq!(mydb_step(stmt), MYDB_DONE);
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+
+            // The results of other statements are stepped through too, and their columns are
+            // known once the first row is.
+            let first_row = |command: &str, stmt: &mut *mut PreparedStatement| {
+                assert_eq!(prepare(command, stmt), MYDB_OK);
+                assert_eq!(mydb_step(*stmt), MYDB_ROW);
+            };
+            first_row("pragma integrity_check", &mut stmt);
+            assert_eq!(mydb_column_count(stmt), 1);
+            assert_eq!(text(mydb_column_text(stmt, 0)), "ok");
+            assert_eq!(mydb_step(stmt), MYDB_DONE);
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+            first_row("pragma table_info(users)", &mut stmt);
+            assert_eq!(mydb_column_count(stmt), 4);
+            assert_eq!(text(mydb_column_name(stmt, 1)), "name");
+            assert_eq!(text(mydb_column_text(stmt, 1)), "id");
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+            first_row("explain select id from users", &mut stmt);
+            assert_eq!(text(mydb_column_name(stmt, 0)), "plan");
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+            first_row("insert into users values (3, 'c', NULL) returning id", &mut stmt);
+            assert_eq!(text(mydb_column_name(stmt, 0)), "id");
+            assert_eq!(mydb_column_int64(stmt, 0), 3);
+            assert_eq!(mydb_step(stmt), MYDB_DONE);
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+
+            assert_eq!(prepare("selec", &mut stmt), MYDB_ERROR);
+            assert!(stmt.is_null());
+            assert_eq!(text(mydb_errmsg(db)), "expected statement, found 'selec' at column 1");
+            assert_eq!(mydb_step(ptr::null_mut()), MYDB_MISUSE);
+
+            // A panic in the engine is an error, rather than unwinding into the caller.
+            (*db).database.as_ref().unwrap().commit_hook(|| panic!("the hook panicked"));
+            assert_eq!(prepare("insert into users values (2, 'b', NULL)", &mut stmt), MYDB_OK);
+            assert_eq!(mydb_step(stmt), MYDB_ERROR);
+            assert_eq!(text(mydb_errmsg(db)), "internal error: the hook panicked");
+            assert_eq!(mydb_finalize(stmt), MYDB_OK);
+            assert_eq!(mydb_close(db), MYDB_OK);
+        }
+    }
+}
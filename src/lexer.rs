@@ -0,0 +1,185 @@
+//! Splitting the text of a statement into tokens, for the parser.
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::error::ParseError;
+
+
+/// A token, along with where it is in the text.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Token {
+    pub(crate) kind: TokenKind,
+    /// The byte offsets of the token in the text, which include any quotes.
+    pub(crate) span: Range<usize>,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    /// A keyword, name or number, e.g. `select`, `users` or `42`, or anything else that isn't
+    /// punctuation or quoted, e.g. the `jdoe@example` in `jdoe@example.com`.
+    Word(String),
+    /// A name in double quotes, which is never taken as a keyword, without the quotes.
+    QuotedName(String),
+    /// Text in single quotes, without the quotes, and with each pair of quotes in it, which stands
+    /// for one quote, made into one.
+    Text(String),
+    Symbol(&'static str),
+}
+
+
+/// The tokens that are made of punctuation, and so don't have to be separated from the ones
+/// around them by spaces. Two-character symbols come first, so that e.g. `<=` isn't taken as `<`.
+const SYMBOLS: [&str; 16] =
+    ["||", "!=", "<=", ">=", "(", ")", ",", ".", ";", "+", "-", "*", "/", "=", "<", ">"];
+
+
+impl Token {
+    /// Return whether the token is the given keyword or symbol, ignoring case.
+    pub(crate) fn is(&self, expected: &str) -> bool {
+        match &self.kind {
+            TokenKind::Word(word) => word.eq_ignore_ascii_case(expected),
+            TokenKind::Symbol(symbol) => *symbol == expected,
+            TokenKind::QuotedName(_) | TokenKind::Text(_) => false,
+        }
+    }
+
+    /// Return the name that the token stands for if it is a word or a quoted name.
+    pub(crate) fn name(&self) -> Option<&str> {
+        match &self.kind {
+            TokenKind::Word(name) | TokenKind::QuotedName(name) => Some(name),
+            TokenKind::Text(_) | TokenKind::Symbol(_) => None,
+        }
+    }
+
+    /// Return whether the token comes right after the other one in the text, with no space in
+    /// between.
+    pub(crate) fn follows(&self, other: &Token) -> bool {
+        self.span.start == other.span.end
+    }
+}
+
+
+// How the token is shown in error messages. Words and symbols are shown in single quotes, like
+// text, since that is how they would be written in English.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            TokenKind::Word(word) => write!(f, "'{}'", word),
+            TokenKind::QuotedName(name) => write!(f, "\"{}\"", name.replace('"', "\"\"")),
+            TokenKind::Text(text) => write!(f, "'{}'", text.replace('\'', "''")),
+            TokenKind::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+
+/// Return the text that the tokens stand for, with a space between any two that weren't next to
+/// each other.
+pub(crate) fn tokens_text(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && !token.follows(&tokens[i - 1]) {
+            text.push(' ');
+        }
+        match &token.kind {
+            TokenKind::Word(word) => text.push_str(word),
+            TokenKind::Symbol(symbol) => text.push_str(symbol),
+            // These are quoted as in the text, which is also how errors show them.
+            TokenKind::QuotedName(_) | TokenKind::Text(_) => text.push_str(&token.to_string()),
+        }
+    }
+    text
+}
+
+
+/// Split the text of a statement into tokens, or return an error if a quote is never closed.
+pub(crate) fn tokenize(text: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while let Some(c) = text[start..].chars().next() {
+        let rest = &text[start..];
+        if c.is_whitespace() {
+            start += c.len_utf8();
+            continue;
+        }
+
+        let (kind, len) = if c == '\'' || c == '"' {
+            let (contents, len) = quoted(rest, c).ok_or_else(|| {
+                let what = if c == '\'' { "text" } else { "name" };
+                ParseError::new(format!("unterminated quoted {}", what), start..text.len())
+            })?;
+            let kind = if c == '\'' {
+                TokenKind::Text(contents)
+            } else {
+                TokenKind::QuotedName(contents)
+            };
+            (kind, len)
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            (TokenKind::Symbol(symbol), symbol.len())
+        } else {
+            let len = rest.char_indices()
+                .find(|&(i, c)| {
+                    is_word_end(c, &rest[i..]) && !continues_number(&rest[..i], &rest[i..])
+                })
+                .map_or(rest.len(), |(i, _)| i);
+            // There is no BLOB type, since no column of the table could hold one, so a literal
+            // like x'00ff' is an error rather than a name followed by text.
+            if rest[..len].eq_ignore_ascii_case("x") && rest[len..].starts_with('\'') {
+                let end = quoted(&rest[len..], '\'').map_or(text.len(), |(_, n)| start + len + n);
+                let message = String::from("BLOB literals are not supported");
+                return Err(ParseError::new(message, start..end));
+            }
+            (TokenKind::Word(String::from(&rest[..len])), len)
+        };
+        tokens.push(Token { kind, span: start..start + len });
+        start += len;
+    }
+    Ok(tokens)
+}
+
+
+/// Return the contents of the quoted token at the start of the text, which starts with the given
+/// quote, and the length of the token. A quote is doubled to put it in the contents.
+fn quoted(text: &str, quote: char) -> Option<(String, usize)> {
+    let mut contents = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != quote {
+            contents.push(c);
+        } else if chars.peek().is_some_and(|&(_, next)| next == quote) {
+            contents.push(quote);
+            chars.next();
+        } else {
+            return Some((contents, i + 1));
+        }
+    }
+    None
+}
+
+
+/// Return whether a word ends at the start of the text, whose first character is given.
+fn is_word_end(c: char, text: &str) -> bool {
+    c.is_whitespace() || c == '\'' || c == '"' || SYMBOLS.iter().any(|s| text.starts_with(s))
+}
+
+
+/// Return whether the text continues the number before it, despite starting with punctuation, as
+/// the `.5` in `1.5` and the `-3` in `2e-3` do.
+fn continues_number(number: &str, text: &str) -> bool {
+    let mut chars = text.chars();
+    let (c, next) = (chars.next(), chars.next());
+    let is_digit = |c: char| c.is_ascii_digit();
+    if !number.starts_with(is_digit) || !next.is_some_and(is_digit) {
+        return false;
+    }
+    match c {
+        Some('.') => number.chars().all(is_digit),
+        Some('+') | Some('-') => {
+            let mantissa = number.strip_suffix(['e', 'E']).unwrap_or("");
+            !mantissa.is_empty() && mantissa.chars().all(|c| c.is_ascii_digit() || c == '.')
+        },
+        _ => false,
+    }
+}
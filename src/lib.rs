@@ -0,0 +1,57 @@
+/*!
+ * A basic SQLite clone in Rust.
+ *
+ * Based on "Let's Build a Simple Database" tutorial by cstack.
+ * https://cstack.github.io/db_tutorial/
+ *
+ * The main data structure is a Table, which is a B-tree whose nodes are fixed-size (4096 byte)
+ * pages of binary data. Rows are stored as cells in the leaf nodes, keyed by their id. Each index
+ * on the table is another B-tree in the same file, and the first page of the file is a header
+ * that says where they are.
+ *
+ * The `lexer` module splits statements into tokens, `parser` parses them into the `Statement`s
+ * and expressions that `sql` defines, `exec` executes them on a `Table`, whose B-trees are in
+ * `btree`, and `pager` reads and writes their pages through a `Vfs`, which is usually a file and
+ * uses `os` for what differs between operating systems. Programs use the engine through
+ * `Database`, which is what the shell in main.rs does, and everything that can fail returns an
+ * `Error`.
+ * `explain` describes how a statement would be executed, for EXPLAIN, and what executing it did,
+ * for EXPLAIN ANALYZE, and `csv` reads the files of rows that COPY loads.
+ * `schema` describes the tables to programs that inspect them, and with the `serde` feature,
+ * `row_serde` maps rows to and from a program's own structs. With the `tokio` feature, async
+ * programs use the engine through an `AsyncDatabase` instead, and C programs through the
+ * functions in `ffi`. With the `tracing` feature, what the engine does is logged through the
+ * macros in `log`.
+ *
+ * Author:  Ian Fisher (iafisher@protonmail.com)
+ * Version: May 2019
+ */
+#[cfg(feature = "tokio")]
+mod async_database;
+mod btree;
+mod csv;
+mod error;
+mod exec;
+mod explain;
+mod ffi;
+mod lexer;
+mod log;
+mod os;
+mod pager;
+mod parser;
+#[cfg(feature = "serde")]
+mod row_serde;
+mod schema;
+mod sql;
+#[cfg(test)]
+mod testing;
+
+#[cfg(feature = "tokio")]
+pub use crate::async_database::{AsyncDatabase, RowStream};
+pub use crate::error::{Error, ParseError, Result};
+pub use crate::exec::{
+    Action, Database, MetaCommandResult, OpenOptions, Pool, PooledConnection, Transaction,
+};
+pub use crate::pager::{LockLevel, Vfs};
+pub use crate::schema::{ColumnInfo, IndexInfo};
+pub use crate::sql::{parse, Ast, ColumnType, FromValue, Row, Statement, Value};
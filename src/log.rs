@@ -0,0 +1,100 @@
+//! Logging what the engine does, such as which pages it reads and writes and how long statements
+//! take, through the `tracing` crate when the `tracing` feature is on. The macros take the same
+//! arguments as tracing's, and without the feature they expand to nothing, so an argument must
+//! not be computed only to be logged.
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($arg:tt)*) => { tracing::trace!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($arg:tt)*) => { tracing::debug!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {};
+}
+
+
+pub(crate) use {debug, trace};
+
+
+#[cfg(all(test, feature = "tracing"))]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::exec::*;
+    use crate::testing::*;
+
+    #[test]
+    fn tracing() {
+        use std::fmt;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        // A subscriber that keeps the message of every event.
+        struct Messages(Arc<Mutex<Vec<String>>>);
+
+        impl Visit for &Messages {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0.lock().unwrap().push(format!("{:?}", value));
+                }
+            }
+        }
+
+        impl tracing::Subscriber for Messages {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, _span: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                event.record(&mut &*self);
+            }
+
+            fn enter(&self, _span: &Id) {}
+
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let path = &temp_path("testdb-tracing.mysql");
+        drop(db_open_new(path));
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Messages(Arc::clone(&messages)), || {
+            let database = Database::open(path).unwrap();
+            assert!(database.execute("insert into users values (1, 'a', 'a@x')").is_ok());
+            assert!(database.query("select * from users").is_ok());
+        });
+
+        let messages = messages.lock().unwrap();
+        for message in [
+            "took lock",
+            "page cache hit",
+            "began transaction",
+            "writing changes",
+            "wrote page to file",
+            "committed transaction",
+            "executed statement",
+        ] {
+            assert!(messages.iter().any(|m| m == message), "{}", message);
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! The parts of using the database file that differ between operating systems: locking bytes of
+//! it, mapping it into memory, and telling whether it is still the file at its path. Each system
+//! has its own `FileLocks`, `Mmap` and `is_same_file`, which the pager uses the same way. The
+//! system calls are made through the `libc` crate on Unix and `windows-sys` on Windows.
+
+pub(crate) use self::platform::{FileLocks, Mmap, is_same_file};
+
+
+/// A lock on a byte of a file. Any number of open files can have a read lock on a byte, but only
+/// one can have a write lock on it, and only if no other has a read lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ByteLock {
+    Unlocked,
+    Read,
+    Write,
+}
+
+
+#[cfg(unix)]
+mod platform {
+    use std::fs;
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::raw::{c_int, c_void};
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    use super::ByteLock;
+
+    // Open file description locks belong to an open file rather than to the process, so two
+    // connections to a database in one process lock each other out too. Other systems only have
+    // locks that belong to the process.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SET_LOCK: c_int = libc::F_OFD_SETLK;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SET_LOCK: c_int = libc::F_SETLK;
+
+
+    /// Take a read or write lock on the given bytes of the file, or release the lock on them, and
+    /// return whether it was done.
+    fn set_lock(file: &File, lock: ByteLock, start: i64, len: i64) -> io::Result<bool> {
+        // The other fields of the structure differ between systems, and are all zero.
+        let mut argument: libc::flock = unsafe { mem::zeroed() };
+        argument.l_type = match lock {
+            ByteLock::Unlocked => libc::F_UNLCK,
+            ByteLock::Read => libc::F_RDLCK,
+            ByteLock::Write => libc::F_WRLCK,
+        } as _;
+        argument.l_whence = libc::SEEK_SET as _;
+        argument.l_start = start as libc::off_t;
+        argument.l_len = len as libc::off_t;
+        if unsafe { libc::fcntl(file.as_raw_fd(), SET_LOCK, &mut argument) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::PermissionDenied => Ok(false),
+            _ => Err(error),
+        }
+    }
+
+
+    /// The locks that an open file has on bytes of it.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) struct FileLocks;
+
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    impl FileLocks {
+        pub(crate) fn new(_file: &File) -> io::Result<Self> {
+            Ok(FileLocks)
+        }
+
+        /// Lock the given bytes of the file, or release the lock on them, and return whether it
+        /// was done. A lock can't be taken if another open file has one in its way.
+        pub(crate) fn lock(
+            &mut self,
+            file: &File,
+            lock: ByteLock,
+            start: i64,
+            len: i64,
+        ) -> io::Result<bool> {
+            set_lock(file, lock, start, len)
+        }
+    }
+
+
+    /// A lock that an open file in this process has on a byte of a file, which is identified by
+    /// its device and inode.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    struct HeldLock {
+        file: (u64, u64),
+        offset: i64,
+        owner: u64,
+        lock: ByteLock,
+    }
+
+
+    // A lock that belongs to the process can't keep its other connections out, so the locks that
+    // each of them has are kept here, and the process's lock on each byte is the strongest of
+    // theirs. As for any lock that belongs to the process, closing any file that it has open on
+    // the database releases all of them.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    static HELD_LOCKS: std::sync::Mutex<Vec<HeldLock>> = std::sync::Mutex::new(Vec::new());
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    static NEXT_OWNER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+
+    /// The locks that an open file has on bytes of it.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) struct FileLocks {
+        file: (u64, u64),
+        owner: u64,
+    }
+
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    impl FileLocks {
+        pub(crate) fn new(file: &File) -> io::Result<Self> {
+            let metadata = file.metadata()?;
+            let owner = NEXT_OWNER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(FileLocks { file: (metadata.dev(), metadata.ino()), owner })
+        }
+
+        /// Lock the given bytes of the file, or release the lock on them, and return whether it
+        /// was done. A lock can't be taken if another open file has one in its way.
+        pub(crate) fn lock(
+            &mut self,
+            file: &File,
+            lock: ByteLock,
+            start: i64,
+            len: i64,
+        ) -> io::Result<bool> {
+            let mut held = HELD_LOCKS.lock().unwrap();
+            for offset in start..start+len {
+                let others: Vec<ByteLock> = held.iter()
+                    .filter(|other| other.file == self.file && other.offset == offset)
+                    .filter(|other| other.owner != self.owner)
+                    .map(|other| other.lock)
+                    .collect();
+                let is_blocked = match lock {
+                    ByteLock::Unlocked => false,
+                    ByteLock::Read => others.contains(&ByteLock::Write),
+                    ByteLock::Write => !others.is_empty(),
+                };
+                let strongest = others.into_iter().chain([lock]).max().unwrap();
+                if is_blocked || !set_lock(file, strongest, offset, 1)? {
+                    return Ok(false);
+                }
+
+                held.retain(|other| {
+                    (other.file, other.offset, other.owner) != (self.file, offset, self.owner)
+                });
+                if lock != ByteLock::Unlocked {
+                    held.push(HeldLock { file: self.file, offset, owner: self.owner, lock });
+                }
+            }
+            Ok(true)
+        }
+    }
+
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    impl Drop for FileLocks {
+        fn drop(&mut self) {
+            HELD_LOCKS.lock().unwrap().retain(|other| other.owner != self.owner);
+        }
+    }
+
+
+    /// A read-only mapping of the start of the database file into memory. Pages are only ever
+    /// written with write(), which is visible through the mapping, so that writes still happen in
+    /// the order that the journal and the log need.
+    pub(crate) struct Mmap {
+        ptr: *mut c_void,
+        pub(crate) len: usize,
+    }
+
+
+    impl Mmap {
+        /// Map the first `len` bytes of the file, or return None if it can't be mapped.
+        pub(crate) fn new(file: &File, len: usize) -> Option<Self> {
+            let fd = file.as_raw_fd();
+            let ptr = unsafe {
+                libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, fd, 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                None
+            } else {
+                Some(Mmap { ptr, len })
+            }
+        }
+
+        pub(crate) fn bytes(&self) -> &[u8] {
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+
+    // The mapping is only ever read, and is unmapped by whichever thread ends up dropping it.
+    // Sending it can't let a remap race a read on another thread: the slices that bytes() returns
+    // borrow the mapping, and it is only replaced or dropped through the pager that owns it,
+    // which takes `&mut`, so no slice can outlive it. It isn't Sync, so two threads never read
+    // through it at once, and a connection shared between threads reaches its pager through a
+    // mutex.
+    unsafe impl Send for Mmap {}
+
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+
+
+    /// Return whether the open file is the one at the given path, which it isn't if the file was
+    /// removed or replaced since it was opened.
+    pub(crate) fn is_same_file(file: &File, path: &str) -> bool {
+        match (file.metadata(), fs::metadata(path)) {
+            (Ok(opened), Ok(named)) => opened.dev() == named.dev() && opened.ino() == named.ino(),
+            _ => false,
+        }
+    }
+}
+
+
+#[cfg(windows)]
+mod platform {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::raw::c_void;
+    use std::os::windows::io::AsRawHandle;
+
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_LOCK_VIOLATION};
+    use windows_sys::Win32::Storage::FileSystem::{
+        BY_HANDLE_FILE_INFORMATION, GetFileInformationByHandle, LOCKFILE_EXCLUSIVE_LOCK,
+        LOCKFILE_FAIL_IMMEDIATELY, LockFileEx, UnlockFileEx,
+    };
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+    use windows_sys::Win32::System::Memory::{
+        CreateFileMappingW, FILE_MAP_READ, MEMORY_MAPPED_VIEW_ADDRESS, MapViewOfFile,
+        PAGE_READONLY, UnmapViewOfFile,
+    };
+
+    use super::ByteLock;
+
+
+    /// The argument to LockFileEx() and UnlockFileEx() that says where the bytes start.
+    fn overlapped_at(offset: i64) -> OVERLAPPED {
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.Anonymous.Anonymous.Offset = offset as u32;
+        overlapped.Anonymous.Anonymous.OffsetHigh = (offset >> 32) as u32;
+        overlapped
+    }
+
+
+    /// The locks that an open file has on bytes of it. Windows can't change a lock from one kind
+    /// to another, or release part of one, so each byte is locked on its own, and which lock it has
+    /// is kept here.
+    pub(crate) struct FileLocks {
+        held: HashMap<i64, ByteLock>,
+    }
+
+
+    impl FileLocks {
+        pub(crate) fn new(_file: &File) -> io::Result<Self> {
+            Ok(FileLocks { held: HashMap::new() })
+        }
+
+        /// Lock the given bytes of the file, or release the lock on them, and return whether it
+        /// was done. A lock can't be taken if another open file has one in its way.
+        pub(crate) fn lock(
+            &mut self,
+            file: &File,
+            lock: ByteLock,
+            start: i64,
+            len: i64,
+        ) -> io::Result<bool> {
+            for offset in start..start+len {
+                let held = self.held.remove(&offset).unwrap_or(ByteLock::Unlocked);
+                if held == lock {
+                    self.held.insert(offset, held);
+                    continue;
+                }
+                // The old lock is released before the new one is taken, and taken again if the
+                // new one can't be.
+                if held != ByteLock::Unlocked {
+                    unlock_byte(file, offset)?;
+                }
+                if lock == ByteLock::Unlocked {
+                    continue;
+                }
+                if !lock_byte(file, lock, offset)? {
+                    if held != ByteLock::Unlocked && lock_byte(file, held, offset)? {
+                        self.held.insert(offset, held);
+                    }
+                    return Ok(false);
+                }
+                self.held.insert(offset, lock);
+            }
+            Ok(true)
+        }
+    }
+
+
+    /// Take a read or write lock on a byte of the file, and return whether it was taken.
+    fn lock_byte(file: &File, lock: ByteLock, offset: i64) -> io::Result<bool> {
+        let mut flags = LOCKFILE_FAIL_IMMEDIATELY;
+        if lock == ByteLock::Write {
+            flags |= LOCKFILE_EXCLUSIVE_LOCK;
+        }
+        let mut overlapped = overlapped_at(offset);
+        if unsafe { LockFileEx(file.as_raw_handle(), flags, 0, 1, 0, &mut overlapped) } != 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+            Ok(false)
+        } else {
+            Err(error)
+        }
+    }
+
+
+    fn unlock_byte(file: &File, offset: i64) -> io::Result<()> {
+        let mut overlapped = overlapped_at(offset);
+        if unsafe { UnlockFileEx(file.as_raw_handle(), 0, 1, 0, &mut overlapped) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+
+    /// A read-only mapping of the start of the database file into memory. Pages are only ever
+    /// written with WriteFile(), which is visible through the mapping, so that writes still happen
+    /// in the order that the journal and the log need.
+    pub(crate) struct Mmap {
+        ptr: *mut c_void,
+        pub(crate) len: usize,
+    }
+
+
+    impl Mmap {
+        /// Map the first `len` bytes of the file, or return None if it can't be mapped.
+        pub(crate) fn new(file: &File, len: usize) -> Option<Self> {
+            let handle = file.as_raw_handle();
+            let mapping = unsafe {
+                CreateFileMappingW(
+                    handle,
+                    std::ptr::null(),
+                    PAGE_READONLY,
+                    0,
+                    0,
+                    std::ptr::null(),
+                )
+            };
+            if mapping.is_null() {
+                return None;
+            }
+            // The view keeps the mapping open until it is unmapped.
+            let ptr = unsafe { MapViewOfFile(mapping, FILE_MAP_READ, 0, 0, len) }.Value;
+            unsafe { CloseHandle(mapping) };
+            if ptr.is_null() {
+                None
+            } else {
+                Some(Mmap { ptr, len })
+            }
+        }
+
+        pub(crate) fn bytes(&self) -> &[u8] {
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+
+    // The mapping is only ever read, and is unmapped by whichever thread ends up dropping it.
+    // Sending it can't let a remap race a read on another thread: the slices that bytes() returns
+    // borrow the mapping, and it is only replaced or dropped through the pager that owns it,
+    // which takes `&mut`, so no slice can outlive it. It isn't Sync, so two threads never read
+    // through it at once, and a connection shared between threads reaches its pager through a
+    // mutex.
+    unsafe impl Send for Mmap {}
+
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe { UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: self.ptr }) };
+        }
+    }
+
+
+    fn file_information(file: &File) -> Option<BY_HANDLE_FILE_INFORMATION> {
+        let mut information: BY_HANDLE_FILE_INFORMATION = unsafe { mem::zeroed() };
+        if unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut information) } == 0 {
+            None
+        } else {
+            Some(information)
+        }
+    }
+
+
+    /// Return whether the open file is the one at the given path, which it isn't if the file was
+    /// removed or replaced since it was opened.
+    pub(crate) fn is_same_file(file: &File, path: &str) -> bool {
+        let named = match File::open(path) {
+            Ok(named) => named,
+            Err(_) => return false,
+        };
+        match (file_information(file), file_information(&named)) {
+            (Some(opened), Some(named)) => {
+                (opened.dwVolumeSerialNumber, opened.nFileIndexHigh, opened.nFileIndexLow)
+                    == (named.dwVolumeSerialNumber, named.nFileIndexHigh, named.nFileIndexLow)
+            },
+            _ => false,
+        }
+    }
+}
+
+
+// Without an operating system, e.g. in a browser, there are no files to lock or map, and no other
+// processes to keep out. Databases there are kept in memory.
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use std::fs::File;
+    use std::io;
+
+    use super::ByteLock;
+
+
+    /// The locks that an open file has on bytes of it.
+    pub(crate) struct FileLocks;
+
+
+    impl FileLocks {
+        pub(crate) fn new(_file: &File) -> io::Result<Self> {
+            Ok(FileLocks)
+        }
+
+        pub(crate) fn lock(
+            &mut self,
+            _file: &File,
+            _lock: ByteLock,
+            _start: i64,
+            _len: i64,
+        ) -> io::Result<bool> {
+            Ok(true)
+        }
+    }
+
+
+    /// A mapping of the database file into memory, which is never made.
+    pub(crate) struct Mmap {
+        pub(crate) len: usize,
+    }
+
+
+    impl Mmap {
+        pub(crate) fn new(_file: &File, _len: usize) -> Option<Self> {
+            None
+        }
+
+        pub(crate) fn bytes(&self) -> &[u8] {
+            &[]
+        }
+    }
+
+
+    pub(crate) fn is_same_file(_file: &File, _path: &str) -> bool {
+        true
+    }
+}
@@ -0,0 +1,2442 @@
+//! Reading and writing the pages of the database file, through its cache, rollback journal or
+//! write-ahead log, and the locks that keep connections from getting in each other's way.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::mem;
+use std::path::PathBuf;
+use std::str;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::btree::{
+    FREE_PAGE_NEXT_OFFSET, FREE_PAGE_TYPE, Index, IndexStats, NODE_TYPE_OFFSET, Stats, Trigger,
+    View,
+};
+use crate::error::Error;
+use crate::exec::Action;
+use crate::log::{debug, trace};
+use crate::os::{ByteLock, FileLocks, Mmap};
+
+
+pub(crate) const PAGE_SIZE: usize = 4096;  // Equivalent to virtual memory page size on many OSes.
+
+// The last bytes of every page, whether it is the header or a node, are a checksum of the rest of
+// the page. It is set when the page is written to disk.
+const PAGE_CHECKSUM_SIZE: usize = 4;
+pub(crate) const PAGE_CHECKSUM_OFFSET: usize = PAGE_SIZE - PAGE_CHECKSUM_SIZE;
+
+// The first page of the database file is a header rather than a node. The table's B-tree is
+// rooted at the page after it, and the roots of indexes are wherever they were allocated.
+pub(crate) const HEADER_PAGE_NUM: usize = 0;
+pub(crate) const TABLE_ROOT_PAGE_NUM: usize = 1;
+
+// Header layout. The header starts with magic bytes that identify the file as a database, the
+// version of the file format, and the page size, followed by the number of rows in the table, the
+// page number of its root, and the page number of the first free page, or 0 if there are none.
+// The max id is the largest id ever assigned to a row, so that
+// automatically assigned ids are never reused, even after the row with the largest id is deleted.
+// It is followed by a description of each index: its name (padded with zero bytes), the column
+// it indexes, and the page number of its root. After those is the user version, which the
+// database's user sets to whatever they like with `pragma user_version`, and then the page number
+// of the page that holds the views and triggers, or 0 if there are none. The change counter, at
+// the end of the header, is incremented each time changes are written, so that other processes
+// can tell that the pages they have cached are out of date.
+pub(crate) const HEADER_MAGIC: &[u8; HEADER_MAGIC_SIZE] = b"mysql database\0\0";
+pub(crate) const FORMAT_VERSION: u32 = 3;
+pub(crate) const HEADER_MAGIC_SIZE: usize = 16;
+pub(crate) const HEADER_MAGIC_OFFSET: usize = 0;
+const HEADER_FORMAT_VERSION_SIZE: usize = 4;
+pub(crate) const HEADER_FORMAT_VERSION_OFFSET: usize = HEADER_MAGIC_OFFSET + HEADER_MAGIC_SIZE;
+const HEADER_PAGE_SIZE_SIZE: usize = 4;
+pub(crate) const HEADER_PAGE_SIZE_OFFSET: usize =
+    HEADER_FORMAT_VERSION_OFFSET + HEADER_FORMAT_VERSION_SIZE;
+const HEADER_ROW_COUNT_SIZE: usize = 4;
+const HEADER_ROW_COUNT_OFFSET: usize = HEADER_PAGE_SIZE_OFFSET + HEADER_PAGE_SIZE_SIZE;
+const HEADER_ROOT_PAGE_SIZE: usize = 4;
+pub(crate) const HEADER_ROOT_PAGE_OFFSET: usize = HEADER_ROW_COUNT_OFFSET + HEADER_ROW_COUNT_SIZE;
+const HEADER_FREE_LIST_SIZE: usize = 4;
+const HEADER_FREE_LIST_OFFSET: usize = HEADER_ROOT_PAGE_OFFSET + HEADER_ROOT_PAGE_SIZE;
+const HEADER_MAX_ID_SIZE: usize = 4;
+const HEADER_MAX_ID_OFFSET: usize = HEADER_FREE_LIST_OFFSET + HEADER_FREE_LIST_SIZE;
+const HEADER_NUM_INDEXES_SIZE: usize = 4;
+const HEADER_NUM_INDEXES_OFFSET: usize = HEADER_MAX_ID_OFFSET + HEADER_MAX_ID_SIZE;
+const HEADER_INDEXES_OFFSET: usize = HEADER_NUM_INDEXES_OFFSET + HEADER_NUM_INDEXES_SIZE;
+pub(crate) const INDEX_NAME_SIZE: usize = 32;
+const INDEX_COLUMN_SIZE: usize = 4;
+const INDEX_ROOT_PAGE_SIZE: usize = 4;
+const INDEX_ENTRY_SIZE: usize = INDEX_NAME_SIZE + INDEX_COLUMN_SIZE + INDEX_ROOT_PAGE_SIZE;
+const HEADER_CHANGE_COUNTER_SIZE: usize = 4;
+pub(crate) const HEADER_CHANGE_COUNTER_OFFSET: usize =
+    PAGE_CHECKSUM_OFFSET - HEADER_CHANGE_COUNTER_SIZE;
+const HEADER_SCHEMA_PAGE_SIZE: usize = 4;
+pub(crate) const HEADER_SCHEMA_PAGE_OFFSET: usize =
+    HEADER_CHANGE_COUNTER_OFFSET - HEADER_SCHEMA_PAGE_SIZE;
+const HEADER_USER_VERSION_SIZE: usize = 4;
+const HEADER_USER_VERSION_OFFSET: usize = HEADER_SCHEMA_PAGE_OFFSET - HEADER_USER_VERSION_SIZE;
+pub(crate) const MAX_INDEXES: usize =
+    (HEADER_USER_VERSION_OFFSET - HEADER_INDEXES_OFFSET) / INDEX_ENTRY_SIZE;
+
+// Schema page layout. The page starts with the number of views, followed by each view's name and
+// the text of its SELECT. After those is the number of triggers, followed by each trigger's name,
+// the action it fires on, and the text of its statements. Then is the number of tables that have
+// statistics, which is 0 or 1, followed by the table's number of rows and then the number of
+// indexes, with each index's name, number of distinct values, number of samples and samples.
+// Next is the number of partial indexes, followed by each one's name and the text of its WHERE
+// clause. Last is the number of indexes on expressions, followed by each one's name and the text
+// of its expression. Each string and sample is preceded by its length in bytes.
+const SCHEMA_COUNT_SIZE: usize = 4;
+const SCHEMA_STRING_LENGTH_SIZE: usize = 4;
+
+// Journal layout. The journal starts with the number of pages the database file had before it was
+// written to and the number of pages saved in the journal, followed by each page's number and its
+// original contents.
+const JOURNAL_NUM_PAGES_SIZE: usize = 4;
+const JOURNAL_NUM_PAGES_OFFSET: usize = 0;
+const JOURNAL_NUM_ENTRIES_SIZE: usize = 4;
+const JOURNAL_NUM_ENTRIES_OFFSET: usize = JOURNAL_NUM_PAGES_OFFSET + JOURNAL_NUM_PAGES_SIZE;
+const JOURNAL_HEADER_SIZE: usize = JOURNAL_NUM_ENTRIES_OFFSET + JOURNAL_NUM_ENTRIES_SIZE;
+const JOURNAL_PAGE_NUM_SIZE: usize = 4;
+const JOURNAL_ENTRY_SIZE: usize = JOURNAL_PAGE_NUM_SIZE + PAGE_SIZE;
+
+// Write-ahead log layout. The log is a sequence of frames, each of which has a page's number, the
+// number of pages in the database if the frame is the last one of a commit or else 0, and then the
+// page's contents.
+const WAL_PAGE_NUM_SIZE: usize = 4;
+const WAL_PAGE_NUM_OFFSET: usize = 0;
+const WAL_DB_NUM_PAGES_SIZE: usize = 4;
+const WAL_DB_NUM_PAGES_OFFSET: usize = WAL_PAGE_NUM_OFFSET + WAL_PAGE_NUM_SIZE;
+const WAL_FRAME_HEADER_SIZE: usize = WAL_DB_NUM_PAGES_OFFSET + WAL_DB_NUM_PAGES_SIZE;
+pub(crate) const WAL_FRAME_SIZE: usize = WAL_FRAME_HEADER_SIZE + PAGE_SIZE;
+pub(crate) const DEFAULT_WAL_AUTOCHECKPOINT: usize = 1000;
+
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 2000;  // In pages, so 8 MB.
+const MAX_BUSY_DELAY: u64 = 100;  // In milliseconds.
+
+// The bytes of the database file that are locked for each level of lock, as in SQLite. They are
+// far past the pages of most databases, and since the locks are advisory, they don't keep anything
+// from reading or writing them anyway.
+const PENDING_BYTE: i64 = 0x4000_0000;
+const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+const SHARED_BYTE: i64 = PENDING_BYTE + 2;
+
+
+/// An abstraction for fetching pages. Pages are kept in a cache of limited size, which the least
+/// recently used page is evicted from to make room for another.
+pub(crate) struct Pager {
+    pub(crate) vfs: Box<dyn Vfs>,
+    pub(crate) path: String,
+    journal_path: String,
+    wal_path: String,
+    // The write-ahead log, if the database is in WAL mode.
+    pub(crate) wal: Option<WalHandle>,
+    // The number of pages in the log at which it is checkpointed after a commit, or 0 for never.
+    pub(crate) wal_autocheckpoint: usize,
+    pub(crate) synchronous: Synchronous,
+    // The most bytes of the database file to map into memory, or 0 to read it with read().
+    pub(crate) mmap_size: usize,
+    // The mapping of the database file, if it is mapped. It is made when a page is read, and
+    // removed whenever the file is truncated, since reading a mapped page past the end of the file
+    // is an error.
+    pub(crate) map: Option<Mmap>,
+    // The length of the database file, or in WAL mode, what it will be once the log is copied into
+    // it.
+    pub(crate) file_length: usize,
+    pub(crate) num_pages: usize,
+    pub(crate) pages: HashMap<usize, CachedPage>,
+    // The maximum number of pages in the cache.
+    pub(crate) cache_size: usize,
+    // Incremented each time a page is used, to tell which cached page was used least recently.
+    clock: u64,
+    // The rollback journal, from when a changed page is first written to the database file until
+    // every change has been written.
+    journal: Option<File>,
+    // The pages whose original contents are in the journal.
+    journaled: HashSet<usize>,
+    // A snapshot from when the current transaction began, followed by one from each savepoint
+    // within it, oldest first. A transaction is active if this isn't empty.
+    snapshots: Vec<Snapshot>,
+    // What was done when the database was opened to recover from the last session being
+    // interrupted, if anything.
+    pub(crate) recovered: Vec<String>,
+    pub(crate) lock: LockLevel,
+    // How many milliseconds to keep trying to take a lock that another connection is in the way
+    // of, or 0 to give up right away.
+    pub(crate) busy_timeout: u64,
+    // The database's change counter as of when the cache was last known to be up to date.
+    pub(crate) change_counter: u32,
+    // How many pages have been read from disk, and how many times a page was already in the cache
+    // when it was needed, which EXPLAIN ANALYZE reports for each step of a statement.
+    pub(crate) pages_read: u64,
+    pub(crate) cache_hits: u64,
+}
+
+
+/// When the pager waits for writes to reach the disk, from least to most often. With `Full`, which
+/// is the default, every commit is durable once it is done. With `Normal`, writes are still made
+/// durable before anything that depends on them, such as writing pages after their journal or
+/// emptying the write-ahead log after a checkpoint, so the database can't be corrupted, but in WAL
+/// mode the last commits may be lost if the system crashes. With `Off`, the operating system
+/// decides when writes reach the disk, so a system crash can corrupt the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Synchronous {
+    Off,
+    Normal,
+    Full,
+}
+
+
+impl Synchronous {
+    /// Parse a setting by name or by number, as `0`, `1` or `2`.
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "off" | "0" => Some(Synchronous::Off),
+            "normal" | "1" => Some(Synchronous::Normal),
+            "full" | "2" => Some(Synchronous::Full),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Synchronous::Off => "off",
+            Synchronous::Normal => "normal",
+            Synchronous::Full => "full",
+        }
+    }
+}
+
+
+/// The lock that a connection has on the database, from least to most. Any number of connections
+/// can have a shared lock, which is needed to read. One of them at a time can have a reserved lock,
+/// which is needed to change pages in the cache, while the others keep reading. An exclusive lock
+/// is needed to write to the database file, and it can only be taken once no other connection has a
+/// shared lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    Unlocked,
+    Shared,
+    Reserved,
+    Exclusive,
+}
+
+
+/// The storage that the pager keeps the database's pages in, other than its cache. The database
+/// is usually kept in a file, but implementing this trait lets it be kept anywhere, or lets tests
+/// fail writes on purpose, and `Database::open_with_vfs` opens a database on it. Offsets and
+/// lengths are in bytes, but pages are always read and written whole, so page `n` starts at byte
+/// `n * page.len()`. Storage can be sent to another thread, along with the connection that it
+/// belongs to.
+pub trait Vfs: Send {
+    /// Open the database at the given path, only for reading if `read_only` is true, and creating
+    /// it if it doesn't exist if `create` is true. A database that is only read is never created.
+    fn open(path: &str, read_only: bool, create: bool) -> io::Result<Self> where Self: Sized;
+
+    /// Read the page with the given number into the buffer, which is the size of a page.
+    fn read_page(&mut self, page_num: usize, page: &mut [u8]) -> io::Result<()>;
+
+    /// Write the page with the given number, adding to the end of the database if it is past it.
+    fn write_page(&mut self, page_num: usize, page: &[u8]) -> io::Result<()>;
+
+    /// The length of the database.
+    fn len(&self) -> io::Result<usize>;
+
+    /// Whether the database is empty, which it is when it has just been created.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Change the length, removing what is past it or adding zero bytes.
+    fn set_len(&mut self, length: usize) -> io::Result<()>;
+
+    /// Wait until everything that was written is durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Try to take a lock on the database of the given level, which is the one above the level of
+    /// the lock it has, and return whether it was taken.
+    fn lock(&mut self, _level: LockLevel) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Release the lock on the database down to the given level, which is `Shared` or `Unlocked`.
+    fn unlock(&mut self, _level: LockLevel) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the database outlasts the process. If it doesn't, there is nothing to recover after
+    /// a crash, so it has no journal or write-ahead log.
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    /// The file that the database is in, if it is in one, which is then memory-mapped.
+    fn file(&self) -> Option<&File> {
+        None
+    }
+
+    /// Whether the database can only be read, in which case no lock above a shared one is taken
+    /// on it.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+
+/// A database kept in a file.
+pub(crate) struct FileVfs {
+    file: File,
+    locks: FileLocks,
+    read_only: bool,
+}
+
+
+impl Vfs for FileVfs {
+    fn open(path: &str, read_only: bool, create: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(create && !read_only)
+            .truncate(false)
+            .open(path)?;
+        let locks = FileLocks::new(&file)?;
+        Ok(FileVfs { file, locks, read_only })
+    }
+
+    fn read_page(&mut self, page_num: usize, page: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
+        self.file.read_exact(page)
+    }
+
+    fn write_page(&mut self, page_num: usize, page: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
+        self.file.write_all(page)
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        Ok(self.file.metadata()?.len() as usize)
+    }
+
+    fn set_len(&mut self, length: usize) -> io::Result<()> {
+        self.file.set_len(length as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Lock bytes of the file that other connections to the database lock too. The locks are
+    /// released when the file is closed, even if the process dies.
+    fn lock(&mut self, level: LockLevel) -> io::Result<bool> {
+        match level {
+            LockLevel::Unlocked => Ok(true),
+            // A connection that is waiting for an exclusive lock holds the pending byte, which
+            // keeps new shared locks from being taken while the ones in its way are released.
+            LockLevel::Shared => {
+                if !self.lock_bytes(ByteLock::Read, PENDING_BYTE, 1)? {
+                    return Ok(false);
+                }
+                let is_locked = self.lock_bytes(ByteLock::Read, SHARED_BYTE, 1)?;
+                self.lock_bytes(ByteLock::Unlocked, PENDING_BYTE, 1)?;
+                Ok(is_locked)
+            },
+            LockLevel::Reserved => self.lock_bytes(ByteLock::Write, RESERVED_BYTE, 1),
+            LockLevel::Exclusive => {
+                Ok(self.lock_bytes(ByteLock::Write, PENDING_BYTE, 1)?
+                    && self.lock_bytes(ByteLock::Write, SHARED_BYTE, 1)?)
+            },
+        }
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> io::Result<()> {
+        if level == LockLevel::Unlocked {
+            self.lock_bytes(ByteLock::Unlocked, PENDING_BYTE, 3)?;
+        } else {
+            self.lock_bytes(ByteLock::Read, SHARED_BYTE, 1)?;
+            self.lock_bytes(ByteLock::Unlocked, PENDING_BYTE, 2)?;
+        }
+        Ok(())
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+
+impl FileVfs {
+    /// Lock the given bytes of the file, or release the lock on them, and return whether it was
+    /// done.
+    fn lock_bytes(&mut self, lock: ByteLock, start: i64, len: i64) -> io::Result<bool> {
+        self.locks.lock(&self.file, lock, start, len)
+    }
+}
+
+
+/// A database that is only kept in memory, which holds what its file would.
+#[derive(Default)]
+pub(crate) struct MemoryVfs {
+    bytes: Vec<u8>,
+    // Called with the whole database each time it is synced, e.g. to keep a copy of it where
+    // there are no files to keep it in.
+    persist: Option<Persist>,
+}
+
+
+pub(crate) type Persist = Box<dyn FnMut(&[u8]) + Send>;
+
+
+impl MemoryVfs {
+    /// Keep a database in memory that starts out as the given contents of its file, and that is
+    /// passed to `persist` each time it is synced.
+    pub(crate) fn with_persist(bytes: Vec<u8>, persist: Persist) -> Self {
+        MemoryVfs { bytes, persist: Some(persist) }
+    }
+}
+
+
+impl Vfs for MemoryVfs {
+    fn open(_path: &str, _read_only: bool, _create: bool) -> io::Result<Self> {
+        Ok(MemoryVfs::default())
+    }
+
+    fn read_page(&mut self, page_num: usize, page: &mut [u8]) -> io::Result<()> {
+        let offset = page_num * PAGE_SIZE;
+        let bytes = self.bytes.get(offset..offset+page.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        page.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_num: usize, page: &[u8]) -> io::Result<()> {
+        let offset = page_num * PAGE_SIZE;
+        if self.bytes.len() < offset + page.len() {
+            self.bytes.resize(offset + page.len(), 0);
+        }
+        self.bytes[offset..offset+page.len()].copy_from_slice(page);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        Ok(self.bytes.len())
+    }
+
+    fn set_len(&mut self, length: usize) -> io::Result<()> {
+        self.bytes.resize(length, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(persist) = &mut self.persist {
+            persist(&self.bytes);
+        }
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+
+/// A page in the pager's cache.
+#[derive(Clone)]
+pub(crate) struct CachedPage {
+    data: Vec<u8>,
+    // The pager's clock when the page was last used.
+    last_used: u64,
+    // Whether the page may have changed since it was last written to disk.
+    dirty: bool,
+}
+
+
+/// A write-ahead log, which committed pages are appended to instead of being written to the
+/// database file, until a checkpoint copies them into it. Every connection to the database in this
+/// process shares it, so that each one can keep reading the database as of when its read began
+/// while another appends commits.
+pub(crate) struct Wal {
+    file: File,
+    // The length of the log up to the end of its last commit.
+    pub(crate) length: usize,
+    // The number of pages in the database as of the last commit.
+    num_pages: usize,
+    // The offsets in the log of the committed contents of each page that is in it, oldest first.
+    frames: HashMap<usize, Vec<usize>>,
+    // Incremented by each commit, so that connections can tell that the pages they have cached
+    // are out of date.
+    commits: u64,
+    // The length of the log as of when each connection that is reading began its read.
+    readers: Vec<usize>,
+    // Whether a connection has a reserved lock, and so is changing the database.
+    is_writing: bool,
+    // The database file, with an exclusive lock on it that keeps other processes out while the log
+    // is in use, since only this process knows which pages are in it.
+    lock: Option<File>,
+}
+
+
+/// A connection's use of the write-ahead log that it shares with the other connections.
+pub(crate) struct WalHandle {
+    pub(crate) shared: Arc<Mutex<Wal>>,
+    // The length of the log, the number of pages in the database and the number of commits as of
+    // when this connection's read began. The length is None if it isn't reading.
+    read_length: Option<usize>,
+    num_pages: usize,
+    commits: u64,
+    // The number of commits as of the pages in this connection's cache, or None if it is unknown.
+    cached_commits: Option<u64>,
+    is_writing: bool,
+    // The offset in the log of each page that this connection wrote to it to make room in the
+    // cache before it was committed. These frames follow the last commit, and become part of the
+    // next one.
+    spilled: HashMap<usize, usize>,
+}
+
+
+// The write-ahead logs of the databases in WAL mode that are open in this process, by the canonical
+// path of the database file.
+pub(crate) static WALS: Mutex<Vec<(PathBuf, Weak<Mutex<Wal>>)>> = Mutex::new(Vec::new());
+
+
+/// Return the write-ahead log that the connections in this process to the database at the given
+/// path share, if any of them are in WAL mode.
+pub(crate) fn find_wal(path: &str) -> Option<Arc<Mutex<Wal>>> {
+    let key = wal_key(path);
+    let wals = WALS.lock().unwrap();
+    wals.iter().filter(|(other, _)| *other == key).find_map(|(_, wal)| wal.upgrade())
+}
+
+
+/// Make the write-ahead log of the database at the given path, which is kept in the given storage,
+/// one that other connections in this process can find. The log keeps the lock that the storage
+/// has on the database file for as long as any connection uses it.
+fn share_wal(path: &str, mut wal: Wal, vfs: &dyn Vfs) -> Arc<Mutex<Wal>> {
+    // Cloning the file shares the open file, and so the lock, with the storage.
+    wal.lock = vfs.file().and_then(|file| file.try_clone().ok());
+    let wal = Arc::new(Mutex::new(wal));
+    let mut wals = WALS.lock().unwrap();
+    wals.retain(|(_, other)| other.strong_count() > 0);
+    wals.push((wal_key(path), Arc::downgrade(&wal)));
+    wal
+}
+
+
+/// Return the canonical form of a database's path, so that its log is found whichever path it is
+/// opened by.
+pub(crate) fn wal_key(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+
+impl WalHandle {
+    fn new(shared: Arc<Mutex<Wal>>) -> Self {
+        let wal = shared.lock().unwrap();
+        let (num_pages, commits) = (wal.num_pages, wal.commits);
+        drop(wal);
+        WalHandle {
+            shared,
+            read_length: None,
+            num_pages,
+            commits,
+            cached_commits: Some(commits),
+            is_writing: false,
+            spilled: HashMap::new(),
+        }
+    }
+
+    /// Try to take a lock of the given level, the one above the level of the lock this connection
+    /// has, and return whether it was taken. A shared lock begins a read of the database as it is
+    /// now. Only one connection at a time can have a reserved lock, and only if nothing has been
+    /// committed since its read began. An exclusive lock adds nothing, since commits are appended
+    /// to the log while others read.
+    fn lock(&mut self, level: LockLevel) -> bool {
+        let mut wal = self.shared.lock().unwrap();
+        match level {
+            LockLevel::Shared => {
+                self.read_length = Some(wal.length);
+                self.num_pages = wal.num_pages;
+                self.commits = wal.commits;
+                let length = wal.length;
+                wal.readers.push(length);
+            },
+            LockLevel::Reserved => {
+                if wal.is_writing || self.commits != wal.commits {
+                    return false;
+                }
+                wal.is_writing = true;
+                self.is_writing = true;
+            },
+            LockLevel::Unlocked | LockLevel::Exclusive => (),
+        }
+        true
+    }
+
+    fn unlock(&mut self, level: LockLevel) {
+        let mut wal = self.shared.lock().unwrap();
+        if self.is_writing {
+            wal.is_writing = false;
+            self.is_writing = false;
+        }
+        if level == LockLevel::Unlocked {
+            if let Some(length) = self.read_length.take() {
+                let position = wal.readers.iter().position(|&other| other == length).unwrap();
+                wal.readers.swap_remove(position);
+            }
+        }
+    }
+
+    /// Return the offset in the log of the contents of the given page as this connection sees
+    /// them, if the log has them.
+    fn find_frame(&self, wal: &Wal, page_num: usize) -> Option<usize> {
+        if let Some(&offset) = self.spilled.get(&page_num) {
+            return Some(offset);
+        }
+        let end = self.read_length.unwrap_or(wal.length);
+        wal.frames.get(&page_num)?.iter().rev().find(|&&offset| offset < end).copied()
+    }
+}
+
+
+impl Drop for WalHandle {
+    fn drop(&mut self) {
+        self.unlock(LockLevel::Unlocked);
+    }
+}
+
+
+/// The original contents of the pages that have changed since a snapshot was taken, which can be
+/// restored to undo every change made since.
+struct Snapshot {
+    // The name of the savepoint, or None for a snapshot taken by BEGIN.
+    name: Option<String>,
+    num_pages: usize,
+    // Each page as it was when it was first changed after the snapshot was taken.
+    pages: HashMap<usize, CachedPage>,
+}
+
+
+impl Pager {
+    pub(crate) fn new(path: &str, mut vfs: Box<dyn Vfs>) -> io::Result<Self> {
+        let journal_path = format!("{}-journal", path);
+        let wal_path = format!("{}-wal", path);
+        let mut recovered = Vec::new();
+        // If another connection in this process is using the log, there is nothing to recover.
+        let shared = if vfs.is_persistent() { find_wal(path) } else { None };
+        let (file_length, wal) = if let Some(shared) = shared {
+            let file_length = shared.lock().unwrap().num_pages * PAGE_SIZE;
+            (file_length, Some(shared))
+        } else if vfs.is_persistent() {
+            let (file_length, wal) =
+                recover(vfs.as_mut(), &journal_path, &wal_path, &mut recovered)?;
+            (file_length, wal.map(|wal| share_wal(path, wal, vfs.as_ref())))
+        } else {
+            (vfs.len()?, None)
+        };
+        let wal = wal.map(WalHandle::new);
+
+        Ok(Self {
+            vfs,
+            path: String::from(path),
+            journal_path,
+            wal_path,
+            wal,
+            wal_autocheckpoint: DEFAULT_WAL_AUTOCHECKPOINT,
+            synchronous: Synchronous::Full,
+            mmap_size: 0,
+            map: None,
+            file_length,
+            num_pages: file_length / PAGE_SIZE,
+            pages: HashMap::new(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            clock: 0,
+            journal: None,
+            journaled: HashSet::new(),
+            snapshots: Vec::new(),
+            recovered,
+            lock: LockLevel::Unlocked,
+            busy_timeout: 0,
+            change_counter: 0,
+            pages_read: 0,
+            cache_hits: 0,
+        })
+    }
+
+    /// Take a lock on the database of at least the given level, a level at a time, and return
+    /// whether the cache was discarded because another connection changed the database while this
+    /// one had no lock on it. It is an error if another connection's lock is in the way for longer
+    /// than the busy timeout, in which case the lock is left at the highest level that could be
+    /// taken.
+    pub(crate) fn lock(&mut self, level: LockLevel) -> Result<bool, Error> {
+        if level >= LockLevel::Reserved && self.vfs.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let mut is_discarded = false;
+        let mut waited = 0;
+        let mut delay = 1;
+        let held = self.lock;
+        while self.lock < level {
+            let next = match self.lock {
+                LockLevel::Unlocked => LockLevel::Shared,
+                LockLevel::Shared => LockLevel::Reserved,
+                _ => LockLevel::Exclusive,
+            };
+            // Another connection in this process may have switched the database to WAL mode.
+            if next == LockLevel::Shared && self.wal.is_none() && self.vfs.is_persistent() {
+                if let Some(shared) = find_wal(&self.path) {
+                    let mut wal = WalHandle::new(shared);
+                    wal.cached_commits = None;
+                    self.wal = Some(wal);
+                }
+            }
+
+            let is_locked = match &mut self.wal {
+                Some(wal) => wal.lock(next),
+                None => self.vfs.lock(next)?,
+            };
+            if is_locked {
+                trace!(level = ?next, "took lock");
+                self.lock = next;
+                if next == LockLevel::Shared {
+                    is_discarded |= self.check_for_changes()?;
+                }
+                continue;
+            }
+
+            // The connection with the reserved lock may be waiting for this one to stop reading,
+            // so a transaction or statement that has been reading gives up right away, and
+            // otherwise, the shared lock that was only just taken is let go of while waiting. A
+            // shared lock that was already held is never let go of, since the pages in the cache
+            // are only known to be current while it is.
+            let is_reading =
+                next == LockLevel::Reserved && (self.in_transaction() || held >= LockLevel::Shared);
+            if is_reading || waited >= self.busy_timeout {
+                debug!(level = ?next, waited, "gave up waiting for lock");
+                return Err(Error::Locked);
+            }
+            debug!(level = ?next, waited, "waiting for lock");
+            if next == LockLevel::Reserved {
+                self.unlock(LockLevel::Unlocked)?;
+            }
+            let sleep = delay.min(self.busy_timeout - waited);
+            thread::sleep(Duration::from_millis(sleep));
+            waited += sleep;
+            delay = (delay * 2).min(MAX_BUSY_DELAY);
+        }
+        Ok(is_discarded)
+    }
+
+    /// Release the lock on the database down to the given level.
+    pub(crate) fn unlock(&mut self, level: LockLevel) -> io::Result<()> {
+        if self.lock > level {
+            match &mut self.wal {
+                Some(wal) => wal.unlock(level),
+                None => self.vfs.unlock(level)?,
+            }
+            self.lock = level;
+        }
+        Ok(())
+    }
+
+    /// Once a shared lock is taken after having had no lock, discard the cache if another
+    /// connection changed the database in the meantime, and return whether it was discarded. A
+    /// connection that died while writing may have left a journal or a log behind, which is then
+    /// recovered from the same way as when the database is opened.
+    fn check_for_changes(&mut self) -> Result<bool, Error> {
+        if let Some(wal) = &mut self.wal {
+            if wal.cached_commits == Some(wal.commits) {
+                return Ok(false);
+            }
+            wal.cached_commits = Some(wal.commits);
+            let num_pages = wal.num_pages;
+            debug!("another connection changed the database, so the cache was discarded");
+            self.pages.clear();
+            self.map = None;
+            self.file_length = num_pages * PAGE_SIZE;
+            self.num_pages = num_pages;
+            return Ok(true);
+        }
+        if !self.vfs.is_persistent() {
+            return Ok(false);
+        }
+
+        // Any connection that is using a journal, or a log, has an exclusive lock, so one that is
+        // there now was left behind.
+        let is_interrupted =
+            fs::metadata(&self.journal_path).is_ok() || fs::metadata(&self.wal_path).is_ok();
+        let mut file_length = self.vfs.len()?;
+        if is_interrupted {
+            debug!("recovering from an interrupted session");
+            self.lock(LockLevel::Exclusive)?;
+            let (length, wal) = recover(
+                self.vfs.as_mut(),
+                &self.journal_path,
+                &self.wal_path,
+                &mut self.recovered,
+            )?;
+            file_length = length;
+            match wal {
+                Some(wal) => {
+                    // From here on, the lock on the database file is the log's, and this
+                    // connection only has a shared lock on the log.
+                    let mut wal = WalHandle::new(share_wal(&self.path, wal, self.vfs.as_ref()));
+                    wal.lock(LockLevel::Shared);
+                    self.wal = Some(wal);
+                    self.lock = LockLevel::Shared;
+                },
+                None => self.unlock(LockLevel::Shared)?,
+            }
+        }
+
+        let header = self.read_page(HEADER_PAGE_NUM)?;
+        let change_counter = read_u32(&header, HEADER_CHANGE_COUNTER_OFFSET);
+        if !is_interrupted && change_counter == self.change_counter {
+            return Ok(false);
+        }
+        debug!("another connection changed the database, so the cache was discarded");
+        self.pages.clear();
+        self.map = None;
+        self.file_length = file_length;
+        self.num_pages = file_length / PAGE_SIZE;
+        self.change_counter = change_counter;
+        Ok(true)
+    }
+
+    /// Return the given page to be changed, reading it from disk first if it is not already in the
+    /// cache. The page is marked dirty.
+    pub(crate) fn get_page(&mut self, page_num: usize) -> io::Result<&mut [u8]> {
+        self.cache_page(page_num)?;
+
+        // The page is saved the first time it's changed after a snapshot, in case it needs to be
+        // restored.
+        if let Some(snapshot) = self.snapshots.last_mut() {
+            if page_num < snapshot.num_pages && !snapshot.pages.contains_key(&page_num) {
+                snapshot.pages.insert(page_num, self.pages[&page_num].clone());
+            }
+        }
+
+        let page = self.pages.get_mut(&page_num).unwrap();
+        page.dirty = true;
+        Ok(&mut page.data)
+    }
+
+    /// Return the given page to be read, reading it from disk first if it is not already in the
+    /// cache.
+    pub(crate) fn get_page_ref(&mut self, page_num: usize) -> io::Result<&[u8]> {
+        self.cache_page(page_num)?;
+        Ok(&self.pages[&page_num].data)
+    }
+
+    /// Make sure that the given page is in the cache, and mark it as the most recently used.
+    fn cache_page(&mut self, page_num: usize) -> io::Result<()> {
+        self.clock += 1;
+        if let Some(page) = self.pages.get_mut(&page_num) {
+            trace!(page_num, "page cache hit");
+            page.last_used = self.clock;
+            self.cache_hits += 1;
+            return Ok(());
+        }
+        trace!(page_num, "page cache miss");
+
+        // A page past the end of the database is new, so it starts out zeroed, and it has never
+        // been written.
+        let page = if page_num < self.num_pages {
+            self.pages_read += 1;
+            CachedPage { data: self.read_page(page_num)?, last_used: self.clock, dirty: false }
+        } else {
+            self.num_pages = page_num + 1;
+            CachedPage { data: vec![0; PAGE_SIZE], last_used: self.clock, dirty: true }
+        };
+        self.evict_pages(self.cache_size - 1);
+        self.pages.insert(page_num, page);
+        Ok(())
+    }
+
+    /// Change the number of pages that the cache can hold, evicting pages if it holds more.
+    pub(crate) fn set_cache_size(&mut self, cache_size: usize) {
+        self.cache_size = cache_size;
+        self.evict_pages(cache_size);
+    }
+
+    /// Evict the least recently used pages from the cache until it holds no more than the given
+    /// number. A dirty page is written to disk before it is evicted, and if that fails, it stays in
+    /// the cache, which is then left holding more pages than it should until the changes are
+    /// written.
+    fn evict_pages(&mut self, max_pages: usize) {
+        let mut can_spill = None;
+        while self.pages.len() > max_pages {
+            // An unchanged page is evicted without any change to the lock, so only when the least
+            // recently used page is a changed one is the exclusive lock needed to write it early
+            // tried for. If another connection is in the way, only unchanged pages are evicted.
+            let lru = self.pages.values().min_by_key(|page| page.last_used);
+            let is_lru_dirty = lru.is_some_and(|page| page.dirty);
+            if is_lru_dirty && can_spill.is_none() {
+                can_spill = Some(self.try_lock_to_spill());
+            }
+            let can_spill = can_spill == Some(true);
+            let lru = self.pages.iter()
+                .filter(|(_, page)| can_spill || !page.dirty)
+                .min_by_key(|(_, page)| page.last_used);
+            let (&page_num, page) = match lru {
+                Some(lru) => lru,
+                None => break,
+            };
+            if page.dirty && self.spill_page(page_num).is_err() {
+                break;
+            }
+            trace!(page_num, "evicted page from cache");
+            self.pages.remove(&page_num);
+        }
+    }
+
+    /// Try once to take the exclusive lock that writing a changed page early needs, and return
+    /// whether it is held. Only a connection that is writing, and so has a reserved lock, tries,
+    /// and it doesn't wait, so that the readers in the way aren't held up and no lock that the
+    /// statement holds is let go of.
+    fn try_lock_to_spill(&mut self) -> bool {
+        if self.lock < LockLevel::Reserved {
+            return false;
+        }
+        let busy_timeout = mem::replace(&mut self.busy_timeout, 0);
+        let is_locked = self.lock(LockLevel::Exclusive).is_ok();
+        self.busy_timeout = busy_timeout;
+        is_locked
+    }
+
+    /// Write a changed page to disk ahead of the rest of the changes, so that it can be evicted. In
+    /// WAL mode, it is written to the log, but it isn't committed until the rest of the changes
+    /// are. Otherwise, its original contents are saved in the journal first, as for any other
+    /// write to the database file.
+    fn spill_page(&mut self, page_num: usize) -> io::Result<()> {
+        debug!(page_num, "spilling page to make room in the cache");
+        let page = &mut self.pages.get_mut(&page_num).unwrap().data;
+        set_page_checksum(page);
+        if let Some(handle) = &mut self.wal {
+            let mut wal = handle.shared.lock().unwrap();
+            let offset = match handle.spilled.get(&page_num) {
+                Some(&offset) => offset,
+                None => wal.length + handle.spilled.len() * WAL_FRAME_SIZE + WAL_FRAME_HEADER_SIZE,
+            };
+            let mut frame = vec![0; WAL_FRAME_HEADER_SIZE];
+            write_u32(&mut frame, WAL_PAGE_NUM_OFFSET, page_num as u32);
+            frame.extend_from_slice(page);
+            wal.file.seek(SeekFrom::Start((offset - WAL_FRAME_HEADER_SIZE) as u64))?;
+            wal.file.write_all(&frame)?;
+            handle.spilled.insert(page_num, offset);
+        } else {
+            self.write_journal(&[page_num])?;
+            self.flush(page_num)?;
+            self.file_length = self.file_length.max((page_num + 1) * PAGE_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Return whether any changed pages have been written to disk to make room in the cache since
+    /// the changes were last written.
+    pub(crate) fn has_spilled_pages(&self) -> bool {
+        match &self.wal {
+            Some(handle) => !handle.spilled.is_empty(),
+            None => self.journal.is_some(),
+        }
+    }
+
+    /// Return the number of a page that is not in use, which is taken off the free list if there
+    /// are any free pages, and otherwise is a new page at the end of the database file.
+    pub(crate) fn get_unused_page_num(&mut self) -> io::Result<usize> {
+        let page_num = header_free_list(self.get_page_ref(HEADER_PAGE_NUM)?);
+        if page_num == 0 {
+            return Ok(self.num_pages);
+        }
+
+        let next_page_num = read_u32(self.get_page_ref(page_num)?, FREE_PAGE_NEXT_OFFSET) as usize;
+        set_header_free_list(self.get_page(HEADER_PAGE_NUM)?, next_page_num);
+        Ok(page_num)
+    }
+
+    /// Put a page that is no longer in use on the free list, so that it can be reused.
+    pub(crate) fn free_page(&mut self, page_num: usize) -> io::Result<()> {
+        let next_page_num = header_free_list(self.get_page_ref(HEADER_PAGE_NUM)?);
+        let page = self.get_page(page_num)?;
+        page[..PAGE_CHECKSUM_OFFSET].fill(0);
+        page[NODE_TYPE_OFFSET] = FREE_PAGE_TYPE;
+        write_u32(page, FREE_PAGE_NEXT_OFFSET, next_page_num as u32);
+        set_header_free_list(self.get_page(HEADER_PAGE_NUM)?, page_num);
+        Ok(())
+    }
+
+    /// Take a snapshot of the pages, which starts a transaction if one is not already active.
+    pub(crate) fn begin(&mut self, name: Option<&str>) {
+        if self.snapshots.is_empty() {
+            debug!("began transaction");
+        }
+        debug!(savepoint = name, "took snapshot");
+        self.snapshots.push(Snapshot {
+            name: name.map(String::from),
+            num_pages: self.num_pages,
+            pages: HashMap::new(),
+        });
+    }
+
+    /// Keep every change made since the transaction began, ending it.
+    pub(crate) fn commit(&mut self) {
+        debug!("committed transaction");
+        self.release(0);
+    }
+
+    /// Undo every change made since the transaction began, ending it.
+    pub(crate) fn rollback(&mut self) {
+        if !self.snapshots.is_empty() {
+            debug!("rolled back transaction");
+            // Pages that were written to the log to make room in the cache were never committed.
+            if let Some(handle) = self.wal.as_mut().filter(|handle| !handle.spilled.is_empty()) {
+                handle.spilled.clear();
+                let wal = handle.shared.lock().unwrap();
+                // If the log can't be cut short, the next commit overwrites them anyway.
+                let _ = wal.file.set_len(wal.length as u64);
+            }
+
+            self.rollback_to(0);
+            self.snapshots.clear();
+        }
+    }
+
+    /// Return the position of the most recent savepoint with the given name.
+    pub(crate) fn find_savepoint(&self, name: &str) -> Option<usize> {
+        self.snapshots.iter().rposition(|snapshot| {
+            snapshot.name.as_ref().is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// Forget the snapshot at the given position and every one after it, keeping their changes.
+    /// The pages they saved are passed on to the snapshot before them, unless it saved them too.
+    pub(crate) fn release(&mut self, position: usize) {
+        let released = self.snapshots.split_off(position);
+        if let Some(snapshot) = self.snapshots.last_mut() {
+            for released in released {
+                for (page_num, page) in released.pages {
+                    snapshot.pages.entry(page_num).or_insert(page);
+                }
+            }
+        }
+    }
+
+    /// Undo every change made since the snapshot at the given position, which is kept so that it
+    /// can be rolled back to again. The snapshots after it are forgotten.
+    pub(crate) fn rollback_to(&mut self, position: usize) {
+        self.release(position + 1);
+        let num_pages = self.snapshots[position].num_pages;
+        self.truncate(num_pages);
+        let snapshot = &self.snapshots[position];
+        let restored: Vec<(usize, CachedPage)> = snapshot.pages.iter()
+            .filter(|(&page_num, _)| page_num < num_pages)
+            .map(|(&page_num, page)| (page_num, page.clone()))
+            .collect();
+        for (page_num, mut page) in restored {
+            // A page that was clean may have been written to disk to make room in the cache. If
+            // what is on disk can't be read, the page is written again to be safe.
+            let original = self.read_original(page_num).ok().flatten();
+            page.dirty = page.dirty || original.as_ref() != Some(&page.data);
+            self.clock += 1;
+            page.last_used = self.clock;
+            self.pages.insert(page_num, page);
+        }
+        self.evict_pages(self.cache_size);
+    }
+
+    /// Remove the pages from the given one on, so that the database has the given number of pages.
+    pub(crate) fn truncate(&mut self, num_pages: usize) {
+        self.num_pages = num_pages;
+        self.pages.retain(|&page_num, _| page_num < num_pages);
+    }
+
+    pub(crate) fn in_transaction(&self) -> bool {
+        !self.snapshots.is_empty()
+    }
+
+    /// Read the given page from disk, regardless of whether it is in the cache.
+    pub(crate) fn read_page(&mut self, page_num: usize) -> io::Result<Vec<u8>> {
+        let mut page = vec![0; PAGE_SIZE];
+        if let Some(handle) = &self.wal {
+            let mut wal = handle.shared.lock().unwrap();
+            if let Some(offset) = handle.find_frame(&wal, page_num) {
+                trace!(page_num, "read page from log");
+                wal.file.seek(SeekFrom::Start(offset as u64))?;
+                wal.file.read_exact(&mut page)?;
+                return Ok(page);
+            }
+        }
+
+        let offset = page_num * PAGE_SIZE;
+        if let Some(map) = self.map_file() {
+            if offset + PAGE_SIZE <= map.len {
+                trace!(page_num, "read page from memory map");
+                page.copy_from_slice(&map.bytes()[offset..offset+PAGE_SIZE]);
+                return Ok(page);
+            }
+        }
+
+        trace!(page_num, "read page from file");
+        self.vfs.read_page(page_num, &mut page)?;
+        Ok(page)
+    }
+
+    /// Return the mapping of the database file, mapping it first if it isn't mapped yet, or None
+    /// if memory-mapped I/O is off or the database isn't in a file. If the file can't be mapped,
+    /// memory-mapped I/O is turned off, and the file is read with read() instead.
+    fn map_file(&mut self) -> Option<&Mmap> {
+        if self.map.is_none() && self.mmap_size > 0 {
+            let file = self.vfs.file()?;
+            let file_length = self.vfs.len().ok()?;
+            let len = self.mmap_size.min(file_length) / PAGE_SIZE * PAGE_SIZE;
+            if len == 0 {
+                return None;
+            }
+            self.map = Mmap::new(file, len);
+            if self.map.is_none() {
+                self.mmap_size = 0;
+            }
+        }
+        self.map.as_ref()
+    }
+
+    /// Change the most bytes of the database file that are mapped into memory.
+    pub(crate) fn set_mmap_size(&mut self, mmap_size: usize) {
+        self.mmap_size = mmap_size;
+        self.map = None;
+    }
+
+    /// Write every dirty page to disk, after incrementing the change counter. In WAL mode, they are
+    /// appended to the log. Otherwise, the original pages are saved in a journal first, so that if
+    /// the process dies partway through, the next time the database is opened it can be restored
+    /// to how it was before.
+    ///
+    /// If writing fails, the changes are lost, as described for `discard_changes`.
+    pub(crate) fn write_changes(&mut self) -> io::Result<()> {
+        if !self.has_changes() {
+            return Ok(());
+        }
+        let result = self.write_dirty_pages();
+        if result.is_err() {
+            debug!("writing changes failed, so they were discarded");
+            self.discard_changes();
+        }
+        result
+    }
+
+    /// Write the changes for `write_changes`, stopping at the first write that fails.
+    fn write_dirty_pages(&mut self) -> io::Result<()> {
+        let header = self.get_page(HEADER_PAGE_NUM)?;
+        let change_counter = read_u32(header, HEADER_CHANGE_COUNTER_OFFSET).wrapping_add(1);
+        write_u32(header, HEADER_CHANGE_COUNTER_OFFSET, change_counter);
+        self.change_counter = change_counter;
+
+        let dirty = self.dirty_pages();
+        debug!(pages = dirty.len(), wal = self.wal.is_some(), "writing changes");
+        for page_num in &dirty {
+            let page = self.pages.get_mut(page_num).unwrap();
+            set_page_checksum(&mut page.data);
+            page.dirty = false;
+        }
+
+        if self.wal.is_some() {
+            return self.append_to_wal(&dirty);
+        }
+
+        self.write_journal(&dirty)?;
+        for page_num in dirty {
+            self.flush(page_num)?;
+        }
+        // Pages that were added and then rolled back may have been written to make room in the
+        // cache.
+        self.file_length = self.num_pages * PAGE_SIZE;
+        self.map = None;
+        self.vfs.set_len(self.file_length)?;
+        if self.synchronous >= Synchronous::Normal {
+            self.vfs.sync()?;
+        }
+
+        // Once the journal is gone, the write is complete.
+        if self.journal.is_some() {
+            fs::remove_file(&self.journal_path)?;
+            self.journal = None;
+            self.journaled.clear();
+        }
+        Ok(())
+    }
+
+    /// Forget every change that hasn't been written, after writing them failed partway through,
+    /// so that the cache is read from disk again. A journal that was being written is left for the
+    /// next connection to take a shared lock to recover from, which puts back whatever was
+    /// overwritten. In WAL mode, nothing was committed, but the frames past the last commit are
+    /// cut off all the same.
+    fn discard_changes(&mut self) {
+        self.pages.clear();
+        self.map = None;
+        self.journal = None;
+        self.journaled.clear();
+        if let Some(handle) = &mut self.wal {
+            handle.spilled.clear();
+            handle.cached_commits = None;
+            let wal = handle.shared.lock().unwrap();
+            let _ = wal.file.set_len(wal.length as u64);
+        }
+    }
+
+    /// Return whether there are changes that haven't been written yet.
+    pub(crate) fn has_changes(&self) -> bool {
+        self.pages.values().any(|page| page.dirty) || self.has_spilled_pages()
+    }
+
+    /// Return the number of every dirty page in the cache, in order.
+    pub(crate) fn dirty_pages(&self) -> Vec<usize> {
+        let mut page_nums: Vec<usize> = self.pages.iter()
+            .filter(|(_, page)| page.dirty)
+            .map(|(&page_num, _)| page_num)
+            .collect();
+        page_nums.sort_unstable();
+        page_nums
+    }
+
+    /// Return the contents of the given page on disk, or None if it has never been written.
+    fn read_original(&mut self, page_num: usize) -> io::Result<Option<Vec<u8>>> {
+        let is_spilled = self.wal.as_ref().is_some_and(|wal| wal.spilled.contains_key(&page_num));
+        if page_num < self.file_length / PAGE_SIZE || is_spilled {
+            Ok(Some(self.read_page(page_num)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Save the original contents of the given pages in the journal, creating it if it doesn't
+    /// exist yet. Pages that are new, or that were already saved, are skipped. A database that
+    /// doesn't outlast the process has no journal.
+    pub(crate) fn write_journal(&mut self, page_nums: &[usize]) -> io::Result<()> {
+        if !self.vfs.is_persistent() {
+            return Ok(());
+        }
+        let sync = self.synchronous >= Synchronous::Normal;
+        if self.journal.is_none() {
+            let mut header = [0; JOURNAL_HEADER_SIZE];
+            let file_num_pages = self.file_length / PAGE_SIZE;
+            write_u32(&mut header, JOURNAL_NUM_PAGES_OFFSET, file_num_pages as u32);
+            let mut file = File::create(&self.journal_path)?;
+            file.write_all(&header)?;
+            if sync {
+                file.sync_all()?;
+            }
+            self.journal = Some(file);
+        }
+
+        let mut entries = Vec::new();
+        let mut added = Vec::new();
+        for &page_num in page_nums {
+            if self.journaled.contains(&page_num) {
+                continue;
+            }
+            if let Some(original) = self.read_original(page_num)? {
+                entries.extend_from_slice(&(page_num as u32).to_be_bytes());
+                entries.extend_from_slice(&original);
+                added.push(page_num);
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // The entries are counted only once they are complete, and the pages are only taken to be
+        // saved once they are counted.
+        let journal = self.journal.as_mut().unwrap();
+        journal.seek(SeekFrom::End(0))?;
+        journal.write_all(&entries)?;
+        if sync {
+            journal.sync_all()?;
+        }
+        let num_entries = ((self.journaled.len() + added.len()) as u32).to_be_bytes();
+        journal.seek(SeekFrom::Start(JOURNAL_NUM_ENTRIES_OFFSET as u64))?;
+        journal.write_all(&num_entries)?;
+        if sync {
+            journal.sync_all()?;
+        }
+        self.journaled.extend(added);
+        Ok(())
+    }
+
+    /// Append the given pages to the write-ahead log as one commit, along with any pages that were
+    /// written to it to make room in the cache.
+    fn append_to_wal(&mut self, page_nums: &[usize]) -> io::Result<()> {
+        let handle = self.wal.as_mut().unwrap();
+        let mut wal = handle.shared.lock().unwrap();
+        let start = wal.length + handle.spilled.len() * WAL_FRAME_SIZE;
+        let mut frames = Vec::with_capacity(page_nums.len() * WAL_FRAME_SIZE);
+        for &page_num in page_nums {
+            let mut header = [0; WAL_FRAME_HEADER_SIZE];
+            write_u32(&mut header, WAL_PAGE_NUM_OFFSET, page_num as u32);
+            frames.extend_from_slice(&header);
+            frames.extend_from_slice(&self.pages[&page_num].data);
+        }
+
+        // The last frame of the commit, which may be a spilled one, holds the number of pages.
+        let end = start + frames.len();
+        wal.file.seek(SeekFrom::Start(start as u64))?;
+        wal.file.write_all(&frames)?;
+        let commit_offset = end - WAL_FRAME_SIZE + WAL_DB_NUM_PAGES_OFFSET;
+        wal.file.seek(SeekFrom::Start(commit_offset as u64))?;
+        wal.file.write_all(&(self.num_pages as u32).to_be_bytes())?;
+        if self.synchronous == Synchronous::Full {
+            wal.file.sync_all()?;
+        }
+        let spilled = handle.spilled.drain().collect::<Vec<_>>();
+        for (page_num, offset) in spilled {
+            wal.frames.entry(page_num).or_default().push(offset);
+        }
+        for (i, &page_num) in page_nums.iter().enumerate() {
+            let offset = start + i * WAL_FRAME_SIZE + WAL_FRAME_HEADER_SIZE;
+            wal.frames.entry(page_num).or_default().push(offset);
+        }
+
+        // This connection's read moves on to the commit it just made.
+        let read_length = handle.read_length.replace(end).unwrap_or(wal.length);
+        if let Some(reader) = wal.readers.iter_mut().find(|reader| **reader == read_length) {
+            *reader = end;
+        }
+        wal.length = end;
+        wal.num_pages = self.num_pages;
+        wal.commits += 1;
+        handle.num_pages = self.num_pages;
+        handle.commits = wal.commits;
+        handle.cached_commits = Some(wal.commits);
+        self.file_length = self.num_pages * PAGE_SIZE;
+
+        let num_frames = wal.length / WAL_FRAME_SIZE;
+        drop(wal);
+        // The commit is complete either way, so a checkpoint that fails is just tried again after
+        // the next one.
+        if self.wal_autocheckpoint > 0 && num_frames >= self.wal_autocheckpoint {
+            let _ = self.checkpoint();
+        }
+        Ok(())
+    }
+
+    /// Copy every page in the write-ahead log into the database file, and then empty the log.
+    /// Return the number of pages that were copied, which is 0 if not in WAL mode, if the database
+    /// is read-only, or if another connection is reading an older version of the database than the
+    /// file would then hold.
+    ///
+    /// If copying fails, the log is left as is, so the pages in it are still read from there.
+    pub(crate) fn checkpoint(&mut self) -> io::Result<usize> {
+        let handle = match &mut self.wal {
+            Some(handle) if !self.vfs.is_read_only() => handle,
+            _ => return Ok(0),
+        };
+        let mut wal = handle.shared.lock().unwrap();
+        let num_readers = wal.readers.len() - handle.read_length.is_some() as usize;
+        if num_readers > 0 || handle.read_length.is_some_and(|length| length != wal.length) {
+            return Ok(0);
+        }
+
+        // The commits must be durable before they are copied, since the log is emptied afterwards.
+        let sync = self.synchronous >= Synchronous::Normal;
+        if sync {
+            wal.file.sync_all()?;
+        }
+
+        let mut page = vec![0; PAGE_SIZE];
+        let frames = wal.frames.iter()
+            .map(|(&page_num, offsets)| (page_num, *offsets.last().unwrap()))
+            .collect::<Vec<_>>();
+        for &(page_num, offset) in &frames {
+            wal.file.seek(SeekFrom::Start(offset as u64))?;
+            wal.file.read_exact(&mut page)?;
+            self.vfs.write_page(page_num, &page)?;
+        }
+        self.map = None;
+        self.vfs.set_len(wal.num_pages * PAGE_SIZE)?;
+        if sync {
+            self.vfs.sync()?;
+        }
+
+        // If the process dies before the log is emptied, the checkpoint is just done again. Pages
+        // that were written to the log to make room in the cache follow the committed ones, so
+        // if there are any, the log is left as is.
+        if handle.spilled.is_empty() {
+            wal.file.set_len(0)?;
+            if sync {
+                wal.file.sync_all()?;
+            }
+            wal.length = 0;
+            if let Some(length) = handle.read_length.replace(0) {
+                let reader = wal.readers.iter_mut().find(|reader| **reader == length).unwrap();
+                *reader = 0;
+            }
+        }
+        wal.frames.clear();
+        debug!(pages = frames.len(), "checkpointed log");
+        Ok(frames.len())
+    }
+
+    /// Switch to WAL mode, starting with an empty log.
+    pub(crate) fn enable_wal(&mut self) -> io::Result<()> {
+        if self.wal.is_none() {
+            let (mut wal, _) = open_wal(&self.wal_path)?;
+            wal.num_pages = self.num_pages;
+            let mut handle = WalHandle::new(share_wal(&self.path, wal, self.vfs.as_ref()));
+            if self.lock > LockLevel::Unlocked {
+                handle.lock(LockLevel::Shared);
+            }
+            self.wal = Some(handle);
+        }
+        Ok(())
+    }
+
+    /// Switch out of WAL mode, copying the log into the database file and then removing it. This
+    /// can't be done while another connection in this process is using the log.
+    pub(crate) fn disable_wal(&mut self) -> Result<(), Error> {
+        if let Some(handle) = &self.wal {
+            if Arc::strong_count(&handle.shared) > 1 {
+                return Err(Error::Locked);
+            }
+            self.checkpoint()?;
+            self.wal = None;
+            fs::remove_file(&self.wal_path)?;
+            // The lock on the database file that the log kept is released with it.
+            self.vfs.unlock(LockLevel::Unlocked)?;
+            self.lock = LockLevel::Unlocked;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self, page_num: usize) -> io::Result<()> {
+        trace!(page_num, "wrote page to file");
+        self.vfs.write_page(page_num, &self.pages[&page_num].data)
+    }
+}
+
+
+/// Recover the database from the last session being interrupted, if it was, and return the length
+/// of its file, and its write-ahead log if it is in WAL mode. What was done to recover is added to
+/// `recovered`.
+fn recover(
+    vfs: &mut dyn Vfs,
+    journal_path: &str,
+    wal_path: &str,
+    recovered: &mut Vec<String>,
+) -> io::Result<(usize, Option<Wal>)> {
+    // If there is a journal, the last time the database was closed, it was interrupted while
+    // writing to the file.
+    if fs::metadata(journal_path).is_ok() {
+        recovered.push(replay_journal(vfs, journal_path)?);
+    }
+
+    // A partial page at the end of the file is from a write that never finished, so it holds
+    // nothing that was committed.
+    let mut file_length = vfs.len()?;
+    if !file_length.is_multiple_of(PAGE_SIZE) {
+        recovered.push(format!(
+            "removed a partial page of {} byte(s) from the end of the database file",
+            file_length % PAGE_SIZE,
+        ));
+        file_length -= file_length % PAGE_SIZE;
+        vfs.set_len(file_length)?;
+    }
+
+    // If there is a write-ahead log, the database is in WAL mode, and the pages in the log are
+    // newer than the ones in the file.
+    let wal = if let Ok(metadata) = fs::metadata(wal_path) {
+        let (mut wal, num_pages) = open_wal(wal_path)?;
+        if let Some(num_pages) = num_pages {
+            file_length = num_pages * PAGE_SIZE;
+        }
+        wal.num_pages = file_length / PAGE_SIZE;
+        let num_discarded = metadata.len() as usize - wal.length;
+        if num_discarded > 0 {
+            recovered.push(format!(
+                "discarded {} byte(s) of uncommitted changes from the write-ahead log",
+                num_discarded,
+            ));
+        }
+        Some(wal)
+    } else {
+        None
+    };
+
+    Ok((file_length, wal))
+}
+
+
+/// Open the write-ahead log, creating it if it doesn't exist, and return it along with the number
+/// of pages in the database as of its last commit, if it has one. Frames after the last commit are
+/// discarded, since the process died before the commit was complete.
+fn open_wal(wal_path: &str) -> io::Result<(Wal, Option<usize>)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(wal_path)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+
+    let mut wal = Wal {
+        file,
+        length: 0,
+        num_pages: 0,
+        frames: HashMap::new(),
+        commits: 0,
+        readers: Vec::new(),
+        is_writing: false,
+        lock: None,
+    };
+    let mut num_pages = None;
+    let mut uncommitted = Vec::new();
+    for (i, frame) in contents.chunks_exact(WAL_FRAME_SIZE).enumerate() {
+        let page_num = read_u32(frame, WAL_PAGE_NUM_OFFSET) as usize;
+        uncommitted.push((page_num, i * WAL_FRAME_SIZE + WAL_FRAME_HEADER_SIZE));
+        let db_num_pages = read_u32(frame, WAL_DB_NUM_PAGES_OFFSET) as usize;
+        if db_num_pages != 0 {
+            for (page_num, offset) in uncommitted.drain(..) {
+                wal.frames.entry(page_num).or_default().push(offset);
+            }
+            wal.length = (i + 1) * WAL_FRAME_SIZE;
+            num_pages = Some(db_num_pages);
+        }
+    }
+    wal.file.set_len(wal.length as u64)?;
+
+    Ok((wal, num_pages))
+}
+
+
+/// Restore the database file from its journal, which holds the original contents of each page
+/// that was being overwritten, and then remove the journal. Entries are only counted once they are
+/// complete, and pages are only written once their entries are counted, so entries past the count
+/// are ignored, and a journal that is missing counted entries is just removed. Return a
+/// description of what was done.
+fn replay_journal(vfs: &mut dyn Vfs, journal_path: &str) -> io::Result<String> {
+    let journal = fs::read(journal_path)?;
+    let mut description = String::from("removed an incomplete journal");
+    if journal.len() >= JOURNAL_HEADER_SIZE {
+        let num_pages = read_u32(&journal, JOURNAL_NUM_PAGES_OFFSET) as usize;
+        let num_entries = read_u32(&journal, JOURNAL_NUM_ENTRIES_OFFSET) as usize;
+        if journal.len() >= JOURNAL_HEADER_SIZE + num_entries * JOURNAL_ENTRY_SIZE {
+            let entries = journal[JOURNAL_HEADER_SIZE..].chunks(JOURNAL_ENTRY_SIZE);
+            for entry in entries.take(num_entries) {
+                let page_num = read_u32(entry, 0) as usize;
+                vfs.write_page(page_num, &entry[JOURNAL_PAGE_NUM_SIZE..])?;
+            }
+            // Pages that were added are removed.
+            vfs.set_len(num_pages * PAGE_SIZE)?;
+            vfs.sync()?;
+            description = format!(
+                "rolled back an interrupted write by restoring {} page(s) from the journal",
+                num_entries,
+            );
+        }
+    }
+
+    fs::remove_file(journal_path)?;
+    Ok(description)
+}
+
+
+/// Initialize the header of a new database, which has no rows or indexes.
+pub(crate) fn initialize_header(header: &mut [u8]) {
+    let magic = &mut header[HEADER_MAGIC_OFFSET..HEADER_MAGIC_OFFSET+HEADER_MAGIC_SIZE];
+    magic.copy_from_slice(HEADER_MAGIC);
+    write_u32(header, HEADER_FORMAT_VERSION_OFFSET, FORMAT_VERSION);
+    write_u32(header, HEADER_PAGE_SIZE_OFFSET, PAGE_SIZE as u32);
+    write_u32(header, HEADER_ROW_COUNT_OFFSET, 0);
+    write_u32(header, HEADER_ROOT_PAGE_OFFSET, TABLE_ROOT_PAGE_NUM as u32);
+}
+
+
+pub(crate) fn header_free_list(header: &[u8]) -> usize {
+    read_u32(header, HEADER_FREE_LIST_OFFSET) as usize
+}
+
+
+fn set_header_free_list(header: &mut [u8], page_num: usize) {
+    write_u32(header, HEADER_FREE_LIST_OFFSET, page_num as u32);
+}
+
+
+pub(crate) fn header_row_count(header: &[u8]) -> u32 {
+    read_u32(header, HEADER_ROW_COUNT_OFFSET)
+}
+
+
+pub(crate) fn set_header_row_count(header: &mut [u8], row_count: u32) {
+    write_u32(header, HEADER_ROW_COUNT_OFFSET, row_count);
+}
+
+
+/// Return the CRC-32 checksum of the bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+
+/// Return whether the page's checksum matches its contents.
+pub(crate) fn is_page_checksum_valid(page: &[u8]) -> bool {
+    read_u32(page, PAGE_CHECKSUM_OFFSET) == crc32(&page[..PAGE_CHECKSUM_OFFSET])
+}
+
+
+fn set_page_checksum(page: &mut [u8]) {
+    let checksum = crc32(&page[..PAGE_CHECKSUM_OFFSET]);
+    write_u32(page, PAGE_CHECKSUM_OFFSET, checksum);
+}
+
+
+pub(crate) fn header_max_id(header: &[u8]) -> u32 {
+    read_u32(header, HEADER_MAX_ID_OFFSET)
+}
+
+
+pub(crate) fn set_header_max_id(header: &mut [u8], max_id: u32) {
+    write_u32(header, HEADER_MAX_ID_OFFSET, max_id);
+}
+
+
+pub(crate) fn header_user_version(header: &[u8]) -> i32 {
+    read_u32(header, HEADER_USER_VERSION_OFFSET) as i32
+}
+
+
+pub(crate) fn set_header_user_version(header: &mut [u8], user_version: i32) {
+    write_u32(header, HEADER_USER_VERSION_OFFSET, user_version as u32);
+}
+
+
+/// Return the indexes described in the header.
+pub(crate) fn read_indexes(header: &[u8]) -> Vec<Index> {
+    let num_indexes = read_u32(header, HEADER_NUM_INDEXES_OFFSET) as usize;
+    (0..num_indexes)
+        .map(|i| {
+            let offset = HEADER_INDEXES_OFFSET + i * INDEX_ENTRY_SIZE;
+            let name = &header[offset..offset+INDEX_NAME_SIZE];
+            let name_length = name.iter().position(|&b| b == 0).unwrap_or(INDEX_NAME_SIZE);
+            Index {
+                name: String::from_utf8_lossy(&name[..name_length]).into_owned(),
+                column: read_u32(header, offset + INDEX_NAME_SIZE) as usize,
+                root_page_num: read_u32(header, offset + INDEX_NAME_SIZE + INDEX_COLUMN_SIZE)
+                    as usize,
+                // The expressions and WHERE clauses of indexes are on the schema page.
+                expression: None,
+                where_clause: None,
+            }
+        })
+        .collect()
+}
+
+
+/// Add an index to the ones described in the header. Its name must fit in INDEX_NAME_SIZE bytes.
+pub(crate) fn append_index(header: &mut [u8], index: &Index) {
+    let num_indexes = read_u32(header, HEADER_NUM_INDEXES_OFFSET) as usize;
+    let offset = HEADER_INDEXES_OFFSET + num_indexes * INDEX_ENTRY_SIZE;
+    header[offset..offset+INDEX_NAME_SIZE].fill(0);
+    header[offset..offset+index.name.len()].copy_from_slice(index.name.as_bytes());
+    write_u32(header, offset + INDEX_NAME_SIZE, index.column as u32);
+    write_u32(header, offset + INDEX_NAME_SIZE + INDEX_COLUMN_SIZE, index.root_page_num as u32);
+    write_u32(header, HEADER_NUM_INDEXES_OFFSET, (num_indexes + 1) as u32);
+}
+
+
+/// What a schema page describes.
+#[derive(Default)]
+pub(crate) struct SchemaPage {
+    pub(crate) views: Vec<View>,
+    pub(crate) triggers: Vec<Trigger>,
+    pub(crate) stats: Option<Stats>,
+    /// The name and the text of the WHERE clause of each partial index.
+    pub(crate) where_clauses: Vec<(String, String)>,
+    /// The name and the text of the expression of each index on an expression.
+    pub(crate) expressions: Vec<(String, String)>,
+}
+
+
+/// Return the views, triggers, statistics and indexes' WHERE clauses and expressions described in
+/// a schema page.
+pub(crate) fn read_schema_page(page: &[u8]) -> SchemaPage {
+    let mut reader = SchemaReader { page, offset: 0 };
+    let views = (0..reader.next_count())
+        .map(|_| View { name: reader.next_string(), text: reader.next_string() })
+        .collect();
+    let triggers = (0..reader.next_count())
+        .map(|_| Trigger {
+            name: reader.next_string(),
+            action: match reader.next_string().as_str() {
+                "delete" => Action::Delete,
+                _ => Action::Insert,
+            },
+            body: reader.next_string(),
+        })
+        .collect();
+    let stats = (reader.next_count() > 0).then(|| Stats {
+        num_rows: reader.next_count(),
+        indexes: (0..reader.next_count())
+            .map(|_| IndexStats {
+                name: reader.next_string(),
+                num_distinct: reader.next_count(),
+                samples: (0..reader.next_count()).map(|_| reader.next_bytes().to_vec()).collect(),
+            })
+            .collect(),
+    });
+    let where_clauses =
+        (0..reader.next_count()).map(|_| (reader.next_string(), reader.next_string())).collect();
+    let expressions =
+        (0..reader.next_count()).map(|_| (reader.next_string(), reader.next_string())).collect();
+    SchemaPage { views, triggers, stats, where_clauses, expressions }
+}
+
+
+/// Reads the counts and strings in a schema page, in order.
+struct SchemaReader<'a> {
+    page: &'a [u8],
+    offset: usize,
+}
+
+
+impl SchemaReader<'_> {
+    fn next_count(&mut self) -> usize {
+        self.offset += SCHEMA_COUNT_SIZE;
+        read_u32(self.page, self.offset - SCHEMA_COUNT_SIZE) as usize
+    }
+
+    fn next_string(&mut self) -> String {
+        String::from_utf8_lossy(self.next_bytes()).into_owned()
+    }
+
+    fn next_bytes(&mut self) -> &[u8] {
+        let length = read_u32(self.page, self.offset) as usize;
+        let start = self.offset + SCHEMA_STRING_LENGTH_SIZE;
+        self.offset = start + length;
+        &self.page[start..self.offset]
+    }
+}
+
+
+/// Return the contents of a schema page that describes the views, triggers and statistics, and
+/// the WHERE clauses and expressions of the given indexes that have them, or None if they don't
+/// fit in a page.
+pub(crate) fn encode_schema_page(
+    views: &[View], triggers: &[Trigger], stats: Option<&Stats>, indexes: &[Index]
+) -> Option<Vec<u8>> {
+    let mut contents = Vec::new();
+    contents.extend_from_slice(&(views.len() as u32).to_be_bytes());
+    for view in views {
+        push_schema_string(&mut contents, &view.name);
+        push_schema_string(&mut contents, &view.text);
+    }
+    contents.extend_from_slice(&(triggers.len() as u32).to_be_bytes());
+    for trigger in triggers {
+        push_schema_string(&mut contents, &trigger.name);
+        let action = match trigger.action {
+            Action::Insert => "insert",
+            Action::Delete => "delete",
+        };
+        push_schema_string(&mut contents, action);
+        push_schema_string(&mut contents, &trigger.body);
+    }
+    contents.extend_from_slice(&u32::from(stats.is_some()).to_be_bytes());
+    if let Some(stats) = stats {
+        contents.extend_from_slice(&(stats.num_rows as u32).to_be_bytes());
+        contents.extend_from_slice(&(stats.indexes.len() as u32).to_be_bytes());
+        for index in &stats.indexes {
+            push_schema_string(&mut contents, &index.name);
+            contents.extend_from_slice(&(index.num_distinct as u32).to_be_bytes());
+            contents.extend_from_slice(&(index.samples.len() as u32).to_be_bytes());
+            for sample in &index.samples {
+                push_schema_bytes(&mut contents, sample);
+            }
+        }
+    }
+    let where_clauses: Vec<(&String, &String)> = indexes.iter()
+        .filter_map(|index| index.where_clause.as_ref().map(|(text, _)| (&index.name, text)))
+        .collect();
+    contents.extend_from_slice(&(where_clauses.len() as u32).to_be_bytes());
+    for (name, text) in where_clauses {
+        push_schema_string(&mut contents, name);
+        push_schema_string(&mut contents, text);
+    }
+    let expressions: Vec<(&String, &String)> = indexes.iter()
+        .filter_map(|index| index.expression.as_ref().map(|(text, _)| (&index.name, text)))
+        .collect();
+    contents.extend_from_slice(&(expressions.len() as u32).to_be_bytes());
+    for (name, text) in expressions {
+        push_schema_string(&mut contents, name);
+        push_schema_string(&mut contents, text);
+    }
+    (contents.len() <= PAGE_CHECKSUM_OFFSET).then_some(contents)
+}
+
+
+fn push_schema_string(contents: &mut Vec<u8>, string: &str) {
+    push_schema_bytes(contents, string.as_bytes());
+}
+
+
+fn push_schema_bytes(contents: &mut Vec<u8>, bytes: &[u8]) {
+    contents.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    contents.extend_from_slice(bytes);
+}
+
+
+pub(crate) fn read_u32(source: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&source[offset..offset+4]);
+    u32::from_be_bytes(bytes)
+}
+
+
+pub(crate) fn write_u32(destination: &mut [u8], offset: usize, value: u32) {
+    destination[offset..offset+4].copy_from_slice(&value.to_be_bytes());
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::fs::{File, OpenOptions};
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::btree::*;
+    use crate::error::Error;
+    use crate::exec::*;
+    use crate::pager::*;
+    use crate::parser::*;
+    use crate::sql::*;
+    use crate::testing::*;
+
+    #[test]
+    fn many_pages() {
+        let path = &temp_path("testdb-many-pages.mysql");
+        let mut table = db_open_new(path);
+        table.pager.set_cache_size(8);
+
+        // The database isn't limited in size, and only the cached pages are kept in memory.
+        let mut nrows = 0;
+        while table.pager.num_pages <= 200 {
+            nrows += 1;
+            let command = format!("insert into users values ({}, 'jdoe', 'jdoe@x')", nrows);
+            assert!(execute_statement(&prepare_statement(&command).unwrap(), &mut table).is_ok());
+            assert!(table.pager.pages.len() <= 8);
+        }
+        assert_eq!(table_num_rows(&mut table).unwrap(), nrows);
+        drop(table);
+
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), (1..=nrows as u32).collect::<Vec<_>>());
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dirty_pages() {
+        let path = &temp_path("testdb-dirty.mysql");
+        let mut table = db_open_new(path);
+        run(&mut table, "begin").unwrap();
+        run(&mut table, "insert into users values (1, 'alice', 'a@x'), (2, 'bob', 'b@x')").unwrap();
+        assert_eq!(table.pager.dirty_pages(), [0, 1]);
+        run(&mut table, "commit").unwrap();
+        assert!(table.pager.dirty_pages().is_empty());
+        drop(table);
+
+        // Reading pages doesn't make them dirty, so nothing is written.
+        let mut table = db_open(path).unwrap();
+        run(&mut table, "select").unwrap();
+        run(&mut table, "pragma journal_mode = wal").unwrap();
+        assert!(table.pager.dirty_pages().is_empty());
+        run(&mut table, "select * from users where id = 2").unwrap();
+        assert_eq!(wal_length(&table), 0);
+
+        // Only the pages that changed are written.
+        run(&mut table, "delete 2").unwrap();
+        assert!(table.pager.dirty_pages().is_empty());
+        assert_eq!(wal_length(&table), 2 * WAL_FRAME_SIZE);
+    }
+
+    #[test]
+    fn memory_mapped_io() {
+        let path = &temp_path("testdb-mmap.mysql");
+        let mut table = db_open_new(path);
+        for i in 1..200 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            run(&mut table, &command).unwrap();
+        }
+        drop(table);
+
+        // Only the start of the file is mapped, and the rest is read with read().
+        let mut table = db_open(path).unwrap();
+        run(&mut table, "pragma mmap_size = 16384").unwrap();
+        run(&mut table, "pragma cache_size = 4").unwrap();
+        assert_eq!(check_table(&mut table), (1..200).collect::<Vec<_>>());
+        assert_eq!(table.pager.map.as_ref().unwrap().len, 4 * PAGE_SIZE);
+
+        // Writes are seen through the mapping, and it is removed before the file is truncated.
+        run(&mut table, "pragma mmap_size = 1000000").unwrap();
+        run(&mut table, "pragma journal_mode = wal").unwrap();
+        for i in 1..150 {
+            run(&mut table, &format!("delete {}", i)).unwrap();
+        }
+        assert!(table.pager.checkpoint().unwrap() > 0);
+        assert!(table.pager.map.is_none());
+        assert_eq!(check_table(&mut table), (150..200).collect::<Vec<_>>());
+        assert!(table.pager.map.is_some());
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        run(&mut table, "vacuum").unwrap();
+        assert_eq!(table.pager.mmap_size, 1000000);
+        assert_eq!(check_table(&mut table), (150..200).collect::<Vec<_>>());
+
+        let statement = prepare_statement("pragma mmap_size = lots").unwrap();
+        assert_eq!(
+            execute_statement(&statement, &mut table),
+            Err(Error::Invalid("invalid number of bytes")),
+        );
+    }
+
+    #[test]
+    fn locking() {
+        let path = &temp_path("testdb-lock.mysql");
+        let mut writer = db_open_new(path);
+        let mut reader = db_open(path).unwrap();
+
+        // Other connections can read while one of them changes the table, but not change it too.
+        assert!(run(&mut writer, "begin").is_ok());
+        assert!(run(&mut writer, "insert into users values (1, 'a', 'a@x')").is_ok());
+        assert!(run(&mut reader, "select").is_ok());
+        assert!(check_table(&mut reader).is_empty());
+        let insert = "insert into users values (2, 'b', 'b@x')";
+        assert_eq!(run(&mut reader, insert), Err(Error::Locked));
+        assert_eq!(reader.pager.lock, LockLevel::Unlocked);
+
+        // The changes can't be written while another connection is reading, and no connection can
+        // start reading until they are.
+        assert!(run(&mut reader, "begin").is_ok());
+        assert!(run(&mut reader, "select").is_ok());
+        let mut other = db_open(path).unwrap();
+        assert!(run(&mut other, "select").is_ok());
+        assert!(check_table(&mut other).is_empty());
+        drop(other);
+        assert_eq!(run(&mut writer, "commit"), Err(Error::Locked));
+        assert!(db_open(path).is_err_and(|e| e == Error::Locked));
+        assert!(run(&mut reader, "commit").is_ok());
+        assert!(run(&mut writer, "commit").is_ok());
+        assert_eq!(writer.pager.lock, LockLevel::Unlocked);
+
+        // Each connection sees the other's changes the next time it takes a lock.
+        assert!(run(&mut reader, "select").is_ok());
+        assert_eq!(check_table(&mut reader), vec![1]);
+        assert!(run(&mut reader, "create index u on users(username)").is_ok());
+        assert!(run(&mut reader, "vacuum").is_ok());
+        assert!(run(&mut writer, insert).is_ok());
+        assert_eq!(writer.indexes.len(), 1);
+        assert_eq!(check_table(&mut writer), vec![1, 2]);
+        check_index(&mut writer, 0);
+        assert!(integrity_check(&mut writer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn wal_readers() {
+        let path = &temp_path("testdb-wal-readers.mysql");
+        let mut writer = db_open_new(path);
+        assert!(run(&mut writer, "insert into users values (1, 'a', 'a@x')").is_ok());
+        assert!(run(&mut writer, "pragma journal_mode = wal").is_ok());
+        let mut reader = db_open(path).unwrap();
+        assert!(reader.pager.wal.is_some());
+
+        // A read sees the database as it was when it began, while another connection commits.
+        assert!(run(&mut reader, "begin").is_ok());
+        assert!(run(&mut reader, "select").is_ok());
+        for i in 2..50 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            assert!(run(&mut writer, &command).is_ok());
+        }
+        assert_eq!(check_table(&mut reader), vec![1]);
+        assert_eq!(check_table(&mut writer), (1..50).collect::<Vec<_>>());
+        let insert = "insert into users values (50, 'b', 'b@x')";
+        assert_eq!(run(&mut reader, insert), Err(Error::Locked));
+
+        // The log can't be copied into the database file until every read of it has ended.
+        assert_eq!(writer.pager.checkpoint().unwrap(), 0);
+        assert!(run(&mut reader, "commit").is_ok());
+        assert!(run(&mut reader, "select").is_ok());
+        assert_eq!(check_table(&mut reader), (1..50).collect::<Vec<_>>());
+        assert!(writer.pager.checkpoint().unwrap() > 0);
+
+        // Only one connection can change the database at a time.
+        assert!(run(&mut reader, "begin").is_ok());
+        assert!(run(&mut reader, insert).is_ok());
+        assert_eq!(run(&mut writer, "delete 1"), Err(Error::Locked));
+        assert!(run(&mut reader, "commit").is_ok());
+        assert!(run(&mut writer, "delete 1").is_ok());
+        assert!(run(&mut reader, "select").is_ok());
+        assert_eq!(check_table(&mut reader), (2..51).collect::<Vec<_>>());
+        assert!(integrity_check(&mut reader).unwrap().is_empty());
+
+        // Pages that a transaction writes to the log to make room in the cache are never seen by
+        // other connections, whether it is rolled back or committed.
+        assert!(run(&mut writer, "pragma cache_size = 3").is_ok());
+        for end in ["rollback", "commit"] {
+            assert!(run(&mut writer, "begin").is_ok());
+            for i in 100..300 {
+                let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+                assert!(run(&mut writer, &command).is_ok());
+            }
+            assert!(writer.pager.has_spilled_pages());
+            assert!(run(&mut reader, "select").is_ok());
+            assert_eq!(check_table(&mut reader), (2..51).collect::<Vec<_>>());
+            assert!(run(&mut writer, end).is_ok());
+        }
+        assert!(run(&mut reader, "select").is_ok());
+        assert_eq!(check_table(&mut reader), (2..51).chain(100..300).collect::<Vec<_>>());
+        assert!(integrity_check(&mut reader).unwrap().is_empty());
+
+        // Leaving WAL mode has to wait until no other connection uses the log.
+        let command = "pragma journal_mode = delete";
+        assert_eq!(run(&mut writer, command), Err(Error::Locked));
+        drop(reader);
+        assert!(run(&mut writer, command).is_ok());
+        let mut reader = db_open(path).unwrap();
+        assert!(reader.pager.wal.is_none());
+        assert_eq!(check_table(&mut reader), (2..51).chain(100..300).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn busy_timeout() {
+        let path = &temp_path("testdb-busy.mysql");
+        let mut table = db_open_new(path);
+        assert!(run(&mut table, "pragma busy_timeout = 30").is_ok());
+        let command = "pragma busy_timeout = forever";
+        assert_eq!(run(&mut table, command), Err(Error::Invalid("invalid number of milliseconds")));
+
+        // Another connection writes for a while, and then commits.
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            let mut table = db_open(&writer_path).unwrap();
+            assert!(run(&mut table, "pragma busy_timeout = 5000").is_ok());
+            assert!(run(&mut table, "begin").is_ok());
+            assert!(run(&mut table, "insert into users values (1, 'a', 'a@x')").is_ok());
+            sender.send(()).unwrap();
+            thread::sleep(Duration::from_millis(300));
+            assert!(run(&mut table, "commit").is_ok());
+        });
+        receiver.recv().unwrap();
+
+        let start = std::time::Instant::now();
+        let insert = "insert into users values (2, 'b', 'b@x')";
+        assert_eq!(run(&mut table, insert), Err(Error::Locked));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert!(run(&mut table, "pragma busy_timeout = 5000").is_ok());
+        assert!(run(&mut table, insert).is_ok());
+        assert_eq!(check_table(&mut table), vec![1, 2]);
+        writer.join().unwrap();
+
+        // A read that has to evict pages from a small cache keeps its lock, so that another
+        // connection's commit waits for it to end, rather than changing the pages under it.
+        assert!(run(&mut table, "begin").is_ok());
+        for id in 3..3000 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", id, id);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        assert!(run(&mut table, "commit").is_ok());
+        assert!(run(&mut table, "pragma cache_size = 4").is_ok());
+        assert!(run(&mut table, "pragma busy_timeout = 50").is_ok());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            let mut table = db_open(&writer_path).unwrap();
+            assert!(run(&mut table, "pragma busy_timeout = 5000").is_ok());
+            assert!(run(&mut table, "begin").is_ok());
+            for id in 1..8 {
+                assert!(run(&mut table, &format!("delete {}", id)).is_ok());
+            }
+            sender.send(()).unwrap();
+            assert!(run(&mut table, "commit").is_ok());
+        });
+        receiver.recv().unwrap();
+        let start = std::time::Instant::now();
+        let rows = query_rows(&prepare_statement("select").unwrap(), &mut table).unwrap();
+        let ids: Vec<u32> = rows.iter().map(Row::id).collect();
+        let all: Vec<u32> = (1..3000).collect();
+        assert!(ids == all || ids == all[7..], "{:?}", &ids[..10]);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        writer.join().unwrap();
+        assert_eq!(check_table(&mut table), all[7..]);
+    }
+
+    #[test]
+    fn memory_database() {
+        let mut table = db_open(MEMORY_DATABASE_PATH).unwrap();
+        for command in ["create index u on users(username)", "pragma cache_size = 4"] {
+            assert!(run(&mut table, command).is_ok());
+        }
+
+        // Pages that are evicted from the cache are kept in memory instead of in a file.
+        for i in 1..200 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        assert!(table.pager.vfs.len().unwrap() > 4 * PAGE_SIZE);
+        assert!(run(&mut table, "begin").is_ok());
+        for i in 1..150 {
+            assert!(run(&mut table, &format!("delete {}", i)).is_ok());
+        }
+        assert!(run(&mut table, "rollback").is_ok());
+        assert_eq!(check_table(&mut table), (1..200).collect::<Vec<_>>());
+        check_index(&mut table, 0);
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+
+        assert!(run(&mut table, "vacuum").is_ok());
+        assert_eq!(check_table(&mut table), (1..200).collect::<Vec<_>>());
+        assert_eq!(
+            run(&mut table, "pragma journal_mode = wal"),
+            Err(Error::Invalid("cannot change the journal mode of an in-memory database")),
+        );
+        drop(table);
+        for suffix in ["", "-journal", "-wal", "-vacuum"] {
+            assert!(fs::metadata(format!("{}{}", MEMORY_DATABASE_PATH, suffix)).is_err());
+        }
+
+        // Each one is separate, and starts out empty.
+        let mut table = db_open(MEMORY_DATABASE_PATH).unwrap();
+        assert!(check_table(&mut table).is_empty());
+    }
+
+    #[test]
+    fn cache_eviction() {
+        for journal_mode in ["delete", "wal"] {
+            let path = temp_path(&format!("testdb-eviction-{}.mysql", journal_mode));
+            let mut table = db_open_new(&path);
+            let insert = |table: &mut Table, ids: std::ops::Range<u32>| {
+                let rows: Vec<String> = ids.map(|i| format!("({}, 'u{}', 'e')", i, i)).collect();
+                run(table, &format!("insert into users values {}", rows.join(", "))).unwrap();
+            };
+            run(&mut table, &format!("pragma journal_mode = {}", journal_mode)).unwrap();
+            run(&mut table, "create index u on users(username)").unwrap();
+            insert(&mut table, 1..51);
+            drop(table);
+
+            // Changed pages that are evicted are written to disk before they're committed, but
+            // they can still be rolled back.
+            let mut table = db_open(&path).unwrap();
+            run(&mut table, "pragma cache_size = 16").unwrap();
+            run(&mut table, "begin").unwrap();
+            insert(&mut table, 51..301);
+            assert!(table.pager.has_spilled_pages());
+            run(&mut table, "savepoint a").unwrap();
+            for i in 1..200 {
+                run(&mut table, &format!("delete {}", i)).unwrap();
+            }
+            run(&mut table, "rollback to a").unwrap();
+            assert_eq!(check_table(&mut table), (1..301).collect::<Vec<_>>());
+            check_index(&mut table, 0);
+            run(&mut table, "rollback").unwrap();
+            assert_eq!(check_table(&mut table), (1..51).collect::<Vec<_>>());
+            check_index(&mut table, 0);
+            assert!(integrity_check(&mut table).unwrap().is_empty());
+
+            run(&mut table, "begin").unwrap();
+            insert(&mut table, 51..301);
+            run(&mut table, "commit").unwrap();
+            drop(table);
+            let mut table = db_open(&path).unwrap();
+            assert!(table.pager.recovered.is_empty());
+            assert_eq!(check_table(&mut table), (1..301).collect::<Vec<_>>());
+            assert!(integrity_check(&mut table).unwrap().is_empty());
+
+            // If the process dies before the changes are committed, the pages that were written
+            // early are undone.
+            run(&mut table, "pragma cache_size = 16").unwrap();
+            run(&mut table, "begin").unwrap();
+            insert(&mut table, 301..501);
+            crash(table);
+            let mut table = db_open(&path).unwrap();
+            assert_eq!(table.pager.recovered.len(), 1);
+            assert_eq!(check_table(&mut table), (1..301).collect::<Vec<_>>());
+            check_index(&mut table, 0);
+            assert!(integrity_check(&mut table).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn journal_recovery() {
+        let path = &temp_path("testdb-journal.mysql");
+        let journal_path = format!("{}-journal", path);
+        let mut table = db_open_new(path);
+        run(&mut table, "insert into users values (1, 'alice', 'a@x'), (2, 'bob', 'b@x')").unwrap();
+        drop(table);
+        assert!(fs::metadata(&journal_path).is_err());
+
+        // Simulate the process dying after only some of the changed pages have been written.
+        let mut table = db_open(path).unwrap();
+        run(&mut table, "begin").unwrap();
+        run(&mut table, "create index u on users(username)").unwrap();
+        for i in 3..40 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            run(&mut table, &command).unwrap();
+        }
+        run(&mut table, "delete 1").unwrap();
+        let dirty = table.pager.dirty_pages();
+        assert!(dirty.len() > 2);
+        table.pager.write_journal(&dirty).unwrap();
+        table.pager.flush(dirty[0]).unwrap();
+        table.pager.flush(dirty[dirty.len() - 1]).unwrap();
+        crash(table);
+        assert!(fs::metadata(&journal_path).is_ok());
+
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), vec![1, 2]);
+        assert!(table.indexes.is_empty());
+        assert!(fs::metadata(&journal_path).is_err());
+        assert_eq!(table.pager.recovered.len(), 1);
+        assert!(table.pager.recovered[0].starts_with("rolled back an interrupted write"));
+        drop(table);
+
+        // If the journal is incomplete, the database file was never written to.
+        fs::write(&journal_path, [0, 0, 0, 3, 0, 0, 0, 1, 0, 0]).unwrap();
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), vec![1, 2]);
+        assert!(fs::metadata(&journal_path).is_err());
+        assert_eq!(table.pager.recovered, ["removed an incomplete journal"]);
+        drop(table);
+
+        // So is a partial page at the end of the file.
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[1; 100]).unwrap();
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), vec![1, 2]);
+        assert_eq!(fs::metadata(path).unwrap().len() as usize, 2 * PAGE_SIZE);
+        assert_eq!(
+            table.pager.recovered,
+            ["removed a partial page of 100 byte(s) from the end of the database file"]
+        );
+        drop(table);
+        assert!(db_open(path).unwrap().pager.recovered.is_empty());
+    }
+
+    #[test]
+    fn wal_mode() {
+        let path = &temp_path("testdb-wal.mysql");
+        let wal_path = format!("{}-wal", path);
+        let mut table = db_open_new(path);
+        assert!(run(&mut table, "insert into users values (1, 'alice', 'a@x')").is_ok());
+        assert!(run(&mut table, "PRAGMA journal_mode = WAL").is_ok());
+        let file_length = fs::metadata(path).unwrap().len();
+        assert!(run(&mut table, "create index u on users(username)").is_ok());
+        for i in 2..40 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        for command in ["begin", "delete 1", "pragma journal_mode = delete", "rollback"] {
+            let _ = run(&mut table, command);
+        }
+
+        // Commits are in the log as soon as they happen, and none of them are in the database file
+        // yet.
+        crash(table);
+        assert_eq!(fs::metadata(path).unwrap().len(), file_length);
+        let mut table = db_open(path).unwrap();
+        assert!(table.pager.wal.is_some());
+        assert_eq!(check_table(&mut table), (1..40).collect::<Vec<u32>>());
+        check_index(&mut table, 0);
+        drop(table);
+
+        // A commit that was not completely written is ignored.
+        let mut wal = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        let wal_length = wal.metadata().unwrap().len();
+        wal.write_all(&[0; WAL_FRAME_SIZE]).unwrap();
+        wal.write_all(&[0; 100]).unwrap();
+        let mut table = db_open(path).unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), wal_length);
+        let description = format!(
+            "discarded {} byte(s) of uncommitted changes from the write-ahead log",
+            WAL_FRAME_SIZE + 100,
+        );
+        assert_eq!(table.pager.recovered, [description]);
+        assert!(run(&mut table, "delete 1").is_ok());
+
+        // Leaving WAL mode copies the log into the database file.
+        assert!(run(&mut table, "pragma journal_mode(delete)").is_ok());
+        assert!(fs::metadata(&wal_path).is_err());
+        assert!(run(&mut table, "pragma journal_mode").is_ok());
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        assert!(table.pager.wal.is_none());
+        assert_eq!(check_table(&mut table), (2..40).collect::<Vec<u32>>());
+        check_index(&mut table, 0);
+
+        assert!(run(&mut table, "begin").is_ok());
+        let error = Error::Invalid("cannot change the journal mode within a transaction");
+        assert_eq!(run(&mut table, "pragma journal_mode = wal"), Err(error));
+        assert!(run(&mut table, "commit").is_ok());
+        assert_eq!(
+            run(&mut table, "pragma journal_mode = memory"),
+            Err(Error::Invalid("unknown journal mode")),
+        );
+        assert_eq!(run(&mut table, "pragma foreign_keys"), Err(Error::Invalid("unknown pragma")));
+        assert!(run(&mut table, "pragma wal_checkpoint").is_ok());
+        for command in ["pragma", "pragma journal_mode =", "pragma journal_mode = wal x"] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn wal_checkpoints() {
+        let path = &temp_path("testdb-checkpoint.mysql");
+        let wal_path = format!("{}-wal", path);
+        let mut table = db_open_new(path);
+        assert_eq!(table.pager.wal_autocheckpoint, DEFAULT_WAL_AUTOCHECKPOINT);
+        for command in ["pragma journal_mode = wal", "pragma wal_autocheckpoint = 0"] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        for i in 1..20 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            assert!(run(&mut table, &command).is_ok());
+        }
+        let file_length = fs::metadata(&wal_path).unwrap().len() as usize;
+        assert_eq!(file_length, wal_length(&table));
+        assert!(file_length >= 19 * WAL_FRAME_SIZE);
+
+        // A manual checkpoint copies each page once, however many times it is in the log.
+        let num_pages = table.pager.num_pages;
+        assert_eq!(table.pager.checkpoint().unwrap(), num_pages);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        assert_eq!(fs::metadata(path).unwrap().len() as usize, num_pages * PAGE_SIZE);
+        assert!(run(&mut table, "pragma wal_checkpoint").is_ok());
+
+        // With automatic checkpoints, the log never reaches the limit after a commit.
+        assert!(run(&mut table, "pragma wal_autocheckpoint(4)").is_ok());
+        for i in 20..60 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            assert!(run(&mut table, &command).is_ok());
+            assert!(wal_length(&table) < 4 * WAL_FRAME_SIZE);
+        }
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), (1..60).collect::<Vec<u32>>());
+
+        let error = Error::Invalid("invalid number of pages");
+        assert_eq!(run(&mut table, "pragma wal_autocheckpoint = x"), Err(error));
+        let error = Error::Invalid("wal_checkpoint does not take a value");
+        assert_eq!(run(&mut table, "pragma wal_checkpoint = 1"), Err(error));
+    }
+
+    #[test]
+    fn synchronous_setting() {
+        let path = &temp_path("testdb-synchronous.mysql");
+        let mut table = db_open_new(path);
+        assert_eq!(table.pager.synchronous, Synchronous::Full);
+        assert!(run(&mut table, "pragma synchronous = NORMAL").is_ok());
+        assert_eq!(table.pager.synchronous, Synchronous::Normal);
+        assert!(run(&mut table, "pragma synchronous = 0").is_ok());
+        assert_eq!(table.pager.synchronous, Synchronous::Off);
+        let error = Error::Invalid("unknown synchronous setting");
+        assert_eq!(run(&mut table, "pragma synchronous = 3"), Err(error));
+        assert_eq!(table.pager.synchronous, Synchronous::Off);
+
+        // Without syncing, writes still reach the file, just not necessarily the disk.
+        for command in [
+            "insert into users values (1, 'alice', 'a@x')",
+            "pragma journal_mode = wal",
+            "insert into users values (2, 'bob', 'b@x')",
+            "pragma wal_checkpoint",
+            "insert into users values (3, 'carol', 'c@x')",
+        ] {
+            assert!(run(&mut table, command).is_ok(), "{}", command);
+        }
+        drop(table);
+        let mut table = db_open(path).unwrap();
+        assert_eq!(check_table(&mut table), vec![1, 2, 3]);
+        assert_eq!(table.pager.synchronous, Synchronous::Full);
+    }
+
+    #[test]
+    fn file_header() {
+        let path = &temp_path("testdb-header.mysql");
+        let mut table = db_open_new(path);
+        let insert = prepare_statement("insert into users values (1, 'a', 'b'), (2, 'c', 'd')");
+        assert!(execute_statement(&insert.unwrap(), &mut table).is_ok());
+        assert!(execute_statement(&prepare_statement("delete 1").unwrap(), &mut table).is_ok());
+        drop(table);
+
+        let mut header = vec![0; PAGE_SIZE];
+        File::open(path).unwrap().read_exact(&mut header).unwrap();
+        assert_eq!(&header[..HEADER_MAGIC_SIZE], HEADER_MAGIC);
+        assert_eq!(read_u32(&header, HEADER_FORMAT_VERSION_OFFSET), FORMAT_VERSION);
+        assert_eq!(read_u32(&header, HEADER_PAGE_SIZE_OFFSET) as usize, PAGE_SIZE);
+        assert_eq!(header_row_count(&header), 1);
+        assert_eq!(read_u32(&header, HEADER_ROOT_PAGE_OFFSET) as usize, TABLE_ROOT_PAGE_NUM);
+
+        let write_header = |offset: usize, bytes: &[u8]| {
+            let mut file = OpenOptions::new().write(true).open(path).unwrap();
+            file.seek(SeekFrom::Start(offset as u64)).unwrap();
+            file.write_all(bytes).unwrap();
+        };
+        write_header(HEADER_FORMAT_VERSION_OFFSET, &(FORMAT_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            db_open(path).err(),
+            Some(Error::Corrupt("unsupported database format version")),
+        );
+        write_header(HEADER_FORMAT_VERSION_OFFSET, &FORMAT_VERSION.to_be_bytes());
+        write_header(HEADER_PAGE_SIZE_OFFSET, &1024u32.to_be_bytes());
+        assert_eq!(db_open(path).err(), Some(Error::Corrupt("unsupported database page size")));
+        write_header(HEADER_PAGE_SIZE_OFFSET, &(PAGE_SIZE as u32).to_be_bytes());
+        assert_eq!(check_table(&mut db_open(path).unwrap()), vec![2]);
+
+        // A file that isn't a database is left alone, even if it isn't a whole number of pages.
+        let text = "id,username,email\n1,alice,alice@example.com\n";
+        fs::write(path, text).unwrap();
+        assert_eq!(db_open(path).err(), Some(Error::Corrupt("file is not a database")));
+        assert_eq!(fs::read_to_string(path).unwrap(), text);
+        fs::write(path, "a").unwrap();
+        assert_eq!(db_open(path).err(), Some(Error::Corrupt("file is not a database")));
+    }
+
+    #[test]
+    fn free_pages_are_reused() {
+        let path = &temp_path("testdb-free-list.mysql");
+        let mut table = db_open_new(path);
+        run(&mut table, "create index u on users(username)").unwrap();
+        for i in 1..100 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            run(&mut table, &command).unwrap();
+        }
+        let num_pages = table.pager.num_pages;
+        for i in 1..100 {
+            run(&mut table, &format!("delete {}", i)).unwrap();
+        }
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        assert_eq!(table.pager.num_pages, num_pages);
+        assert_ne!(header_free_list(table.pager.get_page_ref(HEADER_PAGE_NUM).unwrap()), 0);
+        drop(table);
+
+        // The free list survives reopening, and is used up before any new pages are added.
+        let mut table = db_open(path).unwrap();
+        for i in 100..199 {
+            let command = format!("insert into users values ({}, 'user{}', 'e')", i, i);
+            run(&mut table, &command).unwrap();
+        }
+        assert!(integrity_check(&mut table).unwrap().is_empty());
+        assert_eq!(table.pager.num_pages, num_pages);
+        assert_eq!(check_table(&mut table), (100..199).collect::<Vec<u32>>());
+        check_index(&mut table, 0);
+    }
+}
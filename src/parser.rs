@@ -0,0 +1,2328 @@
+//! Parsing the tokens of a statement into a `Statement`, by recursive descent. Each function
+//! parses one part of the grammar, starting at the token at the given position and moving the
+//! position past it, and returns an error saying what was expected if the tokens there aren't
+//! that part.
+
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use crate::btree::Trigger;
+use crate::error::ParseError;
+use crate::exec::Action;
+use crate::lexer::{Token, TokenKind, tokenize, tokens_text};
+use crate::pager::INDEX_NAME_SIZE;
+use crate::sql::{
+    BinaryOperator, COLUMNS, ColumnType, Comparison, Condition, CreateIndex, CreateTrigger,
+    CreateView, Expression, FUNCTIONS, Function, GroupBy, Having, ID_COLUMN, OrderBy, Pragma,
+    Predicate, Row, SelectItem, SetOperator, Statement, NumberError, StatementKind, Subquery,
+    TABLE_NAME, UserFunction, Value, column_index, function_index, is_valid_id, parse_number,
+    parse_value, type_from_name, type_name, user_function, value_fits_column,
+};
+
+
+/// Parse a string into a SQL statement, which may end with a semicolon.
+pub(crate) fn prepare_statement(command: &str) -> Result<Statement, ParseError> {
+    let tokens = tokenize(command).map_err(|e| e.locate(command))?;
+    let tokens = match tokens.split_last() {
+        Some((last, rest)) if last.is(";") => rest,
+        _ => &tokens,
+    };
+    prepare_tokens(command, tokens).map_err(|e| e.locate(command))
+}
+
+
+/// Parse a string of statements separated by semicolons. Each statement is parsed on its own, so
+/// that one that can't be parsed doesn't stop the others from being parsed, except that if the
+/// string can't be split into tokens, there is only the error saying why.
+pub(crate) fn prepare_statements(command: &str) -> Vec<Result<Statement, ParseError>> {
+    let tokens = match tokenize(command) {
+        Ok(tokens) => tokens,
+        Err(e) => return vec![Err(e.locate(command))],
+    };
+    split_statements(&tokens)
+        .into_iter()
+        .filter(|tokens| !tokens.is_empty())
+        .map(|tokens| prepare_tokens(command, tokens).map_err(|e| e.locate(command)))
+        .collect()
+}
+
+
+/// Split the tokens of a string of statements at the semicolons between them, except those in the
+/// body of a CREATE TRIGGER statement, which ends at the semicolon after its `end`.
+fn split_statements(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut in_trigger_body = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let is_trigger = tokens[start].is("create")
+            && tokens.get(start + 1).is_some_and(|token| token.is("trigger"));
+        if is_trigger && token.is("begin") {
+            in_trigger_body = true;
+        } else if token.is(";") && (!in_trigger_body || tokens[i - 1].is("end")) {
+            statements.push(&tokens[start..i]);
+            start = i + 1;
+            in_trigger_body = false;
+        }
+    }
+    statements.push(&tokens[start..]);
+    statements
+}
+
+
+/// Parse the tokens of one statement in the string, whose spans are offsets into all of it.
+fn prepare_tokens(command: &str, tokens: &[Token]) -> Result<Statement, ParseError> {
+    let mut position = 0;
+    let keyword = tokens.first().ok_or_else(|| expected(tokens, 0, "statement"))?;
+    let statement = if keyword.is("explain") {
+        position = tokens.len();
+        prepare_explain(command, tokens)?
+    } else if keyword.is("insert") {
+        if tokens.get(1).is_some_and(|token| token.is("into") || token.is("or")) {
+            prepare_insert_values(tokens, &mut position)?
+        } else {
+            prepare_insert_fields(command, tokens, &mut position)?
+        }
+    } else if keyword.is("select") {
+        prepare_select(tokens, &mut position)?
+    } else if keyword.is("with") {
+        prepare_with(tokens, &mut position)?
+    } else if keyword.is("copy") {
+        prepare_copy(tokens, &mut position)?
+    } else if keyword.is("delete") {
+        prepare_delete(tokens, &mut position)?
+    } else if keyword.is("create") && tokens.get(1).is_some_and(|token| token.is("view")) {
+        prepare_create_view(tokens, &mut position)?
+    } else if keyword.is("create") && tokens.get(1).is_some_and(|token| token.is("trigger")) {
+        prepare_create_trigger(tokens, &mut position)?
+    } else if keyword.is("create") {
+        prepare_create_index(tokens, &mut position)?
+    } else if keyword.is("drop") {
+        prepare_drop(tokens, &mut position)?
+    } else if keyword.is("alter") {
+        prepare_alter(tokens, &mut position)?
+    } else if keyword.is("pragma") {
+        prepare_pragma(tokens, &mut position)?
+    } else if keyword.is("attach") || keyword.is("detach") {
+        prepare_attach(tokens, &mut position)?
+    } else if keyword.is("vacuum") || keyword.is("analyze") {
+        position += 1;
+        // ANALYZE may name the table, though there is only the one.
+        if keyword.is("analyze")
+            && tokens.get(1).and_then(Token::name).is_some_and(is_table_name)
+        {
+            position += 1;
+        }
+        if keyword.is("vacuum") {
+            Statement::new(StatementKind::Vacuum)
+        } else {
+            Statement::new(StatementKind::Analyze)
+        }
+    } else {
+        prepare_transaction(tokens, &mut position)?
+    };
+
+    if position == tokens.len() {
+        Ok(statement)
+    } else {
+        Err(expected(tokens, position, "end of statement"))
+    }
+}
+
+
+/// Parse an EXPLAIN statement, i.e. `explain [analyze] <statement>`, whose plan is output instead
+/// of executing it. With ANALYZE, it is executed, and what each step did is output. Only
+/// statements that read or change rows have a plan.
+fn prepare_explain(command: &str, tokens: &[Token]) -> Result<Statement, ParseError> {
+    let analyze = tokens.get(1).is_some_and(|token| token.is("analyze"));
+    let start = if analyze { 2 } else { 1 };
+    if tokens.len() == start {
+        return Err(expected(tokens, start, "statement"));
+    }
+    let mut statement = prepare_tokens(command, &tokens[start..])?;
+    let has_plan = matches!(
+        statement.kind,
+        StatementKind::Select
+            | StatementKind::Insert
+            | StatementKind::InsertOrReplace
+            | StatementKind::Copy
+            | StatementKind::Delete
+    );
+    if !has_plan || statement.explain {
+        return Err(expected(tokens, start, "SELECT, INSERT, COPY or DELETE statement"));
+    }
+    statement.explain = true;
+    statement.analyze = analyze;
+    Ok(statement)
+}
+
+
+/// Return an error saying what was expected at the given position, and what is there instead.
+fn expected(tokens: &[Token], position: usize, expected: &str) -> ParseError {
+    let found = tokens.get(position).map_or(String::from("end of statement"), Token::to_string);
+    let message = format!("expected {}, found {}", expected, found);
+    ParseError::new(message, span(tokens, position..position + 1))
+}
+
+
+/// Return the span of the text that the tokens in the range of positions cover. Past the last
+/// token, this is the empty span at the end of the statement.
+fn span(tokens: &[Token], positions: Range<usize>) -> Range<usize> {
+    let covered = tokens.get(positions.start..positions.end.min(tokens.len())).unwrap_or(&[]);
+    match (covered.first(), covered.last()) {
+        (Some(first), Some(last)) => first.span.start..last.span.end,
+        _ => {
+            let end = tokens.last().map_or(0, |token| token.span.end);
+            end..end
+        },
+    }
+}
+
+
+/// Move past the token at the given position and return true if it is the expected keyword or
+/// symbol, ignoring case. Otherwise, return false.
+fn skip_token(tokens: &[Token], position: &mut usize, expected: &str) -> bool {
+    if tokens.get(*position).is_some_and(|token| token.is(expected)) {
+        *position += 1;
+        true
+    } else {
+        false
+    }
+}
+
+
+/// Move past the token at the given position if it is the expected keyword or symbol, ignoring
+/// case, or return an error if it isn't.
+fn expect_token(tokens: &[Token], position: &mut usize, expected: &str) -> Result<(), ParseError> {
+    if skip_token(tokens, position, expected) {
+        Ok(())
+    } else {
+        Err(self::expected(tokens, *position, &format!("'{}'", expected)))
+    }
+}
+
+
+/// Move past the name at the given position and return it, or return an error saying that the
+/// kind of name described was expected.
+fn next_name<'a>(
+    tokens: &'a [Token], position: &mut usize, description: &str
+) -> Result<&'a str, ParseError> {
+    let name = tokens.get(*position).and_then(Token::name)
+        .ok_or_else(|| expected(tokens, *position, description))?;
+    *position += 1;
+    Ok(name)
+}
+
+
+/// Like next_name, except that the name must be made only of letters, digits and underscores.
+fn next_word<'a>(
+    tokens: &'a [Token], position: &mut usize, description: &str
+) -> Result<&'a str, ParseError> {
+    match tokens.get(*position).and_then(Token::name) {
+        Some(name) if is_word(name) => {
+            *position += 1;
+            Ok(name)
+        },
+        _ => Err(expected(tokens, *position, description)),
+    }
+}
+
+
+/// Move past the name of the table, or return an error if there is another name there.
+fn expect_table(tokens: &[Token], position: &mut usize) -> Result<(), ParseError> {
+    let name = next_name(tokens, position, "table name")?;
+    if is_table_name(name) {
+        Ok(())
+    } else {
+        let span = span(tokens, *position - 1..*position);
+        Err(ParseError::new(format!("no such table: {}", name), span))
+    }
+}
+
+
+/// Move past the name of a column, which may be qualified by the table's name or alias, e.g.
+/// `u.id`, and return its index, or return an error if there is no such column.
+fn next_column(tokens: &[Token], position: &mut usize) -> Result<usize, ParseError> {
+    if tokens.get(*position + 1).is_some_and(|token| token.is(".")) {
+        let qualifier = next_name(tokens, position, "table name")?;
+        if !qualifier.eq_ignore_ascii_case(&table_qualifier(tokens, *position)) {
+            let span = span(tokens, *position - 1..*position);
+            return Err(ParseError::new(format!("no such table: {}", qualifier), span));
+        }
+        *position += 1;
+    }
+    let name = next_name(tokens, position, "column name")?;
+    column_index(name).ok_or_else(|| {
+        ParseError::new(format!("no such column: {}", name), span(tokens, *position - 1..*position))
+    })
+}
+
+
+/// Return true if the name is made only of letters, digits and underscores, as the names of
+/// savepoints, databases and indexes, and the names and values of pragmas, must be.
+fn is_word(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+
+/// Parse an INSERT statement of the form `insert [<id>] <username> <email>`, in which the values
+/// are separated only by spaces, and so each may contain punctuation, e.g. `jdoe@example.com`, or
+/// be quoted to contain spaces, e.g. `'jane doe'`. If the id is left out, it is assigned when the
+/// statement is executed.
+fn prepare_insert_fields(
+    command: &str, tokens: &[Token], position: &mut usize
+) -> Result<Statement, ParseError> {
+    *position += 1;
+    // The positions of the tokens that make up each field.
+    let mut fields = Vec::new();
+    while *position < tokens.len() {
+        let start = *position;
+        *position += 1;
+        while tokens.get(*position).is_some_and(|next| next.follows(&tokens[*position - 1])) {
+            *position += 1;
+        }
+        fields.push(start..*position);
+    }
+
+    // The id can be left out, in which case it is NULL until it is assigned.
+    let first_column = match fields.len() {
+        n if n == COLUMNS.len() => 0,
+        n if n == COLUMNS.len() - 1 => 1,
+        n => {
+            let message =
+                format!("expected {} or {} values, found {}", COLUMNS.len() - 1, COLUMNS.len(), n);
+            let extra = fields.get(COLUMNS.len()).map_or(tokens.len(), |field| field.start);
+            return Err(ParseError::new(message, span(tokens, extra..tokens.len())));
+        },
+    };
+
+    let mut values = vec![Value::Null; first_column];
+    for (field, column) in fields.into_iter().zip(first_column..) {
+        let span = span(tokens, field);
+        let text = &command[span.clone()];
+        let value = parse_value(text, &COLUMNS[column])
+            .map_err(|e| value_error(e, text, column, span.clone()))?;
+        if column == ID_COLUMN && !is_valid_id(&value) {
+            return Err(invalid_id(span));
+        }
+        values.push(coerce_value(value, column, span)?);
+    }
+
+    Ok(Statement {
+        rows_to_insert: Some(vec![Row { values, columns: None }]),
+        ..Statement::new(StatementKind::Insert)
+    })
+}
+
+
+/// Parse an INSERT statement of the form `insert [or replace] into users [(<column>, ...)] values
+/// (<value>, ...), ... [returning <list>]`, with a constant value for each column in each row.
+/// Without a list of columns, there is a value for each column of the table in order. Columns that
+/// are left out of the list are NULL, except for the id, which is assigned.
+fn prepare_insert_values(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 1;
+    let kind = if skip_token(tokens, position, "or") {
+        expect_token(tokens, position, "replace")?;
+        StatementKind::InsertOrReplace
+    } else {
+        StatementKind::Insert
+    };
+    expect_token(tokens, position, "into")?;
+    expect_table(tokens, position)?;
+
+    let columns = if skip_token(tokens, position, "(") {
+        let mut columns = Vec::new();
+        loop {
+            let column = next_column(tokens, position)?;
+            if columns.contains(&column) {
+                let message = format!("column {} is listed twice", COLUMNS[column].name);
+                return Err(ParseError::new(message, span(tokens, *position - 1..*position)));
+            }
+            columns.push(column);
+            if !skip_token(tokens, position, ",") {
+                break;
+            }
+        }
+        expect_token(tokens, position, ")")?;
+        columns
+    } else {
+        (0..COLUMNS.len()).collect()
+    };
+
+    expect_token(tokens, position, "values")?;
+    let mut rows = Vec::new();
+    loop {
+        rows.push(prepare_values_row(tokens, position, &columns)?);
+        if !skip_token(tokens, position, ",") {
+            break;
+        }
+    }
+    let returning = prepare_returning(tokens, position)?;
+
+    Ok(Statement {
+        rows_to_insert: Some(rows),
+        returning,
+        ..Statement::new(kind)
+    })
+}
+
+
+fn is_table_name(name: &str) -> bool {
+    name.eq_ignore_ascii_case(TABLE_NAME)
+}
+
+
+/// Parse a parenthesized row of values for the given columns. The other columns are NULL, and so
+/// is the id if it isn't given.
+fn prepare_values_row(
+    tokens: &[Token], position: &mut usize, columns: &[usize]
+) -> Result<Row, ParseError> {
+    expect_token(tokens, position, "(")?;
+
+    let mut values = vec![Value::Null; COLUMNS.len()];
+    for (i, column) in columns.iter().enumerate() {
+        if i > 0 {
+            expect_token(tokens, position, ",")?;
+        }
+        let start = *position;
+        let expression = fold_constant(prepare_expression(tokens, position)?);
+        let span = span(tokens, start..*position);
+        let value = match expression {
+            Expression::Literal(value) => value,
+            _ => return Err(ParseError::new(String::from("expected a constant value"), span)),
+        };
+        let value = coerce_value(value, *column, span.clone())?;
+        if *column == ID_COLUMN && value != Value::Null && !is_valid_id(&value) {
+            return Err(invalid_id(span));
+        }
+        values[*column] = value;
+    }
+    expect_token(tokens, position, ")")?;
+    Ok(Row { values, columns: None })
+}
+
+
+/// Return how a column is described in error messages, e.g. `integer id`.
+fn describe_column(column: usize) -> String {
+    format!("{} {}", type_name(COLUMNS[column].column_type), COLUMNS[column].name)
+}
+
+
+/// Return an error for text at the span that couldn't be parsed as a value of the column.
+fn value_error(error: NumberError, text: &str, column: usize, span: Range<usize>) -> ParseError {
+    match error {
+        NumberError::Invalid => {
+            let message = format!("expected {}, found '{}'", describe_column(column), text);
+            ParseError::new(message, span)
+        },
+        NumberError::TooLarge => too_large(text, span),
+    }
+}
+
+
+fn too_large(number: &str, span: Range<usize>) -> ParseError {
+    ParseError::new(format!("number is too large: {}", number), span)
+}
+
+
+/// Return true unless the type is text. NULL counts as a number, since it can take the place of
+/// one.
+fn is_numeric(value_type: Option<ColumnType>) -> bool {
+    value_type != Some(ColumnType::Text)
+}
+
+
+/// Return an error for a value that is in the text at the span and can't be an id.
+fn invalid_id(span: Range<usize>) -> ParseError {
+    ParseError::new(format!("id must be an integer from 0 to {}", u32::MAX), span)
+}
+
+
+/// Coerce the value, which is in the text at the span, to the column's type, or return an error if
+/// it doesn't convert or is longer than the column allows.
+fn coerce_value(value: Value, column: usize, span: Range<usize>) -> Result<Value, ParseError> {
+    let column_def = &COLUMNS[column];
+    let message = match value.coerce(column_def.column_type) {
+        Some(value) if value_fits_column(&value, column_def) => return Ok(value),
+        Some(_) => format!("{} is longer than {} bytes", column_def.name, column_def.max_length),
+        None => format!("expected {}, found {:?}", describe_column(column), value),
+    };
+    Err(ParseError::new(message, span))
+}
+
+
+/// Parse a `copy users from '<path>'` statement.
+fn prepare_copy(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 1;
+    expect_table(tokens, position)?;
+    expect_token(tokens, position, "from")?;
+    let path = next_path(tokens, position)?;
+
+    Ok(Statement {
+        copy_from: Some(path),
+        ..Statement::new(StatementKind::Copy)
+    })
+}
+
+
+/// Move past the quoted path of a file and return it.
+fn next_path(tokens: &[Token], position: &mut usize) -> Result<String, ParseError> {
+    match tokens.get(*position).map(|token| &token.kind) {
+        Some(TokenKind::Text(path)) if !path.is_empty() => {
+            *position += 1;
+            Ok(path.clone())
+        },
+        _ => Err(expected(tokens, *position, "quoted path")),
+    }
+}
+
+
+/// Parse a `delete <id> [returning <list>]` statement.
+fn prepare_delete(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 1;
+    let id = match tokens.get(*position).map(|token| &token.kind) {
+        Some(TokenKind::Word(word)) => match parse_number(word) {
+            Ok(Value::Integer(id)) => Some(u32::try_from(id).map_err(|_| {
+                invalid_id(span(tokens, *position..*position + 1))
+            })?),
+            Err(NumberError::TooLarge) => {
+                return Err(too_large(word, span(tokens, *position..*position + 1)));
+            },
+            _ => None,
+        },
+        _ => None,
+    };
+    let id = id.ok_or_else(|| expected(tokens, *position, "integer id"))?;
+    *position += 1;
+    let returning = prepare_returning(tokens, position)?;
+
+    Ok(Statement {
+        id_to_delete: Some(id),
+        returning,
+        ..Statement::new(StatementKind::Delete)
+    })
+}
+
+
+/// Parse a `create index <name> on users(<column> | <expression>) [where <condition>]` statement.
+/// An index on an expression of one column, e.g. `lower(email)`, has entries for the expression's
+/// values instead of the column's.
+fn prepare_create_index(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 1;
+    expect_token(tokens, position, "index")?;
+    let name = next_word(tokens, position, "index name")?;
+    if name.len() > INDEX_NAME_SIZE {
+        let message = format!("index name is longer than {} bytes", INDEX_NAME_SIZE);
+        return Err(ParseError::new(message, span(tokens, *position - 1..*position)));
+    }
+    expect_token(tokens, position, "on")?;
+    expect_table(tokens, position)?;
+    expect_token(tokens, position, "(")?;
+    let is_column = tokens.get(*position + 1).is_some_and(|token| token.is(")") || token.is("."));
+    let (column, expression) = if is_column {
+        (next_column(tokens, position)?, None)
+    } else {
+        let start = *position;
+        let (column, _) = prepare_index_on(tokens, position)?;
+        (column, Some(tokens_text(&tokens[start..*position])))
+    };
+    expect_token(tokens, position, ")")?;
+    let where_clause = if skip_token(tokens, position, "where") {
+        let start = *position;
+        prepare_index_where(tokens, position)?;
+        Some(tokens_text(&tokens[start..*position]))
+    } else {
+        None
+    };
+
+    Ok(Statement {
+        index_to_create: Some(CreateIndex {
+            name: String::from(name),
+            column,
+            expression,
+            where_clause,
+        }),
+        ..Statement::new(StatementKind::CreateIndex)
+    })
+}
+
+
+/// Parse the WHERE clause of a partial index, which is checked for each row as it is inserted or
+/// deleted, so it can't depend on anything but the row.
+fn prepare_index_where(tokens: &[Token], position: &mut usize) -> Result<Predicate, ParseError> {
+    let start = *position;
+    let predicate = prepare_or(tokens, position)?;
+    // A call to now() that doesn't depend on the row has already been replaced by its value.
+    let calls_now =
+        tokens[start..*position].windows(2).any(|pair| pair[0].is("now") && pair[1].is("("));
+    if calls_now || !predicate.is_deterministic() {
+        let message = "an index's WHERE clause can't have a subquery or call now()";
+        return Err(ParseError::new(String::from(message), span(tokens, start..*position)));
+    }
+    Ok(predicate)
+}
+
+
+/// Parse the expression that an index is on, returning the one column that it depends on with it.
+/// Its values have to be of the column's type, since the index's keys are laid out for them.
+fn prepare_index_on(
+    tokens: &[Token], position: &mut usize
+) -> Result<(usize, Expression), ParseError> {
+    let start = *position;
+    let expression = prepare_expression(tokens, position)?;
+    let error = |message: &str| {
+        Err(ParseError::new(String::from(message), span(tokens, start..*position)))
+    };
+    if !expression.is_deterministic() {
+        return error("an index's expression can't have a subquery or call now()");
+    }
+    let mut columns = expression.columns();
+    columns.sort_unstable();
+    columns.dedup();
+    let column = match columns[..] {
+        [column] => column,
+        _ => return error("an index's expression must use exactly one column"),
+    };
+    if expression.value_type() != Some(COLUMNS[column].column_type) {
+        return error("an index's expression must have the type of its column");
+    }
+    Ok((column, expression))
+}
+
+
+/// Parse the text of an index's expression, as it was saved when the index was created.
+pub(crate) fn prepare_index_expression(text: &str) -> Result<Expression, ParseError> {
+    let tokens = tokenize(text).map_err(|e| e.locate(text))?;
+    let mut position = 0;
+    let (_, expression) = prepare_index_on(&tokens, &mut position).map_err(|e| e.locate(text))?;
+    if position == tokens.len() {
+        Ok(expression)
+    } else {
+        Err(expected(&tokens, position, "end of expression").locate(text))
+    }
+}
+
+
+/// Parse the text of a partial index's WHERE clause, as it was saved when the index was created.
+pub(crate) fn prepare_index_predicate(text: &str) -> Result<Predicate, ParseError> {
+    let tokens = tokenize(text).map_err(|e| e.locate(text))?;
+    let mut position = 0;
+    let predicate = prepare_index_where(&tokens, &mut position).map_err(|e| e.locate(text))?;
+    if position == tokens.len() {
+        Ok(predicate)
+    } else {
+        Err(expected(&tokens, position, "end of WHERE clause").locate(text))
+    }
+}
+
+
+/// Parse a `pragma <name>` statement, or a `pragma <name> = <value>` statement, which may also be
+/// written `pragma <name>(<value>)`.
+fn prepare_pragma(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 1;
+    let name = next_word(tokens, position, "pragma name")?;
+    let value = if skip_token(tokens, position, "=") {
+        Some(next_pragma_value(tokens, position)?)
+    } else if skip_token(tokens, position, "(") {
+        let value = next_pragma_value(tokens, position)?;
+        expect_token(tokens, position, ")")?;
+        Some(value)
+    } else {
+        None
+    };
+
+    Ok(Statement {
+        pragma: Some(Pragma { name: String::from(name), value }),
+        ..Statement::new(StatementKind::Pragma)
+    })
+}
+
+
+/// Parse the value of a pragma, which is a word, or a number that may be negative.
+fn next_pragma_value(tokens: &[Token], position: &mut usize) -> Result<String, ParseError> {
+    let sign = if skip_token(tokens, position, "-") { "-" } else { "" };
+    let value = next_word(tokens, position, "pragma value")?;
+    Ok(format!("{}{}", sign, value))
+}
+
+
+/// Parse an `attach [database] '<path>' as <name>` or `detach [database] <name>` statement.
+fn prepare_attach(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    let kind = if skip_token(tokens, position, "attach") {
+        StatementKind::Attach
+    } else {
+        *position += 1;
+        StatementKind::Detach
+    };
+    skip_token(tokens, position, "database");
+
+    let attach_path = match kind {
+        StatementKind::Attach => {
+            let path = next_path(tokens, position)?;
+            expect_token(tokens, position, "as")?;
+            Some(path)
+        },
+        _ => None,
+    };
+    let name = next_word(tokens, position, "database name")?;
+
+    Ok(Statement {
+        database: Some(String::from(name)),
+        attach_path,
+        ..Statement::new(kind)
+    })
+}
+
+
+/// Parse a statement that controls transactions: `begin`, `commit` (or `end`), `rollback`,
+/// `savepoint <name>`, `release [savepoint] <name>` or `rollback to [savepoint] <name>`. The word
+/// `transaction` may follow `begin`, `commit`, `end` and `rollback`.
+fn prepare_transaction(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    let kind = if skip_token(tokens, position, "begin") {
+        skip_token(tokens, position, "transaction");
+        StatementKind::Begin
+    } else if skip_token(tokens, position, "commit") || skip_token(tokens, position, "end") {
+        skip_token(tokens, position, "transaction");
+        StatementKind::Commit
+    } else if skip_token(tokens, position, "rollback") {
+        skip_token(tokens, position, "transaction");
+        if skip_token(tokens, position, "to") {
+            skip_token(tokens, position, "savepoint");
+            StatementKind::RollbackTo
+        } else {
+            StatementKind::Rollback
+        }
+    } else if skip_token(tokens, position, "release") {
+        skip_token(tokens, position, "savepoint");
+        StatementKind::Release
+    } else if skip_token(tokens, position, "savepoint") {
+        StatementKind::Savepoint
+    } else {
+        return Err(expected(tokens, *position, "statement"));
+    };
+
+    let savepoint = match kind {
+        StatementKind::Savepoint | StatementKind::RollbackTo | StatementKind::Release => {
+            Some(String::from(next_word(tokens, position, "savepoint name")?))
+        },
+        _ => None,
+    };
+
+    Ok(Statement {
+        savepoint,
+        ..Statement::new(kind)
+    })
+}
+
+
+/// Parse a CREATE VIEW statement, i.e. `create view <name> as <select>`. The view's SELECT is
+/// kept as text.
+///
+/// Like a common table expression, a view can only stand in for the table: its SELECT must return
+/// every column of the table, in order. Views that project, reorder or rename columns are not
+/// supported and are rejected here.
+fn prepare_create_view(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 2;
+    let name = next_word(tokens, position, "view name")?;
+    if is_table_name(name) {
+        let span = span(tokens, *position - 1..*position);
+        return Err(ParseError::new(format!("table {} already exists", name), span));
+    }
+    expect_token(tokens, position, "as")?;
+    if !is_subquery(tokens, *position) {
+        return Err(expected(tokens, *position, "'select'"));
+    }
+
+    let start = *position;
+    let select = prepare_select(tokens, position)?;
+    if !has_table_columns(&select) {
+        let message = "a view must select every column of the table";
+        return Err(ParseError::new(String::from(message), span(tokens, start..*position)));
+    }
+
+    Ok(Statement {
+        view_to_create: Some(CreateView {
+            name: String::from(name),
+            text: tokens_text(&tokens[start..*position]),
+        }),
+        ..Statement::new(StatementKind::CreateView)
+    })
+}
+
+
+/// Parse a DROP VIEW or DROP TRIGGER statement, i.e. `drop view <name>` or `drop trigger <name>`.
+fn prepare_drop(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 1;
+    let is_view = skip_token(tokens, position, "view");
+    if !is_view && !skip_token(tokens, position, "trigger") {
+        return Err(expected(tokens, *position, "'view' or 'trigger'"));
+    }
+    let description = if is_view { "view name" } else { "trigger name" };
+    let name = Some(String::from(next_word(tokens, position, description)?));
+
+    Ok(Statement {
+        view: if is_view { name.clone() } else { None },
+        trigger: if is_view { None } else { name },
+        ..Statement::new(if is_view { StatementKind::DropView } else { StatementKind::DropTrigger })
+    })
+}
+
+
+/// Parse an ALTER TABLE statement, i.e. `alter table users rename to <name>` or
+/// `alter table users rename column <column> to <name>`, which is always an error once it has been
+/// parsed. The schema is fixed, and names in statements are resolved as they are parsed, without a
+/// database, so neither the table nor its columns can be renamed.
+fn prepare_alter(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    let start = *position;
+    *position += 1;
+    expect_token(tokens, position, "table")?;
+    expect_table(tokens, position)?;
+    expect_token(tokens, position, "rename")?;
+    let what = if skip_token(tokens, position, "column") {
+        format!("column {}", COLUMNS[next_column(tokens, position)?].name)
+    } else {
+        format!("table {}", TABLE_NAME)
+    };
+    expect_token(tokens, position, "to")?;
+    next_word(tokens, position, "new name")?;
+    let message = format!("cannot rename {}, since the schema is fixed", what);
+    Err(ParseError::new(message, span(tokens, start..*position)))
+}
+
+
+/// Parse a CREATE TRIGGER statement, i.e.
+/// `create trigger <name> after insert|delete on users [for each row] begin <statement>; ... end`.
+fn prepare_create_trigger(
+    tokens: &[Token], position: &mut usize
+) -> Result<Statement, ParseError> {
+    *position += 2;
+    let name = next_word(tokens, position, "trigger name")?;
+    expect_token(tokens, position, "after")?;
+    let action = if skip_token(tokens, position, "insert") {
+        Action::Insert
+    } else if skip_token(tokens, position, "delete") {
+        Action::Delete
+    } else {
+        return Err(expected(tokens, *position, "'insert' or 'delete'"));
+    };
+    expect_token(tokens, position, "on")?;
+    expect_table(tokens, position)?;
+    if skip_token(tokens, position, "for") {
+        expect_token(tokens, position, "each")?;
+        expect_token(tokens, position, "row")?;
+    }
+    expect_token(tokens, position, "begin")?;
+
+    // The body ends at the first `end` that isn't in a statement.
+    let start = *position;
+    while !tokens.get(*position).is_some_and(|token| token.is("end")) {
+        *position += tokens[*position..].iter().position(|token| token.is(";"))
+            .ok_or_else(|| expected(tokens, tokens.len(), "';'"))? + 1;
+    }
+    let body = &tokens[start..*position];
+    if body.is_empty() {
+        return Err(expected(tokens, *position, "'insert into' or 'delete'"));
+    }
+    prepare_trigger_body(body, action, &placeholder_row())?;
+    *position += 1;
+
+    Ok(Statement {
+        trigger_to_create: Some(CreateTrigger {
+            name: String::from(name),
+            action,
+            body: tokens_text(body),
+        }),
+        ..Statement::new(StatementKind::CreateTrigger)
+    })
+}
+
+
+/// Parse the statements of a trigger, as it fires for the given row.
+pub(crate) fn prepare_trigger(trigger: &Trigger, row: &Row) -> Result<Vec<Statement>, ParseError> {
+    let tokens = tokenize(&trigger.body).map_err(|e| e.locate(&trigger.body))?;
+    prepare_trigger_body(&tokens, trigger.action, row).map_err(|e| e.locate(&trigger.body))
+}
+
+
+/// Parse the statements in a trigger's body, each of which is followed by a semicolon, with the
+/// values of the row that fired the trigger in place of `new.<column>` after an insert, or
+/// `old.<column>` after a delete. Only INSERT INTO and DELETE statements can be in the body.
+fn prepare_trigger_body(
+    tokens: &[Token], action: Action, row: &Row
+) -> Result<Vec<Statement>, ParseError> {
+    let tokens = substitute_row(tokens, action, row)?;
+    let mut statements = Vec::new();
+    let mut rest = &tokens[..];
+    while !rest.is_empty() {
+        let end = rest.iter().position(|token| token.is(";"))
+            .ok_or_else(|| expected(rest, rest.len(), "';'"))?;
+        let statement = &rest[..end];
+        let is_allowed = match statement {
+            [delete, ..] if delete.is("delete") => true,
+            [insert, into, ..] => insert.is("insert") && (into.is("into") || into.is("or")),
+            _ => false,
+        };
+        if !is_allowed {
+            return Err(expected(rest, 0, "'insert into' or 'delete'"));
+        }
+        statements.push(prepare_tokens("", statement)?);
+        rest = &rest[end + 1..];
+    }
+    Ok(statements)
+}
+
+
+/// Replace each `new.<column>` after an insert, or `old.<column>` after a delete, with a literal of
+/// the row's value for the column, which spans the same text.
+fn substitute_row(tokens: &[Token], action: Action, row: &Row) -> Result<Vec<Token>, ParseError> {
+    let qualifier = match action {
+        Action::Insert => "new",
+        Action::Delete => "old",
+    };
+    let mut substituted = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let is_row_column = (tokens[i].is("new") || tokens[i].is("old"))
+            && tokens.get(i + 1).is_some_and(|token| token.is("."));
+        if !is_row_column {
+            substituted.push(tokens[i].clone());
+            i += 1;
+            continue;
+        }
+
+        let span = span(tokens, i..i + 3);
+        match tokens.get(i + 2).and_then(Token::name).and_then(column_index) {
+            Some(column) if tokens[i].is(qualifier) => {
+                let kind = match &row.values[column] {
+                    Value::Text(text) => TokenKind::Text(text.clone()),
+                    Value::Integer(n) => TokenKind::Word(n.to_string()),
+                    Value::Real(x) => TokenKind::Word(format!("{:?}", x)),
+                    Value::Null => TokenKind::Word(String::from("null")),
+                };
+                substituted.push(Token { kind, span });
+                i += 3;
+            },
+            _ => {
+                let name = tokens_text(&tokens[i..(i + 3).min(tokens.len())]);
+                return Err(ParseError::new(format!("no such column: {}", name), span));
+            },
+        }
+    }
+    Ok(substituted)
+}
+
+
+/// Return a row to check a trigger's statements with when it is created, which has a value of the
+/// right type for each column.
+fn placeholder_row() -> Row {
+    let values = COLUMNS.iter().map(|column| match column.column_type {
+        ColumnType::Integer => Value::Integer(1),
+        ColumnType::Real => Value::Real(0.0),
+        ColumnType::Text => Value::Text(String::new()),
+    });
+    Row { values: values.collect(), columns: None }
+}
+
+
+/// Parse a SELECT statement that starts with a WITH clause, i.e.
+/// `with <name> as (<select>), ... <select>`.
+///
+/// Columns are resolved against the table's schema while parsing, so a common table expression
+/// can only stand in for the table: its SELECT must return every column of the table, in order.
+/// Common table expressions that project, reorder or rename columns, such as
+/// `with x as (select id from users)`, are not supported and are rejected here.
+fn prepare_with(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    *position += 1;
+    let mut common_tables: Vec<(String, Statement)> = Vec::new();
+    loop {
+        let name = next_name(tokens, position, "table name")?;
+        if is_table_name(name)
+            || common_tables.iter().any(|(other, _)| other.eq_ignore_ascii_case(name))
+        {
+            let span = span(tokens, *position - 1..*position);
+            return Err(ParseError::new(format!("table {} already exists", name), span));
+        }
+        expect_token(tokens, position, "as")?;
+        expect_token(tokens, position, "(")?;
+        if !is_subquery(tokens, *position) {
+            return Err(expected(tokens, *position, "'select'"));
+        }
+
+        let start = *position;
+        let select = prepare_select(tokens, position)?;
+        if !has_table_columns(&select) {
+            let message = "a common table expression must select every column of the table";
+            return Err(ParseError::new(String::from(message), span(tokens, start..*position)));
+        }
+        expect_token(tokens, position, ")")?;
+        common_tables.push((String::from(name), select));
+        if !skip_token(tokens, position, ",") {
+            break;
+        }
+    }
+
+    if !tokens.get(*position).is_some_and(|token| token.is("select")) {
+        return Err(expected(tokens, *position, "'select'"));
+    }
+    let mut statement = prepare_select(tokens, position)?;
+    statement.common_tables = common_tables;
+    Ok(statement)
+}
+
+
+/// Return true if the results of the SELECT, and those of any it is combined with, have the
+/// table's columns, with values of each column's type or NULL.
+fn has_table_columns(select: &Statement) -> bool {
+    let has_columns = match &select.select_list {
+        None => true,
+        Some(items) => {
+            items.len() == COLUMNS.len()
+                && items.iter().zip(COLUMNS.iter()).all(|(item, column)| {
+                    item.value_type().is_none_or(|value_type| value_type == column.column_type)
+                })
+        },
+    };
+    has_columns && select.compound.iter().all(|(_, select)| has_table_columns(select))
+}
+
+
+/// Parse a SELECT statement, which is one SELECT or several whose results are combined by
+/// `union [all]`, `intersect` or `except`, from left to right. Each must have the same number of
+/// result columns, and none can have an ORDER BY clause, since the combined results are ordered
+/// by their values, except for those of UNION ALL.
+fn prepare_select(tokens: &[Token], position: &mut usize) -> Result<Statement, ParseError> {
+    let start = *position;
+    let mut statement = prepare_simple_select(tokens, position)?;
+    while let Some(operator) = next_set_operator(tokens, position) {
+        let select_start = *position;
+        if !tokens.get(*position).is_some_and(|token| token.is("select")) {
+            return Err(expected(tokens, *position, "'select'"));
+        }
+        let select = prepare_simple_select(tokens, position)?;
+
+        if statement.order_by.is_some() || select.order_by.is_some() {
+            let message = String::from("ORDER BY can't be used in a compound SELECT");
+            return Err(ParseError::new(message, span(tokens, start..*position)));
+        }
+        if select.result_columns().len() != statement.result_columns().len() {
+            let message = "compound SELECTs must have the same number of columns";
+            let span = span(tokens, select_start..*position);
+            return Err(ParseError::new(String::from(message), span));
+        }
+        statement.compound.push((operator, select));
+    }
+    Ok(statement)
+}
+
+
+/// Move past a set operator and return it, if there is one.
+fn next_set_operator(tokens: &[Token], position: &mut usize) -> Option<SetOperator> {
+    let operator = if skip_token(tokens, position, "union") {
+        if skip_token(tokens, position, "all") { SetOperator::UnionAll } else { SetOperator::Union }
+    } else if skip_token(tokens, position, "intersect") {
+        SetOperator::Intersect
+    } else if skip_token(tokens, position, "except") {
+        SetOperator::Except
+    } else {
+        return None;
+    };
+    Some(operator)
+}
+
+
+/// Return true if the token is one of the keywords that separate the SELECTs of a compound SELECT.
+fn is_set_operator(token: &Token) -> bool {
+    ["union", "intersect", "except"].iter().any(|keyword| token.is(keyword))
+}
+
+
+/// Parse a SELECT of the form `select [distinct] [<select list>] [from [<database>.]users [[as]
+/// <alias>]] [where <condition>] [group by <column> [having <condition>]] [order by <column>
+/// [asc|desc]]`. The select list is `*` if it is left out.
+fn prepare_simple_select(
+    tokens: &[Token], position: &mut usize
+) -> Result<Statement, ParseError> {
+    *position += 1;
+
+    // `select last_insert_rowid()` and `select changes()` are statements of their own.
+    let function_kind = match tokens {
+        [_, function, open, close] if open.is("(") && close.is(")") => {
+            if function.is("last_insert_rowid") {
+                Some(StatementKind::LastInsertRowid)
+            } else if function.is("changes") {
+                Some(StatementKind::Changes)
+            } else {
+                None
+            }
+        },
+        _ => None,
+    };
+    if let Some(kind) = function_kind {
+        *position = tokens.len();
+        return Ok(Statement::new(kind));
+    }
+
+    let distinct = skip_token(tokens, position, "distinct");
+    let select_list = match tokens.get(*position) {
+        None => None,
+        // A SELECT in parentheses ends at the one that closes them.
+        Some(token) if is_clause_keyword(token) || token.is(")") => None,
+        Some(_) if skip_token(tokens, position, "*") => None,
+        Some(_) => Some(prepare_select_items(tokens, position)?),
+    };
+
+    // The table may be qualified by the name of the database it's in, e.g. `other.users`.
+    // Otherwise it may be a common table expression, or else a view, which only the executor can
+    // tell exists.
+    let mut database = None;
+    let mut common_table = None;
+    let mut view = None;
+    if skip_token(tokens, position, "from") {
+        if tokens.get(*position + 1).is_some_and(|token| token.is(".")) {
+            database = Some(String::from(next_word(tokens, position, "database name")?));
+            *position += 1;
+            expect_table(tokens, position)?;
+        } else if let Some(name) = common_table_name(tokens, *position) {
+            common_table = Some(String::from(name));
+            *position += 1;
+        } else if tokens.get(*position).is_some_and(is_view_name) {
+            view = Some(String::from(next_word(tokens, position, "view name")?));
+        } else {
+            expect_table(tokens, position)?;
+        }
+        // Columns can be qualified by the alias, which table_qualifier finds.
+        prepare_alias(tokens, position)?;
+    }
+
+    let where_clause = if skip_token(tokens, position, "where") {
+        Some(prepare_or(tokens, position)?)
+    } else {
+        None
+    };
+    let group_by = if skip_token(tokens, position, "group") {
+        Some(prepare_group_by(tokens, position)?)
+    } else {
+        None
+    };
+    let order_by = if skip_token(tokens, position, "order") {
+        Some(prepare_order_by(tokens, position)?)
+    } else {
+        None
+    };
+
+    if !is_valid_grouping(select_list.as_deref(), group_by.as_ref(), order_by.as_ref()) {
+        let message = "a grouped or aggregate query can only use the grouped column and count(*)";
+        return Err(ParseError::new(String::from(message), span(tokens, 0..*position)));
+    }
+
+    Ok(Statement {
+        where_clause,
+        order_by,
+        select_list,
+        group_by,
+        distinct,
+        database,
+        common_table,
+        view,
+        ..Statement::new(StatementKind::Select)
+    })
+}
+
+
+/// Return the name at the position if it is the name of a common table expression that a SELECT
+/// there can read, i.e. one that the WITH clause at the start of the statement defines before the
+/// position, so that none of them can read itself or the ones after it.
+fn common_table_name(tokens: &[Token], position: usize) -> Option<&str> {
+    let name = tokens.get(position)?.name()?;
+    if !tokens.first().is_some_and(|token| token.is("with")) {
+        return None;
+    }
+
+    // Each definition is `<name> as (<select>)`, followed by a comma if there is another one.
+    let mut start = 1;
+    while let Some(defined) = tokens.get(start).and_then(Token::name) {
+        let mut end = start + 2;
+        let mut depth = 0;
+        loop {
+            let token = tokens.get(end)?;
+            if token.is("(") {
+                depth += 1;
+            } else if token.is(")") {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            end += 1;
+        }
+        if end >= position {
+            return None;
+        } else if defined.eq_ignore_ascii_case(name) {
+            return Some(name);
+        } else if !tokens.get(end + 1).is_some_and(|token| token.is(",")) {
+            return None;
+        }
+        start = end + 2;
+    }
+    None
+}
+
+
+/// Return true if the token can be the name of a view, i.e. it is a word other than the table's
+/// name and the keywords that could come after the table.
+fn is_view_name(token: &Token) -> bool {
+    token.name().is_some_and(|name| is_word(name) && !is_table_name(name))
+        && !is_clause_keyword(token)
+}
+
+
+/// Return true for the keywords that can end a select list.
+fn is_clause_keyword(token: &Token) -> bool {
+    ["from", "where", "group", "order"].iter().any(|keyword| token.is(keyword))
+        || is_set_operator(token)
+}
+
+
+/// Parse a comma-separated list of expressions and `count(*)`, each of which may be given an
+/// alias.
+fn prepare_select_items(
+    tokens: &[Token], position: &mut usize
+) -> Result<Vec<SelectItem>, ParseError> {
+    let mut items = Vec::new();
+    loop {
+        let item = if is_count_star(&tokens[*position..]) {
+            *position += 4;
+            SelectItem::CountStar
+        } else {
+            SelectItem::Expression(prepare_expression(tokens, position)?)
+        };
+        items.push(match prepare_alias(tokens, position)? {
+            Some(alias) => SelectItem::Aliased(Box::new(item), alias),
+            None => item,
+        });
+
+        if !skip_token(tokens, position, ",") {
+            break;
+        }
+    }
+    Ok(items)
+}
+
+
+/// Parse a RETURNING clause, if there is one. Its list is either `*`, for every column, or a
+/// comma-separated list of expressions. Return None if there isn't a clause.
+fn prepare_returning(
+    tokens: &[Token], position: &mut usize
+) -> Result<Option<Vec<SelectItem>>, ParseError> {
+    if !skip_token(tokens, position, "returning") {
+        return Ok(None);
+    }
+
+    if skip_token(tokens, position, "*") {
+        let columns = (0..COLUMNS.len()).map(Expression::Column).map(SelectItem::Expression);
+        return Ok(Some(columns.collect()));
+    }
+    let start = *position;
+    let items = prepare_select_items(tokens, position)?;
+    if items.iter().any(SelectItem::is_aggregate) {
+        let message = String::from("RETURNING can't contain count(*)");
+        return Err(ParseError::new(message, span(tokens, start..*position)));
+    }
+    if items.iter().any(|item| !item.subqueries().is_empty()) {
+        let message = String::from("RETURNING can't contain a subquery");
+        return Err(ParseError::new(message, span(tokens, start..*position)));
+    }
+    Ok(Some(items))
+}
+
+
+/// Parse an alias for a select item or a table, which is a name either after AS or on its own,
+/// e.g. `as user_id` or `u`. Return None if there isn't one.
+fn prepare_alias(tokens: &[Token], position: &mut usize) -> Result<Option<String>, ParseError> {
+    let has_as = skip_token(tokens, position, "as");
+    match tokens.get(*position) {
+        // A reserved word isn't an alias unless it's quoted, so that e.g. a misspelled clause
+        // such as `select id limit from users` is an error rather than `limit` naming the item.
+        Some(token) if is_reserved_word(token) => {},
+        Some(Token { kind: TokenKind::Word(_) | TokenKind::QuotedName(_), .. }) => {
+            return Ok(Some(String::from(next_name(tokens, position, "alias")?)));
+        },
+        _ => {},
+    }
+    if has_as {
+        Err(expected(tokens, *position, "alias"))
+    } else {
+        Ok(None)
+    }
+}
+
+
+/// Return true for the keywords that can't be used as an alias without quoting them, which are
+/// those that can follow a select item or a table, and others that SQL reserves for clauses.
+fn is_reserved_word(token: &Token) -> bool {
+    [
+        "all", "and", "as", "between", "by", "distinct", "except", "from", "group", "having",
+        "in", "intersect", "is", "join", "like", "limit", "not", "null", "offset", "on", "or",
+        "order", "returning", "select", "union", "values", "where", "with",
+    ].iter().any(|keyword| token.is(keyword))
+}
+
+
+/// Return the name that a column at the given position can be qualified by, e.g. `u` in `u.id`,
+/// which is the alias that the FROM clause of its SELECT gives the table, or else the table's name.
+fn table_qualifier(tokens: &[Token], position: usize) -> String {
+    // The SELECT starts after the innermost parenthesis around the position that starts a
+    // subquery, if there is one, and ends at a set operator or the parenthesis that closes it.
+    let mut start = 0;
+    let mut depth = 0;
+    for i in (0..position).rev() {
+        if tokens[i].is(")") {
+            depth += 1;
+        } else if tokens[i].is("(") && depth > 0 {
+            depth -= 1;
+        } else if tokens[i].is("(") && is_subquery(tokens, i + 1) {
+            start = i + 1;
+            break;
+        }
+    }
+
+    // The position of FROM in the SELECT.
+    let mut from = None;
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        if token.is("(") {
+            depth += 1;
+        } else if token.is(")") && depth == 0 {
+            break;
+        } else if token.is(")") {
+            depth -= 1;
+        } else if depth == 0 && is_set_operator(token) {
+            if i > position {
+                break;
+            }
+            from = None;
+        } else if depth == 0 && token.is("from") && from.is_none() {
+            from = Some(i);
+        }
+    }
+
+    if let Some(from) = from {
+        // Past the table's name, and the name of its database if it has one.
+        let mut position = from + 2;
+        if tokens.get(position).is_some_and(|token| token.is(".")) {
+            position += 2;
+        }
+        if let Ok(Some(alias)) = prepare_alias(tokens, &mut position) {
+            return alias;
+        }
+        // Otherwise it is the name of the table or common table expression.
+        if let Some(name) = tokens.get(position - 1).and_then(Token::name) {
+            return String::from(name);
+        }
+    }
+    String::from(TABLE_NAME)
+}
+
+
+/// Return true if the tokens start with `count(*)`.
+fn is_count_star(tokens: &[Token]) -> bool {
+    match tokens {
+        [count, open, star, close, ..] => {
+            count.is("count") && open.is("(") && star.is("*") && close.is(")")
+        },
+        _ => false,
+    }
+}
+
+
+/// Parse what follows GROUP, i.e. `by <column> [having <condition>]`.
+fn prepare_group_by(tokens: &[Token], position: &mut usize) -> Result<GroupBy, ParseError> {
+    expect_token(tokens, position, "by")?;
+    let column = next_column(tokens, position)?;
+    let having = if skip_token(tokens, position, "having") {
+        Some(prepare_having(tokens, position)?)
+    } else {
+        None
+    };
+    Ok(GroupBy { column, having })
+}
+
+
+/// Parse a HAVING condition, which is either a comparison of an expression to a constant or of
+/// the form `count(*) <operator> <integer>`.
+fn prepare_having(tokens: &[Token], position: &mut usize) -> Result<Having, ParseError> {
+    let start = *position;
+    if is_count_star(&tokens[*position..]) {
+        *position += 4;
+        let comparison = next_comparison(tokens, position)?;
+        let operand = *position;
+        let count = coerce_constant(
+            fold_constant(prepare_expression(tokens, position)?),
+            ColumnType::Integer,
+        );
+        return match count {
+            Expression::Literal(value @ Value::Integer(_)) => {
+                Ok(Having { item: SelectItem::CountStar, comparison, value })
+            },
+            _ => {
+                let message = String::from("expected an integer constant");
+                Err(ParseError::new(message, span(tokens, operand..*position)))
+            },
+        };
+    }
+
+    match prepare_test(tokens, position)? {
+        Predicate::Condition(Condition {
+            left, comparison, right: Expression::Literal(value)
+        }) => Ok(Having { item: SelectItem::Expression(left), comparison, value }),
+        _ => {
+            let message = String::from("HAVING must compare an expression to a constant");
+            Err(ParseError::new(message, span(tokens, start..*position)))
+        },
+    }
+}
+
+
+/// Return true if a statement that groups rows, or computes an aggregate over all of them, only
+/// refers to values that are the same for every row in a group.
+fn is_valid_grouping(
+    select_list: Option<&[SelectItem]>, group_by: Option<&GroupBy>, order_by: Option<&OrderBy>
+) -> bool {
+    let is_aggregate = select_list.is_some_and(|items| items.iter().any(SelectItem::is_aggregate));
+    if group_by.is_none() && !is_aggregate {
+        return true;
+    }
+
+    let group_column = group_by.map(|group_by| group_by.column);
+    let is_grouped = |item: &SelectItem| match item.unaliased() {
+        SelectItem::Expression(expression) => {
+            expression.columns().into_iter().all(|column| Some(column) == group_column)
+        },
+        SelectItem::CountStar => true,
+        SelectItem::Aliased(..) => unreachable!(),
+    };
+
+    select_list.is_some_and(|items| items.iter().all(is_grouped))
+        && group_by.and_then(|group_by| group_by.having.as_ref())
+            .is_none_or(|having| is_grouped(&having.item))
+        && order_by.is_none_or(|order_by| Some(order_by.column) == group_column)
+}
+
+
+/// Parse what follows ORDER, i.e. `by <column> [asc|desc]`.
+fn prepare_order_by(tokens: &[Token], position: &mut usize) -> Result<OrderBy, ParseError> {
+    expect_token(tokens, position, "by")?;
+    let column = next_column(tokens, position)?;
+    let descending = if skip_token(tokens, position, "desc") {
+        true
+    } else {
+        skip_token(tokens, position, "asc");
+        false
+    };
+    Ok(OrderBy { column, descending })
+}
+
+
+/// Parse one or more AND expressions separated by OR. NOT binds more tightly than AND, which binds
+/// more tightly than OR.
+fn prepare_or(tokens: &[Token], position: &mut usize) -> Result<Predicate, ParseError> {
+    let mut predicate = prepare_and(tokens, position)?;
+    while skip_token(tokens, position, "or") {
+        let right = prepare_and(tokens, position)?;
+        predicate = Predicate::Or(Box::new(predicate), Box::new(right));
+    }
+    Ok(predicate)
+}
+
+
+/// Parse one or more NOT expressions separated by AND.
+fn prepare_and(tokens: &[Token], position: &mut usize) -> Result<Predicate, ParseError> {
+    let mut predicate = prepare_not(tokens, position)?;
+    while skip_token(tokens, position, "and") {
+        let right = prepare_not(tokens, position)?;
+        predicate = Predicate::And(Box::new(predicate), Box::new(right));
+    }
+    Ok(predicate)
+}
+
+
+/// Parse a test or parenthesized predicate, preceded by any number of NOTs.
+fn prepare_not(tokens: &[Token], position: &mut usize) -> Result<Predicate, ParseError> {
+    if skip_token(tokens, position, "not") {
+        return Ok(Predicate::Not(Box::new(prepare_not(tokens, position)?)));
+    }
+
+    // Parentheses can also be part of the expression at the start of a test, as in
+    // `(id + 1) * 2 = 4`, in which case what's inside them isn't a predicate. If it is neither,
+    // the error that is further along is the one that says what is wrong.
+    let start = *position;
+    if skip_token(tokens, position, "(") {
+        let predicate = prepare_or(tokens, position)
+            .and_then(|predicate| expect_token(tokens, position, ")").map(|_| predicate));
+        let predicate_error = match predicate {
+            Ok(predicate) => return Ok(predicate),
+            Err(e) => e,
+        };
+        *position = start;
+        return prepare_test(tokens, position).map_err(|test_error| {
+            if predicate_error.span().start > test_error.span().start {
+                predicate_error
+            } else {
+                test_error
+            }
+        });
+    }
+    prepare_test(tokens, position)
+}
+
+
+/// Parse a test of an expression. That is, a comparison with another expression, or one of
+/// `is [not] null`, `[not] like <pattern>`, `[not] between <low> and <high>` and
+/// `[not] in (<value>, ...)`.
+fn prepare_test(tokens: &[Token], position: &mut usize) -> Result<Predicate, ParseError> {
+    let start = *position;
+    let left = prepare_expression(tokens, position)?;
+    let negated = skip_token(tokens, position, "not");
+
+    let predicate = if !negated && skip_token(tokens, position, "is") {
+        let comparison = if skip_token(tokens, position, "not") {
+            Comparison::IsNotNull
+        } else {
+            Comparison::IsNull
+        };
+        expect_token(tokens, position, "null")?;
+        let null = Expression::Literal(Value::Null);
+        let span = span(tokens, start..*position);
+        Predicate::Condition(prepare_condition(left, comparison, null, span)?)
+    } else if skip_token(tokens, position, "like") {
+        let pattern = prepare_operand(tokens, position, &left)?;
+        let span = span(tokens, start..*position);
+        Predicate::Condition(prepare_condition(left, Comparison::Like, pattern, span)?)
+    } else if skip_token(tokens, position, "between") {
+        // The column must be at least the low value and at most the high one, so on the id this
+        // becomes a range of the table to scan.
+        let low = prepare_operand(tokens, position, &left)?;
+        expect_token(tokens, position, "and")?;
+        let high = prepare_operand(tokens, position, &left)?;
+        let span = span(tokens, start..*position);
+        let low =
+            prepare_condition(left.clone(), Comparison::GreaterThanOrEqual, low, span.clone())?;
+        let high = prepare_condition(left, Comparison::LessThanOrEqual, high, span)?;
+        Predicate::And(Box::new(Predicate::Condition(low)), Box::new(Predicate::Condition(high)))
+    } else if skip_token(tokens, position, "in") {
+        expect_token(tokens, position, "(")?;
+        if is_subquery(tokens, *position) {
+            let subquery = Expression::Subquery(prepare_subquery(tokens, position)?);
+            expect_token(tokens, position, ")")?;
+            let span = span(tokens, start..*position);
+            let condition = prepare_condition(left, Comparison::In, subquery, span)?;
+            return Ok(if negated {
+                Predicate::Not(Box::new(Predicate::Condition(condition)))
+            } else {
+                Predicate::Condition(condition)
+            });
+        }
+
+        // This is the same as comparing to each of the values and combining the results with OR.
+        let mut predicate = None;
+        loop {
+            let value = prepare_operand(tokens, position, &left)?;
+            let span = span(tokens, start..*position);
+            let equal = prepare_condition(left.clone(), Comparison::Equal, value, span)?;
+            let equal = Predicate::Condition(equal);
+            predicate = Some(match predicate {
+                Some(predicate) => Predicate::Or(Box::new(predicate), Box::new(equal)),
+                None => equal,
+            });
+            if !skip_token(tokens, position, ",") {
+                break;
+            }
+        }
+        expect_token(tokens, position, ")")?;
+        predicate.unwrap()
+    } else if !negated {
+        let comparison = next_comparison(tokens, position)?;
+        let right = prepare_operand(tokens, position, &left)?;
+        let span = span(tokens, start..*position);
+        Predicate::Condition(prepare_condition(left, comparison, right, span)?)
+    } else {
+        return Err(expected(tokens, *position, "'like', 'between' or 'in'"));
+    };
+    Ok(if negated { Predicate::Not(Box::new(predicate)) } else { predicate })
+}
+
+
+/// Build a condition comparing two expressions, or return an error if their values can't be
+/// compared in that way. A constant on either side is coerced to the type of the other, or to text
+/// for LIKE. The span is that of the condition in the text.
+fn prepare_condition(
+    left: Expression, comparison: Comparison, right: Expression, span: Range<usize>
+) -> Result<Condition, ParseError> {
+    let (left, right) = match comparison {
+        Comparison::IsNull | Comparison::IsNotNull => (left, right),
+        Comparison::Like => {
+            (coerce_constant(left, ColumnType::Text), coerce_constant(right, ColumnType::Text))
+        },
+        _ => {
+            let right = match left.value_type() {
+                Some(left_type) => coerce_constant(right, left_type),
+                None => right,
+            };
+            match right.value_type() {
+                Some(right_type) => (coerce_constant(left, right_type), right),
+                None => (left, right),
+            }
+        },
+    };
+    let (left_type, right_type) = (left.value_type(), right.value_type());
+    let types_match = match comparison {
+        Comparison::IsNull | Comparison::IsNotNull => true,
+        // Either side can be NULL, which is never of the wrong type.
+        Comparison::Like => {
+            left_type.is_none_or(|left_type| left_type == ColumnType::Text)
+                && right_type.is_none_or(|right_type| right_type == ColumnType::Text)
+        },
+        // Integers and real numbers can be compared to each other.
+        _ => {
+            left_type.is_none()
+                || right_type.is_none()
+                || is_numeric(left_type) == is_numeric(right_type)
+        },
+    };
+    if !types_match {
+        let message = match comparison {
+            Comparison::Like => String::from("LIKE can only be applied to text"),
+            _ => format!(
+                "can't compare {} to {}",
+                type_name(left_type.unwrap()),
+                type_name(right_type.unwrap())
+            ),
+        };
+        return Err(ParseError::new(message, span));
+    }
+
+    let condition = Condition { left, comparison, right };
+    let is_null_test = matches!(condition.comparison, Comparison::IsNull | Comparison::IsNotNull);
+    // A real number isn't an id, but can still be compared to one.
+    match condition.column_and_value() {
+        Some((ID_COLUMN, Value::Real(_))) => Ok(condition),
+        Some((ID_COLUMN, value)) if !is_null_test && !is_valid_id(value) => Err(invalid_id(span)),
+        _ => Ok(condition),
+    }
+}
+
+
+/// Parse the expression that another is compared to. If the other expression is a column, a word
+/// that isn't quoted is a value of the column's type, e.g. `username = jdoe`, and it takes in the
+/// dots and words right after it, e.g. `email = jdoe@example.com`. Parts of the expression that
+/// don't depend on the row are evaluated here, so that the planner sees constants.
+fn prepare_operand(
+    tokens: &[Token], position: &mut usize, other: &Expression
+) -> Result<Expression, ParseError> {
+    if let (Expression::Column(column), Some(Token { kind: TokenKind::Word(word), .. })) =
+        (other, tokens.get(*position))
+    {
+        let mut text = word.clone();
+        let mut end = *position + 1;
+        while let Some(next) = tokens.get(end).filter(|next| next.follows(&tokens[end - 1])) {
+            match &next.kind {
+                TokenKind::Word(word) => text.push_str(word),
+                TokenKind::Symbol(".") => text.push('.'),
+                _ => break,
+            }
+            end += 1;
+        }
+
+        // A qualified column, e.g. `u.id`, isn't a value.
+        let is_qualified_column = end == *position + 3
+            && tokens[*position + 1].is(".")
+            && word.eq_ignore_ascii_case(&table_qualifier(tokens, *position))
+            && tokens[*position + 2].name().and_then(column_index).is_some();
+        let is_single_word = column_index(&text).is_none()
+            && !is_qualified_column
+            && tokens.get(end).is_none_or(|next| !next.is("(") && !is_arithmetic_operator(next));
+        if is_single_word {
+            match parse_value(&text, &COLUMNS[*column]) {
+                Ok(value) => {
+                    *position = end;
+                    return Ok(Expression::Literal(value));
+                },
+                // A number of another type, e.g. a real number compared to an integer column, is
+                // parsed as an expression.
+                Err(NumberError::Invalid) if parse_number(&text).is_ok() => {},
+                Err(e) => return Err(value_error(e, &text, *column, span(tokens, *position..end))),
+            }
+        }
+    }
+    Ok(fold_constant(prepare_expression(tokens, position)?))
+}
+
+
+/// Coerce a constant to the given type if it converts without losing any information. Any other
+/// expression is left as it is, as is a constant that doesn't convert.
+fn coerce_constant(expression: Expression, to: ColumnType) -> Expression {
+    let coerced = match &expression {
+        Expression::Literal(value) => value.coerce(to),
+        _ => None,
+    };
+    coerced.map_or(expression, Expression::Literal)
+}
+
+
+/// Coerce a constant that is text to an integer, or to a real number if it isn't whole, for
+/// arithmetic. Any other expression is left as it is.
+fn coerce_to_number(expression: Expression) -> Expression {
+    let coerced = match &expression {
+        Expression::Literal(value @ Value::Text(_)) => {
+            value.coerce(ColumnType::Integer).or_else(|| value.coerce(ColumnType::Real))
+        },
+        _ => None,
+    };
+    coerced.map_or(expression, Expression::Literal)
+}
+
+
+/// Replace an expression that doesn't depend on the row with its value.
+fn fold_constant(expression: Expression) -> Expression {
+    // A subquery's results aren't known until it is executed.
+    if expression.columns().is_empty() && expression.subqueries().is_empty() {
+        Expression::Literal(expression.evaluate(&Row { values: Vec::new(), columns: None }))
+    } else {
+        expression
+    }
+}
+
+
+fn is_arithmetic_operator(token: &Token) -> bool {
+    ["+", "-", "*", "/", "||"].iter().any(|operator| token.is(operator))
+}
+
+
+/// Parse an expression. As in SQLite, `||` binds most tightly, then `*` and `/`, then `+` and `-`,
+/// and all of them are left-associative.
+fn prepare_expression(tokens: &[Token], position: &mut usize) -> Result<Expression, ParseError> {
+    prepare_binary(tokens, position, 1)
+}
+
+
+/// Parse operands separated by binary operators that bind at least as tightly as the given
+/// precedence.
+fn prepare_binary(
+    tokens: &[Token], position: &mut usize, precedence: u8
+) -> Result<Expression, ParseError> {
+    if precedence > BinaryOperator::Concat.precedence() {
+        return prepare_negation(tokens, position);
+    }
+
+    let start = *position;
+    let mut expression = prepare_binary(tokens, position, precedence + 1)?;
+    loop {
+        let operator = match tokens.get(*position).map(|token| &token.kind) {
+            Some(TokenKind::Symbol("+")) => BinaryOperator::Add,
+            Some(TokenKind::Symbol("-")) => BinaryOperator::Subtract,
+            Some(TokenKind::Symbol("*")) => BinaryOperator::Multiply,
+            Some(TokenKind::Symbol("/")) => BinaryOperator::Divide,
+            Some(TokenKind::Symbol("||")) => BinaryOperator::Concat,
+            _ => break,
+        };
+        if operator.precedence() != precedence {
+            break;
+        }
+        let symbol = &tokens[*position];
+        *position += 1;
+
+        let mut right = prepare_binary(tokens, position, precedence + 1)?;
+        // Arithmetic is only on numbers, while anything can be concatenated.
+        if operator != BinaryOperator::Concat {
+            expression = coerce_to_number(expression);
+            right = coerce_to_number(right);
+        }
+        let is_number = |expression: &Expression| is_numeric(expression.value_type());
+        if operator != BinaryOperator::Concat && !(is_number(&expression) && is_number(&right)) {
+            let message = format!("{} can only be applied to numbers", symbol);
+            return Err(ParseError::new(message, span(tokens, start..*position)));
+        }
+        expression = Expression::Binary(operator, Box::new(expression), Box::new(right));
+    }
+    Ok(expression)
+}
+
+
+/// Parse an operand, preceded by any number of minus signs.
+fn prepare_negation(tokens: &[Token], position: &mut usize) -> Result<Expression, ParseError> {
+    let start = *position;
+    if !skip_token(tokens, position, "-") {
+        return prepare_primary(tokens, position);
+    }
+
+    // A minus sign right before a number is part of it, so that the smallest integer, whose
+    // absolute value is too large for an integer, can be written.
+    if let Some(Token { kind: TokenKind::Word(word), .. }) = tokens.get(*position) {
+        if let Ok(value) = parse_number(&format!("-{}", word)) {
+            *position += 1;
+            return Ok(Expression::Literal(value));
+        }
+    }
+
+    let operand = coerce_to_number(prepare_negation(tokens, position)?);
+    if operand.value_type() == Some(ColumnType::Text) {
+        let message = String::from("'-' can only be applied to numbers");
+        return Err(ParseError::new(message, span(tokens, start..*position)));
+    }
+    Ok(Expression::Negate(Box::new(operand)))
+}
+
+
+/// Parse a column, a literal, a function call or a parenthesized expression.
+fn prepare_primary(tokens: &[Token], position: &mut usize) -> Result<Expression, ParseError> {
+    let token = tokens.get(*position).ok_or_else(|| expected(tokens, *position, "expression"))?;
+    *position += 1;
+
+    match &token.kind {
+        TokenKind::Symbol("(") if is_subquery(tokens, *position) => {
+            let subquery = prepare_subquery(tokens, position)?;
+            expect_token(tokens, position, ")")?;
+            Ok(Expression::Subquery(subquery))
+        },
+        TokenKind::Symbol("(") => {
+            let expression = prepare_expression(tokens, position)?;
+            expect_token(tokens, position, ")")?;
+            Ok(expression)
+        },
+        TokenKind::Symbol(_) => Err(expected(tokens, *position - 1, "expression")),
+        TokenKind::Text(text) => Ok(Expression::Literal(Value::Text(text.clone()))),
+        TokenKind::Word(name) | TokenKind::QuotedName(name) => {
+            let no_such = |what: &str| {
+                let message = format!("no such {}: {}", what, name);
+                ParseError::new(message, token.span.clone())
+            };
+            if tokens.get(*position).is_some_and(|next| next.is(".")) {
+                *position -= 1;
+                next_column(tokens, position).map(Expression::Column)
+            } else if token.is("cast") && skip_token(tokens, position, "(") {
+                prepare_cast(tokens, position)
+            } else if skip_token(tokens, position, "(") {
+                if let Some(function) = function_index(name) {
+                    prepare_call(tokens, position, function)
+                } else if let Some(function) = user_function(name) {
+                    prepare_user_call(tokens, position, function)
+                } else {
+                    Err(no_such("function"))
+                }
+            } else if let Some(column) = column_index(name) {
+                Ok(Expression::Column(column))
+            } else if let TokenKind::QuotedName(_) = token.kind {
+                Err(no_such("column"))
+            } else if name.eq_ignore_ascii_case("null") {
+                Ok(Expression::Literal(Value::Null))
+            } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+                match parse_number(name) {
+                    Ok(value) => Ok(Expression::Literal(value)),
+                    Err(NumberError::TooLarge) => Err(too_large(name, token.span.clone())),
+                    Err(NumberError::Invalid) => Err(expected(tokens, *position - 1, "expression")),
+                }
+            } else if is_word(name) {
+                Err(no_such("column"))
+            } else {
+                Err(expected(tokens, *position - 1, "expression"))
+            }
+        },
+    }
+}
+
+
+/// Return true if a subquery starts at the position, which is right after a parenthesis.
+fn is_subquery(tokens: &[Token], position: usize) -> bool {
+    tokens.get(position).is_some_and(|token| token.is("select"))
+}
+
+
+/// Parse a subquery, up to the parenthesis that ends it. It must have one column, whose values
+/// are its results.
+fn prepare_subquery(tokens: &[Token], position: &mut usize) -> Result<Arc<Subquery>, ParseError> {
+    let start = *position;
+    let select = prepare_select(tokens, position)?;
+    if select.result_columns().len() != 1 {
+        let message = String::from("a subquery must have one column");
+        return Err(ParseError::new(message, span(tokens, start..*position)));
+    }
+    let text = tokens_text(&tokens[start..*position]);
+    Ok(Arc::new(Subquery { select, text, values: Mutex::new(Vec::new()) }))
+}
+
+
+/// Parse what follows `cast(`, i.e. `<expression> as <type>)`.
+fn prepare_cast(tokens: &[Token], position: &mut usize) -> Result<Expression, ParseError> {
+    let operand = prepare_expression(tokens, position)?;
+    expect_token(tokens, position, "as")?;
+    let name = next_word(tokens, position, "type name")?;
+    let to = type_from_name(name).ok_or_else(|| {
+        ParseError::new(format!("no such type: {}", name), span(tokens, *position - 1..*position))
+    })?;
+    expect_token(tokens, position, ")")?;
+    Ok(Expression::Cast(Box::new(operand), to))
+}
+
+
+/// Parse the arguments of a call of the given function, starting after the opening parenthesis.
+/// Constant arguments are coerced to the types of the function's parameters.
+fn prepare_call(
+    tokens: &[Token], position: &mut usize, function: usize
+) -> Result<Expression, ParseError> {
+    // The call starts with the function's name, before the parenthesis.
+    let start = *position - 2;
+    let (arguments, argument_spans) = prepare_arguments(tokens, position)?;
+    let Function { name, parameters, required, .. } = &FUNCTIONS[function];
+    if arguments.len() < *required || arguments.len() > parameters.len() {
+        let message = format!("wrong number of arguments to {}", name);
+        return Err(ParseError::new(message, span(tokens, start..*position)));
+    }
+    let arguments: Vec<Expression> = arguments.into_iter()
+        .zip(*parameters)
+        .map(|(argument, parameter)| coerce_constant(argument, *parameter))
+        .collect();
+    for ((argument, parameter), span) in arguments.iter().zip(*parameters).zip(argument_spans) {
+        if argument.value_type().is_some_and(|value_type| value_type != *parameter) {
+            let message = format!("expected {} argument to {}", type_name(*parameter), name);
+            return Err(ParseError::new(message, span));
+        }
+    }
+    Ok(Expression::Call(function, arguments))
+}
+
+
+/// Parse the arguments of a call of a function that a program defined, as `prepare_call` does.
+/// They can be of any type.
+fn prepare_user_call(
+    tokens: &[Token], position: &mut usize, function: Arc<UserFunction>
+) -> Result<Expression, ParseError> {
+    let start = *position - 2;
+    let (arguments, _) = prepare_arguments(tokens, position)?;
+    if arguments.len() != function.num_args {
+        let message = format!("wrong number of arguments to {}", function.name);
+        return Err(ParseError::new(message, span(tokens, start..*position)));
+    }
+    Ok(Expression::UserCall(function, arguments))
+}
+
+
+/// Parse the arguments of a call, up to and including the closing parenthesis, and return them
+/// along with the span of each one.
+fn prepare_arguments(
+    tokens: &[Token], position: &mut usize
+) -> Result<(Vec<Expression>, Vec<Range<usize>>), ParseError> {
+    let mut arguments = Vec::new();
+    let mut argument_spans = Vec::new();
+    if !skip_token(tokens, position, ")") {
+        loop {
+            let argument_start = *position;
+            arguments.push(prepare_expression(tokens, position)?);
+            argument_spans.push(span(tokens, argument_start..*position));
+            if !skip_token(tokens, position, ",") {
+                break;
+            }
+        }
+        expect_token(tokens, position, ")")?;
+    }
+    Ok((arguments, argument_spans))
+}
+
+
+/// Move past a comparison operator and return the comparison that it stands for.
+fn next_comparison(tokens: &[Token], position: &mut usize) -> Result<Comparison, ParseError> {
+    let comparison = match tokens.get(*position).map(|token| &token.kind) {
+        Some(TokenKind::Symbol("=")) => Comparison::Equal,
+        Some(TokenKind::Symbol("!=")) => Comparison::NotEqual,
+        Some(TokenKind::Symbol("<")) => Comparison::LessThan,
+        Some(TokenKind::Symbol(">")) => Comparison::GreaterThan,
+        Some(TokenKind::Symbol("<=")) => Comparison::LessThanOrEqual,
+        Some(TokenKind::Symbol(">=")) => Comparison::GreaterThanOrEqual,
+        _ => return Err(expected(tokens, *position, "comparison operator")),
+    };
+    *position += 1;
+    Ok(comparison)
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::btree::*;
+    use crate::exec::*;
+    use crate::parser::*;
+    use crate::sql::*;
+    use crate::testing::*;
+
+    #[test]
+    fn username_too_long() {
+        let result = prepare_statement(
+            "insert 1 a-string-that-has-more-than-32-characters-in-it user@example.com"
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn lexer_and_parser() {
+        use crate::lexer::{Token, TokenKind, tokenize};
+
+        let tokens = tokenize("select \"user name\", 'it''s'||x.y>=1").unwrap();
+        let word = |s: &str| TokenKind::Word(String::from(s));
+        assert_eq!(
+            tokens.iter().map(|token| token.kind.clone()).collect::<Vec<_>>(),
+            vec![
+                word("select"),
+                TokenKind::QuotedName(String::from("user name")),
+                TokenKind::Symbol(","),
+                TokenKind::Text(String::from("it's")),
+                TokenKind::Symbol("||"),
+                word("x"),
+                TokenKind::Symbol("."),
+                word("y"),
+                TokenKind::Symbol(">="),
+                word("1"),
+            ]
+        );
+        assert_eq!(tokens[3], Token { kind: TokenKind::Text(String::from("it's")), span: 20..27 });
+        assert!(tokens[4].follows(&tokens[3]) && !tokens[3].follows(&tokens[2]));
+        assert!(tokenize("select 'abc").is_err());
+        assert!(tokenize("select \"abc").is_err());
+
+        let path = &temp_path("testdb-parser.mysql");
+        let mut table = db_open_new(path);
+        for command in [
+            "INSERT INTO Users VALUES (1, 'it''s', 'a b@x')",
+            "insert 2 'o''brien' b@x.com",
+            "Insert Into \"users\" (\"id\", \"username\") Values (3, 'carol')",
+        ] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+        assert_eq!(
+            results(&mut table, "SELECT \"username\" FROM \"users\" WHERE Email = 'a b@x'"),
+            vec![vec![text("it's")]]
+        );
+        assert_eq!(
+            results(&mut table, "select username from users where email = b@x.com"),
+            vec![vec![text("o'brien")]]
+        );
+        assert_eq!(
+            results(&mut table, "select id from users where username = 'where' or id=3"),
+            vec![vec![Value::Integer(3)]]
+        );
+
+        for command in [
+            "select 'abc",
+            "select \"nosuch\"",
+            "select \"null\"",
+            "select * from users where",
+            "select * where id = 1 from users",
+            "\"select\" *",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn quoted_strings() {
+        let mut table = db_open_new(&temp_path("testdb-quoted.mysql"));
+        for command in [
+            "insert into users values (1, 'O''Brien', 'o brien@x')",
+            "insert 2 'jane doe' 'j,doe@x'",
+            "insert 'it''s' ''''",
+            &format!("insert into users values (4, '{}''', 'x')", "a".repeat(31)),
+        ] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+        let long_name = format!("insert into users values (5, '{}''', 'x')", "a".repeat(32));
+        assert!(prepare_statement(&long_name).is_err());
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+        assert_eq!(
+            results(&mut table, "select username, email from users where id < 4"),
+            vec![
+                vec![text("O'Brien"), text("o brien@x")],
+                vec![text("jane doe"), text("j,doe@x")],
+                vec![text("it's"), text("'")],
+            ]
+        );
+        assert_eq!(
+            results(&mut table, "select id from users where username = 'O''Brien'"),
+            vec![vec![Value::Integer(1)]]
+        );
+        assert_eq!(
+            results(&mut table, "select id from users where username like '% %' or email = ''''"),
+            vec![vec![Value::Integer(2)], vec![Value::Integer(3)]]
+        );
+
+        // Items are labelled with quotes doubled, as they would be written.
+        let select = prepare_statement("select 'it''s ' || username from users").unwrap();
+        let names: Vec<String> = select.select_list.unwrap().iter().map(SelectItem::name).collect();
+        assert_eq!(names, ["'it''s ' || username"]);
+        assert!(prepare_statement(&format!("select {}", names[0])).is_ok());
+
+        for command in ["select where username = 'O'Brien'", "insert 6 'jane doe x@x"] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (command, message) in [
+            ("delete abc", "expected integer id, found 'abc' at column 8"),
+            ("insert abc jdoe jdoe@x", "expected integer id, found 'abc' at column 8"),
+            ("insert 1 jdoe jdoe@x extra", "expected 2 or 3 values, found 4 at column 22"),
+            ("select where id = 1 and", "expected expression, found end of statement at column 24"),
+            ("select where nosuch = 1", "no such column: nosuch at column 14"),
+            ("insert into users values (1, 'a')", "expected ',', found ')' at column 33"),
+            ("select upper(id)", "expected text argument to upper at column 14"),
+            ("select where (id = 1", "expected ')', found end of statement at column 21"),
+            ("select order by id x", "expected end of statement, found 'x' at column 20"),
+            ("select 'abc", "unterminated quoted text at column 8"),
+            ("", "expected statement, found end of statement at column 1"),
+            (
+                "alter table users rename to people",
+                "cannot rename table users, since the schema is fixed at column 1",
+            ),
+            (
+                "alter table users rename column EMAIL to mail",
+                "cannot rename column email, since the schema is fixed at column 1",
+            ),
+            ("alter table users rename column mail to email", "no such column: mail at column 33"),
+            ("alter table people rename to users", "no such table: people at column 13"),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+
+        // Columns count characters, while spans are in bytes.
+        let error = prepare_statement("select 'é' || nosuch").unwrap_err();
+        assert_eq!(error.message(), "no such column: nosuch");
+        assert_eq!((error.column(), error.span()), (15, 15..21));
+    }
+
+    #[test]
+    fn multiple_statements() {
+        let mut table = db_open_new(&temp_path("testdb-multiple.mysql"));
+        let statements =
+            Statement::parse_all("insert 1 a a@x; insert 2 b b@x;delete abc; insert 3 'c;d' c@x;");
+        assert_eq!(statements.len(), 4);
+        let error = statements[2].as_ref().unwrap_err();
+        assert_eq!(error.to_string(), "expected integer id, found 'abc' at column 39");
+        for statement in statements.iter().flatten() {
+            assert!(execute_statement(statement, &mut table).is_ok());
+        }
+
+        let select = prepare_statement("select username from users;").unwrap();
+        let text = |s: &str| vec![Value::Text(String::from(s))];
+        let results = select_results(&select, &mut table).unwrap();
+        assert_eq!(results, vec![text("a"), text("b"), text("c;d")]);
+
+        assert!(prepare_statement("select; select").is_err());
+        assert!(Statement::parse_all(" ; ;").is_empty());
+        assert_eq!(Statement::parse_all("select 'a; select").len(), 1);
+    }
+
+    #[test]
+    fn parse_only() {
+        let ast = crate::parse("select username where id = 1;").unwrap();
+        assert!(matches!(ast.kind, StatementKind::Select));
+        let error = crate::parse("select where id =").unwrap_err();
+        assert_eq!(error.to_string(), "expected expression, found end of statement at column 18");
+
+
+        let database = Database::open(&temp_path("testdb-parse-only.mysql")).unwrap();
+        // Meta-commands return their output rather than printing it.
+        for (command, output) in [
+            (
+                ".check select; delete abc",
+                vec!["OK", "Error: expected integer id, found 'abc' at column 16"],
+            ),
+            (".check", vec!["Error: expected statement, found end of statement at column 1"]),
+            (".checkpoint", vec!["0 page(s) checkpointed"]),
+        ] {
+            match database.meta_command(command) {
+                MetaCommandResult::Success(lines) => assert_eq!(lines, output),
+                _ => panic!("{} failed", command),
+            }
+        }
+    }
+
+    #[test]
+    fn numeric_literals() {
+        let mut table = db_open_new(&temp_path("testdb-numbers.mysql"));
+        for command in ["insert 0x10 a a@x", "insert +2 b b@x", "insert 3 c c@x"] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        assert_eq!(
+            results(&mut table, "select -5, 0xff, 0xffffffffffffffff, -9223372036854775808"),
+            vec![vec![
+                Value::Integer(-5), Value::Integer(255), Value::Integer(-1),
+                Value::Integer(i64::MIN),
+            ]; 3]
+        );
+        assert_eq!(
+            results(&mut table, "select 1.5, 2e-3, id / 2.0, -id * 1.5, 1.0 / 0 where id = 3"),
+            vec![vec![
+                Value::Real(1.5), Value::Real(0.002), Value::Real(1.5), Value::Real(-4.5),
+                Value::Null,
+            ]]
+        );
+        // Integers and real numbers compare by value.
+        assert_eq!(
+            results(&mut table, "select id where id > 2.5 or id = 2.0"),
+            vec![vec![Value::Integer(2)], vec![Value::Integer(3)], vec![Value::Integer(16)]]
+        );
+        assert_eq!(Value::Integer(16), Value::Real(16.0));
+        assert_eq!(
+            results(&mut table, "select id where id = 0x10"), vec![vec![Value::Integer(16)]]
+        );
+        // Real numbers are written so that they parse as real numbers again.
+        let select = prepare_statement("select 2.0 * id from users").unwrap();
+        assert_eq!(select.select_list.unwrap()[0].name(), "2.0 * id");
+
+        for (command, message) in [
+            ("select 9223372036854775808", "number is too large: 9223372036854775808 at column 8"),
+            ("select 0x10000000000000000", "number is too large: 0x10000000000000000 at column 8"),
+            ("select 1e999", "number is too large: 1e999 at column 8"),
+            (
+                "delete 99999999999999999999",
+                "number is too large: 99999999999999999999 at column 8",
+            ),
+            ("delete 4294967296", "id must be an integer from 0 to 4294967295 at column 8"),
+            ("insert 1.5 a a@x", "expected integer id, found '1.5' at column 8"),
+            ("select 1.5 || 'x' - 1", "'-' can only be applied to numbers at column 8"),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn cast_and_coercion() {
+        let mut table = db_open_new(&temp_path("testdb-cast.mysql"));
+        for command in [
+            "insert into users values ('1', 42, 'a@x')",
+            "insert into users values (2.0, 'b', 1.5)",
+            "insert 0x3 c c@x",
+        ] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+        assert_eq!(
+            results(&mut table, "select username, email where id = '1' or id = 2"),
+            vec![vec![text("42"), text("a@x")], vec![text("b"), text("1.5")]]
+        );
+        assert_eq!(
+            results(&mut table, "select id where username = 42 or id in ('3', 2.5)"),
+            vec![vec![Value::Integer(1)], vec![Value::Integer(3)]]
+        );
+        assert_eq!(
+            results(&mut table, "select '2' * id, -'1.5', upper(1.5), abs('-7') where id = 3"),
+            vec![vec![Value::Integer(6), Value::Real(-1.5), text("1.5"), Value::Integer(7)]]
+        );
+
+        assert_eq!(
+            results(&mut table, "select cast(id as real), cast(email as real) where id = 2"),
+            vec![vec![Value::Real(2.0), Value::Real(1.5)]]
+        );
+        assert_eq!(
+            results(
+                &mut table,
+                "select cast(-2.7 as int), cast(' 12 ' as integer), cast('x' as int), \
+                 cast(1e300 as integer), cast(id / 2.0 as text), cast(null as text) where id = 3",
+            ),
+            vec![vec![
+                Value::Integer(-2), Value::Integer(12), Value::Null, Value::Integer(i64::MAX),
+                text("1.5"), Value::Null,
+            ]]
+        );
+        let select = prepare_statement("select cast(id + 1 as TEXT) || 'x'").unwrap();
+        assert_eq!(select.select_list.unwrap()[0].name(), "cast(id + 1 as text) || 'x'");
+
+        for (command, message) in [
+            ("select cast(id as blob)", "no such type: blob at column 19"),
+            ("select X'00ff' = 1", "BLOB literals are not supported at column 8"),
+            ("select cast(id text)", "expected 'as', found 'text' at column 16"),
+            ("select where id = 'abc'", "can't compare integer to text at column 14"),
+            ("select where id = 5e9", "id must be an integer from 0 to 4294967295 at column 14"),
+            ("select cast(id as int) || 'a' + 1", "'+' can only be applied to numbers at column 8"),
+            (
+                "insert into users values ('x', 'a', 'b')",
+                "expected integer id, found \"x\" at column 27",
+            ),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let mut table = db_open_new(&temp_path("testdb-aliases.mysql"));
+        for command in ["insert 1 a a@x", "insert 2 b b@x", "insert 3 b c@x"] {
+            let insert = prepare_statement(command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok(), "{}", command);
+        }
+
+        let select = prepare_statement(
+            "select u.id as user_id, upper(u.username) name, \"U\".email \"E-mail\" \
+             from users as u where u.id >= 2 and email = u.email order by u.id desc",
+        ).unwrap();
+        assert_eq!(select.result_columns(), ["user_id", "name", "E-mail"]);
+        let text = |s: &str| Value::Text(String::from(s));
+        assert_eq!(
+            select_results(&select, &mut table).unwrap(),
+            vec![
+                vec![Value::Integer(3), text("B"), text("c@x")],
+                vec![Value::Integer(2), text("B"), text("b@x")],
+            ]
+        );
+
+        let select = prepare_statement(
+            "select users.username, count(*) as n from users group by users.username",
+        ).unwrap();
+        assert_eq!(select.result_columns(), ["username", "n"]);
+        assert_eq!(
+            select_results(&select, &mut table).unwrap(),
+            vec![vec![text("a"), Value::Integer(1)], vec![text("b"), Value::Integer(2)]]
+        );
+
+        let insert = "insert into users values (4, 'd', 'd@x') returning users.id as new_id";
+        let insert = prepare_statement(insert).unwrap();
+        assert_eq!(insert.returning.unwrap()[0].name(), "new_id");
+
+        // A reserved word is only an alias when it's quoted.
+        let select = prepare_statement("select id \"limit\" from users u").unwrap();
+        assert_eq!(select.result_columns(), ["limit"]);
+
+        for (command, message) in [
+            ("select users.id from users u", "no such table: users at column 8"),
+            ("select x.id", "no such table: x at column 8"),
+            ("select u.nosuch from users u", "no such column: nosuch at column 10"),
+            ("select id as from users", "expected alias, found 'from' at column 14"),
+            ("select id as limit from users", "expected alias, found 'limit' at column 14"),
+            ("select id limit from users", "expected end of statement, found 'limit' at column 11"),
+        ] {
+            assert_eq!(prepare_statement(command).unwrap_err().to_string(), message);
+        }
+    }
+}
@@ -0,0 +1,596 @@
+//! Converting rows to and from Rust types with serde, which is behind the `serde` feature. A
+//! struct is matched with a row by name, each field with the column of the same name.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use serde::ser::{self, Impossible, Serialize, SerializeMap, SerializeStruct};
+
+use crate::error::Error;
+use crate::exec::Database;
+use crate::sql::{COLUMNS, Row, Value, column_index, prepare_insert_rows};
+
+
+impl Database {
+    /// Parse and execute a SELECT statement as `query` does, and deserialize each of its results
+    /// into a T, e.g. a struct with a field for each item in the select list.
+    pub fn query_as<T: DeserializeOwned>(
+        &self,
+        command: &str,
+    ) -> Result<impl Iterator<Item = Result<T, Error>>, Error> {
+        Ok(self.query(command)?.map(|row| row.and_then(|row| from_row(&row))))
+    }
+
+    /// Insert a row serialized from a struct or a map, and return the number of rows inserted.
+    /// Columns that it has no field for are NULL, except for the id, which is assigned.
+    pub fn insert<T: Serialize>(&self, value: &T) -> Result<usize, Error> {
+        let row = to_row(value)?;
+        let insert = prepare_insert_rows(vec![row])
+            .ok_or(Error::Invalid("row does not fit the table's columns"))?;
+        self.execute_statement(&insert)
+    }
+}
+
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::Conversion(message.to_string())
+    }
+}
+
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::Conversion(message.to_string())
+    }
+}
+
+
+/// Deserialize a T from a row.
+pub(crate) fn from_row<T: DeserializeOwned>(row: &Row) -> Result<T, Error> {
+    T::deserialize(RowDeserializer { row })
+}
+
+
+/// Serialize a T into a row of the table.
+pub(crate) fn to_row<T: Serialize>(value: &T) -> Result<Row, Error> {
+    let mut serializer = RowSerializer { values: vec![Value::Null; COLUMNS.len()], column: None };
+    value.serialize(&mut serializer)?;
+    Ok(Row { values: serializer.values, columns: None })
+}
+
+
+/// Deserializes a row as a map from column names to values, or as a sequence of its values.
+struct RowDeserializer<'a> {
+    row: &'a Row,
+}
+
+
+impl<'de> de::Deserializer<'de> for RowDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(RowAccess {
+            names: self.row.column_names(),
+            values: &self.row.values,
+            position: 0,
+        })
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(RowAccess { names: Vec::new(), values: &self.row.values, position: 0 })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct map struct enum identifier ignored_any
+    }
+}
+
+
+/// Walks through a row's values, along with their names when it's deserialized as a map.
+struct RowAccess<'a> {
+    names: Vec<&'a str>,
+    values: &'a [Value],
+    position: usize,
+}
+
+
+impl<'de> MapAccess<'de> for RowAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.names.get(self.position) {
+            Some(&name) => seed.deserialize(name.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = &self.values[self.position];
+        self.position += 1;
+        seed.deserialize(ValueDeserializer { value })
+    }
+}
+
+
+impl<'de> SeqAccess<'de> for RowAccess<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.values.get(self.position) {
+            Some(value) => {
+                self.position += 1;
+                seed.deserialize(ValueDeserializer { value }).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+
+/// Deserializes a value as an integer, a string, or a unit for NULL. An Option is None for NULL,
+/// and otherwise Some of whatever the value is.
+struct ValueDeserializer<'a> {
+    value: &'a Value,
+}
+
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Null => visitor.visit_unit(),
+            Value::Integer(n) => visitor.visit_i64(*n),
+            Value::Real(x) => visitor.visit_f64(*x),
+            Value::Text(s) => visitor.visit_string(s.clone()),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+
+/// Serializes a struct or a map into the values of a row of the table, each field into the column
+/// of the same name.
+struct RowSerializer {
+    values: Vec<Value>,
+    // The column of the map entry whose key was just serialized.
+    column: Option<usize>,
+}
+
+
+impl RowSerializer {
+    /// Serialize the value of the field with the given name into its column.
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let column = column_index(name)
+            .ok_or_else(|| Error::Conversion(format!("no such column: {}", name)))?;
+        self.values[column] = value.serialize(ValueSerializer)?;
+        Ok(())
+    }
+}
+
+
+fn unsupported_row() -> Error {
+    Error::Conversion(String::from("a row can only be serialized from a struct or a map"))
+}
+
+
+impl ser::Serializer for &mut RowSerializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Impossible<(), Error>;
+    type SerializeTuple = Impossible<(), Error>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_char(self, _v: char) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_str(self, _v: &str) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<(), Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(unsupported_row())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(unsupported_row())
+    }
+}
+
+
+impl SerializeStruct for &mut RowSerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        RowSerializer::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+
+impl SerializeMap for &mut RowSerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let name = match key.serialize(ValueSerializer)? {
+            Value::Text(name) => name,
+            _ => return Err(Error::Conversion(String::from("a column name must be a string"))),
+        };
+        let column = column_index(&name)
+            .ok_or_else(|| Error::Conversion(format!("no such column: {}", name)))?;
+        self.column = Some(column);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let column = self.column.take().unwrap();
+        self.values[column] = value.serialize(ValueSerializer)?;
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+
+/// Serializes a field into a value: an integer into an integer, a string or a character into
+/// text, and None or a unit into NULL.
+struct ValueSerializer;
+
+
+fn unsupported_value() -> Error {
+    Error::Conversion(String::from("a value can only be an integer, a string or None"))
+}
+
+
+impl ValueSerializer {
+    fn integer<T: TryInto<i64>>(n: T) -> Result<Value, Error> {
+        n.try_into()
+            .map(Value::Integer)
+            .map_err(|_| Error::Conversion(String::from("integer is too large for a column")))
+    }
+}
+
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = Impossible<Value, Error>;
+    type SerializeTuple = Impossible<Value, Error>;
+    type SerializeTupleStruct = Impossible<Value, Error>;
+    type SerializeTupleVariant = Impossible<Value, Error>;
+    type SerializeMap = Impossible<Value, Error>;
+    type SerializeStruct = Impossible<Value, Error>;
+    type SerializeStructVariant = Impossible<Value, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Integer(i64::from(v)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        ValueSerializer::integer(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Value, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Value, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Value, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(Value::Text(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Value, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Err(unsupported_value())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(unsupported_value())
+    }
+}
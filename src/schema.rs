@@ -0,0 +1,82 @@
+//! Describing the schema of a database to a program, rather than printing it for a person as the
+//! shell does.
+
+use crate::error::Error;
+use crate::exec::Database;
+use crate::pager::LockLevel;
+use crate::sql::{COLUMNS, ColumnType, ID_COLUMN, TABLE_NAME};
+
+
+/// A column of a table, as described by `Database::columns`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub column_type: ColumnType,
+    /// The maximum length in bytes of a text value, or None for other types.
+    pub max_length: Option<usize>,
+    /// Whether the column is the table's key, i.e. its id.
+    pub primary_key: bool,
+}
+
+
+/// An index on a table, as described by `Database::indexes`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexInfo {
+    pub name: String,
+    pub table: String,
+    pub column: String,
+    /// The expression of the column that the index is on, or None if it is on the column itself.
+    pub expression: Option<String>,
+    /// The WHERE clause of a partial index, or None for an index with an entry for every row.
+    pub where_clause: Option<String>,
+}
+
+
+impl Database {
+    /// Return the names of the tables in the database.
+    pub fn tables(&self) -> Vec<String> {
+        vec![String::from(TABLE_NAME)]
+    }
+
+    /// Return the columns of the table with the given name, in order.
+    pub fn columns(&self, table: &str) -> Result<Vec<ColumnInfo>, Error> {
+        check_table_name(table)?;
+        let columns = COLUMNS.iter().enumerate().map(|(i, column)| ColumnInfo {
+            name: String::from(column.name),
+            column_type: column.column_type,
+            max_length: match column.column_type {
+                ColumnType::Integer | ColumnType::Real => None,
+                ColumnType::Text => Some(column.max_length),
+            },
+            primary_key: i == ID_COLUMN,
+        });
+        Ok(columns.collect())
+    }
+
+    /// Return the indexes on the table with the given name, in the order they were created.
+    pub fn indexes(&self, table: &str) -> Result<Vec<IndexInfo>, Error> {
+        check_table_name(table)?;
+        // The indexes are read from the header, which another connection may have changed.
+        let table = &mut *self.table();
+        table.lock(LockLevel::Shared)?;
+        let indexes = table.indexes.iter().map(|index| IndexInfo {
+            name: index.name.clone(),
+            table: String::from(TABLE_NAME),
+            column: String::from(COLUMNS[index.column].name),
+            expression: index.expression.as_ref().map(|(text, _)| text.clone()),
+            where_clause: index.where_clause.as_ref().map(|(text, _)| text.clone()),
+        });
+        let indexes = indexes.collect();
+        table.finish_statement()?;
+        Ok(indexes)
+    }
+}
+
+
+fn check_table_name(table: &str) -> Result<(), Error> {
+    if table.eq_ignore_ascii_case(TABLE_NAME) {
+        Ok(())
+    } else {
+        Err(Error::Invalid("no such table"))
+    }
+}
@@ -0,0 +1,2123 @@
+//! Parsing SQL statements into `Statement`s, and the values, rows and expressions in them.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ptr;
+use std::str;
+use std::sync::{Arc, Mutex};
+#[cfg(not(all(target_family = "wasm", target_os = "unknown")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::btree::{ID_KEY_SIZE, Index, NULL_TAG, encode_index_value};
+use crate::error::{Error, ParseError};
+use crate::exec::Action;
+use crate::parser::{prepare_statement, prepare_statements};
+
+
+/// A parsed SQL statement.
+#[derive(Debug)]
+pub struct Statement {
+    pub(crate) kind: StatementKind,
+    // Ids may be NULL, in which case they are assigned when the statement is executed.
+    pub(crate) rows_to_insert: Option<Vec<Row>>,
+    pub(crate) copy_from: Option<String>,
+    pub(crate) id_to_delete: Option<u32>,
+    pub(crate) where_clause: Option<Predicate>,
+    pub(crate) order_by: Option<OrderBy>,
+    pub(crate) index_to_create: Option<CreateIndex>,
+    // None for a select list of `*`, i.e. whole rows.
+    pub(crate) select_list: Option<Vec<SelectItem>>,
+    pub(crate) group_by: Option<GroupBy>,
+    pub(crate) distinct: bool,
+    // The values to output for each row that an INSERT or DELETE affects.
+    pub(crate) returning: Option<Vec<SelectItem>>,
+    // The name of the savepoint that a SAVEPOINT, ROLLBACK TO or RELEASE statement refers to.
+    pub(crate) savepoint: Option<String>,
+    pub(crate) pragma: Option<Pragma>,
+    // The attached database that a statement refers to, e.g. `other` in `from other.users`. None
+    // for the main database.
+    pub(crate) database: Option<String>,
+    // The file that an ATTACH statement opens.
+    pub(crate) attach_path: Option<String>,
+    // The SELECTs whose results are combined in turn with a SELECT statement's, e.g. by `union
+    // select ...`.
+    pub(crate) compound: Vec<(SetOperator, Statement)>,
+    // The common table expressions that a WITH clause defines, with their names, which the
+    // statement's SELECTs can read instead of the table.
+    pub(crate) common_tables: Vec<(String, Statement)>,
+    // The name of the common table expression that a SELECT reads instead of the table, if any.
+    pub(crate) common_table: Option<String>,
+    // The view that a SELECT reads instead of the table, or that a DROP VIEW statement drops.
+    pub(crate) view: Option<String>,
+    pub(crate) view_to_create: Option<CreateView>,
+    // The trigger that a DROP TRIGGER statement drops.
+    pub(crate) trigger: Option<String>,
+    pub(crate) trigger_to_create: Option<CreateTrigger>,
+    // Whether the statement's plan is output instead of executing it, as `explain` before it asks.
+    pub(crate) explain: bool,
+    // Whether an explained statement is executed anyway, as `explain analyze` asks, so that what
+    // each step of the plan did is output instead.
+    pub(crate) analyze: bool,
+}
+
+
+/// An operation combining the results of two SELECTs. Except for UNION ALL, which keeps every
+/// result of both, duplicate results are left out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SetOperator {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+
+impl SetOperator {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SetOperator::Union => "UNION",
+            SetOperator::UnionAll => "UNION ALL",
+            SetOperator::Intersect => "INTERSECT",
+            SetOperator::Except => "EXCEPT",
+        }
+    }
+}
+
+
+/// The syntax tree that parsing a statement produces, which is the statement itself.
+pub type Ast = Statement;
+
+
+#[derive(Debug)]
+pub(crate) enum StatementKind {
+    Insert,
+    InsertOrReplace,
+    Select,
+    Copy,
+    Delete,
+    LastInsertRowid,
+    Changes,
+    CreateIndex,
+    CreateView,
+    DropView,
+    CreateTrigger,
+    DropTrigger,
+    Analyze,
+    Begin,
+    Commit,
+    Rollback,
+    Savepoint,
+    RollbackTo,
+    Release,
+    Pragma,
+    Vacuum,
+    Attach,
+    Detach,
+}
+
+
+/// The type of a column's values, or of the values of an expression. No column of the table holds
+/// real numbers, but expressions can compute them. There is no BLOB type: the schema is fixed, so
+/// no column could hold one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+
+/// Return the name of a type, as it is written in SQL.
+pub(crate) fn type_name(column_type: ColumnType) -> &'static str {
+    match column_type {
+        ColumnType::Integer => "integer",
+        ColumnType::Real => "real",
+        ColumnType::Text => "text",
+    }
+}
+
+
+/// Return the type with the given name, as written in a CAST.
+pub(crate) fn type_from_name(name: &str) -> Option<ColumnType> {
+    match name.to_ascii_lowercase().as_str() {
+        "integer" | "int" => Some(ColumnType::Integer),
+        "real" => Some(ColumnType::Real),
+        "text" => Some(ColumnType::Text),
+        _ => None,
+    }
+}
+
+
+/// The definition of a column of the table.
+pub(crate) struct ColumnDef {
+    pub(crate) name: &'static str,
+    pub(crate) column_type: ColumnType,
+    // The maximum length in bytes of a text value. Unused for other types.
+    pub(crate) max_length: usize,
+}
+
+
+/// The columns of the table. There is no CREATE TABLE, so the schema is fixed. The first column is
+/// the id, which is also the row's key in the B-tree.
+pub(crate) const COLUMNS: [ColumnDef; 3] = [
+    ColumnDef { name: "id", column_type: ColumnType::Integer, max_length: 0 },
+    ColumnDef { name: "username", column_type: ColumnType::Text, max_length: 32 },
+    ColumnDef { name: "email", column_type: ColumnType::Text, max_length: 255 },
+];
+pub(crate) const ID_COLUMN: usize = 0;
+/// The name of the table.
+pub(crate) const TABLE_NAME: &str = "users";
+
+
+/// Return the index of the column with the given name.
+pub(crate) fn column_index(name: &str) -> Option<usize> {
+    COLUMNS.iter().position(|column| column.name.eq_ignore_ascii_case(name))
+}
+
+
+/// A value in a row. NULL sorts first, then numbers, which compare by their value whether they are
+/// integers or real numbers, and then text.
+#[derive(Clone)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    /// A real number, which is never infinite or NaN, since arithmetic that would give one gives
+    /// NULL instead.
+    Real(f64),
+    Text(String),
+}
+
+
+impl Value {
+    /// The position of the value's type in the order that values of different types sort in.
+    fn type_order(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+        }
+    }
+
+    /// Convert the value to a type without losing any information, or return None if it can't
+    /// be. This is how a value is coerced wherever one of another type is expected: when it is
+    /// inserted into a column, when a constant is compared to a value, and when a constant is an
+    /// operand of arithmetic or an argument of a function. The value converts from each type to
+    /// each other if:
+    ///
+    /// | from \ to | integer            | real                | text   |
+    /// |-----------|--------------------|---------------------|--------|
+    /// | integer   | always             | if exactly the same | always |
+    /// | real      | if it is whole     | always              | always |
+    /// | text      | if a whole number  | if a number         | always |
+    ///
+    /// NULL converts to NULL of any type. A number becomes text as it is written, e.g. `2.0`, and
+    /// text is a number if it is written as a numeric literal is, e.g. `'0x10'`.
+    pub(crate) fn coerce(&self, to: ColumnType) -> Option<Value> {
+        match (self, to) {
+            (Value::Integer(n), ColumnType::Real) => {
+                let x = *n as f64;
+                if real_to_integer(x) == Some(*n) { Some(Value::Real(x)) } else { None }
+            },
+            (Value::Real(x), ColumnType::Integer) => real_to_integer(*x).map(Value::Integer),
+            (Value::Text(s), ColumnType::Integer | ColumnType::Real) => {
+                parse_number(s).ok()?.coerce(to)
+            },
+            _ => Some(self.cast(to)),
+        }
+    }
+
+    /// Convert the value to a type as `CAST` does. Unlike coercion, this always succeeds, but
+    /// information may be lost: a real number becomes an integer by dropping its fractional part,
+    /// or the largest or smallest integer if it is beyond them, an integer becomes the nearest
+    /// real number, and text becomes a number if what it holds, without surrounding whitespace,
+    /// is one, or NULL if not.
+    pub(crate) fn cast(&self, to: ColumnType) -> Value {
+        match (self, to) {
+            (Value::Integer(n), ColumnType::Real) => Value::Real(*n as f64),
+            // `as` truncates towards zero and saturates.
+            (Value::Real(x), ColumnType::Integer) => Value::Integer(*x as i64),
+            (Value::Integer(n), ColumnType::Text) => Value::Text(n.to_string()),
+            (Value::Real(x), ColumnType::Text) => Value::Text(format!("{:?}", x)),
+            (Value::Text(s), ColumnType::Integer | ColumnType::Real) => {
+                parse_number(s.trim()).map_or(Value::Null, |number| number.cast(to))
+            },
+            _ => self.clone(),
+        }
+    }
+}
+
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Integer(a), Value::Real(b)) => compare_integer_to_real(*a, *b),
+            (Value::Real(a), Value::Integer(b)) => compare_integer_to_real(*b, *a).reverse(),
+            (Value::Real(a), Value::Real(b)) => a.total_cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            _ => self.type_order().cmp(&other.type_order()),
+        }
+    }
+}
+
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+
+impl Eq for Value {}
+
+
+// Equal values must hash the same, so a real number that equals an integer hashes as it.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.type_order().hash(state);
+        match self {
+            Value::Null => {},
+            Value::Integer(n) => n.hash(state),
+            Value::Real(x) => match real_to_integer(*x) {
+                Some(n) => n.hash(state),
+                // Zero, the only number with two representations, is whole, so any other equal
+                // real numbers have the same bits.
+                None => x.to_bits().hash(state),
+            },
+            Value::Text(s) => s.hash(state),
+        }
+    }
+}
+
+
+/// Return the integer that a real number is equal to, if there is one.
+fn real_to_integer(x: f64) -> Option<i64> {
+    // i64::MAX isn't exactly representable, so it rounds up to 2^63, which is too large.
+    if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 {
+        Some(x as i64)
+    } else {
+        None
+    }
+}
+
+
+/// Compare an integer to a real number. Large integers can't all be represented exactly as real
+/// numbers, so they are compared as integers when the real number is a whole one that fits.
+fn compare_integer_to_real(a: i64, b: f64) -> Ordering {
+    match real_to_integer(b) {
+        Some(b) => a.cmp(&b),
+        None => (a as f64).total_cmp(&b),
+    }
+}
+
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(n) => write!(f, "{}", n),
+            // Unlike Display, Debug always writes a decimal point or an exponent, e.g. `2.0`, so
+            // that a real number can be told from an integer, and parses as one.
+            Value::Real(x) => write!(f, "{:?}", x),
+            Value::Text(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+
+/// A row of the table, with one value for each column in COLUMNS, or a result of a query with a
+/// select list, with one value for each item in it.
+#[derive(Clone)]
+pub struct Row {
+    pub(crate) values: Vec<Value>,
+    // The names of the values of a query's result, which are shared by all of its results. None
+    // for a row of the table, whose values are named by COLUMNS.
+    pub(crate) columns: Option<Arc<[String]>>,
+}
+
+
+impl Row {
+    /// Return the row's values, in the order of the columns.
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// Return the value of the column with the given name, converted to a Rust type, e.g.
+    /// `row.get::<u32>("id")`. It is an error if there is no such column, or if its value can't be
+    /// converted to the type, which includes NULL unless the type is an Option.
+    pub fn get<T: FromValue>(&self, column: &str) -> Result<T, Error> {
+        let names = self.column_names();
+        let value = names.iter()
+            .position(|name| name.eq_ignore_ascii_case(column))
+            .and_then(|position| self.values.get(position))
+            .ok_or(Error::Invalid("no such column"))?;
+        T::from_value(value).ok_or(Error::Invalid("value has the wrong type for the column"))
+    }
+
+    /// Return the names of the row's values, in order.
+    pub(crate) fn column_names(&self) -> Vec<&str> {
+        match &self.columns {
+            Some(columns) => columns.iter().map(String::as_str).collect(),
+            None => COLUMNS.iter().map(|column| column.name).collect(),
+        }
+    }
+
+    /// Return the row's id, i.e. its key in the B-tree.
+    pub(crate) fn id(&self) -> u32 {
+        match self.values[ID_COLUMN] {
+            Value::Integer(id) => id as u32,
+            _ => panic!("Row has a non-integer id"),
+        }
+    }
+}
+
+
+impl fmt::Debug for Row {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("Row");
+        for (name, value) in self.column_names().into_iter().zip(&self.values) {
+            debug.field(name, value);
+        }
+        debug.finish()
+    }
+}
+
+
+/// A Rust type that a value can be converted to by `Row::get`.
+pub trait FromValue: Sized {
+    /// Convert the value, or return None if it isn't of this type, or doesn't fit in it.
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+
+impl FromValue for i32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        i32::try_from(i64::from_value(value)?).ok()
+    }
+}
+
+
+impl FromValue for u32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        u32::try_from(i64::from_value(value)?).ok()
+    }
+}
+
+
+impl FromValue for u64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        u64::try_from(i64::from_value(value)?).ok()
+    }
+}
+
+
+// Integers convert to real numbers too, although large ones are rounded.
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Integer(n) => Some(*n as f64),
+            Value::Real(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Text(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+
+// NULL converts to None, and any other value to Some if it converts to the inner type.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Comparison {
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterThan,
+    LessThanOrEqual,
+    GreaterThanOrEqual,
+    IsNull,
+    IsNotNull,
+    /// Matches text against a pattern, where `%` stands for any string and `_` for any one
+    /// character, and a backslash makes the character after it stand for itself.
+    Like,
+    /// Whether the value is one of the results of a subquery, which is compared to instead of a
+    /// constant.
+    In,
+}
+
+
+impl Comparison {
+    /// Return whether the value compares to the constant in this way, or None if that is unknown
+    /// because one of them is NULL. The constant is ignored for IS NULL and IS NOT NULL, and is the
+    /// pattern for LIKE.
+    pub(crate) fn evaluate(&self, value: &Value, constant: &Value) -> Option<bool> {
+        match self {
+            Comparison::IsNull => return Some(*value == Value::Null),
+            Comparison::IsNotNull => return Some(*value != Value::Null),
+            _ => {},
+        }
+
+        if *value == Value::Null || *constant == Value::Null {
+            return None;
+        }
+
+        if let Comparison::Like = self {
+            return match (value, constant) {
+                (Value::Text(text), Value::Text(pattern)) => Some(like_matches(text, pattern)),
+                _ => panic!("LIKE compares non-text values"),
+            };
+        }
+
+        let ordering = value.cmp(constant);
+        Some(match self {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+            Comparison::LessThan => ordering == Ordering::Less,
+            Comparison::GreaterThan => ordering == Ordering::Greater,
+            Comparison::LessThanOrEqual => ordering != Ordering::Greater,
+            Comparison::GreaterThanOrEqual => ordering != Ordering::Less,
+            Comparison::IsNull | Comparison::IsNotNull | Comparison::Like | Comparison::In => {
+                unreachable!()
+            },
+        })
+    }
+}
+
+
+/// A piece of a LIKE pattern.
+#[derive(PartialEq)]
+enum LikePart {
+    Char(char),
+    AnyChar,
+    AnyString,
+}
+
+
+/// Return true if the text matches the LIKE pattern. As with `=`, case matters.
+pub(crate) fn like_matches(text: &str, pattern: &str) -> bool {
+    let mut parts = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        parts.push(match c {
+            '%' => LikePart::AnyString,
+            '_' => LikePart::AnyChar,
+            // A backslash at the end of the pattern stands for itself.
+            '\\' => LikePart::Char(chars.next().unwrap_or('\\')),
+            c => LikePart::Char(c),
+        });
+    }
+
+    // Match greedily, and on a mismatch go back to the last `%` and have it take one more
+    // character. Going back any further can't help, since that `%` could take as many characters
+    // as an earlier one would.
+    let text: Vec<char> = text.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    let mut last_any_string = None;
+    while t < text.len() {
+        match parts.get(p) {
+            Some(LikePart::AnyString) => {
+                last_any_string = Some((p, t));
+                p += 1;
+            },
+            Some(LikePart::AnyChar) => {
+                t += 1;
+                p += 1;
+            },
+            Some(LikePart::Char(c)) if *c == text[t] => {
+                t += 1;
+                p += 1;
+            },
+            _ => match last_any_string {
+                Some((any_p, any_t)) => {
+                    last_any_string = Some((any_p, any_t + 1));
+                    p = any_p + 1;
+                    t = any_t + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    parts[p..].iter().all(|part| *part == LikePart::AnyString)
+}
+
+
+/// An expression computing a value from a row.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Expression {
+    Column(usize),
+    Literal(Value),
+    Negate(Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+    /// A call of the function at the given index of FUNCTIONS.
+    Call(usize, Vec<Expression>),
+    /// A call of a function that a program defined on the connection that parsed the statement.
+    UserCall(Arc<UserFunction>, Vec<Expression>),
+    Cast(Box<Expression>, ColumnType),
+    /// A subquery whose value is its first result.
+    Subquery(Arc<Subquery>),
+}
+
+
+impl Expression {
+    pub(crate) fn evaluate(&self, row: &Row) -> Value {
+        match self {
+            Expression::Column(column) => row.values[*column].clone(),
+            Expression::Literal(value) => value.clone(),
+            Expression::Negate(operand) => match operand.evaluate(row) {
+                // Only the smallest integer's negation overflows, which is promoted like
+                // arithmetic that overflows.
+                Value::Integer(n) => {
+                    n.checked_neg().map_or(Value::Real(-(n as f64)), Value::Integer)
+                },
+                Value::Real(x) => Value::Real(-x),
+                _ => Value::Null,
+            },
+            Expression::Binary(operator, left, right) => {
+                operator.apply(left.evaluate(row), right.evaluate(row))
+            },
+            Expression::Call(function, arguments) => {
+                let arguments: Vec<Value> =
+                    arguments.iter().map(|argument| argument.evaluate(row)).collect();
+                // None of the functions do anything with NULL but return it.
+                if arguments.contains(&Value::Null) {
+                    Value::Null
+                } else {
+                    (FUNCTIONS[*function].call)(&arguments)
+                }
+            },
+            Expression::UserCall(function, arguments) => {
+                let arguments: Vec<Value> =
+                    arguments.iter().map(|argument| argument.evaluate(row)).collect();
+                (function.call)(&arguments)
+            },
+            Expression::Cast(operand, to) => operand.evaluate(row).cast(*to),
+            Expression::Subquery(subquery) => {
+                subquery.values.lock().unwrap().first().cloned().unwrap_or(Value::Null)
+            },
+        }
+    }
+
+    /// Return the type of the expression's values, or None if it is always NULL or, for a function
+    /// that a program defined, isn't known until it is called.
+    pub(crate) fn value_type(&self) -> Option<ColumnType> {
+        match self {
+            Expression::Column(column) => Some(COLUMNS[*column].column_type),
+            Expression::Literal(Value::Null) => None,
+            Expression::Literal(Value::Integer(_)) => Some(ColumnType::Integer),
+            Expression::Literal(Value::Real(_)) => Some(ColumnType::Real),
+            Expression::Literal(Value::Text(_)) => Some(ColumnType::Text),
+            Expression::Negate(operand) => operand.value_type().or(Some(ColumnType::Integer)),
+            Expression::Binary(BinaryOperator::Concat, ..) => Some(ColumnType::Text),
+            // Arithmetic with a real number gives a real number.
+            Expression::Binary(_, left, right) => {
+                if left.value_type() == Some(ColumnType::Real)
+                    || right.value_type() == Some(ColumnType::Real)
+                {
+                    Some(ColumnType::Real)
+                } else {
+                    Some(ColumnType::Integer)
+                }
+            },
+            Expression::Call(function, _) => Some(FUNCTIONS[*function].return_type),
+            Expression::UserCall(..) => None,
+            Expression::Cast(_, to) => Some(*to),
+            Expression::Subquery(subquery) => subquery.value_type(),
+        }
+    }
+
+    /// Return the columns that the expression's value depends on, which don't include those that
+    /// its subqueries read.
+    pub(crate) fn columns(&self) -> Vec<usize> {
+        match self {
+            Expression::Column(column) => vec![*column],
+            Expression::Literal(_) | Expression::Subquery(_) => Vec::new(),
+            Expression::Negate(operand) | Expression::Cast(operand, _) => operand.columns(),
+            Expression::Binary(_, left, right) => [left.columns(), right.columns()].concat(),
+            Expression::Call(_, arguments) | Expression::UserCall(_, arguments) => {
+                arguments.iter().flat_map(Expression::columns).collect()
+            },
+        }
+    }
+
+    /// Return the subqueries in the expression, which have to be run before it is evaluated.
+    pub(crate) fn subqueries(&self) -> Vec<&Subquery> {
+        match self {
+            Expression::Column(_) | Expression::Literal(_) => Vec::new(),
+            Expression::Negate(operand) | Expression::Cast(operand, _) => operand.subqueries(),
+            Expression::Binary(_, left, right) => {
+                [left.subqueries(), right.subqueries()].concat()
+            },
+            Expression::Call(_, arguments) | Expression::UserCall(_, arguments) => {
+                arguments.iter().flat_map(Expression::subqueries).collect()
+            },
+            Expression::Subquery(subquery) => vec![subquery],
+        }
+    }
+
+    /// Return whether the expression always has the same value for the same row, which is true
+    /// unless it has a subquery, whose results change with the table, or calls `now()` or a
+    /// function that a program defined, which may not be defined the next time it is parsed.
+    pub(crate) fn is_deterministic(&self) -> bool {
+        match self {
+            Expression::Column(_) | Expression::Literal(_) => true,
+            Expression::Negate(operand) | Expression::Cast(operand, _) => {
+                operand.is_deterministic()
+            },
+            Expression::Binary(_, left, right) => {
+                left.is_deterministic() && right.is_deterministic()
+            },
+            Expression::Call(function, arguments) => {
+                FUNCTIONS[*function].name != "now"
+                    && arguments.iter().all(Expression::is_deterministic)
+            },
+            Expression::UserCall(..) | Expression::Subquery(_) => false,
+        }
+    }
+
+    /// Return how tightly the expression binds, for deciding where it needs parentheses.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Binary(operator, ..) => operator.precedence(),
+            Expression::Negate(_) => 4,
+            Expression::Column(_)
+            | Expression::Literal(_)
+            | Expression::Call(..)
+            | Expression::UserCall(..)
+            | Expression::Cast(..)
+            | Expression::Subquery(_) => 5,
+        }
+    }
+}
+
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parenthesize = |expression: &Expression, precedence: u8| {
+            if expression.precedence() < precedence {
+                format!("({})", expression)
+            } else {
+                expression.to_string()
+            }
+        };
+
+        match self {
+            Expression::Column(column) => write!(f, "{}", COLUMNS[*column].name),
+            // Quotes in the text are doubled, so that it can be parsed again.
+            Expression::Literal(Value::Text(s)) => write!(f, "'{}'", s.replace('\'', "''")),
+            Expression::Literal(value) => write!(f, "{:?}", value),
+            Expression::Negate(operand) => write!(f, "-{}", parenthesize(operand, 5)),
+            Expression::Binary(operator, left, right) => {
+                // The operators are left-associative, so an operand on the right that binds just
+                // as tightly needs parentheses, e.g. `id - (id - 1)`.
+                let precedence = operator.precedence();
+                let left = parenthesize(left, precedence);
+                let right = parenthesize(right, precedence + 1);
+                write!(f, "{} {} {}", left, operator.symbol(), right)
+            },
+            Expression::Call(function, arguments) => {
+                let arguments: Vec<String> = arguments.iter().map(Expression::to_string).collect();
+                write!(f, "{}({})", FUNCTIONS[*function].name, arguments.join(", "))
+            },
+            Expression::UserCall(function, arguments) => {
+                let arguments: Vec<String> = arguments.iter().map(Expression::to_string).collect();
+                write!(f, "{}({})", function.name, arguments.join(", "))
+            },
+            Expression::Cast(operand, to) => write!(f, "cast({} as {})", operand, type_name(*to)),
+            Expression::Subquery(subquery) => write!(f, "({})", subquery.text),
+        }
+    }
+}
+
+
+/// A SELECT in an expression, e.g. `(select id from users order by id desc)`. Its columns are those
+/// of its own table, so its results don't depend on the row, and they are computed before the
+/// expression is evaluated.
+#[derive(Debug)]
+pub(crate) struct Subquery {
+    pub(crate) select: Statement,
+    // How the SELECT is written, which names the expression.
+    pub(crate) text: String,
+    // The value of each of the SELECT's results, from when it was last executed.
+    pub(crate) values: Mutex<Vec<Value>>,
+}
+
+
+impl Subquery {
+    /// Return the type of the values of the SELECT's only column.
+    fn value_type(&self) -> Option<ColumnType> {
+        self.select.select_list.as_ref().and_then(|items| items[0].value_type())
+    }
+
+    /// Return whether the value is one of the subquery's results, or None if that is unknown
+    /// because it is NULL or there is a NULL result.
+    pub(crate) fn contains(&self, value: &Value) -> Option<bool> {
+        let values = self.values.lock().unwrap();
+        if values.is_empty() {
+            Some(false)
+        } else if *value != Value::Null && values.contains(value) {
+            Some(true)
+        } else if *value == Value::Null || values.contains(&Value::Null) {
+            None
+        } else {
+            Some(false)
+        }
+    }
+}
+
+
+// A subquery is only equal to itself, since two with the same SELECT can have different results
+// when one has been executed more recently.
+impl PartialEq for Subquery {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Concat,
+}
+
+
+impl BinaryOperator {
+    /// Apply the operator to two values. Arithmetic is on numbers, and is on real numbers if
+    /// either operand is one, and concatenation casts numbers to text. Any NULL operand gives
+    /// NULL, as does dividing by zero or a real result that is infinite. Integer arithmetic whose
+    /// result doesn't fit in an integer is done on real numbers instead, as SQLite does, so e.g.
+    /// `9223372036854775807 + 1` is 9.223372036854776e18.
+    fn apply(self, left: Value, right: Value) -> Value {
+        let text = |value: Value| match value.cast(ColumnType::Text) {
+            Value::Text(s) => s,
+            _ => unreachable!(),
+        };
+
+        match (self, left, right) {
+            (_, Value::Null, _) | (_, _, Value::Null) => Value::Null,
+            (BinaryOperator::Concat, left, right) => Value::Text(text(left) + &text(right)),
+            (operator, Value::Integer(a), Value::Integer(b)) => {
+                let result = match operator {
+                    BinaryOperator::Add => a.checked_add(b),
+                    BinaryOperator::Subtract => a.checked_sub(b),
+                    BinaryOperator::Multiply => a.checked_mul(b),
+                    BinaryOperator::Divide => a.checked_div(b),
+                    BinaryOperator::Concat => unreachable!(),
+                };
+                match result {
+                    Some(result) => Value::Integer(result),
+                    // Dividing by zero is NULL either way.
+                    None => operator.apply(Value::Real(a as f64), Value::Real(b as f64)),
+                }
+            },
+            (operator, left, right) => {
+                let (a, b) = match (f64::from_value(&left), f64::from_value(&right)) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => panic!("Arithmetic on a non-numeric value"),
+                };
+                let result = match operator {
+                    BinaryOperator::Add => a + b,
+                    BinaryOperator::Subtract => a - b,
+                    BinaryOperator::Multiply => a * b,
+                    BinaryOperator::Divide if b == 0.0 => return Value::Null,
+                    BinaryOperator::Divide => a / b,
+                    BinaryOperator::Concat => unreachable!(),
+                };
+                if result.is_finite() {
+                    Value::Real(result)
+                } else {
+                    Value::Null
+                }
+            },
+        }
+    }
+
+    pub(crate) fn precedence(self) -> u8 {
+        match self {
+            BinaryOperator::Add | BinaryOperator::Subtract => 1,
+            BinaryOperator::Multiply | BinaryOperator::Divide => 2,
+            BinaryOperator::Concat => 3,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Concat => "||",
+        }
+    }
+}
+
+
+/// A built-in scalar function.
+pub(crate) struct Function {
+    pub(crate) name: &'static str,
+    // The types of the function's parameters, of which all but the first `required` are optional.
+    pub(crate) parameters: &'static [ColumnType],
+    pub(crate) required: usize,
+    return_type: ColumnType,
+    // Called with a value of the right type for each argument, none of which is NULL.
+    call: fn(&[Value]) -> Value,
+}
+
+
+/// The functions that can be called in expressions.
+pub(crate) const FUNCTIONS: [Function; 8] = [
+    Function {
+        name: "upper",
+        parameters: &[ColumnType::Text],
+        required: 1,
+        return_type: ColumnType::Text,
+        call: |arguments| Value::Text(text_argument(&arguments[0]).to_uppercase()),
+    },
+    Function {
+        name: "lower",
+        parameters: &[ColumnType::Text],
+        required: 1,
+        return_type: ColumnType::Text,
+        call: |arguments| Value::Text(text_argument(&arguments[0]).to_lowercase()),
+    },
+    Function {
+        name: "length",
+        parameters: &[ColumnType::Text],
+        required: 1,
+        return_type: ColumnType::Integer,
+        call: |arguments| Value::Integer(text_argument(&arguments[0]).chars().count() as i64),
+    },
+    Function {
+        name: "substr",
+        parameters: &[ColumnType::Text, ColumnType::Integer, ColumnType::Integer],
+        required: 2,
+        return_type: ColumnType::Text,
+        call: substr,
+    },
+    Function {
+        name: "abs",
+        parameters: &[ColumnType::Integer],
+        required: 1,
+        return_type: ColumnType::Integer,
+        // The absolute value of the smallest integer overflows, so it is a real number instead, as
+        // with arithmetic.
+        call: |arguments| {
+            let n = integer_argument(&arguments[0]);
+            n.checked_abs().map_or(Value::Real((n as f64).abs()), Value::Integer)
+        },
+    },
+    // Times are integers, counting seconds since the Unix epoch in UTC.
+    Function {
+        name: "now",
+        parameters: &[],
+        required: 0,
+        return_type: ColumnType::Integer,
+        call: |_| current_time(),
+    },
+    Function {
+        name: "date",
+        parameters: &[ColumnType::Integer],
+        required: 1,
+        return_type: ColumnType::Text,
+        call: |arguments| {
+            let time = integer_argument(&arguments[0]);
+            Value::Text(format_time("%Y-%m-%d", time).unwrap())
+        },
+    },
+    Function {
+        name: "strftime",
+        parameters: &[ColumnType::Text, ColumnType::Integer],
+        required: 2,
+        return_type: ColumnType::Text,
+        call: |arguments| {
+            let time = integer_argument(&arguments[1]);
+            format_time(text_argument(&arguments[0]), time).map_or(Value::Null, Value::Text)
+        },
+    },
+];
+
+
+/// Return the index of the function with the given name.
+pub(crate) fn function_index(name: &str) -> Option<usize> {
+    FUNCTIONS.iter().position(|function| function.name.eq_ignore_ascii_case(name))
+}
+
+
+/// A scalar function that a program defined on a connection with `Database::create_function`.
+pub(crate) struct UserFunction {
+    pub(crate) name: String,
+    pub(crate) num_args: usize,
+    // Called with the value of each argument, which, unlike for a built-in function, may be NULL.
+    pub(crate) call: ScalarFunction,
+}
+
+
+pub(crate) type ScalarFunction = Box<dyn Fn(&[Value]) -> Value + Send + Sync>;
+
+
+// Two calls are of the same function only if it is the same definition of it.
+impl PartialEq for UserFunction {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+
+impl fmt::Debug for UserFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+
+thread_local! {
+    // The functions defined on the connection whose state is locked on this thread, which names
+    // that aren't those of built-in functions are looked up in when a statement is parsed.
+    static USER_FUNCTIONS: RefCell<Vec<Arc<UserFunction>>> = const { RefCell::new(Vec::new()) };
+}
+
+
+/// Make the given functions the ones that statements parsed on this thread can call, and return
+/// the ones that they could before.
+pub(crate) fn replace_user_functions(
+    functions: Vec<Arc<UserFunction>>
+) -> Vec<Arc<UserFunction>> {
+    USER_FUNCTIONS.with(|current| current.replace(functions))
+}
+
+
+/// Return the function with the given name that statements parsed on this thread can call, other
+/// than the built-in ones.
+pub(crate) fn user_function(name: &str) -> Option<Arc<UserFunction>> {
+    USER_FUNCTIONS.with(|functions| {
+        functions.borrow().iter().find(|function| function.name.eq_ignore_ascii_case(name)).cloned()
+    })
+}
+
+
+fn text_argument(value: &Value) -> &str {
+    match value {
+        Value::Text(s) => s,
+        _ => panic!("Function called with a non-text argument"),
+    }
+}
+
+
+/// Return the current time. Without an operating system, e.g. in a browser, there is no clock to
+/// read it from, so it is NULL.
+#[cfg(not(all(target_family = "wasm", target_os = "unknown")))]
+fn current_time() -> Value {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Value::Integer(now.as_secs() as i64)
+}
+
+
+#[cfg(all(target_family = "wasm", target_os = "unknown"))]
+fn current_time() -> Value {
+    Value::Null
+}
+
+
+fn integer_argument(value: &Value) -> i64 {
+    match value {
+        Value::Integer(n) => *n,
+        // Integer arithmetic that overflows gives a real number, which is truncated toward zero,
+        // or to the largest or smallest integer if it's out of range.
+        Value::Real(x) => *x as i64,
+        _ => panic!("Function called with a non-integer argument"),
+    }
+}
+
+
+/// Implement `substr(text, start[, length])` as SQLite does. Characters are numbered from 1, and
+/// a negative start counts back from the end of the text. Without a length, the substring runs to
+/// the end of the text, and with a negative length it is the characters before the start instead
+/// of after.
+fn substr(arguments: &[Value]) -> Value {
+    let chars: Vec<char> = text_argument(&arguments[0]).chars().collect();
+    let num_chars = chars.len() as i64;
+    let mut start = integer_argument(&arguments[1]);
+    let mut length = arguments.get(2).map_or(num_chars, integer_argument);
+
+    // Turn the start into an offset from the start of the text. A start of 0 is the position
+    // just before the first character, so it takes up one of the length.
+    if start < 0 {
+        start = start.saturating_add(num_chars);
+    } else if start > 0 {
+        start -= 1;
+    } else {
+        length = length.saturating_sub(1);
+    }
+    if length < 0 {
+        start = start.saturating_add(length);
+        length = length.saturating_neg();
+    }
+
+    // The part of the range that's before the start of the text is left out.
+    let end = start.saturating_add(length).clamp(0, num_chars);
+    let start = start.clamp(0, num_chars);
+    Value::Text(chars[start as usize..end as usize].iter().collect())
+}
+
+
+/// Format a time given in seconds since the Unix epoch, replacing `%Y`, `%m`, `%d`, `%H`, `%M`,
+/// `%S` and `%s` with the year, month, day, hour, minute, second and the time itself, and `%%`
+/// with `%`, as strftime does. Return None if the format has any other `%` sequence.
+fn format_time(format: &str, time: i64) -> Option<String> {
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    let seconds = time.rem_euclid(86400);
+
+    let mut formatted = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+
+        let field = match chars.next()? {
+            'Y' => format!("{:04}", year),
+            'm' => format!("{:02}", month),
+            'd' => format!("{:02}", day),
+            'H' => format!("{:02}", seconds / 3600),
+            'M' => format!("{:02}", seconds / 60 % 60),
+            'S' => format!("{:02}", seconds % 60),
+            's' => time.to_string(),
+            '%' => String::from("%"),
+            _ => return None,
+        };
+        formatted.push_str(&field);
+    }
+    Some(formatted)
+}
+
+
+/// Return the year, month and day of the date the given number of days after 1970-01-01, in the
+/// proleptic Gregorian calendar. This is Howard Hinnant's `civil_from_days` algorithm, which
+/// works in 400-year eras that start on March 1st so that leap days come at the end of a year.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+
+/// An item in a SELECT statement's select list.
+#[derive(Debug, PartialEq)]
+pub(crate) enum SelectItem {
+    Expression(Expression),
+    CountStar,
+    /// An item given a name with AS, e.g. `id as user_id`, which labels its values instead.
+    Aliased(Box<SelectItem>, String),
+}
+
+
+impl SelectItem {
+    /// Return the name that labels the item's values in the output.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            SelectItem::Expression(expression) => expression.to_string(),
+            SelectItem::CountStar => String::from("count(*)"),
+            SelectItem::Aliased(_, alias) => alias.clone(),
+        }
+    }
+
+    /// Return the item without any alias.
+    pub(crate) fn unaliased(&self) -> &SelectItem {
+        match self {
+            SelectItem::Aliased(item, _) => item.unaliased(),
+            item => item,
+        }
+    }
+
+    pub(crate) fn is_aggregate(&self) -> bool {
+        *self.unaliased() == SelectItem::CountStar
+    }
+
+    /// Return the type of the item's values, or None if they are always NULL.
+    pub(crate) fn value_type(&self) -> Option<ColumnType> {
+        match self.unaliased() {
+            SelectItem::Expression(expression) => expression.value_type(),
+            _ => Some(ColumnType::Integer),
+        }
+    }
+
+    /// Return the subqueries in the item.
+    pub(crate) fn subqueries(&self) -> Vec<&Subquery> {
+        match self.unaliased() {
+            SelectItem::Expression(expression) => expression.subqueries(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Return the columns that the item's value depends on.
+    pub(crate) fn columns(&self) -> Vec<usize> {
+        match self.unaliased() {
+            SelectItem::Expression(expression) => expression.columns(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Return the item's value for a group of rows, given any one of the rows and the number of
+    /// rows in the group. A row on its own is a group of one.
+    pub(crate) fn value(&self, row: &Row, count: usize) -> Value {
+        match self {
+            SelectItem::Expression(expression) => expression.evaluate(row),
+            SelectItem::CountStar => Value::Integer(count as i64),
+            SelectItem::Aliased(item, _) => item.value(row, count),
+        }
+    }
+}
+
+
+/// A GROUP BY clause, with its HAVING clause if there is one.
+#[derive(Debug)]
+pub(crate) struct GroupBy {
+    pub(crate) column: usize,
+    pub(crate) having: Option<Having>,
+}
+
+
+/// A HAVING clause comparing a value computed for each group to a constant value.
+#[derive(Debug)]
+pub(crate) struct Having {
+    pub(crate) item: SelectItem,
+    pub(crate) comparison: Comparison,
+    pub(crate) value: Value,
+}
+
+
+/// An ORDER BY clause.
+#[derive(Debug)]
+pub(crate) struct OrderBy {
+    pub(crate) column: usize,
+    pub(crate) descending: bool,
+}
+
+
+/// A CREATE INDEX statement.
+#[derive(Debug)]
+pub(crate) struct CreateIndex {
+    pub(crate) name: String,
+    pub(crate) column: usize,
+    // The text of the expression of the column that the index is on, if it isn't on the column
+    // itself.
+    pub(crate) expression: Option<String>,
+    // The text of the WHERE clause of a partial index, which only has entries for the rows that
+    // match it.
+    pub(crate) where_clause: Option<String>,
+}
+
+
+/// A CREATE VIEW statement, with the text of the view's SELECT.
+#[derive(Debug)]
+pub(crate) struct CreateView {
+    pub(crate) name: String,
+    pub(crate) text: String,
+}
+
+
+/// A CREATE TRIGGER statement, with the text of the statements in its body.
+#[derive(Debug)]
+pub(crate) struct CreateTrigger {
+    pub(crate) name: String,
+    pub(crate) action: Action,
+    pub(crate) body: String,
+}
+
+
+/// A PRAGMA statement, which queries a setting, or changes it if there is a value.
+#[derive(Debug)]
+pub(crate) struct Pragma {
+    pub(crate) name: String,
+    pub(crate) value: Option<String>,
+}
+
+
+/// A WHERE clause, which combines conditions with AND, OR and NOT.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Predicate {
+    Condition(Condition),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
+
+impl Predicate {
+    pub(crate) fn matches(&self, row: &Row) -> bool {
+        self.evaluate(row) == Some(true)
+    }
+
+    /// Return whether the row satisfies the predicate, or None if that is unknown because of a
+    /// comparison with NULL. As in SQL, NOT of an unknown result is still unknown, but an unknown
+    /// result AND false is false, and an unknown result OR true is true.
+    fn evaluate(&self, row: &Row) -> Option<bool> {
+        match self {
+            Predicate::Condition(condition) => condition.evaluate(row),
+            Predicate::And(left, right) => match (left.evaluate(row), right.evaluate(row)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Predicate::Or(left, right) => match (left.evaluate(row), right.evaluate(row)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Predicate::Not(predicate) => predicate.evaluate(row).map(|result| !result),
+        }
+    }
+
+    /// Return the columns that the predicate's conditions compare, which don't include those that
+    /// their subqueries read.
+    pub(crate) fn columns(&self) -> Vec<usize> {
+        match self {
+            Predicate::Condition(condition) => {
+                [condition.left.columns(), condition.right.columns()].concat()
+            },
+            Predicate::And(left, right) | Predicate::Or(left, right) => {
+                [left.columns(), right.columns()].concat()
+            },
+            Predicate::Not(predicate) => predicate.columns(),
+        }
+    }
+
+    /// Return the subqueries in the predicate's conditions.
+    pub(crate) fn subqueries(&self) -> Vec<&Subquery> {
+        match self {
+            Predicate::Condition(condition) => {
+                [condition.left.subqueries(), condition.right.subqueries()].concat()
+            },
+            Predicate::And(left, right) | Predicate::Or(left, right) => {
+                [left.subqueries(), right.subqueries()].concat()
+            },
+            Predicate::Not(predicate) => predicate.subqueries(),
+        }
+    }
+
+    /// Return the conditions that every matching row satisfies, i.e. the ones that are joined to
+    /// the rest of the predicate by AND.
+    pub(crate) fn conjuncts(&self) -> Vec<&Condition> {
+        match self {
+            Predicate::Condition(condition) => vec![condition],
+            Predicate::And(left, right) => {
+                let mut conjuncts = left.conjuncts();
+                conjuncts.extend(right.conjuncts());
+                conjuncts
+            },
+            Predicate::Or(..) | Predicate::Not(..) => Vec::new(),
+        }
+    }
+
+    /// Return whether every row that matches this predicate also matches the other one, as far
+    /// as can be told from their conditions without reading any rows. False means it isn't
+    /// known, rather than that it doesn't.
+    pub(crate) fn implies(&self, other: &Predicate) -> bool {
+        if self == other {
+            return true;
+        }
+        match other {
+            Predicate::Condition(condition) => {
+                self.conjuncts().iter().any(|conjunct| conjunct.implies(condition))
+            },
+            Predicate::And(left, right) => self.implies(left) && self.implies(right),
+            Predicate::Or(left, right) => self.implies(left) || self.implies(right),
+            Predicate::Not(_) => false,
+        }
+    }
+
+    /// Return whether the predicate always gives the same result for the same row.
+    pub(crate) fn is_deterministic(&self) -> bool {
+        match self {
+            Predicate::Condition(condition) => {
+                condition.left.is_deterministic() && condition.right.is_deterministic()
+            },
+            Predicate::And(left, right) | Predicate::Or(left, right) => {
+                left.is_deterministic() && right.is_deterministic()
+            },
+            Predicate::Not(predicate) => predicate.is_deterministic(),
+        }
+    }
+}
+
+
+/// A condition comparing the values of two expressions for each row.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Condition {
+    pub(crate) left: Expression,
+    pub(crate) comparison: Comparison,
+    // Guaranteed to be a valid id if the left side is the id and this is a constant, unless the
+    // comparison is IS NULL or IS NOT NULL.
+    pub(crate) right: Expression,
+}
+
+
+impl Condition {
+    fn evaluate(&self, row: &Row) -> Option<bool> {
+        if let (Comparison::In, Expression::Subquery(subquery)) = (&self.comparison, &self.right) {
+            return subquery.contains(&self.left.evaluate(row));
+        }
+        self.comparison.evaluate(&self.left.evaluate(row), &self.right.evaluate(row))
+    }
+
+    /// Return the column and the constant if the condition compares a column to a constant, which
+    /// is the only kind of condition that can narrow down the rows to read.
+    pub(crate) fn column_and_value(&self) -> Option<(usize, &Value)> {
+        match (&self.left, &self.right) {
+            (Expression::Column(column), Expression::Literal(value)) => Some((*column, value)),
+            _ => None,
+        }
+    }
+
+    /// Return whether every row that satisfies this condition also satisfies the other one, which
+    /// is known if they are the same, or if they compare the same column to constants and every
+    /// value that this one allows is one that the other allows.
+    pub(crate) fn implies(&self, other: &Condition) -> bool {
+        if self == other {
+            return true;
+        }
+        let (value, other_value) = match (self.column_and_value(), other.column_and_value()) {
+            (Some((column, value)), Some((other_column, other_value)))
+                if column == other_column => (value, other_value),
+            _ => return false,
+        };
+        // A comparison with NULL is never true, and for a row to satisfy one with a constant that
+        // isn't NULL, its value can't be NULL.
+        if *value == Value::Null {
+            return false;
+        }
+        match (&self.comparison, &other.comparison) {
+            (_, Comparison::IsNotNull) => true,
+            _ if *other_value == Value::Null => false,
+            (Comparison::Equal, Comparison::Like) if !matches!(value, Value::Text(_)) => false,
+            (Comparison::Equal, _) => other.comparison.evaluate(value, other_value) == Some(true),
+            (
+                Comparison::GreaterThan,
+                Comparison::GreaterThan | Comparison::GreaterThanOrEqual,
+            ) => value >= other_value,
+            (Comparison::GreaterThanOrEqual, Comparison::GreaterThan) => value > other_value,
+            (Comparison::GreaterThanOrEqual, Comparison::GreaterThanOrEqual) => {
+                value >= other_value
+            },
+            (Comparison::LessThan, Comparison::LessThan | Comparison::LessThanOrEqual) => {
+                value <= other_value
+            },
+            (Comparison::LessThanOrEqual, Comparison::LessThan) => value < other_value,
+            (Comparison::LessThanOrEqual, Comparison::LessThanOrEqual) => value <= other_value,
+            _ => false,
+        }
+    }
+
+    /// Return the id that the condition compares the id column to, if it does.
+    pub(crate) fn id_value(&self) -> Option<u32> {
+        match self.column_and_value() {
+            Some((ID_COLUMN, Value::Integer(id))) => Some(*id as u32),
+            _ => None,
+        }
+    }
+
+    /// Return the id to start scanning from, since rows are sorted by id and so no row before it
+    /// can match.
+    pub(crate) fn first_possible_id(&self) -> u32 {
+        match (self.id_value(), &self.comparison) {
+            (Some(id), Comparison::Equal) => id,
+            (Some(id), Comparison::GreaterThan) => id.saturating_add(1),
+            (Some(id), Comparison::GreaterThanOrEqual) => id,
+            _ => 0,
+        }
+    }
+
+    /// Return true if no row at or after this one can match, since rows are sorted by id.
+    pub(crate) fn is_past_last_match(&self, row: &Row) -> bool {
+        match (self.id_value(), &self.comparison) {
+            (Some(id), Comparison::Equal) => row.id() > id,
+            (Some(id), Comparison::LessThan) => row.id() >= id,
+            (Some(id), Comparison::LessThanOrEqual) => row.id() > id,
+            _ => false,
+        }
+    }
+
+    /// Return the range of keys `[start, end)` in the given index that holds the entries of every
+    /// row that can match, where an end of None means the rest of the index. Return None if the
+    /// condition doesn't compare what the index is on to a constant, or if the matching rows are
+    /// not all in one range.
+    pub(crate) fn index_range(&self, index: &Index) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+        let column = &COLUMNS[index.column];
+        let mut comparison = self.comparison;
+        let value = match (&self.right, &index.expression) {
+            (Expression::Literal(value), None) if self.left == Expression::Column(index.column) => {
+                value
+            },
+            (Expression::Literal(value), Some((_, expression))) if self.left == *expression => {
+                // An expression's text is cut short in the keys, so a key equal to the value can
+                // be of a row whose text is greater.
+                if comparison == Comparison::GreaterThan {
+                    comparison = Comparison::GreaterThanOrEqual;
+                }
+                // The value has to be encoded in the same way as the keys.
+                if !value_fits_column(value, column) {
+                    return None;
+                }
+                value
+            },
+            _ => return None,
+        };
+        // Index keys only hold values of their column's type, so a real number compared to an
+        // integer column has to be compared to each row instead.
+        if let Value::Real(_) = value {
+            return None;
+        }
+        let value = encode_index_value(value, column);
+        // Keys are a value followed by an id, so this is greater than every key with the value.
+        let after = |value: Vec<u8>| [value, vec![u8::MAX; ID_KEY_SIZE + 1]].concat();
+        // Less than every key with a value that isn't NULL, and greater than those with NULL.
+        let not_null = vec![NULL_TAG + 1];
+
+        match comparison {
+            Comparison::Equal => Some((value.clone(), Some(after(value)))),
+            Comparison::LessThan => Some((not_null, Some(value))),
+            Comparison::GreaterThan => Some((after(value), None)),
+            Comparison::LessThanOrEqual => Some((not_null, Some(after(value)))),
+            Comparison::GreaterThanOrEqual => Some((value, None)),
+            Comparison::IsNull => Some((vec![NULL_TAG], Some(not_null))),
+            Comparison::IsNotNull => Some((not_null, None)),
+            Comparison::NotEqual | Comparison::Like | Comparison::In => None,
+        }
+    }
+}
+
+
+/// Check that a string is a SQL statement without executing it, returning its syntax tree or
+/// where and why it isn't one. No database is needed, so the parser can be exercised on its own.
+pub fn parse(sql: &str) -> Result<Ast, ParseError> {
+    prepare_statement(sql)
+}
+
+
+impl Statement {
+    /// Return a statement of the given kind with none of its clauses, for the parser to fill in.
+    pub(crate) fn new(kind: StatementKind) -> Self {
+        Statement {
+            kind,
+            rows_to_insert: None,
+            copy_from: None,
+            id_to_delete: None,
+            where_clause: None,
+            order_by: None,
+            index_to_create: None,
+            select_list: None,
+            group_by: None,
+            distinct: false,
+            returning: None,
+            savepoint: None,
+            pragma: None,
+            database: None,
+            attach_path: None,
+            compound: Vec::new(),
+            common_tables: Vec::new(),
+            common_table: None,
+            view: None,
+            view_to_create: None,
+            trigger: None,
+            trigger_to_create: None,
+            explain: false,
+            analyze: false,
+        }
+    }
+
+    /// Parse a string into a SQL statement, or return where and why it isn't one.
+    pub fn parse(command: &str) -> Result<Self, ParseError> {
+        prepare_statement(command)
+    }
+
+    /// Parse a string of statements separated by semicolons, returning for each one either it or
+    /// where and why it isn't a statement.
+    pub fn parse_all(command: &str) -> Vec<Result<Self, ParseError>> {
+        prepare_statements(command)
+    }
+
+    /// Return the names of the columns of the statement's results, which only a SELECT statement
+    /// has.
+    pub(crate) fn result_columns(&self) -> Vec<String> {
+        match (&self.kind, &self.select_list) {
+            (StatementKind::Select, Some(items)) => items.iter().map(SelectItem::name).collect(),
+            (StatementKind::Select, None) => {
+                COLUMNS.iter().map(|column| String::from(column.name)).collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Return the subqueries in a SELECT, without those of the SELECTs it is combined with or of
+    /// the subqueries themselves.
+    pub(crate) fn subqueries(&self) -> Vec<&Subquery> {
+        let mut subqueries = Vec::new();
+        for item in self.select_list.iter().flatten() {
+            subqueries.extend(item.subqueries());
+        }
+        if let Some(predicate) = &self.where_clause {
+            subqueries.extend(predicate.subqueries());
+        }
+        if let Some(having) = self.group_by.as_ref().and_then(|group_by| group_by.having.as_ref()) {
+            subqueries.extend(having.item.subqueries());
+        }
+        subqueries
+    }
+
+    /// Return the columns of the table that a SELECT reads from each row, without those that the
+    /// SELECTs it is combined with or its subqueries read. A select list of `*` reads them all.
+    pub(crate) fn columns(&self) -> Vec<usize> {
+        let mut columns: Vec<usize> = match &self.select_list {
+            Some(items) => items.iter().flat_map(SelectItem::columns).collect(),
+            None => (0..COLUMNS.len()).collect(),
+        };
+        if let Some(predicate) = &self.where_clause {
+            columns.extend(predicate.columns());
+        }
+        if let Some(group_by) = &self.group_by {
+            columns.push(group_by.column);
+            if let Some(having) = &group_by.having {
+                columns.extend(having.item.columns());
+            }
+        }
+        columns.extend(self.order_by.as_ref().map(|order_by| order_by.column));
+        columns
+    }
+
+    /// Return every SELECT that executing a SELECT statement runs: itself, its common table
+    /// expressions, the SELECTs that it is combined with, and their subqueries, along with the
+    /// SELECTs in those.
+    pub(crate) fn selects(&self) -> Vec<&Statement> {
+        let mut selects = vec![self];
+        let common_tables = self.common_tables.iter().map(|(_, select)| select);
+        for select in common_tables.chain(self.compound.iter().map(|(_, select)| select)) {
+            selects.extend(select.selects());
+        }
+        for subquery in self.subqueries() {
+            selects.extend(subquery.select.selects());
+        }
+        selects
+    }
+
+    /// Return whether the rows that the statement outputs are results, i.e. it is a SELECT
+    /// statement or has a RETURNING clause, rather than lines of text, such as an EXPLAIN
+    /// statement's plan or a PRAGMA statement's values.
+    pub fn has_results(&self) -> bool {
+        !self.explain && (matches!(self.kind, StatementKind::Select) || self.returning.is_some())
+    }
+
+    /// Return whether the statement inserts or deletes rows, in which case executing it returns
+    /// the number of rows that it changed.
+    pub fn changes_rows(&self) -> bool {
+        (!self.explain || self.analyze) && matches!(
+            self.kind,
+            StatementKind::Insert
+                | StatementKind::InsertOrReplace
+                | StatementKind::Copy
+                | StatementKind::Delete
+        )
+    }
+}
+
+
+/// Return an INSERT statement for rows that were made by a program rather than parsed, or None if
+/// any of them doesn't fit the table's columns.
+#[cfg(feature = "serde")]
+pub(crate) fn prepare_insert_rows(rows: Vec<Row>) -> Option<Statement> {
+    // As with a parsed INSERT, each value is coerced to its column's type.
+    let rows = rows.into_iter().map(|row| {
+        if row.values.len() != COLUMNS.len() {
+            return None;
+        }
+        let values = row.values.iter()
+            .zip(&COLUMNS)
+            .map(|(value, column)| value.coerce(column.column_type))
+            .collect::<Option<Vec<Value>>>()?;
+        Some(Row { values, ..row })
+    }).collect::<Option<Vec<Row>>>()?;
+    let fits = rows.iter().all(|row| {
+        let id = &row.values[ID_COLUMN];
+        values_fit_columns(&row.values) && (*id == Value::Null || is_valid_id(id))
+    });
+    if !fits {
+        return None;
+    }
+
+    Some(Statement {
+        rows_to_insert: Some(rows),
+        ..Statement::new(StatementKind::Insert)
+    })
+}
+
+
+/// Return true if there is a value of each column's type, or NULL, for each column, and text
+/// values are no longer than their columns allow.
+pub(crate) fn values_fit_columns(values: &[Value]) -> bool {
+    values.len() == COLUMNS.len()
+        && values.iter().zip(&COLUMNS).all(|(value, column)| value_fits_column(value, column))
+}
+
+
+/// Return true if the value is of the column's type, or NULL, and is no longer than it allows.
+pub(crate) fn value_fits_column(value: &Value, column: &ColumnDef) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Integer(_) => column.column_type == ColumnType::Integer,
+        Value::Real(_) => column.column_type == ColumnType::Real,
+        Value::Text(s) => column.column_type == ColumnType::Text && s.len() <= column.max_length,
+    }
+}
+
+
+/// Parse a literal as a value of the given column's type. Text may optionally be quoted, in which
+/// case a pair of quotes in it stands for one, and any column may be given `null`. A number is
+/// coerced to the column's type, so it is invalid if it doesn't convert, e.g. `2.5` for an
+/// integer.
+pub(crate) fn parse_value(text: &str, column: &ColumnDef) -> Result<Value, NumberError> {
+    if text.eq_ignore_ascii_case("null") {
+        return Ok(Value::Null);
+    }
+
+    match column.column_type {
+        ColumnType::Integer | ColumnType::Real => {
+            parse_number(text)?.coerce(column.column_type).ok_or(NumberError::Invalid)
+        },
+        ColumnType::Text => {
+            if text.len() >= 2 && text.starts_with('\'') && text.ends_with('\'') {
+                Ok(Value::Text(text[1..text.len()-1].replace("''", "'")))
+            } else {
+                Ok(Value::Text(String::from(text)))
+            }
+        },
+    }
+}
+
+
+/// Why a numeric literal couldn't be parsed.
+#[derive(Debug, PartialEq)]
+pub(crate) enum NumberError {
+    /// The text isn't a number.
+    Invalid,
+    /// The text is a number, but too large to be a value, e.g. an integer that doesn't fit in 64
+    /// bits.
+    TooLarge,
+}
+
+
+/// Parse a numeric literal, which may have a sign. It is an integer, either in decimal or in
+/// hexadecimal with a `0x` prefix, or a real number with a decimal point or an exponent, e.g.
+/// `1.5` or `2e-3`. As in SQLite, a hexadecimal integer is the 64 bits of a two's complement
+/// integer, so `0xffffffffffffffff` is -1.
+pub(crate) fn parse_number(text: &str) -> Result<Value, NumberError> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(NumberError::Invalid);
+    }
+
+    if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(NumberError::Invalid);
+        }
+        let n = u64::from_str_radix(hex, 16).map_err(|_| NumberError::TooLarge)? as i64;
+        let n = if negative { n.checked_neg().ok_or(NumberError::TooLarge)? } else { n };
+        return Ok(Value::Integer(n));
+    }
+
+    if digits.chars().all(|c| c.is_ascii_digit()) {
+        // Parsing the sign along with the digits lets the smallest integer, whose absolute value
+        // is too large for an i64, be written.
+        let signed = if negative { format!("-{}", digits) } else { String::from(digits) };
+        return signed.parse::<i64>().map(Value::Integer).map_err(|_| NumberError::TooLarge);
+    }
+
+    match digits.parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(Value::Real(if negative { -x } else { x })),
+        Ok(_) => Err(NumberError::TooLarge),
+        Err(_) => Err(NumberError::Invalid),
+    }
+}
+
+
+/// Ids are the keys of the B-tree, which are 32-bit.
+pub(crate) fn is_valid_id(value: &Value) -> bool {
+    match value {
+        Value::Integer(id) => u32::try_from(*id).is_ok(),
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::btree::*;
+    use crate::exec::*;
+    use crate::parser::*;
+    use crate::sql::*;
+    use crate::testing::*;
+
+    #[test]
+    fn computed_columns() {
+        let mut table = db_open_new(&temp_path("testdb-expressions.mysql"));
+        for (id, username, email) in [(1, "alice", "a@x"), (2, "bob", "null"), (3, "carol", "c@x")]
+        {
+            let command = format!("insert {} {} {}", id, username, email);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+
+        assert_eq!(
+            results(&mut table, "select id * 2, username || '@corp' from users where id < 3"),
+            vec![
+                vec![Value::Integer(2), text("alice@corp")],
+                vec![Value::Integer(4), text("bob@corp")],
+            ]
+        );
+        assert_eq!(
+            results(&mut table, "select id+2*3, (id+2)*3, id-1-1, -id, 7/id where id = 2"),
+            vec![vec![
+                Value::Integer(8), Value::Integer(12), Value::Integer(0), Value::Integer(-2),
+                Value::Integer(3),
+            ]]
+        );
+        // NULL and dividing by zero give NULL.
+        assert_eq!(
+            results(&mut table, "select id || email, email || 'x', id / 0 where id > 1"),
+            vec![
+                vec![Value::Null, Value::Null, Value::Null],
+                vec![text("3c@x"), text("c@xx"), Value::Null],
+            ]
+        );
+        // Integer arithmetic that overflows is done on real numbers instead.
+        assert_eq!(
+            results(&mut table, "select 9223372036854775807 + id where id = 1"),
+            vec![vec![Value::Real(9223372036854775808.0)]]
+        );
+        assert_eq!(
+            results(
+                &mut table,
+                "select 9223372036854775807 * 2, -9223372036854775807 - 2, \
+                 -(-9223372036854775807 - 1), abs(9223372036854775807 + id), \
+                 (9223372036854775807 + id) / 0 where id = 1",
+            ),
+            vec![vec![
+                Value::Real(18446744073709551616.0), Value::Real(-9223372036854775809.0),
+                Value::Real(9223372036854775808.0), Value::Integer(i64::MAX), Value::Null,
+            ]]
+        );
+        let insert = "insert into users values (9223372036854775807 + 1, 'x', 'x@x')";
+        assert_eq!(
+            prepare_statement(insert).unwrap_err().to_string(),
+            "expected integer id, found 9.223372036854776e18 at column 27"
+        );
+        assert_eq!(
+            results(
+                &mut table,
+                "select username || '!', count(*) group by username having count(*) > 0"
+            ),
+            vec![
+                vec![text("alice!"), Value::Integer(1)],
+                vec![text("bob!"), Value::Integer(1)],
+                vec![text("carol!"), Value::Integer(1)],
+            ]
+        );
+
+        let ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+        assert_eq!(ids(&mut table, "select where id * 2 = 4"), [2]);
+        assert_eq!(ids(&mut table, "select where (id + 1) * 2 >= 6 and id != 3"), [2]);
+        assert_eq!(ids(&mut table, "select where username || '!' = 'bob!'"), [2]);
+        assert_eq!(ids(&mut table, "select where id between 1 + 1 and 10 / 2"), [2, 3]);
+        assert_eq!(ids(&mut table, "select where email = username || '@x'"), Vec::<u32>::new());
+
+        // Items are labelled with their expressions, with only the parentheses that are needed.
+        let select = prepare_statement("select (id), (id - (id - 1)) * 2, -(-id), ('a' || 'b')");
+        let select = select.unwrap();
+        let names: Vec<String> = select.select_list.unwrap().iter().map(SelectItem::name).collect();
+        assert_eq!(names, ["id", "(id - (id - 1)) * 2", "-(-id)", "'a' || 'b'"]);
+
+        for command in [
+            "select username * 2",
+            "select -email",
+            "select id +",
+            "select (id",
+            "select nosuch",
+            "select where id = 'abc'",
+            "select where id like 'a%'",
+            "select where id = 1 - 2",
+            "select username from users group by username having id > 1",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn scalar_functions() {
+        let mut table = db_open_new(&temp_path("testdb-functions.mysql"));
+        for (id, username, email) in [(1, "Alice", "A@x"), (2, "bob", "null"), (3, "élan", "e@x")]
+        {
+            let command = format!("insert {} {} {}", id, username, email);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let results = |table: &mut Table, command: &str| -> Vec<Vec<Value>> {
+            let select = prepare_statement(command).unwrap();
+            select_results(&select, table).unwrap()
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+
+        assert_eq!(
+            results(&mut table, "select UPPER(username), lower(email), length(username), abs(-id)"),
+            vec![
+                vec![text("ALICE"), text("a@x"), Value::Integer(5), Value::Integer(1)],
+                vec![text("BOB"), Value::Null, Value::Integer(3), Value::Integer(2)],
+                vec![text("ÉLAN"), text("e@x"), Value::Integer(4), Value::Integer(3)],
+            ]
+        );
+        assert_eq!(
+            results(&mut table, "select abs(-9223372036854775807 - id) where id = 1"),
+            vec![vec![Value::Real(9223372036854775808.0)]]
+        );
+
+        for (arguments, substring) in [
+            ("2", "ello"),
+            ("2, 3", "ell"),
+            ("0, 2", "h"),
+            ("-3", "llo"),
+            ("-3, 2", "ll"),
+            ("3, -2", "he"),
+            ("1, -1", ""),
+            ("10", ""),
+            ("-10, 7", "he"),
+        ] {
+            let command = format!("select substr('hello', {}) where id = 1", arguments);
+            assert_eq!(results(&mut table, &command), vec![vec![text(substring)]], "{}", command);
+        }
+
+        let ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+        assert_eq!(ids(&mut table, "select where lower(username) = 'alice'"), [1]);
+        assert_eq!(ids(&mut table, "select where length(username) > 3 order by id desc"), [3, 1]);
+        assert_eq!(
+            ids(&mut table, "select where username = upper(substr(email, 1, 1)) || 'lice'"),
+            [1]
+        );
+
+        let select = prepare_statement("select substr(username, 1, length(email) - 1)").unwrap();
+        let names: Vec<String> = select.select_list.unwrap().iter().map(SelectItem::name).collect();
+        assert_eq!(names, ["substr(username, 1, length(email) - 1)"]);
+
+        for command in [
+            "select upper(id)",
+            "select abs(username)",
+            "select length()",
+            "select length(username, email)",
+            "select substr(username)",
+            "select nosuch(username)",
+            "select upper(username",
+            "select where length(username) = 'x'",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn date_functions() {
+        let mut table = db_open_new(&temp_path("testdb-dates.mysql"));
+        let insert = prepare_statement("insert 1 jdoe jdoe@example.com").unwrap();
+        assert!(execute_statement(&insert, &mut table).is_ok());
+
+        let value = |table: &mut Table, expression: &str| -> Value {
+            let select = prepare_statement(&format!("select {}", expression)).unwrap();
+            select_results(&select, table).unwrap().remove(0).remove(0)
+        };
+        let text = |s: &str| Value::Text(String::from(s));
+
+        assert_eq!(value(&mut table, "date(0)"), text("1970-01-01"));
+        assert_eq!(value(&mut table, "date(-1)"), text("1969-12-31"));
+        assert_eq!(value(&mut table, "date(951782400)"), text("2000-02-29"));
+        assert_eq!(value(&mut table, "date(253402300799)"), text("9999-12-31"));
+        assert_eq!(value(&mut table, "date(-62135596800)"), text("0001-01-01"));
+        assert_eq!(
+            value(&mut table, "strftime('%Y-%m-%dT%H:%M:%S', 1700000000)"),
+            text("2023-11-14T22:13:20")
+        );
+        assert_eq!(value(&mut table, "strftime('%s%%', 86400 * id)"), text("86400%"));
+        assert_eq!(value(&mut table, "strftime('%q', 0)"), Value::Null);
+        assert_eq!(value(&mut table, "strftime('%', 0)"), Value::Null);
+        assert_eq!(value(&mut table, "date(null)"), Value::Null);
+        assert!(value(&mut table, "now()") > Value::Integer(1_700_000_000));
+
+        for command in ["select date(username)", "select now(1)", "select strftime(0, '%Y')"] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn between_and_in() {
+        let mut table = db_open_new(&temp_path("testdb-between-in.mysql"));
+        for id in 1..=30 {
+            let email = if id == 4 { String::from("null") } else { format!("e{}", id % 3) };
+            let command = format!("insert {} user{} {}", id, id, email);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+        assert_eq!(ids(&mut table, "select where id between 10 and 13"), [10, 11, 12, 13]);
+        assert_eq!(ids(&mut table, "select where id between 13 and 10"), Vec::<u32>::new());
+        assert_eq!(ids(&mut table, "select where id >= 28"), [28, 29, 30]);
+        assert_eq!(ids(&mut table, "select where id <= 2 or id not between 3 and 29"), [1, 2, 30]);
+        assert_eq!(
+            ids(&mut table, "select where id between 1 and 5 and email between e1 and e2"),
+            [1, 2, 5]
+        );
+        assert_eq!(
+            ids(&mut table, "select where username in ('user3','user5', user7 , 'nobody')"),
+            [3, 5, 7]
+        );
+        assert_eq!(ids(&mut table, "select where id in (2,4) and email in ('e2', null)"), [2]);
+        assert_eq!(ids(&mut table, "select where id < 6 and email not in (e1, e2)"), [3]);
+
+        // BETWEEN on the id only reads the rows in its range.
+        let select = prepare_statement("select where id between 10 and 20").unwrap();
+        let conjuncts = select.where_clause.as_ref().unwrap().conjuncts();
+        let first_id = conjuncts.iter().map(|condition| condition.first_possible_id()).max();
+        assert_eq!(first_id, Some(10));
+        let past_last = |id: u32| {
+            let row = prepare_row(&[&id.to_string(), "user", "e"]).unwrap();
+            conjuncts.iter().any(|condition| condition.is_past_last_match(&row))
+        };
+        assert!(!past_last(20) && past_last(21));
+
+        for command in [
+            "select where id between 1",
+            "select where id between 1 or 2",
+            "select where id between 1 and -2",
+            "select where id in ()",
+            "select where id in (1, x)",
+            "select where id in 1, 2",
+            "select where id in (1, 2",
+        ] {
+            assert!(prepare_statement(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn like_patterns() {
+        for (text, pattern, matches) in [
+            ("jdoe", "jdoe", true),
+            ("jdoe", "JDOE", false),
+            ("jdoe", "j%", true),
+            ("jdoe", "%e", true),
+            ("jdoe", "%d%", true),
+            ("jdoe", "j_oe", true),
+            ("jdoe", "j_e", false),
+            ("jdoe", "%", true),
+            ("", "%", true),
+            ("", "_", false),
+            ("aaab", "%a%ab", true),
+            ("abcabd", "%ab_", true),
+            ("abcab", "a%b%c", false),
+            ("100%", "100\\%", true),
+            ("1000", "100\\%", false),
+            ("a_b", "a\\_b", true),
+            ("axb", "a\\_b", false),
+            ("a\\b", "a\\\\b", true),
+            ("a\\", "a\\", true),
+        ] {
+            assert_eq!(like_matches(text, pattern), matches, "{} like {}", text, pattern);
+        }
+
+        let mut table = db_open_new(&temp_path("testdb-like.mysql"));
+        for (id, username, email) in [(1, "alice", "a@x"), (2, "bob", "null"), (3, "al_x", "z")] {
+            let command = format!("insert {} {} {}", id, username, email);
+            let insert = prepare_statement(&command).unwrap();
+            assert!(execute_statement(&insert, &mut table).is_ok());
+        }
+
+        let ids = |table: &mut Table, command: &str| -> Vec<u32> {
+            let select = prepare_statement(command).unwrap();
+            select_rows(&select, table).unwrap().iter().map(|row| row.id()).collect()
+        };
+        assert_eq!(ids(&mut table, "select where username like 'al%'"), [1, 3]);
+        assert_eq!(ids(&mut table, "select where username LIKE al\\_%"), [3]);
+        assert_eq!(ids(&mut table, "select where email like '%@x'"), [1]);
+        assert_eq!(ids(&mut table, "select where not email like '%@x'"), [3]);
+
+        assert!(prepare_statement("select where id like 1").is_err());
+        assert!(prepare_statement("select where username like").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Helpers for the unit tests in the other modules.
+
+use std::env;
+use std::fs;
+
+use crate::btree::*;
+use crate::error::Result;
+use crate::exec::*;
+use crate::pager::*;
+use crate::parser::*;
+use crate::sql::*;
+
+
+/// Return the path of a file with the given name in the temporary directory, where the tests keep
+/// their databases.
+pub(crate) fn temp_path(name: &str) -> String {
+    String::from(env::temp_dir().join(name).to_str().unwrap())
+}
+
+
+/// Open a new, empty database at the given path, removing any that is already there.
+pub(crate) fn db_open_new(path: &str) -> Table {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(format!("{}-journal", path));
+    let _ = fs::remove_file(format!("{}-wal", path));
+    db_open(path).unwrap()
+}
+
+
+/// Build a row from the textual values of each of its columns, as an INSERT statement has
+/// them, or return None if they are invalid, including if a value does not have the type of
+/// its column.
+pub(crate) fn prepare_row(fields: &[&str]) -> Option<Row> {
+    if fields.len() != COLUMNS.len() {
+        return None;
+    }
+    let values = fields
+        .iter()
+        .zip(&COLUMNS)
+        .map(|(field, column)| parse_value(field, column).ok())
+        .collect::<Option<Vec<Value>>>()?;
+    let is_valid = values_fit_columns(&values) && is_valid_id(&values[ID_COLUMN]);
+    is_valid.then_some(Row { values, columns: None })
+}
+
+
+pub(crate) fn run(table: &mut Table, command: &str) -> Result<usize> {
+    execute_statement(&prepare_statement(command).unwrap(), table)
+}
+
+
+/// Leave the database as it would be if the process died: nothing more is written to it, its
+/// lock is released, and other connections no longer find its write-ahead log.
+pub(crate) fn crash(mut table: Table) {
+    table.pager.vfs.unlock(LockLevel::Unlocked).unwrap();
+    let key = wal_key(&table.pager.path);
+    WALS.lock().unwrap().retain(|(path, _)| *path != key);
+    std::mem::forget(table);
+}
+
+
+/// Return the length of the committed part of the database's write-ahead log.
+pub(crate) fn wal_length(table: &Table) -> usize {
+    table.pager.wal.as_ref().unwrap().shared.lock().unwrap().length
+}
+
+
+/// Assert that the subtree at the given page is well-formed, and return its keys in order.
+fn check_subtree(pager: &mut Pager, layout: TreeLayout, page_num: usize) -> Vec<Vec<u8>> {
+    let node = pager.get_page_ref(page_num).unwrap().to_vec();
+    match get_node_type(&node) {
+        NodeType::Leaf => (0..leaf_node_num_cells(&node))
+            .map(|i| leaf_node_key(&node, layout, i).to_vec())
+            .collect(),
+        NodeType::Internal => {
+            let mut keys = Vec::new();
+            for i in 0..=internal_node_num_keys(&node) {
+                let child = internal_node_child(&node, layout, i).unwrap();
+                assert_eq!(node_parent(pager.get_page_ref(child).unwrap()), page_num);
+
+
+                let child_keys = check_subtree(pager, layout, child);
+                if i < internal_node_num_keys(&node) {
+                    assert_eq!(child_keys.last().unwrap(), internal_node_key(&node, layout, i));
+                }
+                keys.extend(child_keys);
+            }
+            keys
+        },
+    }
+}
+
+
+/// Assert that the table's B-tree is well-formed, and return its ids in order.
+pub(crate) fn check_table(table: &mut Table) -> Vec<u32> {
+    let root_page_num = table.root_page_num;
+    let keys = check_subtree(&mut table.pager, TABLE_LAYOUT, root_page_num);
+    let row_count = header_row_count(table.pager.get_page_ref(HEADER_PAGE_NUM).unwrap());
+    assert_eq!(row_count as usize, keys.len());
+    keys.iter().map(|key| read_u32(key, 0)).collect()
+}
+
+
+/// Assert that the index's B-tree is well-formed and has an entry for each row of the table.
+pub(crate) fn check_index(table: &mut Table, index_num: usize) {
+    let tree = table.indexes[index_num].tree();
+    let keys = check_subtree(&mut table.pager, tree.layout, tree.root_page_num);
+
+
+    let select = prepare_statement("select").unwrap();
+    let rows = select_rows(&select, table).unwrap();
+    let index = &table.indexes[index_num];
+    let mut expected: Vec<Vec<u8>> = rows.iter().map(|row| index.key(row)).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+}
@@ -0,0 +1,387 @@
+//! Using the engine through `Database`, as a program that embeds it does.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use mysql::{Action, ColumnInfo, ColumnType, Database, Error, IndexInfo, Result};
+
+
+const PAGE_SIZE: usize = 4096;
+
+
+/// Return the path of a database with the given name in the temporary directory, removing any
+/// that is already there.
+fn new_path(name: &str) -> String {
+    let path = String::from(env::temp_dir().join(name).to_str().unwrap());
+    for suffix in ["", "-journal", "-wal"] {
+        let _ = fs::remove_file(format!("{}{}", path, suffix));
+    }
+    path
+}
+
+
+/// Return the ids of the rows of the table, in order.
+fn ids(database: &Database) -> Vec<u32> {
+    database.query("select id").unwrap().map(|row| row.unwrap().get("id").unwrap()).collect()
+}
+
+
+#[test]
+fn transaction_guard() {
+    let path = &new_path("testdb-transaction-guard.mysql");
+    let mut database = Database::open(path).unwrap();
+
+    let transaction = database.transaction().unwrap();
+    assert_eq!(transaction.execute("insert into users values (1, 'a', 'a@x')"), Ok(1));
+    assert!(transaction.commit().is_ok());
+    assert_eq!(ids(&database), vec![1]);
+
+    // A transaction that isn't committed is rolled back, however it's left.
+    let insert_and_fail = |database: &mut Database| -> Result<()> {
+        let transaction = database.transaction()?;
+        transaction.execute("insert into users values (2, 'b', 'b@x')")?;
+        transaction.execute("insert into users values (1, 'c', 'c@x')")?;
+        transaction.commit()
+    };
+    assert!(insert_and_fail(&mut database).is_err());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let transaction = database.transaction().unwrap();
+        transaction.execute("insert into users values (3, 'c', 'c@x')").unwrap();
+        panic!("the transaction is abandoned");
+    }));
+    assert!(result.is_err());
+    let transaction = database.transaction().unwrap();
+    transaction.rollback();
+    assert_eq!(database.execute("commit"), Err(Error::Invalid("no transaction is active")));
+    assert_eq!(ids(&database), vec![1]);
+
+    assert!(database.execute("begin").is_ok());
+    let error = Error::Invalid("a transaction is already active");
+    assert_eq!(database.transaction().err(), Some(error));
+}
+
+
+#[test]
+fn hooks() {
+    let path = &new_path("testdb-hooks.mysql");
+    let database = Database::open(path).unwrap();
+    let updates = Arc::new(Mutex::new(Vec::new()));
+    let updates_seen = Arc::clone(&updates);
+    database.update_hook(move |action, table, id| {
+        updates_seen.lock().unwrap().push((action, table.to_string(), id));
+    });
+    let commits = Arc::new(Mutex::new(0));
+    let commits_seen = Arc::clone(&commits);
+    database.commit_hook(move || {
+        *commits_seen.lock().unwrap() += 1;
+        false
+    });
+
+    for command in [
+        "insert into users values (1, 'a', 'a@x'), (2, 'b', 'b@x')",
+        "insert or replace into users values (2, 'c', 'c@x')",
+        "delete 1",
+        "delete 1",
+        "select * from users",
+    ] {
+        assert!(database.execute(command).is_ok());
+    }
+    let users = String::from("users");
+    assert_eq!(
+        *updates.lock().unwrap(),
+        vec![
+            (Action::Insert, users.clone(), 1),
+            (Action::Insert, users.clone(), 2),
+            (Action::Insert, users.clone(), 2),
+            (Action::Delete, users, 1),
+        ],
+    );
+    // Only the statements that changed the database were committed.
+    assert_eq!(*commits.lock().unwrap(), 3);
+
+    for command in ["begin", "insert into users values (3, 'd', 'd@x')", "commit"] {
+        assert!(database.execute(command).is_ok());
+    }
+    assert_eq!(*commits.lock().unwrap(), 4);
+
+    // A commit hook that returns true rolls the transaction back.
+    database.commit_hook(|| true);
+    let error = || Error::Constraint("the commit hook rolled back the transaction");
+    assert_eq!(database.execute("insert into users values (4, 'e', 'e@x')"), Err(error()));
+    for command in ["savepoint a", "insert into users values (5, 'f', 'f@x')"] {
+        assert!(database.execute(command).is_ok());
+    }
+    assert_eq!(database.execute("release a"), Err(error()));
+    assert_eq!(database.execute("commit"), Err(Error::Invalid("no transaction is active")));
+    assert_eq!(ids(&database), vec![2, 3]);
+}
+
+
+#[test]
+fn shared_connection() {
+    fn is_send_and_sync<T: Send + Sync>() {}
+    is_send_and_sync::<Database>();
+
+    let path = &new_path("testdb-shared.mysql");
+    let database = Arc::new(Database::open(path).unwrap());
+
+    // Threads can share one connection without locking it themselves.
+    let threads = (0..4).map(|i| {
+        let database = Arc::clone(&database);
+        thread::spawn(move || {
+            for id in (1..=25).map(|j| i * 25 + j) {
+                let command = format!("insert into users values ({}, 'user{}', 'e')", id, id);
+                assert_eq!(database.execute(&command), Ok(1));
+                assert!(database.query("select where id = 1").is_ok());
+            }
+        })
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let database = Arc::into_inner(database).unwrap();
+    assert_eq!(ids(&database), (1..=100).collect::<Vec<_>>());
+    let row = database.query("pragma integrity_check").unwrap().next().unwrap().unwrap();
+    assert_eq!(row.get::<String>("integrity_check"), Ok(String::from("ok")));
+}
+
+
+#[test]
+fn query() {
+    let path = &new_path("testdb-query.mysql");
+    let database = Database::open(path).unwrap();
+    for command in [
+        "insert into users values (1, 'a', 'a@x')",
+        "insert into users values (2, 'b', NULL)",
+    ] {
+        assert!(database.execute(command).is_ok());
+    }
+
+    // The results outlive the statement, so other statements can run while they're kept.
+    let rows = database.query("select * from users").unwrap().collect::<Result<Vec<_>>>();
+    assert!(database.execute("delete 1").is_ok());
+    let rows = rows.unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].get::<u32>("id"), Ok(1));
+    assert_eq!(rows[0].get::<String>("USERNAME"), Ok(String::from("a")));
+    assert_eq!(rows[1].get::<Option<String>>("email"), Ok(None));
+    assert_eq!(
+        rows[1].get::<String>("email"),
+        Err(Error::Invalid("value has the wrong type for the column")),
+    );
+    assert_eq!(rows[0].get::<u32>("name"), Err(Error::Invalid("no such column")));
+
+    // Results of a select list are named by its items.
+    let mut rows = database.query("select id, upper(username) from users").unwrap();
+    let row = rows.next().unwrap().unwrap();
+    assert_eq!(row.get::<i64>("id"), Ok(2));
+    assert_eq!(row.get::<String>("upper(username)"), Ok(String::from("B")));
+    assert!(rows.next().is_none());
+
+    assert!(matches!(database.query("delete 2"), Err(Error::Invalid(_))));
+    assert!(matches!(database.query("select from"), Err(Error::Parse(_))));
+}
+
+
+#[cfg(feature = "serde")]
+#[test]
+fn query_as_and_insert() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct User {
+        id: u32,
+        username: String,
+        email: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct NewUser<'a> {
+        username: &'a str,
+    }
+
+    let path = &new_path("testdb-serde.mysql");
+    let database = Database::open(path).unwrap();
+    let user = User { id: 1, username: String::from("a"), email: None };
+    assert_eq!(database.insert(&user), Ok(1));
+    // The id is assigned when the struct has no field for it.
+    assert_eq!(database.insert(&NewUser { username: "b" }), Ok(1));
+    assert_eq!(
+        database.insert(&NewUser { username: &"x".repeat(33) }),
+        Err(Error::Invalid("row does not fit the table's columns")),
+    );
+    assert!(matches!(database.insert(&(1, "c")), Err(Error::Conversion(_))));
+
+    let users = database.query_as::<User>("select * from users").unwrap();
+    let users = users.collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(users.len(), 2);
+    assert_eq!(users[0], user);
+    assert_eq!(users[1].username, "b");
+
+    // Fields are matched with the items of a select list by name, and a row can also be a
+    // tuple of its values.
+    #[derive(Deserialize)]
+    struct Name {
+        #[serde(rename = "upper(username)")]
+        name: String,
+    }
+    let mut names = database.query_as::<Name>("select upper(username) from users").unwrap();
+    assert_eq!(names.next().unwrap().unwrap().name, "A");
+    let query = "select id, username from users";
+    let mut rows = database.query_as::<(i64, String)>(query).unwrap();
+    assert_eq!(rows.next().unwrap(), Ok((1, String::from("a"))));
+
+    let mut names = database.query_as::<User>("select username from users").unwrap();
+    assert!(matches!(names.next().unwrap(), Err(Error::Conversion(_))));
+}
+
+
+#[test]
+fn schema() {
+    let path = &new_path("testdb-schema.mysql");
+    let database = Database::open(path).unwrap();
+    assert_eq!(database.tables(), vec![String::from("users")]);
+
+    let columns = database.columns("USERS").unwrap();
+    assert_eq!(columns.len(), 3);
+    assert_eq!(
+        columns[0],
+        ColumnInfo {
+            name: String::from("id"),
+            column_type: ColumnType::Integer,
+            max_length: None,
+            primary_key: true,
+        },
+    );
+    assert_eq!(columns[2].max_length, Some(255));
+    assert!(!columns[2].primary_key);
+    assert_eq!(database.columns("people"), Err(Error::Invalid("no such table")));
+
+    assert_eq!(database.indexes("users"), Ok(Vec::new()));
+    // An index created by another connection is seen too.
+    let other = Database::open(path).unwrap();
+    assert!(other.execute("create index email_index on users(email)").is_ok());
+    assert_eq!(
+        database.indexes("users"),
+        Ok(vec![IndexInfo {
+            name: String::from("email_index"),
+            table: String::from("users"),
+            column: String::from("email"),
+            expression: None,
+            where_clause: None,
+        }]),
+    );
+    assert_eq!(database.indexes("people"), Err(Error::Invalid("no such table")));
+}
+
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_database() {
+    use futures_core::Stream;
+    use std::future;
+    use std::pin::Pin;
+
+    use mysql::AsyncDatabase;
+
+    let path = &new_path("testdb-async.mysql");
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let database = AsyncDatabase::open(path).await.unwrap();
+        let other = database.clone();
+        assert_eq!(database.execute("insert into users values (1, 'a', 'a@x')").await, Ok(1));
+        assert_eq!(other.execute("insert into users values (2, 'b', NULL)").await, Ok(1));
+        assert!(matches!(database.execute("select from").await, Err(Error::Parse(_))));
+
+        let mut rows = database.query("select * from users").await.unwrap();
+        let mut ids = Vec::new();
+        while let Some(row) = future::poll_fn(|cx| Pin::new(&mut rows).poll_next(cx)).await {
+            ids.push(row.unwrap().get::<u32>("id").unwrap());
+        }
+        assert_eq!(ids, vec![1, 2]);
+
+        // Several statements can run on the connection at once, in a transaction.
+        let result = database
+            .call(|database| {
+                let transaction = database.transaction()?;
+                transaction.execute("delete 1")?;
+                transaction.execute("insert into users values (2, 'c', NULL)")?;
+                transaction.commit()
+            })
+            .await;
+        assert_eq!(result, Err(Error::Constraint("duplicate key")));
+        assert_eq!(database.call(|database| database.execute("delete 1")).await, Ok(1));
+    });
+}
+
+
+#[test]
+fn persisted_memory_database() {
+    let saved = Arc::new(Mutex::new(Vec::new()));
+    let persist = |saved: &Arc<Mutex<Vec<u8>>>| {
+        let saved = Arc::clone(saved);
+        move |contents: &[u8]| *saved.lock().unwrap() = contents.to_vec()
+    };
+    let database = Database::open_in_memory(Vec::new(), persist(&saved)).unwrap();
+    assert_eq!(saved.lock().unwrap().len(), 2 * PAGE_SIZE);
+    for command in ["insert into users values (1, 'a', 'a@x')", "delete 2", "vacuum"] {
+        assert!(database.execute(command).is_ok());
+    }
+    assert!(fs::metadata(":memory:-vacuum").is_err());
+
+    // What was saved is a database file, which can be opened again.
+    let contents = saved.lock().unwrap().clone();
+    let database = Database::open_in_memory(contents, persist(&saved)).unwrap();
+    assert_eq!(ids(&database), vec![1]);
+    assert!(database.execute("pragma synchronous = off").is_ok());
+    assert!(database.execute("insert into users values (2, 'b', 'b@x')").is_ok());
+    let contents = saved.lock().unwrap().clone();
+    let database = Database::open_in_memory(contents, |_: &[u8]| {}).unwrap();
+    assert_eq!(ids(&database), vec![1]);
+
+    let error = Database::open_in_memory(vec![1; PAGE_SIZE], |_: &[u8]| {}).err();
+    assert_eq!(error, Some(Error::Corrupt("file is not a database")));
+}
+
+
+#[test]
+fn open_options() {
+    let path = &new_path("testdb-options.mysql");
+    let error = Database::options().create(false).open(path).err().unwrap();
+    assert!(matches!(error, Error::Io(e) if e.kind() == io::ErrorKind::NotFound));
+    assert!(Database::options().read_only(true).open(path).is_err());
+    assert!(fs::metadata(path).is_err());
+
+    let mut options = Database::options();
+    options.cache_pages(16).page_size(PAGE_SIZE);
+    let writer = options.open(path).unwrap();
+    let row = writer.query("pragma cache_size").unwrap().next().unwrap().unwrap();
+    assert_eq!(row.get::<i64>("cache_size"), Ok(16));
+    assert!(writer.execute("insert into users values (1, 'a', 'a@x')").is_ok());
+
+    // A read-only connection can read what other connections write, but can't write itself.
+    let reader = Database::options().read_only(true).open(path).unwrap();
+    assert_eq!(reader.query("select id from users").unwrap().count(), 1);
+    for command in [
+        "insert into users values (2, 'b', 'b@x')",
+        "delete 1",
+        "create index username on users (username)",
+        "pragma journal_mode = wal",
+        "vacuum",
+    ] {
+        assert_eq!(reader.execute(command), Err(Error::ReadOnly), "{}", command);
+    }
+    assert!(reader.execute("begin").is_ok());
+    assert_eq!(reader.execute("delete 1"), Err(Error::ReadOnly));
+    assert!(reader.execute("rollback").is_ok());
+    assert!(writer.execute("insert into users values (2, 'b', 'b@x')").is_ok());
+    assert_eq!(reader.query("select id from users").unwrap().count(), 2);
+
+    let error = Error::Invalid("invalid number of pages");
+    assert_eq!(Database::options().cache_pages(0).open(path).err(), Some(error));
+    let error = Error::Invalid("unsupported page size");
+    assert_eq!(Database::options().page_size(8192).open(path).err(), Some(error));
+}
@@ -0,0 +1,61 @@
+//! Calling functions that a program defines from SQL.
+
+use std::env;
+use std::fs;
+
+use mysql::{Database, Error, Value};
+
+
+fn open_new(name: &str) -> Database {
+    let path = env::temp_dir().join(name);
+    let _ = fs::remove_file(&path);
+    Database::open(path.to_str().unwrap()).unwrap()
+}
+
+
+fn values(database: &Database, command: &str) -> Vec<Value> {
+    database.query(command).unwrap().map(|row| row.unwrap().values()[0].clone()).collect()
+}
+
+
+#[test]
+fn create_function() {
+    let database = open_new("mysql-test-functions.mysql");
+    database.execute("insert into users values (1, 'a', 'a@x'), (2, 'b', 'b@x')").unwrap();
+    database
+        .create_function("double", 1, |arguments| match arguments[0] {
+            Value::Integer(n) => Value::Integer(2 * n),
+            _ => Value::Null,
+        })
+        .unwrap();
+    assert_eq!(
+        values(&database, "select double(id) from users where double(id) > 2"),
+        [Value::Integer(4)],
+    );
+    assert_eq!(values(&database, "select DOUBLE(null) from users"), [Value::Null, Value::Null]);
+    let statement = database.prepare("select double(double(id)) from users").unwrap();
+    let (_, rows) = database.execute_statement_with_rows(&statement).unwrap();
+    assert_eq!(rows[1].values(), [Value::Integer(8)]);
+    database.execute("create view doubled as select * from users where double(id) = 4").unwrap();
+    assert_eq!(values(&database, "select id from doubled"), [Value::Integer(2)]);
+
+    // It is called with any type of value, and is only defined on the connection.
+    database.create_function("describe", 2, |arguments| {
+        Value::Text(format!("{:?} {:?}", arguments[0], arguments[1]))
+    }).unwrap();
+    assert_eq!(
+        values(&database, "select describe(username, 1.5) from users where id = 1"),
+        [Value::Text(String::from("\"a\" 1.5"))],
+    );
+    assert!(matches!(database.execute("select double(1, 2) from users"), Err(Error::Parse(_))));
+    let other = Database::open(":memory:").unwrap();
+    assert!(matches!(other.query("select double(id)").err(), Some(Error::Parse(_))));
+    assert_eq!(
+        database.create_function("upper", 1, |_| Value::Null).err(),
+        Some(Error::Invalid("cannot replace a built-in function")),
+    );
+
+    // Defining it again replaces it.
+    database.create_function("double", 1, |_| Value::Integer(0)).unwrap();
+    assert_eq!(values(&database, "select double(id) from users"), [0, 0].map(Value::Integer));
+}
@@ -0,0 +1,202 @@
+//! Keeping a database in storage that a program implements itself.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mysql::{Database, Error, LockLevel, Vfs};
+
+
+const PAGE_SIZE: usize = 4096;
+
+
+/// A database kept in a buffer, whose writes fail while `fail_writes` is set.
+#[derive(Default)]
+struct FlakyVfs {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    fail_writes: Arc<AtomicBool>,
+}
+
+
+impl Vfs for FlakyVfs {
+    fn open(_path: &str, _read_only: bool, _create: bool) -> io::Result<Self> {
+        Ok(FlakyVfs::default())
+    }
+
+    fn read_page(&mut self, page_num: usize, page: &mut [u8]) -> io::Result<()> {
+        let offset = page_num * page.len();
+        let bytes = self.bytes.lock().unwrap();
+        let source = bytes.get(offset..offset + page.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        page.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_num: usize, page: &[u8]) -> io::Result<()> {
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(io::Error::other("disk is full"));
+        }
+        let offset = page_num * page.len();
+        let mut bytes = self.bytes.lock().unwrap();
+        if bytes.len() < offset + page.len() {
+            bytes.resize(offset + page.len(), 0);
+        }
+        bytes[offset..offset + page.len()].copy_from_slice(page);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        Ok(self.bytes.lock().unwrap().len())
+    }
+
+    fn set_len(&mut self, length: usize) -> io::Result<()> {
+        self.bytes.lock().unwrap().resize(length, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+
+/// A database kept in a buffer that outlasts the connections opened on it, which can be locked, and
+/// can be made to fail writes.
+struct TestVfs {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    locked: bool,
+    fail_writes: bool,
+}
+
+
+impl Vfs for TestVfs {
+    fn open(_path: &str, _read_only: bool, _create: bool) -> io::Result<Self> {
+        Ok(TestVfs { bytes: Default::default(), locked: false, fail_writes: false })
+    }
+
+    fn read_page(&mut self, page_num: usize, page: &mut [u8]) -> io::Result<()> {
+        let offset = page_num * PAGE_SIZE;
+        page.copy_from_slice(&self.bytes.lock().unwrap()[offset..offset+PAGE_SIZE]);
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_num: usize, page: &[u8]) -> io::Result<()> {
+        if self.fail_writes {
+            return Err(io::Error::other("disk is full"));
+        }
+        let mut bytes = self.bytes.lock().unwrap();
+        let offset = page_num * PAGE_SIZE;
+        if bytes.len() < offset + PAGE_SIZE {
+            bytes.resize(offset + PAGE_SIZE, 0);
+        }
+        bytes[offset..offset+PAGE_SIZE].copy_from_slice(page);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        Ok(self.bytes.lock().unwrap().len())
+    }
+
+    fn set_len(&mut self, length: usize) -> io::Result<()> {
+        self.bytes.lock().unwrap().resize(length, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn lock(&mut self, _level: LockLevel) -> io::Result<bool> {
+        Ok(!self.locked)
+    }
+}
+
+
+/// Return the path of a file with the given name in the temporary directory.
+fn temp_path(name: &str) -> String {
+    String::from(env::temp_dir().join(name).to_str().unwrap())
+}
+
+
+fn ids(database: &Database) -> Vec<i64> {
+    database.query("select id").unwrap().map(|row| row.unwrap().get("id").unwrap()).collect()
+}
+
+
+#[test]
+fn write_errors() {
+    let vfs = FlakyVfs::default();
+    let bytes = vfs.bytes.clone();
+    let fail_writes = vfs.fail_writes.clone();
+    let database = Database::open_with_vfs("flaky.mysql", Box::new(vfs)).unwrap();
+    assert_eq!(database.execute("insert into users values (1, 'a', 'a@x')").unwrap(), 1);
+    assert!(!bytes.lock().unwrap().is_empty());
+
+    // The statement whose write fails changes nothing.
+    fail_writes.store(true, Ordering::SeqCst);
+    let result = database.execute("insert into users values (2, 'b', 'b@x')");
+    assert!(matches!(result, Err(Error::Io(_))));
+    fail_writes.store(false, Ordering::SeqCst);
+    assert_eq!(ids(&database), [1]);
+    assert_eq!(database.execute("insert into users values (3, 'c', 'c@x')").unwrap(), 1);
+    assert_eq!(ids(&database), [1, 3]);
+}
+
+
+#[test]
+fn custom_vfs() {
+    let path = &temp_path("testdb-vfs.mysql");
+    let vfs = TestVfs::open(path, false, true).unwrap();
+    let bytes = vfs.bytes.clone();
+    let database = Database::open_with_vfs(path, Box::new(vfs)).unwrap();
+    assert!(database.execute("insert into users values (1, 'a', 'a@x')").is_ok());
+    drop(database);
+    assert_eq!(bytes.lock().unwrap().len(), 2 * PAGE_SIZE);
+    assert!(fs::metadata(path).is_err());
+
+    let vfs = TestVfs { bytes: bytes.clone(), locked: false, fail_writes: false };
+    let database = Database::open_with_vfs(path, Box::new(vfs)).unwrap();
+    assert_eq!(ids(&database), [1]);
+    drop(database);
+
+    let vfs = TestVfs { bytes, locked: true, fail_writes: false };
+    assert_eq!(Database::open_with_vfs(path, Box::new(vfs)).err(), Some(Error::Locked));
+}
+
+
+#[test]
+fn journal_after_write_errors() {
+    let path = &temp_path("testdb-vfs-errors.mysql");
+    let journal_path = format!("{}-journal", path);
+    let _ = fs::remove_file(&journal_path);
+    let mut vfs = TestVfs::open(path, false, true).unwrap();
+    vfs.fail_writes = true;
+    assert!(matches!(Database::open_with_vfs(path, Box::new(vfs)), Err(Error::Io(_))));
+    let _ = fs::remove_file(&journal_path);
+
+    let vfs = TestVfs::open(path, false, true).unwrap();
+    let bytes = vfs.bytes.clone();
+    let database = Database::open_with_vfs(path, Box::new(vfs)).unwrap();
+    assert!(database.execute("insert into users values (1, 'a', 'a@x')").is_ok());
+    drop(database);
+
+    // The write that fails leaves its journal behind, which puts the database back the way it was
+    // the next time it's opened.
+    let vfs = TestVfs { bytes: bytes.clone(), locked: false, fail_writes: true };
+    let database = Database::open_with_vfs(path, Box::new(vfs)).unwrap();
+    let result = database.execute("insert into users values (2, 'b', 'b@x')");
+    assert!(matches!(result, Err(Error::Io(_))));
+    assert!(fs::metadata(&journal_path).is_ok());
+    drop(database);
+
+    let vfs = TestVfs { bytes, locked: false, fail_writes: false };
+    let database = Database::open_with_vfs(path, Box::new(vfs)).unwrap();
+    assert_eq!(database.recovered().len(), 1);
+    assert_eq!(ids(&database), [1]);
+}